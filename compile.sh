@@ -1,3 +1,16 @@
 #!/usr/bin/bash
+# The QuickJS version and the build time are reported by the `__engine_info` query.
+QUICKJS_VERSION="$(cat "$(find ~/.cargo/git/checkouts -path '*quickjs-wasm-sys/quickjs/VERSION' | head -n 1)" 2>/dev/null)"
+export QUICKJS_VERSION="${QUICKJS_VERSION:-unknown}"
+export BUILD_TIMESTAMP="$(date +%s)"
 QUICKJS_WASM_SYS_WASI_SDK_PATH="/opt/wasi-sdk" CC_wasm32_wasi="/opt/wasi-sdk/bin/clang" cargo build --release --target=wasm32-wasi
 wasi2ic ./target/wasm32-wasi/release/quickjs.wasm ic.wasm
+
+# Public metadata sections let `dfx canister metadata` and other tools audit
+# the deployed script. Hot script uploads are mirrored by the `script_metadata`
+# query.
+ic-wasm ic.wasm -o ic.wasm metadata script:sha256 -d "$(sha256sum src/ic.js | cut -d ' ' -f 1)" -v public
+ic-wasm ic.wasm -o ic.wasm metadata engine:version -d "$(cargo pkgid | cut -d '#' -f 2)" -v public
+if [ -f quickjs.did ]; then
+    ic-wasm ic.wasm -o ic.wasm metadata candid:service -f quickjs.did -v public
+fi
@@ -0,0 +1,156 @@
+// A certified append-only audit log exposed to JS as `ic.audit.record(action,
+// data)`.
+//
+// Entries are appended to a stable log and form a hash chain: the hash of an
+// entry is `sha256(prev_hash || seq || time || caller || action || data)` where
+// `seq` and `time` are 8-byte big-endian numbers, the other fields are
+// prefixed with their 4-byte big-endian length, `data` is the JSON encoding and
+// the `prev_hash` of the first entry is all zeros. The hash of each entry is
+// certified under the path `audit/<seq as 8 big-endian bytes>`, so the
+// `audit_log` query returns entries with a certificate and a witness that
+// proves their inclusion.
+use std::cell::RefCell;
+
+use candid::{CandidType, Deserialize, Principal};
+use ic_stable_structures::StableLog;
+use quickjs_wasm_rs::{CallbackArg, JSContextRef, JSError, JSValueRef};
+
+use crate::{
+    certification, crypto, engine, json,
+    stable::{self, Candid, Memory},
+};
+
+// The label of the subtree of audit entries in the certified state.
+const LABEL: &str = "audit";
+
+/// A recorded action.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct AuditEntry {
+    pub seq: u64,
+    pub time: u64,
+    pub caller: Principal,
+    pub action: String,
+    /// The JSON-encoded data of the action.
+    pub data: String,
+    pub prev_hash: Vec<u8>,
+    pub hash: Vec<u8>,
+}
+
+/// A range of entries with the proof of their inclusion.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct AuditLogPage {
+    pub entries: Vec<AuditEntry>,
+    /// The total number of entries in the log.
+    pub length: u64,
+    pub certificate: Vec<u8>,
+    /// The CBOR-encoded hash tree that proves the hashes of the entries.
+    pub witness: Vec<u8>,
+}
+
+thread_local! {
+    static LOG: RefCell<StableLog<Candid<AuditEntry>, Memory, Memory>> = RefCell::new(
+        StableLog::init(stable::memory(stable::AUDIT_INDEX), stable::memory(stable::AUDIT_DATA)).unwrap()
+    );
+}
+
+fn hash(entry: &AuditEntry) -> [u8; 32] {
+    let mut bytes = entry.prev_hash.clone();
+    bytes.extend_from_slice(&entry.seq.to_be_bytes());
+    bytes.extend_from_slice(&entry.time.to_be_bytes());
+    for field in [
+        entry.caller.as_slice(),
+        entry.action.as_bytes(),
+        entry.data.as_bytes(),
+    ] {
+        bytes.extend_from_slice(&(field.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(field);
+    }
+    crypto::sha256(&bytes)
+}
+
+/// Appends an entry to the log and returns its sequence number.
+pub fn record(action: String, data: &serde_json::Value) -> Result<u64, String> {
+    LOG.with(|log| {
+        let log = log.borrow();
+        let seq = log.len();
+        let prev_hash = match seq.checked_sub(1).and_then(|last| log.get(last)) {
+            Some(Candid(last)) => last.hash,
+            None => vec![0; 32],
+        };
+        let mut entry = AuditEntry {
+            seq,
+            time: ic_cdk::api::time(),
+            caller: ic_cdk::caller(),
+            action,
+            data: data.to_string(),
+            prev_hash,
+            hash: vec![],
+        };
+        let hash = hash(&entry);
+        entry.hash = hash.to_vec();
+        log.append(&Candid(entry))
+            .map_err(|err| format!("Failed to append to the audit log: {:?}", err))?;
+        certification::insert(LABEL, seq.to_be_bytes().to_vec(), hash);
+        Ok(seq)
+    })
+}
+
+/// Returns at most `limit` entries starting from the given one with their
+/// proof. Must be called in a query call.
+pub fn page(start: u64, limit: u64) -> Result<AuditLogPage, String> {
+    LOG.with(|log| {
+        let log = log.borrow();
+        let length = log.len();
+        let end = start.saturating_add(limit).min(length);
+        let entries: Vec<_> = (start..end)
+            .filter_map(|seq| log.get(seq))
+            .map(|Candid(entry)| entry)
+            .collect();
+        let last = end.max(start.saturating_add(1)) - 1;
+        Ok(AuditLogPage {
+            entries,
+            length,
+            certificate: certification::certificate()?,
+            witness: certification::encode(&certification::range_witness(
+                LABEL,
+                &start.to_be_bytes(),
+                &last.to_be_bytes(),
+            )),
+        })
+    })
+}
+
+/// Certifies all entries again. The certified state is not persisted, so this
+/// must be called after an upgrade.
+pub fn recertify() {
+    LOG.with(|log| {
+        for Candid(entry) in log.borrow().iter() {
+            let hash: [u8; 32] = entry.hash.as_slice().try_into().unwrap();
+            certification::insert(LABEL, entry.seq.to_be_bytes().to_vec(), hash);
+        }
+    });
+}
+
+pub fn link(context: &JSContextRef) -> Result<(), anyhow::Error> {
+    // Returns the sequence number of the recorded entry.
+    fn record<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 2 {
+            return Err(JSError::Type(format!("Expected 2 arguments, got {}", args.len())).into());
+        }
+        engine::ensure_writable("ic.audit.record()")?;
+        let action: String = args[0].try_into()?;
+        let data = json::to_json(&args[1].to_js_value()?);
+        let seq = self::record(action, &data).map_err(anyhow::Error::msg)?;
+        context.value_from_f64(seq as f64)
+    }
+
+    let audit = context.object_value()?;
+    audit.set_property("record", context.wrap_callback2(record)?)?;
+
+    engine::namespace(context)?.set_property("audit", audit)?;
+    Ok(())
+}
@@ -0,0 +1,191 @@
+// Verification of JSON Web Tokens signed with HS256, ES256 or EdDSA (Ed25519).
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use quickjs_wasm_rs::JSValue;
+use serde_json::Value;
+use sha2::Sha256;
+
+use crate::eth::abi;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Algorithm {
+    HS256,
+    ES256,
+    EdDSA,
+}
+
+impl Algorithm {
+    fn parse(name: &str) -> Result<Self, anyhow::Error> {
+        match name {
+            "HS256" => Ok(Algorithm::HS256),
+            "ES256" => Ok(Algorithm::ES256),
+            "EdDSA" => Ok(Algorithm::EdDSA),
+            _ => anyhow::bail!("Unsupported JWT algorithm {}", name),
+        }
+    }
+}
+
+/// A key that verifies tokens. The material is the secret for HS256, the SEC1
+/// encoded public key for ES256 and the 32-byte public key for EdDSA.
+pub struct Key {
+    pub kid: Option<String>,
+    pub alg: Algorithm,
+    pub material: Vec<u8>,
+}
+
+impl Key {
+    /// Parses a key from a JS object. Accepts `{alg, kid, secret}` for HS256,
+    /// `{alg, kid, publicKey}` for ES256 and EdDSA, and public JWKs with the
+    /// `EC` (P-256) and `OKP` (Ed25519) key types.
+    pub fn from_js(value: &JSValue) -> Result<Self, anyhow::Error> {
+        let JSValue::Object(fields) = value else {
+            anyhow::bail!("Expected a key to be an object");
+        };
+        let string = |name: &str| match fields.get(name) {
+            Some(JSValue::String(value)) => Some(value.clone()),
+            _ => None,
+        };
+        let kid = string("kid");
+        if let Some(kty) = string("kty") {
+            let coordinate = |name: &str| -> Result<Vec<u8>, anyhow::Error> {
+                let value =
+                    string(name).ok_or_else(|| anyhow::anyhow!("The JWK is missing {}", name))?;
+                Ok(URL_SAFE_NO_PAD.decode(value)?)
+            };
+            return match (kty.as_str(), string("crv").as_deref()) {
+                ("EC", Some("P-256")) => {
+                    let mut material = vec![0x04];
+                    material.extend(coordinate("x")?);
+                    material.extend(coordinate("y")?);
+                    Ok(Key {
+                        kid,
+                        alg: Algorithm::ES256,
+                        material,
+                    })
+                }
+                ("OKP", Some("Ed25519")) => Ok(Key {
+                    kid,
+                    alg: Algorithm::EdDSA,
+                    material: coordinate("x")?,
+                }),
+                (kty, crv) => anyhow::bail!("Unsupported JWK with kty {} and crv {:?}", kty, crv),
+            };
+        }
+        let alg = Algorithm::parse(
+            &string("alg").ok_or_else(|| anyhow::anyhow!("The key is missing alg"))?,
+        )?;
+        let material = match (alg, fields.get("secret"), fields.get("publicKey")) {
+            (Algorithm::HS256, Some(JSValue::String(secret)), _) => secret.clone().into_bytes(),
+            (Algorithm::HS256, Some(JSValue::ArrayBuffer(secret)), _) => secret.clone(),
+            (Algorithm::ES256 | Algorithm::EdDSA, _, Some(public_key)) => abi::bytes(public_key)?,
+            (Algorithm::HS256, _, _) => anyhow::bail!("Expected an HS256 key to have a secret"),
+            _ => anyhow::bail!("Expected an {:?} key to have a publicKey", alg),
+        };
+        Ok(Key { kid, alg, material })
+    }
+
+    fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+        match self.alg {
+            Algorithm::HS256 => {
+                let mut mac = Hmac::<Sha256>::new_from_slice(&self.material).unwrap();
+                mac.update(message);
+                mac.verify_slice(signature).is_ok()
+            }
+            Algorithm::ES256 => {
+                use p256::ecdsa::{signature::Verifier, Signature, VerifyingKey};
+                let (Ok(key), Ok(signature)) = (
+                    VerifyingKey::from_sec1_bytes(&self.material),
+                    Signature::from_slice(signature),
+                ) else {
+                    return false;
+                };
+                key.verify(message, &signature).is_ok()
+            }
+            Algorithm::EdDSA => {
+                use ed25519_dalek::{Signature, VerifyingKey};
+                let (Ok(key), Ok(signature)) = (
+                    <[u8; 32]>::try_from(self.material.as_slice()),
+                    Signature::from_slice(signature),
+                ) else {
+                    return false;
+                };
+                VerifyingKey::from_bytes(&key)
+                    .is_ok_and(|key| key.verify_strict(message, &signature).is_ok())
+            }
+        }
+    }
+}
+
+/// The claim checks in addition to the expiration and not-before times.
+#[derive(Default)]
+pub struct Options {
+    pub issuer: Option<String>,
+    pub audience: Option<String>,
+    /// The allowed clock skew in seconds.
+    pub leeway: u64,
+}
+
+/// Verifies the signature and the claims of the given token at the given time
+/// in seconds and returns its claims.
+pub fn verify(
+    token: &str,
+    keys: &[Key],
+    options: &Options,
+    now: u64,
+) -> Result<Value, anyhow::Error> {
+    let Some((message, signature)) = token.rsplit_once('.') else {
+        anyhow::bail!("Malformed JWT: expected three parts");
+    };
+    let Some((header, payload)) = message
+        .split_once('.')
+        .filter(|(_, payload)| !payload.contains('.'))
+    else {
+        anyhow::bail!("Malformed JWT: expected three parts");
+    };
+    let header: Value = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(header)?)?;
+    let alg = Algorithm::parse(header["alg"].as_str().unwrap_or_default())?;
+    let kid = header["kid"].as_str();
+    let signature = URL_SAFE_NO_PAD.decode(signature)?;
+    let is_valid = keys
+        .iter()
+        .filter(|key| key.alg == alg)
+        .filter(|key| kid.is_none() || key.kid.is_none() || key.kid.as_deref() == kid)
+        .any(|key| key.verify(message.as_bytes(), &signature));
+    if !is_valid {
+        anyhow::bail!("Invalid JWT signature");
+    }
+
+    let claims: Value = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload)?)?;
+    if let Some(exp) = claims.get("exp") {
+        let exp = exp
+            .as_f64()
+            .ok_or_else(|| anyhow::anyhow!("Expected exp to be a number"))?;
+        if exp + options.leeway as f64 <= now as f64 {
+            anyhow::bail!("The JWT has expired");
+        }
+    }
+    if let Some(nbf) = claims.get("nbf") {
+        let nbf = nbf
+            .as_f64()
+            .ok_or_else(|| anyhow::anyhow!("Expected nbf to be a number"))?;
+        if nbf > (now + options.leeway) as f64 {
+            anyhow::bail!("The JWT is not valid yet");
+        }
+    }
+    if let Some(issuer) = &options.issuer {
+        if claims["iss"].as_str() != Some(issuer) {
+            anyhow::bail!("Unexpected JWT issuer");
+        }
+    }
+    if let Some(audience) = &options.audience {
+        let matches = match &claims["aud"] {
+            Value::String(aud) => aud == audience,
+            Value::Array(auds) => auds.iter().any(|aud| aud.as_str() == Some(audience)),
+            _ => false,
+        };
+        if !matches {
+            anyhow::bail!("Unexpected JWT audience");
+        }
+    }
+    Ok(claims)
+}
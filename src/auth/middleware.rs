@@ -0,0 +1,137 @@
+// The identity of the sender of a request and the per-endpoint switch that
+// rejects anonymous senders.
+//
+// The IC validates the delegation chain and the expiry of ingress messages, so
+// the caller of a Candid endpoint is already the effective identity. HTTP
+// requests arrive through the gateway from the anonymous principal. Their
+// identity comes from an `Authorization: Bearer <token>` header that carries
+// either an `ic.sessions` token or a JWT verified with the keys configured by
+// `ic.auth.configure({jwt: {keys, issuer, audience, leeway}})`. Expired and
+// invalid tokens are rejected rather than treated as anonymous.
+use std::{cell::RefCell, collections::HashSet};
+
+use candid::Principal;
+use quickjs_wasm_rs::{JSContextRef, JSValueRef};
+use serde_json::Value;
+
+use super::jwt;
+use crate::{json, sessions};
+
+/// The effective identity of the sender of a request.
+#[derive(Clone, Debug)]
+pub enum Identity {
+    /// The caller of a Candid endpoint.
+    Caller(Principal),
+    /// The sender of an HTTP request with a valid session token.
+    Session { id: String, data: Value },
+    /// The sender of an HTTP request with a valid JWT.
+    Jwt(Value),
+}
+
+impl Identity {
+    /// Returns the identity of the caller of the current message.
+    pub fn caller() -> Self {
+        Identity::Caller(ic_cdk::caller())
+    }
+
+    pub fn is_anonymous(&self) -> bool {
+        matches!(self, Identity::Caller(principal) if *principal == Principal::anonymous())
+    }
+
+    /// Returns a stable key of the sender, e.g. for per-sender counters.
+    pub fn key(&self) -> String {
+        match self {
+            Identity::Caller(principal) => principal.to_text(),
+            Identity::Session { id, .. } => format!("session:{}", id),
+            Identity::Jwt(claims) => format!(
+                "jwt:{}:{}",
+                claims["iss"].as_str().unwrap_or_default(),
+                claims["sub"].as_str().unwrap_or_default()
+            ),
+        }
+    }
+
+    pub fn to_js<'a>(&self, context: &'a JSContextRef) -> Result<JSValueRef<'a>, anyhow::Error> {
+        let js = context.object_value()?;
+        js.set_property("isAnonymous", context.value_from_bool(self.is_anonymous())?)?;
+        let source = match self {
+            Identity::Caller(principal) => {
+                js.set_property("principal", context.value_from_str(&principal.to_text())?)?;
+                "caller"
+            }
+            Identity::Session { data, .. } => {
+                js.set_property(
+                    "session",
+                    quickjs_wasm_rs::to_qjs_value(context, &json::from_json(data))?,
+                )?;
+                "session"
+            }
+            Identity::Jwt(claims) => {
+                js.set_property(
+                    "claims",
+                    quickjs_wasm_rs::to_qjs_value(context, &json::from_json(claims))?,
+                )?;
+                "jwt"
+            }
+        };
+        js.set_property("source", context.value_from_str(source)?)?;
+        // HTTP senders have no principal of their own: the caller is the
+        // gateway. The subject identifies them instead.
+        js.set_property("subject", context.value_from_str(&self.key())?)?;
+        Ok(js)
+    }
+}
+
+thread_local! {
+    // The endpoints, JSON-RPC methods and HTTP routes that reject anonymous
+    // senders.
+    static AUTHENTICATED: RefCell<HashSet<String>> = RefCell::new(HashSet::new());
+
+    static JWT_KEYS: RefCell<Vec<jwt::Key>> = RefCell::new(vec![]);
+    static JWT_OPTIONS: RefCell<jwt::Options> = RefCell::new(jwt::Options::default());
+}
+
+pub fn configure_jwt(keys: Vec<jwt::Key>, options: jwt::Options) {
+    JWT_KEYS.with(|cell| *cell.borrow_mut() = keys);
+    JWT_OPTIONS.with(|cell| *cell.borrow_mut() = options);
+}
+
+/// Makes the given endpoint, JSON-RPC method or HTTP route such as
+/// `POST /orders` reject anonymous senders.
+pub fn reject_anonymous(endpoint: String) {
+    AUTHENTICATED.with(|authenticated| authenticated.borrow_mut().insert(endpoint));
+}
+
+/// Returns an error if the given endpoint rejects the given identity.
+pub fn check(endpoint: &str, identity: &Identity) -> Result<(), String> {
+    let is_rejected = identity.is_anonymous()
+        && AUTHENTICATED.with(|authenticated| authenticated.borrow().contains(endpoint));
+    if is_rejected {
+        return Err(format!("{} rejects anonymous callers", endpoint));
+    }
+    Ok(())
+}
+
+/// Returns the identity of the sender of an HTTP request with the given
+/// `Authorization` header.
+pub fn http_identity(authorization: Option<&str>) -> Result<Identity, String> {
+    let Some(token) = authorization.and_then(|value| value.strip_prefix("Bearer ")) else {
+        return Ok(Identity::caller());
+    };
+    let token = token.trim();
+    if let Some(data) = sessions::get(token) {
+        let id = token.split('.').next().unwrap_or_default().to_string();
+        return Ok(Identity::Session { id, data });
+    }
+    let has_jwt_keys = JWT_KEYS.with(|keys| !keys.borrow().is_empty());
+    if !has_jwt_keys {
+        return Err("Invalid or expired session token".to_string());
+    }
+    let now = ic_cdk::api::time() / 1_000_000_000;
+    JWT_KEYS
+        .with(|keys| {
+            JWT_OPTIONS.with(|options| jwt::verify(token, &keys.borrow(), &options.borrow(), now))
+        })
+        .map(Identity::Jwt)
+        .map_err(|err| err.to_string())
+}
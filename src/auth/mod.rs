@@ -0,0 +1,196 @@
+// Authentication helpers exposed to JS as `ic.auth`.
+//
+// `ic.auth.verifyJwt(token, keys, options)` verifies a bearer token and returns
+// its claims or throws. The keys are an array of key objects or JWKs, e.g.
+// `[{alg: "HS256", secret}]` or the `keys` of a JWKS document. The options are
+// `{issuer, audience, leeway}` with the leeway in seconds.
+//
+// `ic.auth.verifySiwe(message, signature, options)` verifies a Sign-In with
+// Ethereum message and returns its fields including the checksummed `address`
+// of the wallet. The options are `{domain, nonce}`. JS code typically issues
+// the nonce, verifies the message and then creates a session for the address
+// with `ic.sessions.create()`.
+//
+// `ic.auth.identity()` returns the identity of the caller of the current
+// message. HTTP handlers get the identity of the sender in `request.identity`.
+// `ic.auth.rejectAnonymous(endpoint)` makes the given endpoint, JSON-RPC
+// method or HTTP route reject anonymous senders.
+use quickjs_wasm_rs::{CallbackArg, JSContextRef, JSError, JSValue, JSValueRef};
+
+use crate::{
+    engine,
+    eth::{abi, address},
+    jobs, json,
+};
+
+pub mod jwt;
+pub mod middleware;
+pub mod siwe;
+
+pub use middleware::Identity;
+
+pub fn link(context: &JSContextRef) -> Result<(), anyhow::Error> {
+    fn verify_jwt<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() < 2 || args.len() > 3 {
+            return Err(
+                JSError::Type(format!("Expected 2 or 3 arguments, got {}", args.len())).into(),
+            );
+        }
+        let token: String = args[0].try_into()?;
+        let keys = match args[1].to_js_value()? {
+            JSValue::Array(keys) => keys
+                .iter()
+                .map(jwt::Key::from_js)
+                .collect::<Result<Vec<_>, _>>()?,
+            key => vec![jwt::Key::from_js(&key)?],
+        };
+        let options = match args.get(2) {
+            Some(arg) => jwt_options(&arg.to_js_value()?)?,
+            None => jwt::Options::default(),
+        };
+        let now = ic_cdk::api::time() / 1_000_000_000;
+        let claims = jwt::verify(&token, &keys, &options, now)?;
+        quickjs_wasm_rs::to_qjs_value(context, &json::from_json(&claims))
+    }
+
+    fn verify_siwe<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() < 2 || args.len() > 3 {
+            return Err(
+                JSError::Type(format!("Expected 2 or 3 arguments, got {}", args.len())).into(),
+            );
+        }
+        let message: String = args[0].try_into()?;
+        let signature = abi::bytes(&args[1].to_js_value()?)?;
+        let mut options = siwe::Options::default();
+        if let Some(JSValue::Object(fields)) =
+            args.get(2).map(|arg| arg.to_js_value()).transpose()?
+        {
+            if let Some(JSValue::String(domain)) = fields.get("domain") {
+                options.domain = Some(domain.clone());
+            }
+            if let Some(JSValue::String(nonce)) = fields.get("nonce") {
+                options.nonce = Some(nonce.clone());
+            }
+        }
+        let now = (ic_cdk::api::time() / 1_000_000_000) as i64;
+        let message = siwe::verify(&message, &signature, &options, now)?;
+        let js = context.object_value()?;
+        js.set_property("domain", context.value_from_str(&message.domain)?)?;
+        js.set_property(
+            "address",
+            context.value_from_str(&address::to_checksum(&message.address))?,
+        )?;
+        if let Some(statement) = &message.statement {
+            js.set_property("statement", context.value_from_str(statement)?)?;
+        }
+        js.set_property("uri", context.value_from_str(&message.uri)?)?;
+        js.set_property("chainId", context.value_from_f64(message.chain_id as f64)?)?;
+        js.set_property("nonce", context.value_from_str(&message.nonce)?)?;
+        js.set_property("issuedAt", context.value_from_str(&message.issued_at)?)?;
+        if let Some(expiration_time) = &message.expiration_time {
+            js.set_property("expirationTime", context.value_from_str(expiration_time)?)?;
+        }
+        if let Some(not_before) = &message.not_before {
+            js.set_property("notBefore", context.value_from_str(not_before)?)?;
+        }
+        if let Some(request_id) = &message.request_id {
+            js.set_property("requestId", context.value_from_str(request_id)?)?;
+        }
+        let resources = context.array_value()?;
+        for resource in message.resources.iter() {
+            resources.append_property(context.value_from_str(resource)?)?;
+        }
+        js.set_property("resources", resources)?;
+        Ok(js)
+    }
+
+    fn identity<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if !args.is_empty() {
+            return Err(JSError::Type(format!("Expected 0 arguments, got {}", args.len())).into());
+        }
+        Identity::caller().to_js(context)
+    }
+
+    fn reject_anonymous<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 1 {
+            return Err(JSError::Type(format!("Expected 1 argument, got {}", args.len())).into());
+        }
+        let endpoint: String = args[0].try_into()?;
+        middleware::reject_anonymous(endpoint);
+        context.undefined_value()
+    }
+
+    // Usage: `ic.auth.configure({jwt: {keys, issuer, audience, leeway}})`.
+    fn configure<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 1 {
+            return Err(JSError::Type(format!("Expected 1 argument, got {}", args.len())).into());
+        }
+        let JSValue::Object(fields) = args[0].to_js_value()? else {
+            return Err(
+                JSError::Type("Expected the configuration to be an object".to_string()).into(),
+            );
+        };
+        if let Some(jwt) = fields.get("jwt") {
+            let keys = match jwt {
+                JSValue::Object(fields) => fields.get("keys"),
+                _ => None,
+            };
+            let Some(JSValue::Array(keys)) = keys else {
+                return Err(JSError::Type("Expected jwt.keys to be an array".to_string()).into());
+            };
+            let keys = keys
+                .iter()
+                .map(jwt::Key::from_js)
+                .collect::<Result<Vec<_>, _>>()?;
+            middleware::configure_jwt(keys, jwt_options(jwt)?);
+        }
+        context.undefined_value()
+    }
+
+    let auth = context.object_value()?;
+    auth.set_property("verifyJwt", context.wrap_callback2(verify_jwt)?)?;
+    auth.set_property("verifySiwe", context.wrap_callback2(verify_siwe)?)?;
+    auth.set_property("identity", context.wrap_callback2(identity)?)?;
+    auth.set_property("rejectAnonymous", context.wrap_callback2(reject_anonymous)?)?;
+    auth.set_property("configure", context.wrap_callback2(configure)?)?;
+
+    engine::namespace(context)?.set_property("auth", auth)?;
+    Ok(())
+}
+
+// Parses the claim checks `{issuer, audience, leeway}`.
+fn jwt_options(value: &JSValue) -> Result<jwt::Options, anyhow::Error> {
+    let mut options = jwt::Options::default();
+    if let JSValue::Object(fields) = value {
+        if let Some(JSValue::String(issuer)) = fields.get("issuer") {
+            options.issuer = Some(issuer.clone());
+        }
+        if let Some(JSValue::String(audience)) = fields.get("audience") {
+            options.audience = Some(audience.clone());
+        }
+        if let Some(leeway) = fields.get("leeway") {
+            options.leeway = jobs::number(leeway, "leeway")? as u64;
+        }
+    }
+    Ok(options)
+}
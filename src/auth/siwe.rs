@@ -0,0 +1,206 @@
+// Sign-In with Ethereum (EIP-4361) messages signed with `personal_sign`
+// (EIP-191).
+use ethabi::Address;
+use k256::{
+    ecdsa::{RecoveryId, Signature, VerifyingKey},
+    elliptic_curve::sec1::ToEncodedPoint,
+};
+
+use crate::{crypto, eth::address};
+
+const HEADER_SUFFIX: &str = " wants you to sign in with your Ethereum account:";
+
+/// A parsed sign-in message.
+#[derive(Debug, Default)]
+pub struct Message {
+    pub domain: String,
+    pub address: Address,
+    pub statement: Option<String>,
+    pub uri: String,
+    pub version: String,
+    pub chain_id: u64,
+    pub nonce: String,
+    pub issued_at: String,
+    pub expiration_time: Option<String>,
+    pub not_before: Option<String>,
+    pub request_id: Option<String>,
+    pub resources: Vec<String>,
+}
+
+impl Message {
+    pub fn parse(text: &str) -> Result<Self, anyhow::Error> {
+        let mut lines = text.lines();
+        let domain = lines
+            .next()
+            .and_then(|line| line.strip_suffix(HEADER_SUFFIX))
+            .ok_or_else(|| anyhow::anyhow!("Malformed SIWE message: missing the header"))?;
+        let address = lines
+            .next()
+            .and_then(|line| line.strip_prefix("0x"))
+            .ok_or_else(|| anyhow::anyhow!("Malformed SIWE message: missing the address"))?;
+        let address = Address::from_slice(&<[u8; 20]>::try_from(hex::decode(address)?.as_slice())?);
+        let mut message = Message {
+            domain: domain.to_string(),
+            address,
+            ..Default::default()
+        };
+        let mut statement = vec![];
+        let mut in_resources = false;
+        for line in lines {
+            if in_resources {
+                match line.strip_prefix("- ") {
+                    Some(resource) => message.resources.push(resource.to_string()),
+                    None => {
+                        anyhow::bail!("Malformed SIWE message: unexpected line after resources")
+                    }
+                }
+                continue;
+            }
+            match line.split_once(": ") {
+                Some(("URI", value)) => message.uri = value.to_string(),
+                Some(("Version", value)) => message.version = value.to_string(),
+                Some(("Chain ID", value)) => message.chain_id = value.parse()?,
+                Some(("Nonce", value)) => message.nonce = value.to_string(),
+                Some(("Issued At", value)) => message.issued_at = value.to_string(),
+                Some(("Expiration Time", value)) => {
+                    message.expiration_time = Some(value.to_string())
+                }
+                Some(("Not Before", value)) => message.not_before = Some(value.to_string()),
+                Some(("Request ID", value)) => message.request_id = Some(value.to_string()),
+                _ if line == "Resources:" => in_resources = true,
+                _ if message.uri.is_empty() && !line.is_empty() => statement.push(line),
+                _ => {}
+            }
+        }
+        if message.uri.is_empty() || message.nonce.is_empty() || message.issued_at.is_empty() {
+            anyhow::bail!("Malformed SIWE message: missing URI, Nonce or Issued At");
+        }
+        if message.version != "1" {
+            anyhow::bail!("Unsupported SIWE message version {}", message.version);
+        }
+        if !statement.is_empty() {
+            message.statement = Some(statement.join("\n"));
+        }
+        Ok(message)
+    }
+}
+
+/// The hash of a message signed with `personal_sign`.
+pub fn personal_message_hash(message: &[u8]) -> [u8; 32] {
+    let mut bytes = format!("\x19Ethereum Signed Message:\n{}", message.len()).into_bytes();
+    bytes.extend_from_slice(message);
+    crypto::keccak256(&bytes)
+}
+
+/// Recovers the address that signed the given message with `personal_sign`.
+/// The signature is 65 bytes `r || s || v` where `v` is 0, 1, 27 or 28.
+pub fn recover(message: &[u8], signature: &[u8]) -> Result<Address, anyhow::Error> {
+    if signature.len() != 65 {
+        anyhow::bail!(
+            "Expected a 65-byte signature, got {} bytes",
+            signature.len()
+        );
+    }
+    let v = match signature[64] {
+        v @ (0 | 1) => v,
+        v @ (27 | 28) => v - 27,
+        v => anyhow::bail!("Invalid recovery id {}", v),
+    };
+    let id =
+        RecoveryId::from_byte(v).ok_or_else(|| anyhow::anyhow!("Invalid recovery id {}", v))?;
+    let signature = Signature::try_from(&signature[..64])?;
+    let key = VerifyingKey::recover_from_prehash(&personal_message_hash(message), &signature, id)?;
+    address::from_public_key(key.to_encoded_point(false).as_bytes())
+}
+
+/// The checks of a sign-in message in addition to its signature and times.
+#[derive(Default)]
+pub struct Options {
+    pub domain: Option<String>,
+    pub nonce: Option<String>,
+}
+
+/// Verifies the signature and the fields of the given message at the given
+/// time in seconds and returns the parsed message.
+pub fn verify(
+    text: &str,
+    signature: &[u8],
+    options: &Options,
+    now: i64,
+) -> Result<Message, anyhow::Error> {
+    let message = Message::parse(text)?;
+    let signer = recover(text.as_bytes(), signature)?;
+    if signer != message.address {
+        anyhow::bail!(
+            "The message is signed by {} instead of {}",
+            address::to_checksum(&signer),
+            address::to_checksum(&message.address)
+        );
+    }
+    if options
+        .domain
+        .as_ref()
+        .is_some_and(|domain| *domain != message.domain)
+    {
+        anyhow::bail!("Unexpected SIWE domain {}", message.domain);
+    }
+    if options
+        .nonce
+        .as_ref()
+        .is_some_and(|nonce| *nonce != message.nonce)
+    {
+        anyhow::bail!("Unexpected SIWE nonce");
+    }
+    if let Some(expiration_time) = &message.expiration_time {
+        if parse_time(expiration_time)? <= now {
+            anyhow::bail!("The SIWE message has expired");
+        }
+    }
+    if let Some(not_before) = &message.not_before {
+        if parse_time(not_before)? > now {
+            anyhow::bail!("The SIWE message is not valid yet");
+        }
+    }
+    Ok(message)
+}
+
+// Parses an RFC 3339 timestamp like `2021-09-30T16:25:24.000Z` into seconds
+// since the Unix epoch.
+fn parse_time(time: &str) -> Result<i64, anyhow::Error> {
+    let invalid = || anyhow::anyhow!("Invalid RFC 3339 timestamp {}", time);
+    let (date, rest) = time.split_once(['T', 't']).ok_or_else(invalid)?;
+    let number = |s: &str| s.parse::<i64>().map_err(|_| invalid());
+    let mut date = date.splitn(3, '-');
+    let (year, month, day) = match (date.next(), date.next(), date.next()) {
+        (Some(year), Some(month), Some(day)) => (number(year)?, number(month)?, number(day)?),
+        _ => return Err(invalid()),
+    };
+    let (clock, offset) = match rest.find(['Z', 'z', '+', '-']) {
+        Some(index) => rest.split_at(index),
+        None => return Err(invalid()),
+    };
+    let clock = clock.split('.').next().unwrap_or_default();
+    let mut clock = clock.splitn(3, ':');
+    let (hour, minute, second) = match (clock.next(), clock.next(), clock.next()) {
+        (Some(hour), Some(minute), Some(second)) => {
+            (number(hour)?, number(minute)?, number(second)?)
+        }
+        _ => return Err(invalid()),
+    };
+    let offset = match offset {
+        "Z" | "z" => 0,
+        offset => {
+            let sign = if offset.starts_with('-') { -1 } else { 1 };
+            let (hours, minutes) = offset[1..].split_once(':').ok_or_else(invalid)?;
+            sign * (number(hours)? * 3600 + number(minutes)? * 60)
+        }
+    };
+    // Days since the epoch of a date in the proleptic Gregorian calendar.
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+    Ok(days * 86400 + hour * 3600 + minute * 60 + second - offset)
+}
@@ -0,0 +1,112 @@
+// Backup and restore of the persisted JS state.
+//
+// A backup is a Candid-encoded snapshot of the KV entries, the stored config
+// values and the logs, which is exported in chunks. `create()` takes the
+// snapshot in a single message, so it is consistent, and keeps its bytes on
+// the heap until the next backup. The chunks are then read by queries.
+//
+// Restoring collects the uploaded chunks on the heap and imports the snapshot
+// store by store in a single message with `finish_restore()`, so either all
+// of it is imported or nothing. Entries with the same keys are overwritten and
+// other entries are kept. KV values encrypted at rest can only be read if the
+// canister derives the same key, i.e. when restoring into the same canister.
+use std::cell::RefCell;
+
+use candid::{CandidType, Deserialize};
+
+use crate::{config, kv, system_api};
+
+/// The size of a backup chunk. It is small enough to fit into a reply.
+pub const CHUNK_SIZE: u64 = 1024 * 1024;
+
+/// Information necessary to download a backup.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct BackupInfo {
+    /// The total size of the backup in bytes.
+    pub size: u64,
+    /// The size of all chunks except for the last one.
+    pub chunk_size: u64,
+}
+
+// The persisted JS state.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct Snapshot {
+    // The raw KV entries.
+    kv: Vec<(String, String)>,
+    // The stored config values as JSON text.
+    config: Vec<(String, String)>,
+    // The logs from the oldest to the newest entry.
+    logs: Vec<String>,
+}
+
+thread_local! {
+    // The encoded snapshot of the last backup.
+    static BACKUP: RefCell<Vec<u8>> = RefCell::new(vec![]);
+
+    // The chunks of the backup being restored.
+    static RESTORE: RefCell<Vec<u8>> = RefCell::new(vec![]);
+}
+
+/// Takes a snapshot of the persisted JS state for download.
+pub fn create() -> BackupInfo {
+    let snapshot = Snapshot {
+        kv: kv::export(),
+        config: config::export(),
+        logs: system_api::export_logs(),
+    };
+    let bytes = candid::encode_one(&snapshot).unwrap();
+    let size = bytes.len() as u64;
+    BACKUP.with(|backup| *backup.borrow_mut() = bytes);
+    BackupInfo {
+        size,
+        chunk_size: CHUNK_SIZE,
+    }
+}
+
+/// Returns the chunk of the last backup that starts at the given offset.
+pub fn chunk(offset: u64) -> Result<Vec<u8>, String> {
+    BACKUP.with(|backup| {
+        let backup = backup.borrow();
+        let size = backup.len() as u64;
+        if !offset.is_multiple_of(CHUNK_SIZE) || offset >= size {
+            return Err(format!(
+                "Invalid chunk offset {} for backup of size {}",
+                offset, size
+            ));
+        }
+        let end = (offset + CHUNK_SIZE).min(size);
+        Ok(backup[offset as usize..end as usize].to_vec())
+    })
+}
+
+/// Stores the given chunk of a backup. Chunks must be uploaded in order and
+/// an offset of zero starts a new restore.
+pub fn restore_chunk(offset: u64, chunk: Vec<u8>) -> Result<(), String> {
+    RESTORE.with(|restore| {
+        let mut restore = restore.borrow_mut();
+        if offset == 0 {
+            restore.clear();
+        }
+        if offset != restore.len() as u64 || chunk.len() as u64 > CHUNK_SIZE {
+            return Err(format!(
+                "Invalid chunk at offset {} of size {}, expected offset {}",
+                offset,
+                chunk.len(),
+                restore.len()
+            ));
+        }
+        restore.extend_from_slice(&chunk);
+        Ok(())
+    })
+}
+
+/// Imports the uploaded backup into the stores.
+pub fn finish_restore() -> Result<(), String> {
+    let bytes = RESTORE.with(|restore| std::mem::take(&mut *restore.borrow_mut()));
+    let snapshot: Snapshot =
+        candid::decode_one(&bytes).map_err(|err| format!("Invalid backup: {}", err))?;
+    kv::import(snapshot.kv);
+    config::import(snapshot.config);
+    system_api::import_logs(snapshot.logs);
+    Ok(())
+}
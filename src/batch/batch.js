@@ -0,0 +1,17 @@
+// Runs the calls validated by batch/mod.rs one after another and returns one
+// `{result}` or `{error}` object per call.
+Object.defineProperty(globalThis, "__batchInvoke__", {
+	enumerable: false,
+	value: async function (calls) {
+		const results = [];
+		for (const call of calls) {
+			try {
+				const result = await __endpoints__[call.method](...call.args);
+				results.push({ result: result === undefined ? null : result });
+			} catch (e) {
+				results.push({ error: String(e && e.message || e) });
+			}
+		}
+		return results;
+	},
+});
@@ -0,0 +1,103 @@
+// Executes several calls of the endpoints registered with `ic.registerUpdate()`
+// and `ic.registerQuery()` in a single message.
+//
+// Each call is a method name with its arguments as a JSON array. The calls run
+// sequentially in the order of the batch, so a call sees the effects of the
+// previous ones. Every call is checked by the auth middleware, the quotas and
+// the schema of its method as if it was sent on its own, and a failed call
+// does not stop the batch.
+use ic_cdk::api::call::ManualReply;
+use quickjs_wasm_rs::JSContextRef;
+use serde_json::{json, Value};
+
+use crate::{
+    auth::{middleware, Identity},
+    endpoints, engine, json, quotas, redaction, schemas,
+};
+
+// The runner of validated calls defined in batch.js.
+const RUNNER_FILE: &str = "batch.js";
+const RUNNER_SCRIPT: &str = include_str!("batch.js");
+const INVOKE: &str = "__batchInvoke__";
+
+/// The result of a call: its JSON-encoded result or an error message.
+pub type CallResult = Result<String, String>;
+
+// Returns the arguments of a call that passed all checks.
+fn check(method: &str, args: &str, identity: &Identity) -> Result<Value, String> {
+    if endpoints::kind(method).is_none() {
+        return Err(format!("Unknown method {}", method));
+    }
+    let args: Value = serde_json::from_str(args).map_err(|err| err.to_string())?;
+    if !args.is_array() {
+        return Err("The arguments must be a JSON array".to_string());
+    }
+    if let Err(issues) = schemas::validate(method, &args) {
+        return Err(format!(
+            "Invalid arguments of {}: {}",
+            method,
+            schemas::describe(&issues)
+        ));
+    }
+    middleware::check(method, identity)?;
+    quotas::consume(method, identity).map_err(|err| err.to_string())?;
+    Ok(args)
+}
+
+/// Handles `batch_invoke` and replies with one result per call.
+pub fn invoke(calls: Vec<(String, String)>) -> ManualReply<Vec<CallResult>> {
+    let identity = Identity::caller();
+    let checked: Vec<Result<Value, String>> = calls
+        .iter()
+        .map(|(method, args)| {
+            check(method, args, &identity).map(|args| json!({"method": method, "args": args}))
+        })
+        .collect();
+    let runnable: Vec<Value> = checked
+        .iter()
+        .filter_map(|call| call.as_ref().ok().cloned())
+        .collect();
+    if runnable.is_empty() {
+        return ManualReply::one(merge(checked, vec![]));
+    }
+    engine::execute(
+        INVOKE,
+        move |context| {
+            Ok(vec![quickjs_wasm_rs::to_qjs_value(
+                context,
+                &json::from_json(&Value::Array(runnable)),
+            )?])
+        },
+        move |_context, result| {
+            let results = result
+                .and_then(|value| Ok(json::to_json(&quickjs_wasm_rs::from_qjs_value(&value)?)));
+            match results {
+                Ok(Value::Array(results)) => ManualReply::one(merge(checked, results)),
+                Ok(_) => ManualReply::reject("Unexpected result of the batch runner"),
+                Err(err) => ManualReply::reject(redaction::redact(&err.to_string())),
+            }
+        },
+    )
+}
+
+// Merges the rejected calls and the results of the executed calls in the order
+// of the batch.
+fn merge(checked: Vec<Result<Value, String>>, results: Vec<Value>) -> Vec<CallResult> {
+    let mut results = results.into_iter();
+    checked
+        .into_iter()
+        .map(|call| {
+            call?;
+            let result = results.next().unwrap_or(Value::Null);
+            match result.get("error") {
+                Some(message) => Err(message.as_str().unwrap_or_default().to_string()),
+                None => Ok(result["result"].to_string()),
+            }
+        })
+        .collect()
+}
+
+pub fn link(context: &JSContextRef) -> Result<(), anyhow::Error> {
+    context.eval_global(RUNNER_FILE, RUNNER_SCRIPT)?;
+    Ok(())
+}
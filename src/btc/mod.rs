@@ -0,0 +1,293 @@
+// Bitcoin transaction helpers exposed to JS as `ic.btc`.
+//
+// A transaction that spends P2WPKH or P2TR outputs is built in three steps:
+// 1. `ic.btc.buildTx(spec)` returns the sighash of each input.
+// 2. JS signs the sighashes with `sign_with_ecdsa` (P2WPKH) or
+//    `sign_with_schnorr` with the BIP-341 tweak (P2TR key path).
+// 3. `ic.btc.assembleTx(spec, signatures)` returns the signed transaction that
+//    can be submitted with `bitcoin_send_transaction`.
+//
+// The spec is `{network, inputs, outputs, fee, changeAddress}` where each
+// input is `{txid, vout, value, address}` and each output is `{address,
+// value}`. Values are in satoshis. Building is deterministic, so both steps
+// produce the same transaction for the same spec.
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail};
+use bitcoin::{
+    absolute::LockTime,
+    consensus::encode::serialize,
+    hashes::Hash,
+    secp256k1,
+    sighash::{EcdsaSighashType, Prevouts, SighashCache, TapSighashType},
+    transaction::Version,
+    Address, Amount, Network, OutPoint, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Txid,
+    Witness,
+};
+use quickjs_wasm_rs::{CallbackArg, JSContextRef, JSError, JSValue, JSValueRef};
+
+use crate::{engine, jobs};
+
+// Change below this amount is added to the fee instead of creating an output.
+const DUST_THRESHOLD: u64 = 546;
+
+/// The signature of an input: a 64-byte compact ECDSA signature with the
+/// public key for P2WPKH or a 64-byte Schnorr signature for P2TR.
+pub struct InputSignature {
+    pub signature: Vec<u8>,
+    pub public_key: Option<Vec<u8>>,
+}
+
+/// Builds the unsigned transaction from the given spec together with the
+/// outputs spent by its inputs.
+pub fn build(spec: &JSValue) -> Result<(Transaction, Vec<TxOut>), anyhow::Error> {
+    let network = match field(spec, "network")? {
+        JSValue::String(network) => {
+            Network::from_str(network).or_else(|_| match network.as_str() {
+                "mainnet" => Ok(Network::Bitcoin),
+                _ => Err(anyhow!("Unknown network {}", network)),
+            })?
+        }
+        _ => bail!("Expected network to be a string"),
+    };
+    let address = |value: &JSValue| match value {
+        JSValue::String(address) => Ok(Address::from_str(address)?.require_network(network)?),
+        _ => Err(anyhow!("Expected an address string")),
+    };
+
+    let mut inputs = vec![];
+    let mut prevouts = vec![];
+    for input in array(field(spec, "inputs")?)? {
+        let JSValue::String(txid) = field(input, "txid")? else {
+            bail!("Expected txid to be a hex string");
+        };
+        let script_pubkey = address(field(input, "address")?)?.script_pubkey();
+        if !script_pubkey.is_p2wpkh() && !script_pubkey.is_p2tr() {
+            bail!("Only P2WPKH and P2TR inputs are supported");
+        }
+        inputs.push(TxIn {
+            previous_output: OutPoint {
+                txid: Txid::from_str(txid)?,
+                vout: jobs::number(field(input, "vout")?, "vout")? as u32,
+            },
+            script_sig: ScriptBuf::new(),
+            sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+            witness: Witness::new(),
+        });
+        prevouts.push(TxOut {
+            value: Amount::from_sat(jobs::number(field(input, "value")?, "value")? as u64),
+            script_pubkey,
+        });
+    }
+
+    let mut outputs = vec![];
+    for output in array(field(spec, "outputs")?)? {
+        outputs.push(TxOut {
+            value: Amount::from_sat(jobs::number(field(output, "value")?, "value")? as u64),
+            script_pubkey: address(field(output, "address")?)?.script_pubkey(),
+        });
+    }
+
+    let fee = Amount::from_sat(jobs::number(field(spec, "fee")?, "fee")? as u64);
+    let total_in: Amount = prevouts.iter().map(|prevout| prevout.value).sum();
+    let total_out: Amount = outputs.iter().map(|output| output.value).sum();
+    let change = total_in.checked_sub(total_out + fee).ok_or_else(|| {
+        anyhow!(
+            "Insufficient funds: inputs {}, outputs {}, fee {}",
+            total_in,
+            total_out,
+            fee
+        )
+    })?;
+    // Change below the dust threshold goes to the fee.
+    if change.to_sat() >= DUST_THRESHOLD {
+        let change_address = optional_field(spec, "changeAddress").ok_or_else(|| {
+            anyhow!(
+                "The change of {} requires a changeAddress, otherwise it goes to the fee",
+                change
+            )
+        })?;
+        outputs.push(TxOut {
+            value: change,
+            script_pubkey: address(change_address)?.script_pubkey(),
+        });
+    }
+
+    let transaction = Transaction {
+        version: Version::TWO,
+        lock_time: LockTime::ZERO,
+        input: inputs,
+        output: outputs,
+    };
+    Ok((transaction, prevouts))
+}
+
+/// Returns the hash that must be signed for each input.
+pub fn sighashes(
+    transaction: &Transaction,
+    prevouts: &[TxOut],
+) -> Result<Vec<[u8; 32]>, anyhow::Error> {
+    let mut cache = SighashCache::new(transaction);
+    prevouts
+        .iter()
+        .enumerate()
+        .map(|(index, prevout)| {
+            if prevout.script_pubkey.is_p2tr() {
+                let sighash = cache.taproot_key_spend_signature_hash(
+                    index,
+                    &Prevouts::All(prevouts),
+                    TapSighashType::Default,
+                )?;
+                Ok(sighash.to_byte_array())
+            } else {
+                let sighash = cache.p2wpkh_signature_hash(
+                    index,
+                    &prevout.script_pubkey,
+                    prevout.value,
+                    EcdsaSighashType::All,
+                )?;
+                Ok(sighash.to_byte_array())
+            }
+        })
+        .collect()
+}
+
+/// Adds the witnesses with the given signatures to the inputs.
+pub fn assemble(
+    mut transaction: Transaction,
+    prevouts: &[TxOut],
+    signatures: Vec<InputSignature>,
+) -> Result<Transaction, anyhow::Error> {
+    if signatures.len() != prevouts.len() {
+        bail!(
+            "Expected {} signatures, got {}",
+            prevouts.len(),
+            signatures.len()
+        );
+    }
+    for ((input, prevout), signature) in transaction.input.iter_mut().zip(prevouts).zip(signatures)
+    {
+        let mut witness = Witness::new();
+        if prevout.script_pubkey.is_p2tr() {
+            if signature.signature.len() != 64 {
+                bail!("Expected a 64-byte Schnorr signature");
+            }
+            // The default sighash type is implied by a 64-byte signature.
+            witness.push(&signature.signature);
+        } else {
+            let mut ecdsa = secp256k1::ecdsa::Signature::from_compact(&signature.signature)?;
+            // Bitcoin accepts only signatures with a low S value.
+            ecdsa.normalize_s();
+            let mut bytes = ecdsa.serialize_der().to_vec();
+            bytes.push(EcdsaSighashType::All as u8);
+            let public_key = signature
+                .public_key
+                .ok_or_else(|| anyhow!("P2WPKH inputs require a public key"))?;
+            witness.push(bytes);
+            witness.push(secp256k1::PublicKey::from_slice(&public_key)?.serialize());
+        }
+        input.witness = witness;
+    }
+    Ok(transaction)
+}
+
+fn field<'a>(value: &'a JSValue, name: &str) -> Result<&'a JSValue, anyhow::Error> {
+    optional_field(value, name).ok_or_else(|| anyhow!("Missing field {}", name))
+}
+
+fn optional_field<'a>(value: &'a JSValue, name: &str) -> Option<&'a JSValue> {
+    match value {
+        JSValue::Object(fields) => fields
+            .get(name)
+            .filter(|value| !matches!(value, JSValue::Undefined | JSValue::Null)),
+        _ => None,
+    }
+}
+
+fn array(value: &JSValue) -> Result<&[JSValue], anyhow::Error> {
+    match value {
+        JSValue::Array(values) => Ok(values),
+        _ => bail!("Expected an array"),
+    }
+}
+
+fn to_js_transaction<'a>(
+    context: &'a JSContextRef,
+    transaction: &Transaction,
+) -> Result<JSValueRef<'a>, anyhow::Error> {
+    let js = context.object_value()?;
+    js.set_property("tx", context.array_buffer_value(&serialize(transaction))?)?;
+    js.set_property(
+        "txid",
+        context.value_from_str(&transaction.txid().to_string())?,
+    )?;
+    Ok(js)
+}
+
+pub fn link(context: &JSContextRef) -> Result<(), anyhow::Error> {
+    // Returns `{tx, txid, sighashes}` with the unsigned transaction.
+    fn build_tx<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 1 {
+            return Err(JSError::Type(format!("Expected 1 argument, got {}", args.len())).into());
+        }
+        let (transaction, prevouts) = build(&args[0].to_js_value()?)?;
+        let js = to_js_transaction(context, &transaction)?;
+        let sighashes = context.array_value()?;
+        for sighash in self::sighashes(&transaction, &prevouts)? {
+            sighashes.append_property(context.array_buffer_value(&sighash)?)?;
+        }
+        js.set_property("sighashes", sighashes)?;
+        Ok(js)
+    }
+
+    // Usage: `ic.btc.assembleTx(spec, [{signature, publicKey}])`. Returns
+    // `{tx, txid}` with the signed transaction.
+    fn assemble_tx<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 2 {
+            return Err(JSError::Type(format!("Expected 2 arguments, got {}", args.len())).into());
+        }
+        let (transaction, prevouts) = build(&args[0].to_js_value()?)?;
+        let signatures = array(&args[1].to_js_value()?)?
+            .iter()
+            .map(|signature| {
+                let JSValue::ArrayBuffer(bytes) = field(signature, "signature")? else {
+                    return Err(JSError::Type(
+                        "Expected signature to be an ArrayBuffer".to_string(),
+                    )
+                    .into());
+                };
+                let public_key = match optional_field(signature, "publicKey") {
+                    Some(JSValue::ArrayBuffer(bytes)) => Some(bytes.clone()),
+                    Some(_) => {
+                        return Err(JSError::Type(
+                            "Expected publicKey to be an ArrayBuffer".to_string(),
+                        )
+                        .into())
+                    }
+                    None => None,
+                };
+                Ok(InputSignature {
+                    signature: bytes.clone(),
+                    public_key,
+                })
+            })
+            .collect::<Result<Vec<_>, anyhow::Error>>()?;
+        let transaction = assemble(transaction, &prevouts, signatures)?;
+        to_js_transaction(context, &transaction)
+    }
+
+    let btc = context.object_value()?;
+    btc.set_property("buildTx", context.wrap_callback2(build_tx)?)?;
+    btc.set_property("assembleTx", context.wrap_callback2(assemble_tx)?)?;
+
+    engine::namespace(context)?.set_property("btc", btc)?;
+    Ok(())
+}
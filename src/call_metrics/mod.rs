@@ -0,0 +1,298 @@
+// Counters of the outgoing calls of `engine::call()` per destination canister
+// exposed at `GET /metrics` and to JS as `ic.callMetrics`.
+//
+// The counters are kept in stable memory and count calls, rejects and the
+// cycles attached to and refunded by the calls since the first call. For
+// alerts, each destination also has counters of the current hour.
+// `ic.callMetrics.alert({canisterId, maxCallsPerHour, maxCyclesPerHour,
+// handler})` calls the global JS function `handler(canisterId, stats)` in the
+// background when a call crosses a threshold, e.g. to notify an ops canister.
+// Alerts are registered by the script on each start.
+use std::{cell::RefCell, collections::HashMap, fmt::Write, time::Duration};
+
+use candid::{CandidType, Deserialize, Principal};
+use ic_stable_structures::StableBTreeMap;
+use quickjs_wasm_rs::{CallbackArg, JSContextRef, JSError, JSValue, JSValueRef};
+
+use crate::{
+    dead_letters, engine, jobs,
+    stable::{self, Candid, Memory},
+};
+
+/// The HTTP path of the metrics in the Prometheus text format.
+pub const PATH: &str = "/metrics";
+
+const HOUR_NANOS: u64 = 3_600_000_000_000;
+
+/// The counters of the calls to one canister.
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct CallStats {
+    pub calls: u64,
+    pub rejects: u64,
+    pub cycles_attached: u128,
+    pub cycles_refunded: u128,
+    /// The start of the current hour in nanoseconds since the epoch.
+    pub hour_start: u64,
+    pub hour_calls: u64,
+    pub hour_cycles_attached: u128,
+}
+
+// A threshold on the counters of the current hour of one or all canisters.
+struct Alert {
+    canister_id: Option<Principal>,
+    max_calls_per_hour: Option<u64>,
+    max_cycles_per_hour: Option<u128>,
+    handler: String,
+}
+
+thread_local! {
+    static STATS: RefCell<StableBTreeMap<String, Candid<CallStats>, Memory>> =
+        RefCell::new(StableBTreeMap::init(stable::memory(stable::CALL_METRICS)));
+
+    // The destinations of the calls in flight by callback id.
+    static IN_FLIGHT: RefCell<HashMap<i32, Principal>> = RefCell::new(HashMap::new());
+
+    static ALERTS: RefCell<Vec<Alert>> = RefCell::new(vec![]);
+}
+
+pub fn stats(canister_id: &Principal) -> CallStats {
+    STATS.with(|stats| {
+        stats
+            .borrow()
+            .get(&canister_id.to_text())
+            .map(|stats| stats.0)
+            .unwrap_or_default()
+    })
+}
+
+pub fn all_stats() -> Vec<(Principal, CallStats)> {
+    STATS.with(|stats| {
+        stats
+            .borrow()
+            .iter()
+            .filter_map(|(canister_id, stats)| {
+                Some((Principal::from_text(canister_id).ok()?, stats.0))
+            })
+            .collect()
+    })
+}
+
+fn update(canister_id: &Principal, f: impl FnOnce(&mut CallStats)) -> CallStats {
+    let mut stats = stats(canister_id);
+    f(&mut stats);
+    STATS.with(|cell| {
+        cell.borrow_mut()
+            .insert(canister_id.to_text(), Candid(stats.clone()))
+    });
+    stats
+}
+
+/// Counts a performed call with the given attached cycles.
+pub fn started(callback_id: i32, canister_id: Principal, cycles: u128) {
+    IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().insert(callback_id, canister_id));
+    let now = ic_cdk::api::time();
+    let before = stats(&canister_id);
+    let after = update(&canister_id, |stats| {
+        if now >= stats.hour_start + HOUR_NANOS {
+            stats.hour_start = now - now % HOUR_NANOS;
+            stats.hour_calls = 0;
+            stats.hour_cycles_attached = 0;
+        }
+        stats.calls += 1;
+        stats.cycles_attached += cycles;
+        stats.hour_calls += 1;
+        stats.hour_cycles_attached += cycles;
+    });
+    check_alerts(canister_id, &before, &after);
+}
+
+/// Counts the completion of a call with the given refunded cycles.
+pub fn completed(callback_id: i32, cycles_refunded: u128, rejected: bool) {
+    let Some(canister_id) = IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().remove(&callback_id))
+    else {
+        return;
+    };
+    update(&canister_id, |stats| {
+        stats.cycles_refunded += cycles_refunded;
+        if rejected {
+            stats.rejects += 1;
+        }
+    });
+}
+
+/// Forgets a call whose callback was cleaned up after a trap.
+pub fn forget(callback_id: i32) {
+    IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().remove(&callback_id));
+}
+
+// Calls the handlers of the alerts whose thresholds the last call crossed.
+// They run in a timer because the engine is busy with the call.
+fn check_alerts(canister_id: Principal, before: &CallStats, after: &CallStats) {
+    // The counters of the hour were reset by the last call.
+    let (before_calls, before_cycles) = if before.hour_start == after.hour_start {
+        (before.hour_calls, before.hour_cycles_attached)
+    } else {
+        (0, 0)
+    };
+    let handlers: Vec<String> = ALERTS.with(|alerts| {
+        alerts
+            .borrow()
+            .iter()
+            .filter(|alert| alert.canister_id.map_or(true, |id| id == canister_id))
+            .filter(|alert| {
+                let calls = alert
+                    .max_calls_per_hour
+                    .is_some_and(|max| before_calls <= max && after.hour_calls > max);
+                let cycles = alert
+                    .max_cycles_per_hour
+                    .is_some_and(|max| before_cycles <= max && after.hour_cycles_attached > max);
+                calls || cycles
+            })
+            .map(|alert| alert.handler.clone())
+            .collect()
+    });
+    for handler in handlers {
+        let stats = after.clone();
+        ic_cdk_timers::set_timer(Duration::ZERO, move || {
+            dead_letters::spawn(&handler, "call_metrics", move |context| {
+                Ok(vec![
+                    context.value_from_str(&canister_id.to_text())?,
+                    to_js(context, &stats)?,
+                ])
+            });
+        });
+    }
+}
+
+fn to_js<'a>(
+    context: &'a JSContextRef,
+    stats: &CallStats,
+) -> Result<JSValueRef<'a>, anyhow::Error> {
+    let js = context.object_value()?;
+    js.set_property("calls", context.value_from_f64(stats.calls as f64)?)?;
+    js.set_property("rejects", context.value_from_f64(stats.rejects as f64)?)?;
+    js.set_property(
+        "cyclesAttached",
+        context.value_from_f64(stats.cycles_attached as f64)?,
+    )?;
+    js.set_property(
+        "cyclesRefunded",
+        context.value_from_f64(stats.cycles_refunded as f64)?,
+    )?;
+    js.set_property(
+        "hourCalls",
+        context.value_from_f64(stats.hour_calls as f64)?,
+    )?;
+    js.set_property(
+        "hourCyclesAttached",
+        context.value_from_f64(stats.hour_cycles_attached as f64)?,
+    )?;
+    Ok(js)
+}
+
+/// Returns the counters in the Prometheus text format.
+pub fn prometheus() -> String {
+    let mut text = String::new();
+    let metrics: [(&str, &str, fn(&CallStats) -> u128); 4] = [
+        (
+            "outgoing_calls_total",
+            "The number of outgoing calls.",
+            |stats| stats.calls as u128,
+        ),
+        (
+            "outgoing_call_rejects_total",
+            "The number of rejected outgoing calls.",
+            |stats| stats.rejects as u128,
+        ),
+        (
+            "outgoing_cycles_attached_total",
+            "The cycles attached to outgoing calls.",
+            |stats| stats.cycles_attached,
+        ),
+        (
+            "outgoing_cycles_refunded_total",
+            "The cycles refunded by outgoing calls.",
+            |stats| stats.cycles_refunded,
+        ),
+    ];
+    let all = all_stats();
+    for (name, help, value) in metrics {
+        let _ = writeln!(text, "# HELP {} {}", name, help);
+        let _ = writeln!(text, "# TYPE {} counter", name);
+        for (canister_id, stats) in all.iter() {
+            let _ = writeln!(
+                text,
+                "{}{{destination=\"{}\"}} {}",
+                name,
+                canister_id,
+                value(stats)
+            );
+        }
+    }
+    text
+}
+
+pub fn link(context: &JSContextRef) -> Result<(), anyhow::Error> {
+    // Returns the counters of the given canister.
+    fn get<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 1 {
+            return Err(JSError::Type(format!("Expected 1 argument, got {}", args.len())).into());
+        }
+        let canister_id: String = args[0].try_into()?;
+        to_js(context, &stats(&Principal::from_text(canister_id)?))
+    }
+
+    fn alert<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 1 {
+            return Err(JSError::Type(format!("Expected 1 argument, got {}", args.len())).into());
+        }
+        let JSValue::Object(options) = args[0].to_js_value()? else {
+            return Err(JSError::Type("Expected an options object".to_string()).into());
+        };
+        let canister_id = match options.get("canisterId") {
+            None | Some(JSValue::Undefined | JSValue::Null) => None,
+            Some(JSValue::String(canister_id)) => Some(Principal::from_text(canister_id)?),
+            Some(_) => {
+                return Err(JSError::Type("Expected canisterId to be a string".to_string()).into())
+            }
+        };
+        let max = |name: &str| match options.get(name) {
+            None | Some(JSValue::Undefined | JSValue::Null) => Ok(None),
+            Some(value) => jobs::number(value, name).map(Some),
+        };
+        let max_calls_per_hour = max("maxCallsPerHour")?.map(|max| max as u64);
+        let max_cycles_per_hour = max("maxCyclesPerHour")?.map(|max| max as u128);
+        let handler = match options.get("handler") {
+            Some(JSValue::String(handler)) => handler.clone(),
+            _ => {
+                return Err(JSError::Type(
+                    "Expected handler to be the name of a function".to_string(),
+                )
+                .into())
+            }
+        };
+        ALERTS.with(|alerts| {
+            alerts.borrow_mut().push(Alert {
+                canister_id,
+                max_calls_per_hour,
+                max_cycles_per_hour,
+                handler,
+            })
+        });
+        context.undefined_value()
+    }
+
+    let metrics = context.object_value()?;
+    metrics.set_property("get", context.wrap_callback2(get)?)?;
+    metrics.set_property("alert", context.wrap_callback2(alert)?)?;
+    engine::namespace(context)?.set_property("callMetrics", metrics)?;
+    Ok(())
+}
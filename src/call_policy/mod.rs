@@ -0,0 +1,65 @@
+// A policy that restricts the outgoing calls of the JS code to the listed
+// canisters and methods. This matters when the script is not fully trusted.
+//
+// Without a policy all calls are allowed. The policy is set with the
+// `call_policy` field of the init or upgrade argument or by controllers with
+// `set_call_policy()`, and is stored in stable memory. The calls that the
+// linked modules make on behalf of JS, e.g. `ic.icrc`, `ic.outbox`,
+// `ic.evmRpc`, `ic.nns`, `ic.vault` and `ic.pool`, are checked too, so a
+// policy must list the management canister to allow vetKD or the pool. Only
+// the top-up calls and the self-calls of lock waiters are not checked.
+use std::cell::RefCell;
+
+use candid::{CandidType, Deserialize, Principal};
+use ic_stable_structures::StableCell;
+
+use crate::stable::{self, Candid, Memory};
+
+/// The canisters and methods that JS code may call.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct CallPolicy {
+    pub allowed: Vec<AllowedCalls>,
+}
+
+/// The methods of a canister that JS code may call. All methods are allowed if
+/// `methods` is missing.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct AllowedCalls {
+    pub canister_id: Principal,
+    pub methods: Option<Vec<String>>,
+}
+
+thread_local! {
+    static POLICY: RefCell<StableCell<Candid<Option<CallPolicy>>, Memory>> =
+        RefCell::new(StableCell::init(stable::memory(stable::CALL_POLICY), Candid(None)).unwrap());
+}
+
+/// Replaces the policy. `None` allows all calls.
+pub fn set(policy: Option<CallPolicy>) {
+    POLICY.with(|cell| cell.borrow_mut().set(Candid(policy)).unwrap());
+}
+
+pub fn get() -> Option<CallPolicy> {
+    POLICY.with(|cell| cell.borrow().get().0.clone())
+}
+
+/// Fails if the policy does not allow calls of the given method.
+pub fn check(canister_id: &Principal, method: &str) -> Result<(), String> {
+    let Some(policy) = get() else {
+        return Ok(());
+    };
+    let allowed = policy.allowed.iter().any(|allowed| {
+        allowed.canister_id == *canister_id
+            && match &allowed.methods {
+                Some(methods) => methods.iter().any(|allowed| allowed == method),
+                None => true,
+            }
+    });
+    if !allowed {
+        return Err(format!(
+            "The call policy does not allow calls of {} on {}",
+            method, canister_id
+        ));
+    }
+    Ok(())
+}
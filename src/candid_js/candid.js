@@ -0,0 +1,62 @@
+// Prepares JS values for the conversion into Candid in Rust, which sees only
+// plain values: BigInts become `{$type: "BigInt", value: "123"}`, typed arrays
+// become ArrayBuffers and objects with a `$type` field are escaped as
+// `{$type: "Object", value}`.
+Object.defineProperty(globalThis, "__candid__", {
+	enumerable: false,
+	value: (function () {
+		function prepare(value) {
+			if (typeof value === "bigint") {
+				return { $type: "BigInt", value: value.toString() };
+			}
+			if (value === null || typeof value !== "object" || value instanceof ArrayBuffer) {
+				return value;
+			}
+			if (Array.isArray(value)) {
+				return value.map(prepare);
+			}
+			if (ArrayBuffer.isView(value)) {
+				return value.buffer.slice(value.byteOffset, value.byteOffset + value.byteLength);
+			}
+			const fields = {};
+			for (const key of Object.keys(value)) {
+				fields[key] = prepare(value[key]);
+			}
+			return "$type" in fields ? { $type: "Object", value: fields } : fields;
+		}
+
+		// Reverses the tagging of BigInts and escaped objects in decoded values.
+		function restore(value) {
+			if (value === null || typeof value !== "object" || value instanceof ArrayBuffer) {
+				return value;
+			}
+			if (Array.isArray(value)) {
+				return value.map(restore);
+			}
+			if (value.$type === "BigInt") {
+				return BigInt(value.value);
+			}
+			const fields = value.$type === "Object" ? value.value : value;
+			const restored = {};
+			for (const key of Object.keys(fields)) {
+				restored[key] = restore(fields[key]);
+			}
+			return restored;
+		}
+
+		return { prepare, restore };
+	})(),
+});
+
+// Usage: `candid.encode(["text", "opt nat"], ["memo", 42n])` returns the
+// Candid-encoded arguments as an ArrayBuffer and `candid.decode(["nat"],
+// bytes)` returns the array of decoded values.
+globalThis.candid = {
+	encode: (types, values) => __candid__.encode(types, values.map(__candid__.prepare)),
+	decode: (types, bytes) => {
+		const buffer = ArrayBuffer.isView(bytes)
+			? bytes.buffer.slice(bytes.byteOffset, bytes.byteOffset + bytes.byteLength)
+			: bytes;
+		return __candid__.restore(__candid__.decode(types, buffer));
+	},
+};
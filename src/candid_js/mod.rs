@@ -0,0 +1,321 @@
+// Conversion between JS values and Candid values whose types are not known at
+// compile time.
+//
+// Endpoints that accept arbitrary arguments decode the raw argument bytes with
+// `arg_data()` and pass the result to `converters::arguments()`. The values are
+// converted like typed arguments: numbers up to 64 bits become JS numbers,
+// `nat` and `int` become decimal strings, principals become text and `opt`
+// values become the value or `null`. Records become objects keyed by field
+// name or by the numeric id if the name is unknown, tuples become arrays and
+// variants become objects with one property like `{ok: value}`. The type of a
+// `vec` is not part of the decoded value, so a non-empty vector of `nat8`
+// becomes an ArrayBuffer like a `blob`.
+//
+// Endpoints that return arbitrary values pass `reply` as the replier of
+// `engine::execute()`. The result is encoded as a single Candid value with a
+// type derived from the JS value: numbers become `float64`, BigInts `int`,
+// strings `text`, ArrayBuffers and typed arrays `blob`, arrays `vec` of the
+// common type of their elements, objects records and `null` and `undefined`
+// become `null`. Arrays whose elements have different types are rejected. Other
+// types are never guessed: JS annotates a value with its Candid type like
+// `{$type: "nat64", value: 42n}` or `{$type: "opt nat", value: null}`, which is
+// encoded like `candid.encode()` does.
+//
+// JS code that knows the types uses the `candid` global instead, e.g.
+// `candid.encode(["text", "opt nat"], ["memo", 42n])` returns an ArrayBuffer
+// for `ic.call()` and `candid.decode(["nat"], reply)` returns `[42n]`. See
+// typed.rs.
+use std::collections::HashMap;
+
+use anyhow::{bail, Error};
+use candid::{
+    parser::value::{IDLField, IDLValue},
+    types::{Field, Label, Type},
+    IDLArgs, Int, TypeEnv,
+};
+use ic_cdk::api::call::ManualReply;
+use quickjs_wasm_rs::{CallbackArg, JSContextRef, JSError, JSValue, JSValueRef};
+
+use crate::{
+    converters::{ToJs, ToJsArgs},
+    redaction,
+};
+
+mod typed;
+
+// The helper that prepares JS values for `to_candid()`.
+const CANDID_FILE: &str = "candid.js";
+const CANDID_SCRIPT: &str = include_str!("candid.js");
+const CANDID: &str = "__candid__";
+const PREPARE: &str = "prepare";
+
+/// The Candid arguments of the current message.
+pub struct Args(pub IDLArgs);
+
+/// Decodes the raw arguments of the current message.
+pub fn arg_data() -> Result<Args, Error> {
+    Ok(Args(IDLArgs::from_bytes(
+        &ic_cdk::api::call::arg_data_raw(),
+    )?))
+}
+
+impl ToJsArgs for Args {
+    fn to_js_args<'a>(self, context: &'a JSContextRef) -> Result<Vec<JSValueRef<'a>>, Error> {
+        self.0
+            .args
+            .into_iter()
+            .map(|value| value.to_js(context))
+            .collect()
+    }
+}
+
+impl ToJs for IDLValue {
+    fn to_js<'a>(self, context: &'a JSContextRef) -> Result<JSValueRef<'a>, Error> {
+        match self {
+            IDLValue::Null | IDLValue::None | IDLValue::Reserved => context.null_value(),
+            IDLValue::Bool(value) => context.value_from_bool(value),
+            IDLValue::Text(value) | IDLValue::Number(value) => context.value_from_str(&value),
+            IDLValue::Nat(value) => value.to_js(context),
+            IDLValue::Int(value) => value.to_js(context),
+            IDLValue::Nat8(value) => value.to_js(context),
+            IDLValue::Nat16(value) => value.to_js(context),
+            IDLValue::Nat32(value) => value.to_js(context),
+            IDLValue::Nat64(value) => value.to_js(context),
+            IDLValue::Int8(value) => value.to_js(context),
+            IDLValue::Int16(value) => value.to_js(context),
+            IDLValue::Int32(value) => value.to_js(context),
+            IDLValue::Int64(value) => value.to_js(context),
+            IDLValue::Float32(value) => value.to_js(context),
+            IDLValue::Float64(value) => value.to_js(context),
+            IDLValue::Principal(value) | IDLValue::Service(value) => value.to_js(context),
+            IDLValue::Func(principal, method) => {
+                let func = context.object_value()?;
+                func.set_property("principal", principal.to_js(context)?)?;
+                func.set_property("method", context.value_from_str(&method)?)?;
+                Ok(func)
+            }
+            IDLValue::Opt(value) => (*value).to_js(context),
+            IDLValue::Vec(values) => {
+                if !values.is_empty()
+                    && values
+                        .iter()
+                        .all(|value| matches!(value, IDLValue::Nat8(_)))
+                {
+                    let bytes: Vec<u8> = values
+                        .into_iter()
+                        .map(|value| match value {
+                            IDLValue::Nat8(byte) => byte,
+                            _ => unreachable!(),
+                        })
+                        .collect();
+                    return context.array_buffer_value(&bytes);
+                }
+                let array = context.array_value()?;
+                for value in values {
+                    array.append_property(value.to_js(context)?)?;
+                }
+                Ok(array)
+            }
+            IDLValue::Record(fields) => record_to_js(context, fields),
+            IDLValue::Variant(variant) => {
+                let object = context.object_value()?;
+                let field = *variant.0;
+                object.set_property(&label(&field.id), field.val.to_js(context)?)?;
+                Ok(object)
+            }
+        }
+    }
+}
+
+// Converts a tuple into an array and other records into objects.
+fn record_to_js(context: &JSContextRef, fields: Vec<IDLField>) -> Result<JSValueRef, Error> {
+    let is_tuple = !fields.is_empty()
+        && fields
+            .iter()
+            .enumerate()
+            .all(|(i, field)| matches!(field.id, Label::Unnamed(id) if id as usize == i));
+    if is_tuple {
+        let array = context.array_value()?;
+        for field in fields {
+            array.append_property(field.val.to_js(context)?)?;
+        }
+        return Ok(array);
+    }
+    let object = context.object_value()?;
+    for field in fields {
+        object.set_property(&label(&field.id), field.val.to_js(context)?)?;
+    }
+    Ok(object)
+}
+
+fn label(label: &Label) -> String {
+    match label {
+        Label::Named(name) => name.clone(),
+        Label::Id(id) | Label::Unnamed(id) => id.to_string(),
+    }
+}
+
+/// A replier for `engine::execute()` that replies with the JS result encoded
+/// as Candid or rejects with the error.
+pub fn reply<R>(context: &JSContextRef, result: Result<JSValueRef, Error>) -> ManualReply<R> {
+    match result.and_then(|value| encode(context, value)) {
+        Ok(bytes) => {
+            ic_cdk::api::call::reply_raw(&bytes);
+            ManualReply::empty()
+        }
+        Err(err) => ManualReply::reject(redaction::redact(&err.to_string())),
+    }
+}
+
+/// Encodes the given JS value as Candid arguments with a single value.
+pub fn encode(context: &JSContextRef, value: JSValueRef) -> Result<Vec<u8>, Error> {
+    let candid = context.global_object()?.get_property(CANDID)?;
+    let prepared = candid.get_property(PREPARE)?.call(&candid, &[value])?;
+    let (value, ty) = to_candid(&quickjs_wasm_rs::from_qjs_value(&prepared)?)?;
+    Ok(IDLArgs::new(&[value]).to_bytes_with_types(&TypeEnv::new(), &[ty])?)
+}
+
+/// Converts the given prepared JS value into a Candid value and its type.
+pub fn to_candid(value: &JSValue) -> Result<(IDLValue, Type), Error> {
+    Ok(match value {
+        JSValue::Undefined | JSValue::Null => (IDLValue::Null, Type::Null),
+        JSValue::Bool(value) => (IDLValue::Bool(*value), Type::Bool),
+        JSValue::Int(value) => (IDLValue::Float64(*value as f64), Type::Float64),
+        JSValue::Float(value) => (IDLValue::Float64(*value), Type::Float64),
+        JSValue::String(value) => (IDLValue::Text(value.clone()), Type::Text),
+        JSValue::ArrayBuffer(bytes) => (
+            IDLValue::Vec(bytes.iter().map(|byte| IDLValue::Nat8(*byte)).collect()),
+            Type::Vec(Box::new(Type::Nat8)),
+        ),
+        JSValue::Array(values) => vec(values)?,
+        JSValue::Object(fields) => match (fields.get("$type"), fields.get("value")) {
+            (Some(JSValue::String(kind)), Some(JSValue::String(value))) if kind == "BigInt" => {
+                (IDLValue::Int(value.parse::<Int>()?), Type::Int)
+            }
+            (Some(JSValue::String(kind)), Some(JSValue::Object(fields))) if kind == "Object" => {
+                match (fields.get("$type"), fields.get("value")) {
+                    (Some(JSValue::String(ty)), Some(value)) if fields.len() == 2 => {
+                        annotated(ty, value)?
+                    }
+                    _ => record(fields)?,
+                }
+            }
+            _ => record(fields)?,
+        },
+    })
+}
+
+// Converts a value annotated with its Candid type like `{$type: "opt nat",
+// value: 42n}`.
+fn annotated(ty: &str, value: &JSValue) -> Result<(IDLValue, Type), Error> {
+    let ty = typed::parse_types(&[ty.to_string()])?.remove(0);
+    Ok((typed::to_candid(value, &ty)?, ty))
+}
+
+// The elements of a `vec` must have the same type, so arrays that mix types
+// are rejected instead of guessing a common type.
+fn vec(values: &[JSValue]) -> Result<(IDLValue, Type), Error> {
+    let mut element_type = None;
+    let mut elements = Vec::with_capacity(values.len());
+    for value in values {
+        let (value, ty) = to_candid(value)?;
+        match &element_type {
+            None => element_type = Some(ty),
+            Some(element_type) if *element_type == ty => {}
+            Some(element_type) => bail!(
+                "The array mixes values of the Candid types {} and {}, annotate it like {{$type: \"vec opt nat\", value}}",
+                element_type,
+                ty
+            ),
+        }
+        elements.push(value);
+    }
+    // An empty array is a subtype of any `vec`.
+    let element_type = element_type.unwrap_or(Type::Empty);
+    Ok((IDLValue::Vec(elements), Type::Vec(Box::new(element_type))))
+}
+
+// Candid expects the fields of a record ordered by the hashes of their names.
+fn record(fields: &HashMap<String, JSValue>) -> Result<(IDLValue, Type), Error> {
+    let mut fields = fields
+        .iter()
+        .map(|(name, value)| {
+            let (val, ty) = to_candid(value)?;
+            let id = Label::Named(name.clone());
+            Ok((
+                IDLField {
+                    id: id.clone(),
+                    val,
+                },
+                Field { id, ty },
+            ))
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+    fields.sort_by_key(|(field, _)| field.id.get_id());
+    let (values, types) = fields.into_iter().unzip();
+    Ok((IDLValue::Record(values), Type::Record(types)))
+}
+
+// Reads the type expressions of `candid.encode()` and `candid.decode()`.
+fn types(arg: &CallbackArg) -> Result<Vec<candid::types::Type>, Error> {
+    let JSValue::Array(types) = arg.to_js_value()? else {
+        return Err(
+            JSError::Type("Expected the types to be an array of strings".to_string()).into(),
+        );
+    };
+    let types = types
+        .into_iter()
+        .map(|ty| match ty {
+            JSValue::String(ty) => Ok(ty),
+            _ => Err(JSError::Type(
+                "Expected the types to be an array of strings".to_string(),
+            )),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    typed::parse_types(&types)
+}
+
+pub fn link(context: &JSContextRef) -> Result<(), Error> {
+    // Usage: `__candid__.encode(types, values)` with values prepared by
+    // candid.js. Returns an ArrayBuffer.
+    fn encode<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, Error> {
+        if args.len() != 2 {
+            return Err(JSError::Type(format!("Expected 2 arguments, got {}", args.len())).into());
+        }
+        let types = types(&args[0])?;
+        let JSValue::Array(values) = args[1].to_js_value()? else {
+            return Err(JSError::Type("Expected the values to be an array".to_string()).into());
+        };
+        context.array_buffer_value(&typed::encode(&types, &values)?)
+    }
+
+    // Usage: `__candid__.decode(types, bytes)`. Returns the values with tagged
+    // BigInts, which candid.js restores.
+    fn decode<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, Error> {
+        if args.len() != 2 {
+            return Err(JSError::Type(format!("Expected 2 arguments, got {}", args.len())).into());
+        }
+        let types = types(&args[0])?;
+        let JSValue::ArrayBuffer(bytes) = args[1].to_js_value()? else {
+            return Err(
+                JSError::Type("Expected the bytes to be an ArrayBuffer".to_string()).into(),
+            );
+        };
+        let values = typed::decode(&types, &bytes)?;
+        quickjs_wasm_rs::to_qjs_value(context, &JSValue::Array(values))
+    }
+
+    context.eval_global(CANDID_FILE, CANDID_SCRIPT)?;
+    let candid = context.global_object()?.get_property(CANDID)?;
+    candid.set_property("encode", context.wrap_callback2(encode)?)?;
+    candid.set_property("decode", context.wrap_callback2(decode)?)?;
+    Ok(())
+}
@@ -0,0 +1,333 @@
+// Conversion between JS values and Candid values of known types, which backs
+// the `candid.encode(types, values)` and `candid.decode(types, bytes)` globals.
+//
+// The types are Candid type expressions like `nat` or `record { owner :
+// principal; amount : opt nat }`. Unlike the untyped conversion of mod.rs,
+// `nat`, `int`, `nat64` and `int64` become BigInts, `vec nat8` always becomes
+// an ArrayBuffer and missing record fields of `opt` types are `null`. JS
+// passes `nat` and `int` values as BigInts, numbers or decimal strings.
+use std::collections::HashMap;
+
+use anyhow::{anyhow, bail, Error};
+use candid::{
+    parser::{
+        types::IDLType,
+        typing::ast_to_type,
+        value::{IDLField, IDLValue, VariantValue},
+    },
+    types::{Field, Label, Type},
+    IDLArgs, Int, Nat, Principal, TypeEnv,
+};
+use quickjs_wasm_rs::JSValue;
+
+/// Parses Candid type expressions.
+pub fn parse_types(types: &[String]) -> Result<Vec<Type>, Error> {
+    let env = TypeEnv::new();
+    types
+        .iter()
+        .map(|ty| {
+            let ty: IDLType = ty
+                .parse()
+                .map_err(|err| anyhow!("Invalid Candid type {}: {}", ty, err))?;
+            Ok(ast_to_type(&env, &ty)?)
+        })
+        .collect()
+}
+
+/// Encodes the prepared JS values as Candid arguments of the given types.
+pub fn encode(types: &[Type], values: &[JSValue]) -> Result<Vec<u8>, Error> {
+    if types.len() != values.len() {
+        bail!("Expected {} values, got {}", types.len(), values.len());
+    }
+    let values = types
+        .iter()
+        .zip(values)
+        .map(|(ty, value)| to_candid(value, ty))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(IDLArgs::new(&values).to_bytes_with_types(&TypeEnv::new(), types)?)
+}
+
+/// Decodes Candid arguments of the given types into JS values in which
+/// BigInts and escaped objects are tagged like in candid.js.
+pub fn decode(types: &[Type], bytes: &[u8]) -> Result<Vec<JSValue>, Error> {
+    let args = IDLArgs::from_bytes_with_types(bytes, &TypeEnv::new(), types)?;
+    Ok(args
+        .args
+        .into_iter()
+        .zip(types)
+        .map(|(value, ty)| to_js(value, ty))
+        .collect())
+}
+
+/// Converts the prepared JS value into a Candid value of the given type.
+pub fn to_candid(value: &JSValue, ty: &Type) -> Result<IDLValue, Error> {
+    Ok(match (ty, unescape(value)) {
+        (Type::Null, JSValue::Null | JSValue::Undefined) => IDLValue::Null,
+        (Type::Reserved, _) => IDLValue::Reserved,
+        (Type::Bool, JSValue::Bool(value)) => IDLValue::Bool(*value),
+        (Type::Nat, _) => IDLValue::Nat(integer(value)?.parse::<Nat>()?),
+        (Type::Int, _) => IDLValue::Int(integer(value)?.parse::<Int>()?),
+        (Type::Nat8, _) => IDLValue::Nat8(integer(value)?.parse()?),
+        (Type::Nat16, _) => IDLValue::Nat16(integer(value)?.parse()?),
+        (Type::Nat32, _) => IDLValue::Nat32(integer(value)?.parse()?),
+        (Type::Nat64, _) => IDLValue::Nat64(integer(value)?.parse()?),
+        (Type::Int8, _) => IDLValue::Int8(integer(value)?.parse()?),
+        (Type::Int16, _) => IDLValue::Int16(integer(value)?.parse()?),
+        (Type::Int32, _) => IDLValue::Int32(integer(value)?.parse()?),
+        (Type::Int64, _) => IDLValue::Int64(integer(value)?.parse()?),
+        (Type::Float32, _) => IDLValue::Float32(float(value)? as f32),
+        (Type::Float64, _) => IDLValue::Float64(float(value)?),
+        (Type::Text, JSValue::String(value)) => IDLValue::Text(value.clone()),
+        (Type::Principal, JSValue::String(value)) => {
+            IDLValue::Principal(Principal::from_text(value)?)
+        }
+        (Type::Service(_), JSValue::String(value)) => {
+            IDLValue::Service(Principal::from_text(value)?)
+        }
+        (Type::Func(_), JSValue::Object(fields)) => {
+            match (fields.get("principal"), fields.get("method")) {
+                (Some(JSValue::String(principal)), Some(JSValue::String(method))) => {
+                    IDLValue::Func(Principal::from_text(principal)?, method.clone())
+                }
+                _ => bail!("Expected a func to be {{principal, method}}"),
+            }
+        }
+        (Type::Opt(_), JSValue::Null | JSValue::Undefined) => IDLValue::None,
+        (Type::Opt(ty), _) => IDLValue::Opt(Box::new(to_candid(value, ty)?)),
+        (Type::Vec(ty), JSValue::ArrayBuffer(bytes)) if **ty == Type::Nat8 => {
+            IDLValue::Vec(bytes.iter().map(|byte| IDLValue::Nat8(*byte)).collect())
+        }
+        (Type::Vec(ty), JSValue::Array(values)) => IDLValue::Vec(
+            values
+                .iter()
+                .map(|value| to_candid(value, ty))
+                .collect::<Result<_, _>>()?,
+        ),
+        (Type::Record(fields), JSValue::Array(values)) if is_tuple(fields) => {
+            if fields.len() != values.len() {
+                bail!(
+                    "Expected a tuple of {} values, got {}",
+                    fields.len(),
+                    values.len()
+                );
+            }
+            IDLValue::Record(
+                fields
+                    .iter()
+                    .zip(values)
+                    .map(|(field, value)| {
+                        Ok(IDLField {
+                            id: field.id.clone(),
+                            val: to_candid(value, &field.ty)?,
+                        })
+                    })
+                    .collect::<Result<_, Error>>()?,
+            )
+        }
+        (Type::Record(fields), JSValue::Object(values)) => IDLValue::Record(
+            fields
+                .iter()
+                .map(|field| {
+                    let val = match (values.get(&label(&field.id)), &field.ty) {
+                        (Some(value), ty) => to_candid(value, ty)?,
+                        (None, Type::Opt(_) | Type::Null | Type::Reserved) => {
+                            to_candid(&JSValue::Null, &field.ty)?
+                        }
+                        (None, _) => bail!("The record has no field {}", label(&field.id)),
+                    };
+                    Ok(IDLField {
+                        id: field.id.clone(),
+                        val,
+                    })
+                })
+                .collect::<Result<_, Error>>()?,
+        ),
+        (Type::Variant(fields), JSValue::Object(values)) if values.len() == 1 => {
+            let (name, value) = values.iter().next().unwrap();
+            let (index, field) = fields
+                .iter()
+                .enumerate()
+                .find(|(_, field)| label(&field.id) == *name)
+                .ok_or_else(|| anyhow!("The variant has no case {}", name))?;
+            IDLValue::Variant(VariantValue(
+                Box::new(IDLField {
+                    id: field.id.clone(),
+                    val: to_candid(value, &field.ty)?,
+                }),
+                index as u64,
+            ))
+        }
+        // A variant case without a value, e.g. `"active"` for `variant {
+        // active }`.
+        (Type::Variant(_), JSValue::String(name)) => {
+            return to_candid(
+                &JSValue::Object(HashMap::from([(name.clone(), JSValue::Null)])),
+                ty,
+            )
+        }
+        (ty, _) => bail!("Cannot convert the value to {}", ty),
+    })
+}
+
+// Prepared values wrap the fields of escaped objects.
+fn unescape(value: &JSValue) -> &JSValue {
+    match value {
+        JSValue::Object(fields) => match (fields.get("$type"), fields.get("value")) {
+            (Some(JSValue::String(kind)), Some(value)) if kind == "Object" => value,
+            _ => value,
+        },
+        value => value,
+    }
+}
+
+// Returns the decimal text of an integer given as a number, BigInt or string.
+fn integer(value: &JSValue) -> Result<String, Error> {
+    match value {
+        JSValue::Int(value) => Ok(value.to_string()),
+        JSValue::Float(value) if value.fract() == 0.0 => Ok(format!("{:.0}", value)),
+        JSValue::String(value) => Ok(value.clone()),
+        JSValue::Object(fields) => match (fields.get("$type"), fields.get("value")) {
+            (Some(JSValue::String(kind)), Some(JSValue::String(value))) if kind == "BigInt" => {
+                Ok(value.clone())
+            }
+            _ => bail!("Expected an integer"),
+        },
+        _ => bail!("Expected an integer"),
+    }
+}
+
+fn float(value: &JSValue) -> Result<f64, Error> {
+    match value {
+        JSValue::Int(value) => Ok(*value as f64),
+        JSValue::Float(value) => Ok(*value),
+        _ => bail!("Expected a number"),
+    }
+}
+
+fn is_tuple(fields: &[Field]) -> bool {
+    !fields.is_empty()
+        && fields
+            .iter()
+            .enumerate()
+            .all(|(i, field)| matches!(field.id, Label::Unnamed(id) if id as usize == i))
+}
+
+fn label(label: &Label) -> String {
+    match label {
+        Label::Named(name) => name.clone(),
+        Label::Id(id) | Label::Unnamed(id) => id.to_string(),
+    }
+}
+
+fn big_int(value: String) -> JSValue {
+    JSValue::Object(HashMap::from([
+        ("$type".to_string(), JSValue::String("BigInt".to_string())),
+        ("value".to_string(), JSValue::String(value)),
+    ]))
+}
+
+fn to_js(value: IDLValue, ty: &Type) -> JSValue {
+    match value {
+        IDLValue::Null | IDLValue::None | IDLValue::Reserved => JSValue::Null,
+        IDLValue::Bool(value) => JSValue::Bool(value),
+        IDLValue::Text(value) => JSValue::String(value),
+        IDLValue::Number(value) => big_int(value),
+        IDLValue::Nat(value) => big_int(value.to_string()),
+        IDLValue::Int(value) => big_int(value.to_string()),
+        IDLValue::Nat64(value) => big_int(value.to_string()),
+        IDLValue::Int64(value) => big_int(value.to_string()),
+        IDLValue::Nat8(value) => JSValue::Int(value as i32),
+        IDLValue::Nat16(value) => JSValue::Int(value as i32),
+        IDLValue::Nat32(value) => JSValue::Float(value as f64),
+        IDLValue::Int8(value) => JSValue::Int(value as i32),
+        IDLValue::Int16(value) => JSValue::Int(value as i32),
+        IDLValue::Int32(value) => JSValue::Int(value),
+        IDLValue::Float32(value) => JSValue::Float(value as f64),
+        IDLValue::Float64(value) => JSValue::Float(value),
+        IDLValue::Principal(value) | IDLValue::Service(value) => JSValue::String(value.to_text()),
+        IDLValue::Func(principal, method) => JSValue::Object(HashMap::from([
+            (
+                "principal".to_string(),
+                JSValue::String(principal.to_text()),
+            ),
+            ("method".to_string(), JSValue::String(method)),
+        ])),
+        IDLValue::Opt(value) => match ty {
+            Type::Opt(ty) => to_js(*value, ty),
+            ty => to_js(*value, ty),
+        },
+        IDLValue::Vec(values) => match ty {
+            Type::Vec(ty) if **ty == Type::Nat8 => JSValue::ArrayBuffer(
+                values
+                    .into_iter()
+                    .filter_map(|value| match value {
+                        IDLValue::Nat8(byte) => Some(byte),
+                        _ => None,
+                    })
+                    .collect(),
+            ),
+            Type::Vec(ty) => {
+                JSValue::Array(values.into_iter().map(|value| to_js(value, ty)).collect())
+            }
+            _ => JSValue::Array(
+                values
+                    .into_iter()
+                    .map(|value| to_js(value, &Type::Unknown))
+                    .collect(),
+            ),
+        },
+        IDLValue::Record(values) => {
+            let types = match ty {
+                Type::Record(fields) => fields.as_slice(),
+                _ => &[],
+            };
+            let field_type = |id: &Label| {
+                types
+                    .iter()
+                    .find(|field| field.id.get_id() == id.get_id())
+                    .map_or(Type::Unknown, |field| field.ty.clone())
+            };
+            if is_tuple(types) {
+                return JSValue::Array(
+                    values
+                        .into_iter()
+                        .map(|field| {
+                            let ty = field_type(&field.id);
+                            to_js(field.val, &ty)
+                        })
+                        .collect(),
+                );
+            }
+            let fields: HashMap<String, JSValue> = values
+                .into_iter()
+                .map(|field| {
+                    let ty = field_type(&field.id);
+                    (label(&field.id), to_js(field.val, &ty))
+                })
+                .collect();
+            escape(fields)
+        }
+        IDLValue::Variant(VariantValue(field, _)) => {
+            let ty = match ty {
+                Type::Variant(fields) => fields
+                    .iter()
+                    .find(|candidate| candidate.id.get_id() == field.id.get_id())
+                    .map_or(Type::Unknown, |field| field.ty.clone()),
+                _ => Type::Unknown,
+            };
+            escape(HashMap::from([(label(&field.id), to_js(field.val, &ty))]))
+        }
+    }
+}
+
+// Escapes objects that look like tagged values.
+fn escape(fields: HashMap<String, JSValue>) -> JSValue {
+    if fields.contains_key("$type") {
+        JSValue::Object(HashMap::from([
+            ("$type".to_string(), JSValue::String("Object".to_string())),
+            ("value".to_string(), JSValue::Object(fields)),
+        ]))
+    } else {
+        JSValue::Object(fields)
+    }
+}
@@ -0,0 +1,109 @@
+// CBOR encoding and decoding of JS values exposed to JS as `ic.cbor`.
+//
+// Decoding is useful for IC certificates, HTTP gateway payloads and other
+// compact message formats that would be slow to parse in JS.
+use quickjs_wasm_rs::{CallbackArg, JSContextRef, JSError, JSValue, JSValueRef};
+use serde_cbor::Value;
+
+use crate::engine;
+
+/// Converts the given JS value into a CBOR value.
+///
+/// `undefined` becomes `null`, array buffers become byte strings and integral
+/// floats become integers.
+pub fn to_cbor(value: &JSValue) -> Value {
+    match value {
+        JSValue::Undefined | JSValue::Null => Value::Null,
+        JSValue::Bool(value) => Value::Bool(*value),
+        JSValue::Int(value) => Value::Integer(*value as i128),
+        JSValue::Float(value) => {
+            if value.fract() == 0.0 && value.abs() <= 2f64.powi(53) {
+                Value::Integer(*value as i128)
+            } else {
+                Value::Float(*value)
+            }
+        }
+        JSValue::String(value) => Value::Text(value.clone()),
+        JSValue::Array(values) => Value::Array(values.iter().map(to_cbor).collect()),
+        JSValue::ArrayBuffer(bytes) => Value::Bytes(bytes.clone()),
+        JSValue::Object(fields) => Value::Map(
+            fields
+                .iter()
+                .map(|(key, value)| (Value::Text(key.clone()), to_cbor(value)))
+                .collect(),
+        ),
+    }
+}
+
+/// Converts the given CBOR value into a JS value.
+///
+/// Tags are dropped, byte strings become array buffers and map keys that are
+/// not strings are converted to strings. Integers that do not fit into 32 bits
+/// become JS floats and may lose precision.
+pub fn from_cbor(value: &Value) -> JSValue {
+    match value {
+        Value::Null => JSValue::Null,
+        Value::Bool(value) => JSValue::Bool(*value),
+        Value::Integer(value) => match i32::try_from(*value) {
+            Ok(value) => JSValue::Int(value),
+            Err(_) => JSValue::Float(*value as f64),
+        },
+        Value::Float(value) => JSValue::Float(*value),
+        Value::Bytes(bytes) => JSValue::ArrayBuffer(bytes.clone()),
+        Value::Text(value) => JSValue::String(value.clone()),
+        Value::Array(values) => JSValue::Array(values.iter().map(from_cbor).collect()),
+        Value::Map(fields) => JSValue::Object(
+            fields
+                .iter()
+                .map(|(key, value)| (key_to_string(key), from_cbor(value)))
+                .collect(),
+        ),
+        Value::Tag(_, value) => from_cbor(value),
+        _ => JSValue::Undefined,
+    }
+}
+
+fn key_to_string(key: &Value) -> String {
+    match key {
+        Value::Text(key) => key.clone(),
+        Value::Integer(key) => key.to_string(),
+        Value::Bytes(bytes) => bytes.iter().map(|b| format!("{:02x}", b)).collect(),
+        _ => format!("{:?}", key),
+    }
+}
+
+pub fn link(context: &JSContextRef) -> Result<(), anyhow::Error> {
+    fn encode<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 1 {
+            return Err(JSError::Type(format!("Expected 1 argument, got {}", args.len())).into());
+        }
+        let bytes = serde_cbor::to_vec(&to_cbor(&args[0].to_js_value()?))?;
+        context.array_buffer_value(&bytes)
+    }
+
+    fn decode<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 1 {
+            return Err(JSError::Type(format!("Expected 1 argument, got {}", args.len())).into());
+        }
+        let JSValue::ArrayBuffer(bytes) = args[0].to_js_value()? else {
+            return Err(JSError::Type("Expected an ArrayBuffer".to_string()).into());
+        };
+        let value: Value = serde_cbor::from_slice(&bytes)?;
+        quickjs_wasm_rs::to_qjs_value(context, &from_cbor(&value))
+    }
+
+    let cbor = context.object_value()?;
+    cbor.set_property("encode", context.wrap_callback2(encode)?)?;
+    cbor.set_property("decode", context.wrap_callback2(decode)?)?;
+
+    engine::namespace(context)?.set_property("cbor", cbor)?;
+    Ok(())
+}
@@ -0,0 +1,65 @@
+// The JS part of the heap census. It walks the objects reachable from the
+// global object without invoking getters.
+Object.defineProperty(globalThis, "__heapCensus__", {
+	enumerable: false,
+	value: function (maxObjects) {
+		const classes = {};
+		let strings = 0;
+		let stringChars = 0;
+		let arrayBufferBytes = 0;
+		let objects = 0;
+		const visited = new Set();
+		const queue = [globalThis];
+
+		function visit(value) {
+			if (typeof value === "string") {
+				strings += 1;
+				stringChars += value.length;
+			} else if ((typeof value === "object" && value !== null) || typeof value === "function") {
+				if (!visited.has(value)) {
+					visited.add(value);
+					queue.push(value);
+				}
+			}
+		}
+
+		while (queue.length > 0 && objects < maxObjects) {
+			const object = queue.pop();
+			objects += 1;
+			let name = typeof object === "function" ? "Function" : "Object";
+			const prototype = Object.getPrototypeOf(object);
+			if (prototype && prototype.constructor && typeof prototype.constructor.name === "string") {
+				name = prototype.constructor.name || name;
+			}
+			classes[name] = (classes[name] || 0) + 1;
+			if (object instanceof ArrayBuffer) {
+				arrayBufferBytes += object.byteLength;
+			} else if (ArrayBuffer.isView(object)) {
+				visit(object.buffer);
+			} else if (object instanceof Map) {
+				object.forEach((value, key) => {
+					visit(key);
+					visit(value);
+				});
+			} else if (object instanceof Set) {
+				object.forEach(visit);
+			}
+			visit(prototype);
+			for (const key of Object.getOwnPropertyNames(object)) {
+				const descriptor = Object.getOwnPropertyDescriptor(object, key);
+				if (descriptor && "value" in descriptor) {
+					visit(descriptor.value);
+				}
+			}
+		}
+
+		return {
+			objects,
+			truncated: queue.length > 0,
+			classes,
+			strings,
+			stringChars,
+			arrayBufferBytes,
+		};
+	},
+});
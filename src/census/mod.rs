@@ -0,0 +1,37 @@
+// A census of the JS heap for diagnosing memory growth.
+//
+// The bindings of QuickJS do not expose its memory usage statistics, so the
+// census walks the objects reachable from the global object in JS. It counts
+// objects by the name of their constructor, the strings and their characters,
+// and the bytes of ArrayBuffers. Objects that are only reachable from closures
+// or are garbage are not counted. The walk stops after `MAX_OBJECTS` objects to
+// stay within the instruction limit of a query.
+use quickjs_wasm_rs::JSContextRef;
+
+use crate::{engine, json, monitoring};
+
+const CENSUS_FILE: &str = "census.js";
+const CENSUS_SCRIPT: &str = include_str!("census.js");
+
+const HEAP_CENSUS: &str = "__heapCensus__";
+
+const MAX_OBJECTS: i32 = 200_000;
+
+/// Returns the census as JSON together with the size of the Wasm heap.
+pub fn take() -> Result<String, String> {
+    engine::execute_sync(
+        HEAP_CENSUS,
+        |context| Ok(vec![context.value_from_i32(MAX_OBJECTS)?]),
+        |_context, value| {
+            let mut census = json::to_json(&quickjs_wasm_rs::from_qjs_value(&value)?);
+            census["heapMemorySize"] = monitoring::heap_memory_size().into();
+            Ok(census.to_string())
+        },
+    )
+    .map_err(|err| err.to_string())
+}
+
+pub fn link(context: &JSContextRef) -> Result<(), anyhow::Error> {
+    context.eval_global(CENSUS_FILE, CENSUS_SCRIPT)?;
+    Ok(())
+}
@@ -0,0 +1,168 @@
+// The certified state of the canister.
+//
+// The canister has a single certified data root. Features certify their data
+// in separate labeled subtrees of one hash tree, and the root hash of that tree
+// is the certified data of the canister. Clients verify a value by checking the
+// witness against the root hash in the certificate issued by the subnet.
+use std::cell::RefCell;
+
+use ic_certified_map::{AsHashTree, Hash, HashTree, RbTree};
+use serde::Serialize;
+use serde_cbor::Value;
+
+// A subtree of a feature: either a map from keys to hashes or a map from keys
+// to maps from keys to leaves.
+enum Subtree {
+    Flat(RbTree<Vec<u8>, Hash>),
+    Nested(RbTree<Vec<u8>, RbTree<Vec<u8>, Vec<u8>>>),
+}
+
+impl AsHashTree for Subtree {
+    fn root_hash(&self) -> Hash {
+        match self {
+            Subtree::Flat(tree) => tree.root_hash(),
+            Subtree::Nested(tree) => tree.root_hash(),
+        }
+    }
+
+    fn as_hash_tree(&self) -> HashTree<'_> {
+        match self {
+            Subtree::Flat(tree) => tree.as_hash_tree(),
+            Subtree::Nested(tree) => tree.as_hash_tree(),
+        }
+    }
+}
+
+thread_local! {
+    // Maps the label of a feature to its subtree.
+    static TREE: RefCell<RbTree<Vec<u8>, Subtree>> = RefCell::new(RbTree::new());
+}
+
+/// Certifies the given hash under the given key in the subtree with the given
+/// label.
+pub fn insert(label: &str, key: Vec<u8>, hash: Hash) {
+    modify(
+        label,
+        || Subtree::Flat(RbTree::new()),
+        |subtree| {
+            if let Subtree::Flat(subtree) = subtree {
+                subtree.insert(key, hash);
+            }
+        },
+    );
+}
+
+/// Removes the given key from the subtree with the given label.
+pub fn remove(label: &str, key: &[u8]) {
+    modify(
+        label,
+        || Subtree::Flat(RbTree::new()),
+        |subtree| {
+            if let Subtree::Flat(subtree) = subtree {
+                subtree.delete(key);
+            }
+        },
+    );
+}
+
+/// Certifies the given leaf under the path `label/outer/inner`.
+pub fn insert_nested(label: &str, outer: Vec<u8>, inner: Vec<u8>, leaf: Vec<u8>) {
+    modify(
+        label,
+        || Subtree::Nested(RbTree::new()),
+        |subtree| {
+            if let Subtree::Nested(subtree) = subtree {
+                if subtree.get(&outer).is_none() {
+                    subtree.insert(outer.clone(), RbTree::new());
+                }
+                subtree.modify(&outer, |map| map.insert(inner, leaf));
+            }
+        },
+    );
+}
+
+/// Removes the path `label/outer/inner` from the certified state.
+pub fn remove_nested(label: &str, outer: &[u8], inner: &[u8]) {
+    modify(
+        label,
+        || Subtree::Nested(RbTree::new()),
+        |subtree| {
+            if let Subtree::Nested(subtree) = subtree {
+                let mut is_empty = false;
+                subtree.modify(outer, |map| {
+                    map.delete(inner);
+                    is_empty = map.is_empty();
+                });
+                if is_empty {
+                    subtree.delete(outer);
+                }
+            }
+        },
+    );
+}
+
+fn modify(label: &str, new: impl FnOnce() -> Subtree, f: impl FnOnce(&mut Subtree)) {
+    TREE.with(|tree| {
+        let mut tree = tree.borrow_mut();
+        if tree.get(label.as_bytes()).is_none() {
+            tree.insert(label.as_bytes().to_vec(), new());
+        }
+        tree.modify(label.as_bytes(), f);
+        ic_cdk::api::set_certified_data(&tree.root_hash());
+    });
+}
+
+/// Returns a witness that proves the presence or absence of the given key in
+/// the subtree with the given label.
+pub fn witness(label: &str, key: &[u8]) -> Value {
+    TREE.with(|tree| {
+        let tree = tree.borrow();
+        let witness = tree.nested_witness(label.as_bytes(), |subtree| match subtree {
+            Subtree::Flat(subtree) => subtree.witness(key),
+            Subtree::Nested(subtree) => subtree.witness(key),
+        });
+        serde_cbor::value::to_value(witness).unwrap()
+    })
+}
+
+/// Returns a witness that proves the presence or absence of the path
+/// `label/outer/inner`.
+pub fn nested_witness(label: &str, outer: &[u8], inner: &[u8]) -> Value {
+    TREE.with(|tree| {
+        let tree = tree.borrow();
+        let witness = tree.nested_witness(label.as_bytes(), |subtree| match subtree {
+            Subtree::Flat(subtree) => subtree.witness(outer),
+            Subtree::Nested(subtree) => subtree.nested_witness(outer, |map| map.witness(inner)),
+        });
+        serde_cbor::value::to_value(witness).unwrap()
+    })
+}
+
+/// Returns a witness that proves the contents of the key range from `first` to
+/// `last` including the neighbouring keys in the subtree with the given label.
+pub fn range_witness(label: &str, first: &[u8], last: &[u8]) -> Value {
+    TREE.with(|tree| {
+        let tree = tree.borrow();
+        let witness = tree.nested_witness(label.as_bytes(), |subtree| match subtree {
+            Subtree::Flat(subtree) => subtree.value_range(first, last),
+            Subtree::Nested(subtree) => subtree.value_range(first, last),
+        });
+        serde_cbor::value::to_value(witness).unwrap()
+    })
+}
+
+/// Returns the certificate of the certified data. It is available only in
+/// query calls.
+pub fn certificate() -> Result<Vec<u8>, String> {
+    ic_cdk::api::data_certificate()
+        .ok_or_else(|| "The certificate is available only in query calls".to_string())
+}
+
+/// Encodes the given value in the self-describing CBOR format expected by the
+/// agents.
+pub fn encode(value: &Value) -> Vec<u8> {
+    let mut serializer = serde_cbor::ser::Serializer::new(vec![]);
+    serializer.self_describe().unwrap();
+    value.serialize(&mut serializer).unwrap();
+    serializer.into_inner()
+}
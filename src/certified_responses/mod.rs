@@ -0,0 +1,114 @@
+// Certified responses of JS query endpoints.
+//
+// Query calls are answered by a single replica, so their results are not
+// backed by consensus. JS code precomputes the responses of selected queries
+// in update calls with `ic.certifiedResponses.set(key, response)`. Each
+// response is stored in stable memory and its hash is certified, so the
+// `certified_response` endpoint can return the response together with the
+// certificate and a witness that clients verify.
+use std::cell::RefCell;
+
+use candid::{CandidType, Deserialize};
+use ic_stable_structures::StableBTreeMap;
+use quickjs_wasm_rs::{CallbackArg, JSContextRef, JSError, JSValueRef};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    certification, engine,
+    stable::{self, Memory},
+};
+
+// The label of the subtree of certified responses in the certified state.
+const LABEL: &str = "responses";
+
+/// A response with the proof that it is part of the certified state.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct CertifiedResponse {
+    /// The response or `None` if there is no response for the key.
+    pub response: Option<String>,
+    /// The certificate of the certified data issued by the subnet.
+    pub certificate: Vec<u8>,
+    /// The CBOR-encoded hash tree that proves the presence or absence of the
+    /// response.
+    pub witness: Vec<u8>,
+}
+
+thread_local! {
+    static RESPONSES: RefCell<StableBTreeMap<String, String, Memory>> =
+        RefCell::new(StableBTreeMap::init(stable::memory(stable::CERTIFIED_RESPONSES)));
+}
+
+pub fn set(key: String, response: String) {
+    certification::insert(
+        LABEL,
+        key.as_bytes().to_vec(),
+        Sha256::digest(response.as_bytes()).into(),
+    );
+    RESPONSES.with(|responses| responses.borrow_mut().insert(key, response));
+}
+
+pub fn delete(key: &str) -> bool {
+    certification::remove(LABEL, key.as_bytes());
+    RESPONSES.with(|responses| responses.borrow_mut().remove(&key.to_string()).is_some())
+}
+
+/// Returns the response for the given key with its proof. Must be called in a
+/// query call.
+pub fn get(key: &str) -> Result<CertifiedResponse, String> {
+    Ok(CertifiedResponse {
+        response: RESPONSES.with(|responses| responses.borrow().get(&key.to_string())),
+        certificate: certification::certificate()?,
+        witness: certification::encode(&certification::witness(LABEL, key.as_bytes())),
+    })
+}
+
+/// Certifies all stored responses again. The certified state is not persisted,
+/// so this must be called after an upgrade.
+pub fn recertify() {
+    RESPONSES.with(|responses| {
+        for (key, response) in responses.borrow().iter() {
+            certification::insert(
+                LABEL,
+                key.into_bytes(),
+                Sha256::digest(response.as_bytes()).into(),
+            );
+        }
+    });
+}
+
+pub fn link(context: &JSContextRef) -> Result<(), anyhow::Error> {
+    fn set<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 2 {
+            return Err(JSError::Type(format!("Expected 2 arguments, got {}", args.len())).into());
+        }
+        engine::ensure_writable("ic.certifiedResponses.set()")?;
+        let key: String = args[0].try_into()?;
+        let response: String = args[1].try_into()?;
+        self::set(key, response);
+        context.undefined_value()
+    }
+
+    fn delete<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 1 {
+            return Err(JSError::Type(format!("Expected 1 argument, got {}", args.len())).into());
+        }
+        engine::ensure_writable("ic.certifiedResponses.delete()")?;
+        let key: String = args[0].try_into()?;
+        context.value_from_bool(self::delete(&key))
+    }
+
+    let certified_responses = context.object_value()?;
+    certified_responses.set_property("set", context.wrap_callback2(set)?)?;
+    certified_responses.set_property("delete", context.wrap_callback2(delete)?)?;
+
+    engine::namespace(context)?.set_property("certifiedResponses", certified_responses)?;
+    Ok(())
+}
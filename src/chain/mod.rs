@@ -0,0 +1,261 @@
+// Chain fusion helpers exposed to JS as `ic.chain`.
+//
+// `ic.chain.eth.sendTransaction({to, value, data})` sends an EIP-1559
+// transaction from the Ethereum address of the threshold ECDSA key of this
+// canister. It looks up the nonce and the fees with the EVM RPC binding,
+// serializes and signs the transaction and submits it with
+// `eth_sendRawTransaction`. Configure the chain with
+// `ic.chain.eth.configure({chainId, keyName, derivationPath})` and the RPC
+// service with `ic.evmRpc.configure()`.
+use std::cell::RefCell;
+
+use anyhow::anyhow;
+use ethabi::{ethereum_types::U256, Address};
+use ic_cdk::api::management_canister::ecdsa::{
+    sign_with_ecdsa, EcdsaCurve, EcdsaKeyId, SignWithEcdsaArgument,
+};
+use k256::{
+    ecdsa::{RecoveryId, VerifyingKey},
+    elliptic_curve::sec1::ToEncodedPoint,
+};
+use quickjs_wasm_rs::{CallbackArg, JSContextRef, JSError, JSValue, JSValueRef};
+use serde_json::json;
+
+use crate::{
+    engine,
+    eth::{self, abi, address, Signature, Transaction},
+    evm_rpc,
+};
+
+/// The fields of a transaction that JS provides. The remaining fields are
+/// filled in by `send_transaction()`.
+#[derive(Clone, Debug)]
+pub struct TransactionRequest {
+    pub to: Option<Address>,
+    pub value: U256,
+    pub data: Vec<u8>,
+    pub gas_limit: Option<U256>,
+}
+
+#[derive(Clone, Debug)]
+struct EthConfig {
+    chain_id: Option<U256>,
+    key_name: String,
+    derivation_path: Vec<Vec<u8>>,
+}
+
+thread_local! {
+    static ETH_CONFIG: RefCell<EthConfig> = RefCell::new(EthConfig {
+        chain_id: None,
+        key_name: address::DEFAULT_KEY_NAME.to_string(),
+        derivation_path: vec![],
+    });
+}
+
+/// Signs and submits the given transaction and returns its hash.
+pub async fn send_transaction(request: TransactionRequest) -> Result<String, String> {
+    let config = ETH_CONFIG.with(|config| config.borrow().clone());
+    let chain_id = config
+        .chain_id
+        .ok_or_else(|| "The chain id is not configured".to_string())?;
+    let from =
+        address::address_for(config.key_name.clone(), config.derivation_path.clone()).await?;
+    let from_hex = address::to_checksum(&from);
+
+    let nonce =
+        quantity(evm_rpc::request("eth_getTransactionCount", json!([from_hex, "pending"])).await?)?;
+    let gas_price = quantity(evm_rpc::request("eth_gasPrice", json!([])).await?)?;
+    let max_priority_fee_per_gas =
+        quantity(evm_rpc::request("eth_maxPriorityFeePerGas", json!([])).await?)?;
+    // The gas price approximates the current base fee plus the tip, so doubling
+    // it keeps the transaction valid if the base fee rises in the next blocks.
+    let max_fee_per_gas = gas_price * 2 + max_priority_fee_per_gas;
+    let gas_limit = match request.gas_limit {
+        Some(gas_limit) => gas_limit,
+        None => {
+            let mut call = json!({
+                "from": from_hex,
+                "value": format!("{:#x}", request.value),
+                "data": format!("0x{}", hex::encode(&request.data)),
+            });
+            if let Some(to) = request.to {
+                call["to"] = json!(address::to_checksum(&to));
+            }
+            quantity(evm_rpc::request("eth_estimateGas", json!([call])).await?)?
+        }
+    };
+
+    let transaction = Transaction {
+        chain_id,
+        nonce,
+        max_priority_fee_per_gas,
+        max_fee_per_gas,
+        gas_limit,
+        to: request.to,
+        value: request.value,
+        data: request.data,
+        access_list: vec![],
+    };
+    let hash = transaction.signing_hash();
+    let (response,) = sign_with_ecdsa(SignWithEcdsaArgument {
+        message_hash: hash.to_vec(),
+        derivation_path: config.derivation_path,
+        key_id: EcdsaKeyId {
+            curve: EcdsaCurve::Secp256k1,
+            name: config.key_name,
+        },
+    })
+    .await
+    .map_err(|(code, message)| {
+        format!("sign_with_ecdsa failed with code {:?}: {}", code, message)
+    })?;
+    let signature =
+        recoverable_signature(&hash, &response.signature, &from).map_err(|err| err.to_string())?;
+
+    let raw = transaction.serialize(Some(&signature));
+    let result = evm_rpc::request(
+        "eth_sendRawTransaction",
+        json!([format!("0x{}", hex::encode(raw))]),
+    )
+    .await?;
+    result
+        .as_str()
+        .map(|hash| hash.to_string())
+        .ok_or_else(|| format!("Unexpected eth_sendRawTransaction result: {}", result))
+}
+
+// Threshold ECDSA does not return the recovery id, so it is found by recovering
+// the public key with both candidates and comparing the addresses.
+fn recoverable_signature(
+    hash: &[u8; 32],
+    signature: &[u8],
+    from: &Address,
+) -> Result<Signature, anyhow::Error> {
+    let signature = k256::ecdsa::Signature::try_from(signature)?;
+    // Ethereum accepts only signatures with a low S value.
+    let signature = signature.normalize_s().unwrap_or(signature);
+    let y_parity = [false, true]
+        .into_iter()
+        .find(|y_parity| {
+            let id = RecoveryId::new(*y_parity, false);
+            VerifyingKey::recover_from_prehash(hash, &signature, id)
+                .ok()
+                .and_then(|key| {
+                    address::from_public_key(key.to_encoded_point(false).as_bytes()).ok()
+                })
+                .is_some_and(|address| address == *from)
+        })
+        .ok_or_else(|| {
+            anyhow!(
+                "The signature does not match the address {}",
+                address::to_checksum(from)
+            )
+        })?;
+    let bytes = signature.to_bytes();
+    Ok(Signature {
+        y_parity,
+        r: U256::from_big_endian(&bytes[..32]),
+        s: U256::from_big_endian(&bytes[32..]),
+    })
+}
+
+fn quantity(value: serde_json::Value) -> Result<U256, String> {
+    match value.as_str().and_then(|value| value.strip_prefix("0x")) {
+        Some(hex) => U256::from_str_radix(hex, 16)
+            .map_err(|err| format!("Invalid quantity {}: {:?}", value, err)),
+        None => Err(format!("Expected a hex quantity, got {}", value)),
+    }
+}
+
+fn configure(options: &JSValue) -> Result<(), anyhow::Error> {
+    let JSValue::Object(fields) = options else {
+        return Err(JSError::Type("Expected an options object".to_string()).into());
+    };
+    ETH_CONFIG.with(|config| {
+        let mut config = config.borrow_mut();
+        if let Some(chain_id) = fields.get("chainId") {
+            config.chain_id = Some(abi::uint(chain_id)?);
+        }
+        match fields.get("keyName") {
+            Some(JSValue::String(name)) => config.key_name = name.clone(),
+            Some(_) => {
+                return Err(JSError::Type("Expected keyName to be a string".to_string()).into())
+            }
+            None => {}
+        }
+        if let Some(path) = fields.get("derivationPath") {
+            config.derivation_path = eth::derivation_path(path)?;
+        }
+        Ok(())
+    })
+}
+
+fn transaction_request(value: &JSValue) -> Result<TransactionRequest, anyhow::Error> {
+    let JSValue::Object(fields) = value else {
+        return Err(
+            JSError::Type("Expected a transaction object {to, value, data}".to_string()).into(),
+        );
+    };
+    let field = |name: &str| {
+        fields
+            .get(name)
+            .filter(|value| !matches!(value, JSValue::Undefined | JSValue::Null))
+    };
+    Ok(TransactionRequest {
+        to: field("to").map(abi::address).transpose()?,
+        value: field("value")
+            .map(abi::uint)
+            .transpose()?
+            .unwrap_or_default(),
+        data: field("data")
+            .map(abi::bytes)
+            .transpose()?
+            .unwrap_or_default(),
+        gas_limit: field("gasLimit").map(abi::uint).transpose()?,
+    })
+}
+
+pub fn link(context: &JSContextRef) -> Result<(), anyhow::Error> {
+    fn configure<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 1 {
+            return Err(JSError::Type(format!("Expected 1 argument, got {}", args.len())).into());
+        }
+        self::configure(&args[0].to_js_value()?)?;
+        context.undefined_value()
+    }
+
+    // Returns a promise that resolves with the transaction hash.
+    fn send_transaction<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 1 {
+            return Err(JSError::Type(format!("Expected 1 argument, got {}", args.len())).into());
+        }
+        let request = transaction_request(&args[0].to_js_value()?)?;
+        let (id, promise) = engine::promise(context)?;
+        ic_cdk::spawn(async move {
+            let result = self::send_transaction(request).await;
+            engine::settle(id, move |context| match result {
+                Ok(hash) => context.value_from_str(&hash),
+                Err(err) => Err(anyhow::anyhow!(err)),
+            });
+        });
+        Ok(promise)
+    }
+
+    let eth = context.object_value()?;
+    eth.set_property("configure", context.wrap_callback2(configure)?)?;
+    eth.set_property("sendTransaction", context.wrap_callback2(send_transaction)?)?;
+
+    let chain = context.object_value()?;
+    chain.set_property("eth", eth)?;
+
+    engine::namespace(context)?.set_property("chain", chain)?;
+    Ok(())
+}
@@ -0,0 +1,115 @@
+// Compression of binary data exposed to JS as `ic.compress`.
+//
+// The functions are public so that the HTTP layer can compress responses with
+// the same implementation.
+use std::io::{Read, Write};
+
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use quickjs_wasm_rs::{CallbackArg, JSContextRef, JSError, JSValue, JSValueRef};
+
+use crate::engine;
+
+// The maximum size of decompressed data. It protects against decompression
+// bombs that would exhaust the heap of the canister.
+const MAX_DECOMPRESSED_SIZE: u64 = 64 * 1024 * 1024;
+
+// The brotli parameters: the quality from 0 to 11 and the window size.
+const BROTLI_QUALITY: u32 = 9;
+const BROTLI_WINDOW: u32 = 22;
+const BROTLI_BUFFER_SIZE: usize = 4096;
+
+pub fn gzip(bytes: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(vec![], Compression::default());
+    encoder.write_all(bytes).unwrap();
+    encoder.finish().unwrap()
+}
+
+pub fn gunzip(bytes: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+    read_limited(GzDecoder::new(bytes))
+}
+
+pub fn brotli(bytes: &[u8]) -> Vec<u8> {
+    let mut result = vec![];
+    {
+        let mut writer = brotli::CompressorWriter::new(
+            &mut result,
+            BROTLI_BUFFER_SIZE,
+            BROTLI_QUALITY,
+            BROTLI_WINDOW,
+        );
+        writer.write_all(bytes).unwrap();
+    }
+    result
+}
+
+pub fn unbrotli(bytes: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+    read_limited(brotli::Decompressor::new(bytes, BROTLI_BUFFER_SIZE))
+}
+
+fn read_limited(reader: impl Read) -> Result<Vec<u8>, anyhow::Error> {
+    let mut result = vec![];
+    reader
+        .take(MAX_DECOMPRESSED_SIZE + 1)
+        .read_to_end(&mut result)?;
+    if result.len() as u64 > MAX_DECOMPRESSED_SIZE {
+        anyhow::bail!(
+            "The decompressed data exceeds {} bytes",
+            MAX_DECOMPRESSED_SIZE
+        );
+    }
+    Ok(result)
+}
+
+pub fn link(context: &JSContextRef) -> Result<(), anyhow::Error> {
+    // Each function takes one ArrayBuffer and returns an ArrayBuffer.
+    fn bytes(args: &[CallbackArg]) -> Result<Vec<u8>, anyhow::Error> {
+        if args.len() != 1 {
+            return Err(JSError::Type(format!("Expected 1 argument, got {}", args.len())).into());
+        }
+        match args[0].to_js_value()? {
+            JSValue::ArrayBuffer(bytes) => Ok(bytes),
+            _ => Err(JSError::Type("Expected an ArrayBuffer".to_string()).into()),
+        }
+    }
+
+    fn gzip<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        context.array_buffer_value(&self::gzip(&bytes(args)?))
+    }
+
+    fn gunzip<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        context.array_buffer_value(&self::gunzip(&bytes(args)?)?)
+    }
+
+    fn brotli<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        context.array_buffer_value(&self::brotli(&bytes(args)?))
+    }
+
+    fn unbrotli<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        context.array_buffer_value(&self::unbrotli(&bytes(args)?)?)
+    }
+
+    let compress = context.object_value()?;
+    compress.set_property("gzip", context.wrap_callback2(gzip)?)?;
+    compress.set_property("gunzip", context.wrap_callback2(gunzip)?)?;
+    compress.set_property("brotli", context.wrap_callback2(brotli)?)?;
+    compress.set_property("unbrotli", context.wrap_callback2(unbrotli)?)?;
+
+    engine::namespace(context)?.set_property("compress", compress)?;
+    Ok(())
+}
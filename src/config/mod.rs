@@ -0,0 +1,145 @@
+// Runtime configuration exposed to JS as `ic.config`.
+//
+// The script declares each config key with `ic.config.define(key, schema,
+// defaultValue)` where the schema is a JSON Schema. Controllers change values
+// with `set_config(key, json)`, which validates the value against the schema
+// before storing it, and JS reads the current value with `ic.config.get(key)`.
+// Values are stored in stable memory, so they survive upgrades, while the
+// schemas and defaults are declared by the script on each start.
+use std::{cell::RefCell, collections::HashMap};
+
+use ic_stable_structures::StableBTreeMap;
+use quickjs_wasm_rs::{CallbackArg, JSContextRef, JSError, JSValueRef};
+use serde_json::Value;
+
+use crate::{
+    engine, json, schemas,
+    stable::{self, Memory},
+};
+
+thread_local! {
+    // Maps a key to its value as JSON text.
+    static VALUES: RefCell<StableBTreeMap<String, String, Memory>> =
+        RefCell::new(StableBTreeMap::init(stable::memory(stable::CONFIG)));
+
+    // Maps a declared key to its default value.
+    static DEFAULTS: RefCell<HashMap<String, Value>> = RefCell::new(HashMap::new());
+}
+
+// The name of the schema of a key in `schemas`.
+fn schema_name(key: &str) -> String {
+    format!("config:{}", key)
+}
+
+pub fn define(key: String, schema: &Value, default: Value) -> Result<(), anyhow::Error> {
+    schemas::define(schema_name(&key), schema)?;
+    if let Err(issues) = schemas::validate(&schema_name(&key), &default) {
+        anyhow::bail!(
+            "Invalid default of config {}: {}",
+            key,
+            schemas::describe(&issues)
+        );
+    }
+    DEFAULTS.with(|defaults| defaults.borrow_mut().insert(key, default));
+    Ok(())
+}
+
+/// Validates and stores a new value of the given key.
+pub fn set(key: String, value: &str) -> Result<(), String> {
+    if !DEFAULTS.with(|defaults| defaults.borrow().contains_key(&key)) {
+        return Err(format!("The config {} is not defined by the script", key));
+    }
+    let value: Value =
+        serde_json::from_str(value).map_err(|err| format!("Invalid JSON: {}", err))?;
+    if let Err(issues) = schemas::validate(&schema_name(&key), &value) {
+        return Err(format!(
+            "Invalid value of config {}: {}",
+            key,
+            schemas::describe(&issues)
+        ));
+    }
+    VALUES.with(|values| values.borrow_mut().insert(key, value.to_string()));
+    Ok(())
+}
+
+/// Removes the stored value of the given key, so that it has its default.
+pub fn reset(key: String) {
+    VALUES.with(|values| values.borrow_mut().remove(&key));
+}
+
+/// Returns the current value of the given key or `None` if the key is
+/// neither stored nor declared.
+pub fn get(key: &str) -> Option<Value> {
+    let stored = VALUES.with(|values| values.borrow().get(&key.to_string()));
+    match stored {
+        Some(value) => serde_json::from_str(&value).ok(),
+        None => DEFAULTS.with(|defaults| defaults.borrow().get(key).cloned()),
+    }
+}
+
+/// Returns the current values of all declared keys as JSON text.
+pub fn list() -> Vec<(String, String)> {
+    let mut keys: Vec<String> =
+        DEFAULTS.with(|defaults| defaults.borrow().keys().cloned().collect());
+    keys.sort();
+    keys.into_iter()
+        .filter_map(|key| get(&key).map(|value| (key, value.to_string())))
+        .collect()
+}
+
+/// Returns the stored values as JSON text, without the defaults.
+pub fn export() -> Vec<(String, String)> {
+    VALUES.with(|values| values.borrow().iter().collect())
+}
+
+/// Stores the exported values of a backup. They were validated when they were
+/// set.
+pub fn import(values: Vec<(String, String)>) {
+    VALUES.with(|stored| {
+        let mut stored = stored.borrow_mut();
+        for (key, value) in values {
+            stored.insert(key, value);
+        }
+    });
+}
+
+pub fn link(context: &JSContextRef) -> Result<(), anyhow::Error> {
+    // Usage: `ic.config.define('maxItems', {type: 'integer', minimum: 1}, 10)`.
+    fn define<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 3 {
+            return Err(JSError::Type(format!("Expected 3 arguments, got {}", args.len())).into());
+        }
+        let key: String = args[0].try_into()?;
+        let schema = json::to_json(&args[1].to_js_value()?);
+        let default = json::to_json(&args[2].to_js_value()?);
+        self::define(key, &schema, default)?;
+        context.undefined_value()
+    }
+
+    // Returns `undefined` for keys that are not declared.
+    fn get<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 1 {
+            return Err(JSError::Type(format!("Expected 1 argument, got {}", args.len())).into());
+        }
+        let key: String = args[0].try_into()?;
+        match self::get(&key) {
+            Some(value) => quickjs_wasm_rs::to_qjs_value(context, &json::from_json(&value)),
+            None => context.undefined_value(),
+        }
+    }
+
+    let config = context.object_value()?;
+    config.set_property("define", context.wrap_callback2(define)?)?;
+    config.set_property("get", context.wrap_callback2(get)?)?;
+
+    engine::namespace(context)?.set_property("config", config)?;
+    Ok(())
+}
@@ -0,0 +1,155 @@
+// Converters of Candid arguments of the endpoints in lib.rs into JS values.
+//
+// A typed endpoint declares its signature and passes its arguments to
+// `arguments()` instead of writing a bespoke `Arguments` function:
+//
+//     #[ic_cdk_macros::update(manual_reply = true)]
+//     fn transfer(to: Principal, amount: Nat, memo: Option<Blob>) -> ManualReply<String> {
+//         engine::execute("transfer", converters::arguments("transfer", (to, amount, memo)), replier)
+//     }
+//
+// Numbers up to 64 bits become JS numbers, `nat` and `int` become decimal
+// strings that JS can convert with `BigInt()`, blobs become ArrayBuffers and
+// records wrapped in `Json` become objects.
+use anyhow::Error;
+use candid::{CandidType, Deserialize, Int, Nat, Principal};
+use quickjs_wasm_rs::{JSContextRef, JSValueRef};
+use serde::Serialize;
+
+use crate::{auth, engine::Arguments, json, quotas, schemas};
+
+/// A Candid `blob` passed to JS as an ArrayBuffer.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Blob(pub Vec<u8>);
+
+/// A record or variant passed to JS as the object of its JSON encoding.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Json<T>(pub T);
+
+/// A Candid value that can be converted into a JS value.
+pub trait ToJs {
+    fn to_js<'a>(self, context: &'a JSContextRef) -> Result<JSValueRef<'a>, Error>;
+}
+
+impl ToJs for String {
+    fn to_js<'a>(self, context: &'a JSContextRef) -> Result<JSValueRef<'a>, Error> {
+        context.value_from_str(&self)
+    }
+}
+
+impl ToJs for bool {
+    fn to_js<'a>(self, context: &'a JSContextRef) -> Result<JSValueRef<'a>, Error> {
+        context.value_from_bool(self)
+    }
+}
+
+macro_rules! number_to_js {
+    ($($t:ty),*) => {
+        $(
+            impl ToJs for $t {
+                fn to_js<'a>(self, context: &'a JSContextRef) -> Result<JSValueRef<'a>, Error> {
+                    context.value_from_f64(self as f64)
+                }
+            }
+        )*
+    };
+}
+
+number_to_js!(u8, u16, u32, u64, i8, i16, i32, i64, f32, f64);
+
+impl ToJs for Nat {
+    fn to_js<'a>(self, context: &'a JSContextRef) -> Result<JSValueRef<'a>, Error> {
+        context.value_from_str(&self.0.to_string())
+    }
+}
+
+impl ToJs for Int {
+    fn to_js<'a>(self, context: &'a JSContextRef) -> Result<JSValueRef<'a>, Error> {
+        context.value_from_str(&self.0.to_string())
+    }
+}
+
+impl ToJs for Principal {
+    fn to_js<'a>(self, context: &'a JSContextRef) -> Result<JSValueRef<'a>, Error> {
+        context.value_from_str(&self.to_text())
+    }
+}
+
+impl ToJs for Blob {
+    fn to_js<'a>(self, context: &'a JSContextRef) -> Result<JSValueRef<'a>, Error> {
+        context.array_buffer_value(&self.0)
+    }
+}
+
+impl<T: Serialize> ToJs for Json<T> {
+    fn to_js<'a>(self, context: &'a JSContextRef) -> Result<JSValueRef<'a>, Error> {
+        let value = serde_json::to_value(&self.0)?;
+        quickjs_wasm_rs::to_qjs_value(context, &json::from_json(&value))
+    }
+}
+
+impl<T: ToJs> ToJs for Option<T> {
+    fn to_js<'a>(self, context: &'a JSContextRef) -> Result<JSValueRef<'a>, Error> {
+        match self {
+            Some(value) => value.to_js(context),
+            None => context.null_value(),
+        }
+    }
+}
+
+impl<T: ToJs> ToJs for Vec<T> {
+    fn to_js<'a>(self, context: &'a JSContextRef) -> Result<JSValueRef<'a>, Error> {
+        let array = context.array_value()?;
+        for value in self {
+            array.append_property(value.to_js(context)?)?;
+        }
+        Ok(array)
+    }
+}
+
+/// The tuple of arguments of an endpoint.
+pub trait ToJsArgs {
+    fn to_js_args<'a>(self, context: &'a JSContextRef) -> Result<Vec<JSValueRef<'a>>, Error>;
+}
+
+macro_rules! tuple_to_js_args {
+    ($($name:ident),*) => {
+        impl<$($name: ToJs),*> ToJsArgs for ($($name,)*) {
+            #[allow(non_snake_case, unused_variables)]
+            fn to_js_args<'a>(self, context: &'a JSContextRef) -> Result<Vec<JSValueRef<'a>>, Error> {
+                let ($($name,)*) = self;
+                Ok(vec![$($name.to_js(context)?),*])
+            }
+        }
+    };
+}
+
+tuple_to_js_args!();
+tuple_to_js_args!(A);
+tuple_to_js_args!(A, B);
+tuple_to_js_args!(A, B, C);
+tuple_to_js_args!(A, B, C, D);
+tuple_to_js_args!(A, B, C, D, E);
+
+/// Returns the `Arguments` function of the given endpoint that runs the auth
+/// middleware and the quotas of the caller, converts the arguments and checks
+/// them against the schema of the endpoint.
+pub fn arguments<A: ToJsArgs>(endpoint: &str, args: A) -> impl Arguments {
+    let endpoint = endpoint.to_string();
+    constrain(move |context| {
+        let identity = auth::Identity::caller();
+        auth::middleware::check(&endpoint, &identity).map_err(anyhow::Error::msg)?;
+        quotas::consume(&endpoint, &identity)?;
+        let args = args.to_js_args(context)?;
+        schemas::check_arguments(&endpoint, &args)?;
+        Ok(args)
+    })
+}
+
+// Helps the compiler infer the higher-ranked signature of the closure.
+fn constrain<F>(f: F) -> F
+where
+    F: for<'a> FnOnce(&'a JSContextRef) -> Result<Vec<JSValueRef<'a>>, Error>,
+{
+    f
+}
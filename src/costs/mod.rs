@@ -0,0 +1,107 @@
+// Estimates of the cycles charged for operations exposed to JS as
+// `ic.costs.estimate(options)`.
+//
+// The formulas follow the published cost tables, which are given for a subnet
+// of 13 nodes and scale linearly with the number of nodes. The estimates do
+// not include the instructions executed by the canister itself.
+//
+// - `{type: "call", requestBytes, responseBytes, subnetSize}`: an
+//   inter-canister call without the attached cycles.
+// - `{type: "http_outcall", requestBytes, maxResponseBytes, subnetSize}`: an
+//   HTTPS outcall, which is charged for `maxResponseBytes` (2MB by default)
+//   regardless of the actual response.
+// - `{type: "ecdsa_sign", subnetSize}`: a threshold ECDSA signature. The
+//   signing subnet is charged, so the size defaults to the 34 nodes of the
+//   subnet that holds the production key.
+use quickjs_wasm_rs::{CallbackArg, JSContextRef, JSError, JSValue, JSValueRef};
+
+use crate::{engine, jobs};
+
+// The size of the subnets of the published cost tables.
+const REFERENCE_SUBNET_SIZE: u128 = 13;
+
+const DEFAULT_SUBNET_SIZE: u128 = 13;
+const ECDSA_SUBNET_SIZE: u128 = 34;
+
+const CALL_FEE: u128 = 260_000;
+const CALL_BYTE_FEE: u128 = 1_000;
+
+const HTTP_BASE_FEE: u128 = 3_000_000;
+const HTTP_NODE_FEE: u128 = 60_000;
+const HTTP_REQUEST_BYTE_FEE: u128 = 400;
+const HTTP_RESPONSE_BYTE_FEE: u128 = 800;
+
+/// The maximum response size of an HTTPS outcall that does not set one.
+pub const HTTP_DEFAULT_MAX_RESPONSE_BYTES: u64 = 2_000_000;
+
+const ECDSA_SIGN_FEE: u128 = 10_000_000_000;
+
+fn scale(cycles: u128, subnet_size: u128) -> u128 {
+    cycles * subnet_size / REFERENCE_SUBNET_SIZE
+}
+
+/// The cost of an inter-canister call with the given payload sizes.
+pub fn call(request_bytes: u64, response_bytes: u64, subnet_size: u128) -> u128 {
+    let bytes = request_bytes as u128 + response_bytes as u128;
+    scale(CALL_FEE + CALL_BYTE_FEE * bytes, subnet_size)
+}
+
+/// The cost of an HTTPS outcall. The formula is stated per node, so it does
+/// not need scaling.
+pub fn http_outcall(request_bytes: u64, max_response_bytes: u64, subnet_size: u128) -> u128 {
+    let n = subnet_size;
+    (HTTP_BASE_FEE + HTTP_NODE_FEE * n) * n
+        + HTTP_REQUEST_BYTE_FEE * n * request_bytes as u128
+        + HTTP_RESPONSE_BYTE_FEE * n * max_response_bytes as u128
+}
+
+/// The cost of a threshold ECDSA signature on a subnet of the given size.
+pub fn ecdsa_sign(subnet_size: u128) -> u128 {
+    scale(ECDSA_SIGN_FEE, subnet_size)
+}
+
+pub fn link(context: &JSContextRef) -> Result<(), anyhow::Error> {
+    fn estimate<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 1 {
+            return Err(JSError::Type(format!("Expected 1 argument, got {}", args.len())).into());
+        }
+        let JSValue::Object(options) = args[0].to_js_value()? else {
+            return Err(JSError::Type("Expected an options object".to_string()).into());
+        };
+        let param = |name: &str, default: u64| match options.get(name) {
+            None | Some(JSValue::Undefined | JSValue::Null) => Ok(default),
+            Some(value) => jobs::number(value, name).map(|value| value as u64),
+        };
+        let cycles = match options.get("type") {
+            Some(JSValue::String(kind)) if kind == "call" => call(
+                param("requestBytes", 0)?,
+                param("responseBytes", 0)?,
+                param("subnetSize", DEFAULT_SUBNET_SIZE as u64)? as u128,
+            ),
+            Some(JSValue::String(kind)) if kind == "http_outcall" => http_outcall(
+                param("requestBytes", 0)?,
+                param("maxResponseBytes", HTTP_DEFAULT_MAX_RESPONSE_BYTES)?,
+                param("subnetSize", DEFAULT_SUBNET_SIZE as u64)? as u128,
+            ),
+            Some(JSValue::String(kind)) if kind == "ecdsa_sign" => {
+                ecdsa_sign(param("subnetSize", ECDSA_SUBNET_SIZE as u64)? as u128)
+            }
+            _ => {
+                return Err(JSError::Type(
+                    "Expected type to be call, http_outcall or ecdsa_sign".to_string(),
+                )
+                .into());
+            }
+        };
+        context.value_from_f64(cycles as f64)
+    }
+
+    let costs = context.object_value()?;
+    costs.set_property("estimate", context.wrap_callback2(estimate)?)?;
+    engine::namespace(context)?.set_property("costs", costs)?;
+    Ok(())
+}
@@ -0,0 +1,108 @@
+// Cryptographic hash functions exposed to JS as `ic.crypto`.
+//
+// Besides SHA-256, this includes the hash functions used by other chains:
+// Keccak-256 for Ethereum addresses, BLAKE2 for content ids and RIPEMD-160 for
+// Bitcoin P2PKH addresses.
+use blake2::{Blake2b512, Blake2s256};
+use quickjs_wasm_rs::{CallbackArg, JSContextRef, JSError, JSValue, JSValueRef};
+use ripemd::Ripemd160;
+use sha2::{Digest, Sha256};
+use sha3::Keccak256;
+
+use crate::engine;
+
+pub fn sha256(bytes: &[u8]) -> [u8; 32] {
+    Sha256::digest(bytes).into()
+}
+
+pub fn keccak256(bytes: &[u8]) -> [u8; 32] {
+    Keccak256::digest(bytes).into()
+}
+
+pub fn ripemd160(bytes: &[u8]) -> [u8; 20] {
+    Ripemd160::digest(bytes).into()
+}
+
+/// Returns `ripemd160(sha256(bytes))` as used in Bitcoin addresses.
+pub fn hash160(bytes: &[u8]) -> [u8; 20] {
+    ripemd160(&sha256(bytes))
+}
+
+/// Returns the bytes of a string (UTF-8 encoded) or an ArrayBuffer argument.
+pub fn bytes(arg: &CallbackArg) -> Result<Vec<u8>, anyhow::Error> {
+    match arg.to_js_value()? {
+        JSValue::String(string) => Ok(string.into_bytes()),
+        JSValue::ArrayBuffer(bytes) => Ok(bytes),
+        _ => Err(JSError::Type("Expected a string or an ArrayBuffer".to_string()).into()),
+    }
+}
+
+pub fn link(context: &JSContextRef) -> Result<(), anyhow::Error> {
+    // Each function takes a string or an ArrayBuffer and returns the digest as
+    // an ArrayBuffer.
+    fn input(args: &[CallbackArg]) -> Result<Vec<u8>, anyhow::Error> {
+        if args.len() != 1 {
+            return Err(JSError::Type(format!("Expected 1 argument, got {}", args.len())).into());
+        }
+        bytes(&args[0])
+    }
+
+    fn sha256<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        context.array_buffer_value(&self::sha256(&input(args)?))
+    }
+
+    fn keccak256<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        context.array_buffer_value(&self::keccak256(&input(args)?))
+    }
+
+    fn blake2b<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        context.array_buffer_value(&Blake2b512::digest(input(args)?))
+    }
+
+    fn blake2s<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        context.array_buffer_value(&Blake2s256::digest(input(args)?))
+    }
+
+    fn ripemd160<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        context.array_buffer_value(&self::ripemd160(&input(args)?))
+    }
+
+    fn hash160<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        context.array_buffer_value(&self::hash160(&input(args)?))
+    }
+
+    let crypto = context.object_value()?;
+    crypto.set_property("sha256", context.wrap_callback2(sha256)?)?;
+    crypto.set_property("keccak256", context.wrap_callback2(keccak256)?)?;
+    crypto.set_property("blake2b", context.wrap_callback2(blake2b)?)?;
+    crypto.set_property("blake2s", context.wrap_callback2(blake2s)?)?;
+    crypto.set_property("ripemd160", context.wrap_callback2(ripemd160)?)?;
+    crypto.set_property("hash160", context.wrap_callback2(hash160)?)?;
+
+    engine::namespace(context)?.set_property("crypto", crypto)?;
+    Ok(())
+}
@@ -0,0 +1,7 @@
+// The handler of the jobs that retry a requeued callback handler. Calls the
+// global function with the recorded arguments.
+Object.defineProperty(globalThis, "__retryCallback__", {
+	value({ name, args }) {
+		return globalThis[name](...args);
+	},
+});
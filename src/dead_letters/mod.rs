@@ -0,0 +1,179 @@
+// A store of failed background work that can be inspected and requeued.
+//
+// A job that fails all of its attempts ends up here together with its payload
+// and the last error instead of being silently dropped. So does a callback
+// handler run by Rust, e.g. an alert handler or `onShardMapChange`, that
+// throws, with its arguments as the payload. Requeuing a callback enqueues a
+// job that calls the handler with the same arguments. The callbacks of
+// `setTimeout` are closures that cannot be called again, so their errors are
+// only logged.
+use std::{cell::RefCell, rc::Rc};
+
+use candid::{CandidType, Deserialize};
+use ic_stable_structures::{StableBTreeMap, StableCell};
+use quickjs_wasm_rs::JSContextRef;
+use serde_json::{json, Value};
+
+use crate::{
+    engine::{self, Arguments},
+    jobs::{self, Job, RetryPolicy},
+    json,
+    stable::{self, Candid, Memory},
+    system_api::{self, Level},
+};
+
+// The handler of the jobs that retry requeued callbacks.
+const DEAD_LETTERS_FILE: &str = "dead_letters.js";
+const DEAD_LETTERS_SCRIPT: &str = include_str!("dead_letters.js");
+const RETRY_CALLBACK: &str = "__retryCallback__";
+
+/// The failed work of a dead letter.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum Kind {
+    /// A job with its payload.
+    Job,
+    /// A callback handler with the JSON array of its arguments as the payload.
+    Callback,
+}
+
+/// A job that has exhausted all of its attempts.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct DeadLetter {
+    pub id: u64,
+    /// The name of the global JS function that handles the job.
+    pub name: String,
+    /// The JSON-encoded argument of the handler.
+    pub payload: String,
+    /// The error of the last attempt.
+    pub error: String,
+    pub attempts: u32,
+    pub policy: RetryPolicy,
+    /// The time of the last attempt in nanoseconds.
+    pub failed_at: u64,
+    /// Missing for the jobs that failed before callbacks were recorded.
+    pub kind: Option<Kind>,
+}
+
+thread_local! {
+    static DEAD_LETTERS: RefCell<StableBTreeMap<u64, Candid<DeadLetter>, Memory>> =
+        RefCell::new(StableBTreeMap::init(stable::memory(stable::DEAD_LETTERS)));
+
+    // The id of the next dead letter.
+    static NEXT_ID: RefCell<StableCell<u64, Memory>> =
+        RefCell::new(StableCell::init(stable::memory(stable::DEAD_LETTERS_SEQUENCE), 0).unwrap());
+}
+
+/// Moves the given failed job to the dead-letter store.
+pub fn put(job: Job) -> u64 {
+    insert(|id| DeadLetter {
+        id,
+        name: job.name,
+        payload: job.payload,
+        error: job.last_error.unwrap_or_default(),
+        attempts: job.attempts,
+        policy: job.policy,
+        failed_at: ic_cdk::api::time(),
+        kind: Some(Kind::Job),
+    })
+}
+
+/// Runs the global JS callback handler like `engine::spawn()`. If it throws,
+/// the error is logged with the given label and the handler is moved to the
+/// dead-letter store with its arguments.
+pub fn spawn(name: &str, label: &'static str, arguments: impl Arguments + 'static) {
+    let payload = Rc::new(RefCell::new(Value::Null));
+    let recorded = payload.clone();
+    let handler = name.to_string();
+    engine::spawn(
+        name,
+        move |context: &JSContextRef| {
+            let args = arguments(context)?;
+            let json = args
+                .iter()
+                .map(|arg| Ok(json::to_json(&quickjs_wasm_rs::from_qjs_value(arg)?)))
+                .collect::<Result<Vec<_>, anyhow::Error>>()?;
+            *recorded.borrow_mut() = Value::Array(json);
+            Ok(args)
+        },
+        move |_context, result| {
+            let Err(err) = result else {
+                return;
+            };
+            system_api::log(
+                Level::Error,
+                Some(label),
+                &[format!("{}() failed: {}", handler, err).into()],
+            );
+            insert(|id| DeadLetter {
+                id,
+                name: handler,
+                payload: payload.borrow().to_string(),
+                error: err.to_string(),
+                attempts: 1,
+                policy: RetryPolicy::default(),
+                failed_at: ic_cdk::api::time(),
+                kind: Some(Kind::Callback),
+            });
+        },
+    );
+}
+
+fn insert(letter: impl FnOnce(u64) -> DeadLetter) -> u64 {
+    let id = NEXT_ID.with(|next_id| {
+        let mut next_id = next_id.borrow_mut();
+        let id = *next_id.get();
+        next_id.set(id + 1).unwrap();
+        id
+    });
+    DEAD_LETTERS.with(|letters| letters.borrow_mut().insert(id, Candid(letter(id))));
+    id
+}
+
+/// Returns at most `limit` dead letters starting from the given id.
+pub fn list(start: u64, limit: usize) -> Vec<DeadLetter> {
+    DEAD_LETTERS.with(|letters| {
+        letters
+            .borrow()
+            .range(start..)
+            .take(limit)
+            .map(|(_, Candid(letter))| letter)
+            .collect()
+    })
+}
+
+/// Removes the dead letter with the given id and enqueues it as a new job with
+/// the same payload and retry policy. A callback is enqueued as a job that
+/// calls the handler with its arguments. Returns the id of the new job.
+pub fn requeue(id: u64) -> Result<u64, String> {
+    let Candid(letter) = remove(id)?;
+    let payload: Value = serde_json::from_str(&letter.payload).map_err(|err| err.to_string())?;
+    let (name, payload) = match letter.kind {
+        Some(Kind::Callback) => (
+            RETRY_CALLBACK.to_string(),
+            json!({"name": letter.name, "args": payload}),
+        ),
+        Some(Kind::Job) | None => (letter.name, payload),
+    };
+    Ok(jobs::enqueue(
+        name,
+        payload,
+        ic_cdk::api::time(),
+        letter.policy,
+    ))
+}
+
+/// Removes the dead letter with the given id.
+pub fn discard(id: u64) -> Result<(), String> {
+    remove(id).map(|_| ())
+}
+
+fn remove(id: u64) -> Result<Candid<DeadLetter>, String> {
+    DEAD_LETTERS
+        .with(|letters| letters.borrow_mut().remove(&id))
+        .ok_or_else(|| format!("Dead letter {} not found", id))
+}
+
+pub fn link(context: &JSContextRef) -> Result<(), anyhow::Error> {
+    context.eval_global(DEAD_LETTERS_FILE, DEAD_LETTERS_SCRIPT)?;
+    Ok(())
+}
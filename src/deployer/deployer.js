@@ -0,0 +1,58 @@
+// Usage: `await ic.deployer.install(canisterId, chunks, arg, {mode,
+// wasmModuleHash, onProgress})`. The chunks are an array of ArrayBuffers of at
+// most 1 MiB each or an async function that returns the chunk with the given
+// index and `null` after the last one. The argument is a Candid-encoded
+// ArrayBuffer. The mode defaults to "install". `onProgress({stage, chunks,
+// bytes})` is called after each uploaded chunk and before the installation.
+// Without `wasmModuleHash` the module hash is computed from the chunks, which
+// keeps a copy of the whole module on the heap.
+ic.deployer.install = async function (canisterId, chunks, arg, options = {}) {
+	const mode = options.mode || "install";
+	const onProgress = options.onProgress || (() => {});
+	const next = typeof chunks === "function" ? chunks : async (i) => chunks[i] ?? null;
+
+	function equal(a, b) {
+		const x = new Uint8Array(a);
+		const y = new Uint8Array(b);
+		return x.length === y.length && x.every((byte, i) => byte === y[i]);
+	}
+
+	await ic.deployer.clearChunkStore(canisterId);
+	const hashes = [];
+	const uploaded = [];
+	let bytes = 0;
+	for (let i = 0; ; i++) {
+		const chunk = await next(i);
+		if (chunk === null || chunk === undefined) {
+			break;
+		}
+		const hash = await ic.deployer.uploadChunk(canisterId, chunk);
+		if (!equal(hash, ic.crypto.sha256(chunk))) {
+			throw new Error(`The management canister returned a wrong hash for chunk ${i}`);
+		}
+		hashes.push(hash);
+		if (!options.wasmModuleHash) {
+			uploaded.push(new Uint8Array(chunk));
+		}
+		bytes += chunk.byteLength;
+		onProgress({ stage: "upload", chunks: hashes.length, bytes });
+	}
+	if (hashes.length === 0) {
+		throw new Error("Expected at least one chunk");
+	}
+
+	let wasmModuleHash = options.wasmModuleHash;
+	if (!wasmModuleHash) {
+		const module = new Uint8Array(bytes);
+		let offset = 0;
+		for (const chunk of uploaded) {
+			module.set(chunk, offset);
+			offset += chunk.length;
+		}
+		wasmModuleHash = ic.crypto.sha256(module.buffer);
+	}
+
+	onProgress({ stage: "install", chunks: hashes.length, bytes });
+	await ic.deployer.installChunked(canisterId, mode, hashes, wasmModuleHash, arg || new ArrayBuffer(0));
+	await ic.deployer.clearChunkStore(canisterId);
+};
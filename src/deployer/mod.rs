@@ -0,0 +1,160 @@
+// Chunked installation of large Wasm modules exposed to JS as `ic.deployer`.
+//
+// The linked functions map to the chunk store methods of the management
+// canister. deployer.js combines them in `ic.deployer.install(canisterId,
+// chunks, arg, options)`, which uploads the chunks, verifies the hash of each
+// chunk returned by the management canister and installs the module. This
+// canister must be a controller of the target canister.
+use candid::{
+    utils::{decode_args, encode_args},
+    CandidType, Deserialize, Principal,
+};
+use ic_cdk::api::management_canister::main::{CanisterIdRecord, CanisterInstallMode};
+use quickjs_wasm_rs::{CallbackArg, JSContextRef, JSError, JSValue, JSValueRef};
+
+use crate::{crypto, engine};
+
+// The orchestration of the linked functions.
+const DEPLOYER_FILE: &str = "deployer.js";
+const DEPLOYER_SCRIPT: &str = include_str!("deployer.js");
+
+#[derive(CandidType, Deserialize)]
+struct UploadChunkArgs {
+    canister_id: Principal,
+    chunk: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct ChunkHash {
+    hash: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct InstallChunkedCodeArgs {
+    mode: CanisterInstallMode,
+    target_canister: Principal,
+    store_canister: Option<Principal>,
+    chunk_hashes_list: Vec<ChunkHash>,
+    wasm_module_hash: Vec<u8>,
+    arg: Vec<u8>,
+    sender_canister_version: Option<u64>,
+}
+
+fn principal(arg: &CallbackArg) -> Result<Principal, anyhow::Error> {
+    let text: String = arg.try_into()?;
+    Ok(Principal::from_text(text)?)
+}
+
+fn byte_arrays(arg: &CallbackArg) -> Result<Vec<Vec<u8>>, anyhow::Error> {
+    match arg.to_js_value()? {
+        JSValue::Array(values) => values
+            .into_iter()
+            .map(|value| match value {
+                JSValue::ArrayBuffer(bytes) => Ok(bytes),
+                _ => Err(JSError::Type("Expected an array of ArrayBuffers".to_string()).into()),
+            })
+            .collect(),
+        _ => Err(JSError::Type("Expected an array of ArrayBuffers".to_string()).into()),
+    }
+}
+
+pub fn link(context: &JSContextRef) -> Result<(), anyhow::Error> {
+    // Resolves with the hash of the stored chunk as an ArrayBuffer.
+    fn upload_chunk<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 2 {
+            return Err(JSError::Type(format!("Expected 2 arguments, got {}", args.len())).into());
+        }
+        let args = UploadChunkArgs {
+            canister_id: principal(&args[0])?,
+            chunk: crypto::bytes(&args[1])?,
+        };
+        let args = encode_args((args,))?;
+        engine::call(
+            context,
+            Principal::management_canister(),
+            "upload_chunk",
+            &args,
+            |context, bytes| {
+                let (result,) = decode_args::<(ChunkHash,)>(&bytes)?;
+                context.array_buffer_value(&result.hash)
+            },
+        )
+    }
+
+    fn clear_chunk_store<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 1 {
+            return Err(JSError::Type(format!("Expected 1 argument, got {}", args.len())).into());
+        }
+        let args = encode_args((CanisterIdRecord {
+            canister_id: principal(&args[0])?,
+        },))?;
+        engine::call(
+            context,
+            Principal::management_canister(),
+            "clear_chunk_store",
+            &args,
+            |context, _bytes| context.undefined_value(),
+        )
+    }
+
+    // Usage: `ic.deployer.installChunked(canisterId, mode, chunkHashes,
+    // wasmModuleHash, arg)` where the mode is "install", "reinstall" or
+    // "upgrade" and the hashes and the argument are ArrayBuffers.
+    fn install_chunked<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 5 {
+            return Err(JSError::Type(format!("Expected 5 arguments, got {}", args.len())).into());
+        }
+        let target_canister = principal(&args[0])?;
+        let mode: String = args[1].try_into()?;
+        let mode = match mode.as_str() {
+            "install" => CanisterInstallMode::Install,
+            "reinstall" => CanisterInstallMode::Reinstall,
+            "upgrade" => CanisterInstallMode::Upgrade,
+            _ => return Err(JSError::Type(format!("Unknown install mode {}", mode)).into()),
+        };
+        let args = InstallChunkedCodeArgs {
+            mode,
+            target_canister,
+            store_canister: None,
+            chunk_hashes_list: byte_arrays(&args[2])?
+                .into_iter()
+                .map(|hash| ChunkHash { hash })
+                .collect(),
+            wasm_module_hash: crypto::bytes(&args[3])?,
+            arg: crypto::bytes(&args[4])?,
+            sender_canister_version: None,
+        };
+        let args = encode_args((args,))?;
+        engine::call(
+            context,
+            Principal::management_canister(),
+            "install_chunked_code",
+            &args,
+            |context, _bytes| context.undefined_value(),
+        )
+    }
+
+    let deployer = context.object_value()?;
+    deployer.set_property("uploadChunk", context.wrap_callback2(upload_chunk)?)?;
+    deployer.set_property(
+        "clearChunkStore",
+        context.wrap_callback2(clear_chunk_store)?,
+    )?;
+    deployer.set_property("installChunked", context.wrap_callback2(install_chunked)?)?;
+    engine::namespace(context)?.set_property("deployer", deployer)?;
+
+    context.eval_global(DEPLOYER_FILE, DEPLOYER_SCRIPT)?;
+    Ok(())
+}
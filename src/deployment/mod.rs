@@ -0,0 +1,56 @@
+// Blue/green deployment of the JS script.
+//
+// A controller uploads a new version of the script with `stage_script`, which
+// loads it into a second context next to the active one. `check_staged_script`
+// runs the optional `smokeTest()` function of the new script, which must
+// complete without awaiting calls. `promote_script` switches all endpoints to
+// the new script at once and `rollback_script` switches back instantly.
+//
+// The staged and the previous scripts live on the heap, so an upgrade always
+// starts with the script built into the Wasm module. `init` and `post_upgrade`
+// check that script with `self_check()`: it must define the functions called
+// by the endpoints of lib.rs and its optional `selfTest()` function must
+// succeed without awaiting calls. Otherwise the install or upgrade fails.
+use crate::{engine, json};
+
+/// The name of the staged script in stack traces.
+pub const STAGED_SCRIPT_NAME: &str = "staged.js";
+
+/// The name of the optional JS function that checks a staged script.
+pub const SMOKE_TEST: &str = "smokeTest";
+
+/// The name of the optional JS function that checks the active script on
+/// install and upgrade.
+pub const SELF_TEST: &str = "selfTest";
+
+/// Checks that the active script defines all the given functions and runs its
+/// self test if it defines one.
+pub fn self_check(required: &[&str]) -> Result<(), String> {
+    let missing: Vec<&str> = required
+        .iter()
+        .copied()
+        .filter(|name| !engine::is_function_defined(name))
+        .collect();
+    if !missing.is_empty() {
+        return Err(format!(
+            "The script is incompatible with this canister: it does not define {}",
+            missing.join(", ")
+        ));
+    }
+    if engine::is_function_defined(SELF_TEST) {
+        engine::execute_sync(SELF_TEST, |_context| Ok(vec![]), |_context, _value| Ok(()))
+            .map_err(|err| format!("The {}() function of the script failed: {}", SELF_TEST, err))?;
+    }
+    Ok(())
+}
+
+/// Runs the smoke test of the staged script and returns its JSON-encoded
+/// result.
+pub fn check() -> Result<String, String> {
+    engine::execute_staged_sync(
+        SMOKE_TEST,
+        |_context| Ok(vec![]),
+        |_context, value| Ok(json::to_json(&quickjs_wasm_rs::from_qjs_value(&value)?).to_string()),
+    )
+    .map_err(|err| err.to_string())
+}
@@ -0,0 +1,104 @@
+// A web REPL for iterating on a deployed script at `/dev`.
+//
+// Only built with the `dev-repl` feature. A controller calls `dev_session` to
+// get a short-lived token and pastes it into the page. `POST /dev/eval`
+// evaluates the body in the global scope of the live context and returns the
+// result with the recent logs, and `GET /dev/status` returns the status of the
+// engine. The evaluated code runs outside of any call context, so it must not
+// make outgoing calls.
+use std::{cell::RefCell, collections::HashSet};
+
+use serde_json::json;
+
+use crate::{
+    auth::Identity,
+    engine,
+    http::{HttpRequest, HttpResponse},
+    json, monitoring, sessions, system_api,
+};
+
+/// The path of the REPL page. Its API is under this path too.
+pub const PATH: &str = "/dev";
+
+const PAGE: &str = include_str!("dev.html");
+
+// The number of recent log entries returned with each response.
+const LOGS: u64 = 20;
+
+const SESSION_TTL_NANOS: u64 = 60 * 60 * 1_000_000_000;
+
+thread_local! {
+    // The ids of the sessions created with `create_session()`.
+    static SESSIONS: RefCell<HashSet<String>> = RefCell::new(HashSet::new());
+}
+
+pub fn is_dev_path(path: &str) -> bool {
+    path == PATH || path.starts_with("/dev/")
+}
+
+/// Creates a session that is allowed to use the REPL and returns its token.
+pub async fn create_session() -> Result<String, String> {
+    let token = sessions::create(json!({"dev": true}), SESSION_TTL_NANOS).await?;
+    let id = token.split('.').next().unwrap_or_default().to_string();
+    SESSIONS.with(|all| all.borrow_mut().insert(id));
+    Ok(token)
+}
+
+/// Serves the page, which needs no token, without an update call.
+pub fn page() -> HttpResponse {
+    HttpResponse {
+        status_code: 200,
+        headers: vec![(
+            "content-type".to_string(),
+            "text/html; charset=utf-8".to_string(),
+        )],
+        body: PAGE.as_bytes().to_vec(),
+        upgrade: None,
+        streaming_strategy: None,
+    }
+}
+
+pub fn handle(request: &HttpRequest, identity: &Identity) -> HttpResponse {
+    let is_dev_session = match identity {
+        Identity::Session { id, .. } => SESSIONS.with(|all| all.borrow().contains(id)),
+        _ => false,
+    };
+    if !is_dev_session {
+        return HttpResponse::error(401, "unauthorized", "Expected the token of a dev_session");
+    }
+    let logs = || system_api::recent_logs(LOGS);
+    match (request.method.to_uppercase().as_str(), request.path()) {
+        ("POST", "/dev/eval") => {
+            let code = String::from_utf8_lossy(&request.body);
+            match engine::eval("repl.js", &code) {
+                Ok(value) => HttpResponse::json(
+                    200,
+                    &json!({"result": json::to_json(&value), "logs": logs()}),
+                ),
+                Err(err) => {
+                    HttpResponse::json(200, &json!({"error": err.to_string(), "logs": logs()}))
+                }
+            }
+        }
+        ("GET", "/dev/status") => {
+            let status = engine::status();
+            HttpResponse::json(
+                200,
+                &json!({
+                    "result": {
+                        "pendingExecutions": status.pending_executions,
+                        "pendingCalls": status.pending_calls,
+                        "hasStagedScript": status.has_staged,
+                        "canRollBack": status.has_standby,
+                        "draining": status.draining,
+                        "heapMemorySize": monitoring::heap_memory_size(),
+                        "stableMemorySize": ic_cdk::api::stable::stable64_size() * monitoring::WASM_PAGE_SIZE,
+                        "cycles": ic_cdk::api::canister_balance128().to_string(),
+                    },
+                    "logs": logs(),
+                }),
+            )
+        }
+        _ => HttpResponse::error(404, "not_found", "Unknown dev endpoint"),
+    }
+}
@@ -0,0 +1,111 @@
+// Binary encodings of JS values exposed to JS as `ic.encoding`.
+//
+// `ic.encoding.msgpack` encodes and decodes MessagePack, which is a compact
+// alternative to JSON for payloads exchanged with clients.
+use quickjs_wasm_rs::{CallbackArg, JSContextRef, JSError, JSValue, JSValueRef};
+use rmpv::Value;
+
+use crate::engine;
+
+/// Converts the given JS value into a MessagePack value.
+///
+/// `undefined` becomes `nil`, array buffers become binary values and integral
+/// floats become integers.
+pub fn to_msgpack(value: &JSValue) -> Value {
+    match value {
+        JSValue::Undefined | JSValue::Null => Value::Nil,
+        JSValue::Bool(value) => Value::Boolean(*value),
+        JSValue::Int(value) => Value::from(*value),
+        JSValue::Float(value) => {
+            if value.fract() == 0.0 && value.abs() <= 2f64.powi(53) {
+                Value::from(*value as i64)
+            } else {
+                Value::F64(*value)
+            }
+        }
+        JSValue::String(value) => Value::from(value.as_str()),
+        JSValue::Array(values) => Value::Array(values.iter().map(to_msgpack).collect()),
+        JSValue::ArrayBuffer(bytes) => Value::Binary(bytes.clone()),
+        JSValue::Object(fields) => Value::Map(
+            fields
+                .iter()
+                .map(|(key, value)| (Value::from(key.as_str()), to_msgpack(value)))
+                .collect(),
+        ),
+    }
+}
+
+/// Converts the given MessagePack value into a JS value.
+///
+/// Binary and extension values become array buffers and map keys that are not
+/// strings are converted to strings. Integers that do not fit into 32 bits
+/// become JS floats and may lose precision.
+pub fn from_msgpack(value: &Value) -> JSValue {
+    match value {
+        Value::Nil => JSValue::Null,
+        Value::Boolean(value) => JSValue::Bool(*value),
+        Value::Integer(value) => match value.as_i64().map(i32::try_from) {
+            Some(Ok(value)) => JSValue::Int(value),
+            _ => JSValue::Float(value.as_f64().unwrap_or(f64::NAN)),
+        },
+        Value::F32(value) => JSValue::Float(*value as f64),
+        Value::F64(value) => JSValue::Float(*value),
+        Value::String(value) => {
+            JSValue::String(String::from_utf8_lossy(value.as_bytes()).into_owned())
+        }
+        Value::Binary(bytes) | Value::Ext(_, bytes) => JSValue::ArrayBuffer(bytes.clone()),
+        Value::Array(values) => JSValue::Array(values.iter().map(from_msgpack).collect()),
+        Value::Map(fields) => JSValue::Object(
+            fields
+                .iter()
+                .map(|(key, value)| {
+                    let key = match key.as_str() {
+                        Some(key) => key.to_string(),
+                        None => key.to_string(),
+                    };
+                    (key, from_msgpack(value))
+                })
+                .collect(),
+        ),
+    }
+}
+
+pub fn link(context: &JSContextRef) -> Result<(), anyhow::Error> {
+    fn msgpack_encode<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 1 {
+            return Err(JSError::Type(format!("Expected 1 argument, got {}", args.len())).into());
+        }
+        let mut bytes = vec![];
+        rmpv::encode::write_value(&mut bytes, &to_msgpack(&args[0].to_js_value()?))?;
+        context.array_buffer_value(&bytes)
+    }
+
+    fn msgpack_decode<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 1 {
+            return Err(JSError::Type(format!("Expected 1 argument, got {}", args.len())).into());
+        }
+        let JSValue::ArrayBuffer(bytes) = args[0].to_js_value()? else {
+            return Err(JSError::Type("Expected an ArrayBuffer".to_string()).into());
+        };
+        let value = rmpv::decode::read_value(&mut bytes.as_slice())?;
+        quickjs_wasm_rs::to_qjs_value(context, &from_msgpack(&value))
+    }
+
+    let msgpack = context.object_value()?;
+    msgpack.set_property("encode", context.wrap_callback2(msgpack_encode)?)?;
+    msgpack.set_property("decode", context.wrap_callback2(msgpack_decode)?)?;
+
+    let encoding = context.object_value()?;
+    encoding.set_property("msgpack", msgpack)?;
+
+    engine::namespace(context)?.set_property("encoding", encoding)?;
+    Ok(())
+}
@@ -0,0 +1,51 @@
+// The registry of JS endpoint handlers. The engine looks up handlers in the
+// `__endpoints__` object by name before falling back to global functions.
+Object.defineProperty(globalThis, "__endpoints__", {
+	enumerable: false,
+	value: {},
+});
+
+(function () {
+	// Converts a list of parameter schemas into a JSON Schema of the arguments
+	// array. A parameter is a JSON Schema or a type name like "string". The
+	// parameters from the first one marked with `optional: true` may be
+	// omitted.
+	function paramsSchema(params) {
+		const items = params.map((param) => typeof param === "string" ? { type: param } : param);
+		const optional = items.findIndex((item) => item.optional);
+		return {
+			type: "array",
+			items,
+			minItems: optional === -1 ? items.length : optional,
+			maxItems: items.length,
+		};
+	}
+
+	// Registers the handler with the optional schema of its arguments given
+	// as `{params: [...]}` or as a complete JSON Schema in `{schema}`.
+	function register(name, handler, kind, options) {
+		if (options && options.params) {
+			ic.schemas.define(name, paramsSchema(options.params));
+		} else if (options && options.schema) {
+			ic.schemas.define(name, options.schema);
+		}
+		__endpoints__[name] = handler;
+		ic.endpoints.declare(name, kind);
+	}
+
+	// Usage: `ic.registerUpdate(name, handler, {params: ["string", {type:
+	// "integer", minimum: 1}]})`. The options are optional.
+	ic.registerUpdate = function (name, handler, options) {
+		register(name, handler, "update", options);
+	};
+
+	ic.registerQuery = function (name, handler, options) {
+		register(name, handler, "query", options);
+	};
+
+	// Usage: `ic.export(name, handler, {query: true, params})`. Registers an
+	// update endpoint unless `query` is set.
+	ic.export = function (name, handler, options) {
+		register(name, handler, options && options.query ? "query" : "update", options);
+	};
+})();
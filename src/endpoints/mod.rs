@@ -0,0 +1,134 @@
+// The registry of JS endpoints exposed to JS as `ic.registerUpdate(name,
+// handler)`, `ic.registerQuery(name, handler)` and `ic.export(name, handler,
+// {query})`.
+//
+// Registered endpoints are dispatched by name, e.g. by the JSON-RPC server.
+// The Candid methods `dispatch` and `dispatch_query` take the name of the
+// endpoint as the first argument followed by its Candid arguments and reply
+// with the result encoded as Candid. The IC only delivers calls of methods
+// exported by the Wasm module, so these methods stand in for the methods of
+// the script.
+// The optional third argument declares the schema of the arguments, which the
+// dispatchers validate before the handler runs.
+// Query endpoints must not make outgoing calls, so they can run in query calls.
+use std::{cell::RefCell, collections::BTreeMap};
+
+use candid::{parser::value::IDLValue, CandidType, Deserialize};
+use ic_cdk::api::call::ManualReply;
+use quickjs_wasm_rs::{CallbackArg, JSContextRef, JSError, JSValueRef};
+
+use crate::{candid_js, converters, engine, schemas};
+
+// The JS part of the registry that stores the handlers.
+const REGISTRY_FILE: &str = "endpoints.js";
+const REGISTRY_SCRIPT: &str = include_str!("endpoints.js");
+
+#[derive(CandidType, Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Update,
+    Query,
+}
+
+/// The description of an endpoint for clients and tooling.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct EndpointInfo {
+    pub name: String,
+    pub kind: Kind,
+    /// Whether lib.rs exports a Candid method for the endpoint. Other
+    /// endpoints are registered by the script.
+    pub compiled: bool,
+    /// The JSON Schema of the arguments array, if the script defined one.
+    pub schema: Option<String>,
+}
+
+thread_local! {
+    static ENDPOINTS: RefCell<BTreeMap<String, Kind>> = RefCell::new(BTreeMap::new());
+}
+
+/// Returns the kind of the registered endpoint with the given name.
+pub fn kind(name: &str) -> Option<Kind> {
+    ENDPOINTS.with(|endpoints| endpoints.borrow().get(name).copied())
+}
+
+/// Describes the given endpoints of lib.rs and the endpoints registered by the
+/// script. A registered handler with the name of a compiled endpoint is listed
+/// once as compiled.
+pub fn list(compiled: &[(&str, Kind)]) -> Vec<EndpointInfo> {
+    let describe = |name: &str, kind: Kind, compiled: bool| EndpointInfo {
+        name: name.to_string(),
+        kind,
+        compiled,
+        schema: schemas::source(name).map(|schema| schema.to_string()),
+    };
+    let mut list: Vec<EndpointInfo> = compiled
+        .iter()
+        .map(|(name, kind)| describe(name, *kind, true))
+        .collect();
+    ENDPOINTS.with(|endpoints| {
+        for (name, kind) in endpoints.borrow().iter() {
+            if !compiled.iter().any(|(compiled, _)| compiled == name) {
+                list.push(describe(name, *kind, false));
+            }
+        }
+    });
+    list
+}
+
+/// Executes the registered endpoint named by the first Candid argument of the
+/// current message. Only query endpoints can be dispatched in query calls.
+pub fn dispatch<R>(kind: Kind) -> ManualReply<R> {
+    let mut args = match candid_js::arg_data() {
+        Ok(args) => args,
+        Err(err) => return ManualReply::reject(err.to_string()),
+    };
+    let method = match args.0.args.first() {
+        Some(IDLValue::Text(method)) => method.clone(),
+        _ => return ManualReply::reject("Expected the name of the endpoint as the first argument"),
+    };
+    args.0.args.remove(0);
+    match self::kind(&method) {
+        None => ManualReply::reject(format!("No endpoint is registered as {}", method)),
+        Some(Kind::Update) if kind == Kind::Query => ManualReply::reject(format!(
+            "{} is an update endpoint, call it with dispatch",
+            method
+        )),
+        Some(_) if kind == Kind::Query => engine::execute_query(
+            &method,
+            converters::arguments(&method, args),
+            candid_js::reply,
+        ),
+        Some(_) => engine::execute(
+            &method,
+            converters::arguments(&method, args),
+            candid_js::reply,
+        ),
+    }
+}
+
+pub fn link(context: &JSContextRef) -> Result<(), anyhow::Error> {
+    fn declare<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 2 {
+            return Err(JSError::Type(format!("Expected 2 arguments, got {}", args.len())).into());
+        }
+        let name: String = args[0].try_into()?;
+        let kind: String = args[1].try_into()?;
+        let kind = match kind.as_str() {
+            "update" => Kind::Update,
+            "query" => Kind::Query,
+            _ => return Err(JSError::Type(format!("Unknown endpoint kind {}", kind)).into()),
+        };
+        ENDPOINTS.with(|endpoints| endpoints.borrow_mut().insert(name, kind));
+        context.undefined_value()
+    }
+
+    let endpoints = context.object_value()?;
+    endpoints.set_property("declare", context.wrap_callback2(declare)?)?;
+    engine::namespace(context)?.set_property("endpoints", endpoints)?;
+
+    context.eval_global(REGISTRY_FILE, REGISTRY_SCRIPT)?;
+    Ok(())
+}
@@ -22,6 +22,81 @@ Object.defineProperty(globalThis, "__engine__", {
 		// The currently active call context.
 		let entered_call_context = null;
 
+		// The limits on values passed between JS and Rust. See `setLimits`.
+		const limits = {
+			// The maximum number of outgoing calls in flight per call context.
+			// More calls are queued and performed as earlier ones complete.
+			maxConcurrentCalls: 100,
+			// The budget of a call context, so that a buggy handler cannot
+			// drain the canister in one message.
+			maxCallsPerMessage: Infinity,
+			maxCyclesPerMessage: Infinity,
+			maxStringLength: Infinity,
+			maxArrayLength: Infinity,
+			maxArrayBufferBytes: Infinity,
+			// The maximum size of the Candid arguments of an incoming message.
+			// Rust checks it before converting the arguments to JS values.
+			maxArgumentBytes: Infinity,
+		};
+
+		// Throws a RangeError if the value or any value nested in it exceeds
+		// the limits.
+		function checkLimits(value) {
+			const visited = new Set();
+			const stack = [["$", value]];
+			while (stack.length > 0) {
+				const [path, value] = stack.pop();
+				if (typeof value === "string") {
+					if (value.length > limits.maxStringLength) {
+						throw new RangeError(`The string at ${path} has ${value.length} characters, the limit is ${limits.maxStringLength}`);
+					}
+					continue;
+				}
+				if (typeof value !== "object" || value === null || visited.has(value)) {
+					continue;
+				}
+				visited.add(value);
+				if (value instanceof ArrayBuffer || ArrayBuffer.isView(value)) {
+					if (value.byteLength > limits.maxArrayBufferBytes) {
+						throw new RangeError(`The buffer at ${path} has ${value.byteLength} bytes, the limit is ${limits.maxArrayBufferBytes}`);
+					}
+					continue;
+				}
+				if (Array.isArray(value)) {
+					if (value.length > limits.maxArrayLength) {
+						throw new RangeError(`The array at ${path} has ${value.length} elements, the limit is ${limits.maxArrayLength}`);
+					}
+					value.forEach((element, i) => stack.push([`${path}[${i}]`, element]));
+					continue;
+				}
+				for (const key of Object.keys(value)) {
+					stack.push([`${path}.${key}`, value[key]]);
+				}
+			}
+			return value;
+		}
+
+		function hasLimits() {
+			return limits.maxStringLength !== Infinity
+				|| limits.maxArrayLength !== Infinity
+				|| limits.maxArrayBufferBytes !== Infinity;
+		}
+
+		// Sets the limits from an object like `{maxStringLength, maxArrayLength,
+		// maxArrayBufferBytes}`. Missing fields keep their values.
+		function setLimits(options) {
+			for (const name of Object.keys(limits)) {
+				if (options[name] !== undefined) {
+					limits[name] = options[name];
+				}
+			}
+		}
+
+		// Returns the value of the limit with the given name.
+		function getLimit(name) {
+			return limits[name];
+		}
+
 		// Activate the given call context.
 		function enterCallContext(call_context) {
 			if (entered_call_context && entered_call_context != call_context) {
@@ -45,6 +120,14 @@ Object.defineProperty(globalThis, "__engine__", {
 				rejected: null,
 				// The number of pending outgoing calls.
 				pending_calls: 0,
+				// The number of performed outgoing calls that did not complete.
+				calls_in_flight: 0,
+				// The ids of the callbacks of calls waiting for a free slot.
+				queued_calls: [],
+				// The number of outgoing calls and the cycles attached to them
+				// charged against the budget.
+				calls_made: 0,
+				cycles_attached: 0,
 			};
 			call_contexts.set(call_context_id, new_call_context);
 
@@ -71,12 +154,40 @@ Object.defineProperty(globalThis, "__engine__", {
 			// This ensures that the result of the promise is stored in
 			// active call context when the promise settles.
 			Promise.resolve(result)
+				.then((r) => hasLimits() ? checkLimits(r) : r)
 				.then((r) => entered_call_context.replied = r,
 					(e) => entered_call_context.rejected = e)
 
 			return entered_call_context;
 		}
 
+		// The ids of the callbacks of queued calls that got a free slot.
+		let ready_calls = [];
+
+		// Frees the slot of a completed call and moves queued calls of the
+		// call context into the free slots.
+		function releaseCallSlot(call_context, callback) {
+			if (!callback.in_flight) {
+				return;
+			}
+			callback.in_flight = false;
+			call_context.calls_in_flight -= 1;
+			while (call_context.calls_in_flight < limits.maxConcurrentCalls
+				&& call_context.queued_calls.length > 0) {
+				const next = call_context.queued_calls.shift();
+				callbacks.get(next).in_flight = true;
+				call_context.calls_in_flight += 1;
+				ready_calls.push(next);
+			}
+		}
+
+		// Returns the ids of the callbacks of the queued calls to perform now.
+		function takeReadyCalls() {
+			const result = ready_calls;
+			ready_calls = [];
+			return result;
+		}
+
 		// Executes the `reply` callback of an outgoing call.
 		function executeReplyCallback(callback_id, ...args) {
 			let callback = callbacks.get(callback_id);
@@ -85,7 +196,16 @@ Object.defineProperty(globalThis, "__engine__", {
 			let call_context = call_contexts.get(callback.call_context_id);
 			enterCallContext(call_context);
 
+			releaseCallSlot(call_context, callback);
 			call_context.pending_calls -= 1;
+			if (hasLimits()) {
+				try {
+					args.forEach(checkLimits);
+				} catch (e) {
+					callback.reject.call(globalThis, e);
+					return entered_call_context;
+				}
+			}
 			callback.reply.call(globalThis, ...args);
 
 			return entered_call_context;
@@ -99,12 +219,32 @@ Object.defineProperty(globalThis, "__engine__", {
 			let call_context = call_contexts.get(callback.call_context_id);
 			enterCallContext(call_context);
 
+			releaseCallSlot(call_context, callback);
 			call_context.pending_calls -= 1;
 			callback.reject.call(globalThis, ...args);
 
 			return entered_call_context;
 		}
 
+		// The cycles refunded by completed outgoing calls keyed by the
+		// promises of the calls.
+		const refunds = new WeakMap();
+
+		// Records the refunded cycles of an outgoing call before its
+		// callback is executed.
+		function setCyclesRefunded(callback_id, cycles) {
+			const callback = callbacks.get(callback_id);
+			if (callback) {
+				refunds.set(callback.promise, cycles);
+			}
+		}
+
+		// Returns the refunded cycles of the call of the given promise or
+		// `undefined` if the call has not completed.
+		function getCyclesRefunded(promise) {
+			return refunds.get(promise);
+		}
+
 		// Registers a new callback for an outgoing call.
 		function createCallback() {
 			let reply = null;
@@ -131,9 +271,71 @@ Object.defineProperty(globalThis, "__engine__", {
 			return [result, promise];
 		}
 
-		// Unregisters the previously registered callback.
+		// Charges a call with the given cycles attached against the budget of
+		// the entered call context. Throws a RangeError if the call exceeds the
+		// budget. Also used for calls deferred by the outbox.
+		function chargeCall(cycles) {
+			const call_context = entered_call_context;
+			if (call_context === null) {
+				return;
+			}
+			if (call_context.calls_made + 1 > limits.maxCallsPerMessage) {
+				throw new RangeError(`The message made ${call_context.calls_made} calls, the limit is ${limits.maxCallsPerMessage}`);
+			}
+			if (call_context.cycles_attached + cycles > limits.maxCyclesPerMessage) {
+				throw new RangeError(`The message attached ${call_context.cycles_attached} cycles, the limit is ${limits.maxCyclesPerMessage}`);
+			}
+			call_context.calls_made += 1;
+			call_context.cycles_attached += cycles;
+		}
+
+		// Registers a new callback for an outgoing call with the given cycles
+		// attached. The third element of the result tells whether the call may
+		// be performed now or is queued. Throws a RangeError if the call
+		// exceeds the budget of the call context.
+		function createCallCallback(cycles) {
+			chargeCall(cycles);
+			const call_context = entered_call_context;
+			const [id, promise] = createCallback();
+			const is_ready = call_context.calls_in_flight < limits.maxConcurrentCalls;
+			if (is_ready) {
+				callbacks.get(id).in_flight = true;
+				call_context.calls_in_flight += 1;
+			} else {
+				call_context.queued_calls.push(id);
+			}
+			return [id, promise, is_ready];
+		}
+
+		// Unregisters the previously registered callback. The queued calls of
+		// its call context are never performed, so they are unregistered too
+		// and their ids are returned.
 		function removeCallback(callback_id) {
+			const callback = callbacks.get(callback_id);
 			callbacks.delete(callback_id);
+			const call_context = callback && call_contexts.get(callback.call_context_id);
+			if (!call_context) {
+				return [];
+			}
+			const dropped = call_context.queued_calls;
+			call_context.queued_calls = [];
+			dropped.forEach((id) => callbacks.delete(id));
+			return dropped;
+		}
+
+		// Returns the pairs of the ids of the pending callbacks and their call
+		// contexts. Reserved ids are skipped.
+		function listCallbacks() {
+			return [...callbacks]
+				.filter(([, callback]) => callback.call_context_id !== null)
+				.map(([id, callback]) => [id, callback.call_context_id]);
+		}
+
+		// Marks the given ids as taken, so that new callbacks do not reuse the
+		// ids of the callbacks of a replaced context. `removeCallback` frees
+		// them.
+		function reserveCallbacks(ids) {
+			ids.forEach((id) => callbacks.set(id, { call_context_id: null }));
 		}
 
 		// Returns the currently active call context.
@@ -141,14 +343,126 @@ Object.defineProperty(globalThis, "__engine__", {
 			return entered_call_context;
 		}
 
+		// Returns the id of the call context of the given callback or null.
+		function getCallbackCallContext(callback_id) {
+			const callback = callbacks.get(callback_id);
+			return callback ? callback.call_context_id : null;
+		}
+
 		// Exports public methods. 
 		return {
 			executeEndpoint,
 			executeReplyCallback,
 			executeRejectCallback,
 			createCallback,
+			chargeCall,
+			createCallCallback,
 			removeCallback,
+			listCallbacks,
+			reserveCallbacks,
+			takeReadyCalls,
+			setCyclesRefunded,
+			getCyclesRefunded,
 			getEnteredCallContext,
+			getCallbackCallContext,
+			setLimits,
+			getLimit,
 		};
 	})()
-});
\ No newline at end of file
+});
+
+// Usage: `ic.limits.configure({maxConcurrentCalls, maxCallsPerMessage,
+// maxCyclesPerMessage, maxStringLength, maxArrayLength, maxArrayBufferBytes,
+// maxArgumentBytes})`. Messages with larger arguments are rejected before the
+// handler runs. Calls over the budget of a message throw a RangeError. Results
+// of endpoints, outgoing calls and promises of linked functions that exceed the
+// size limits are rejected with a RangeError instead of being converted. Calls
+// over the concurrency limit of a call context are queued and performed as
+// earlier ones complete.
+ic.limits = {
+	configure: (options) => __engine__.setLimits(options),
+};
+
+// Usage: `const promise = ic.call(...); await promise;
+// ic.cyclesRefunded(promise)`. Returns the cycles refunded by the completed
+// call of the promise returned by a linked function or `undefined` while the
+// call is pending. Promises derived with `then()` are not tracked.
+ic.cyclesRefunded = (promise) => __engine__.getCyclesRefunded(promise);
+
+// Usage: `const release = await ic.lock("balances"); try { ... } finally {
+// release(); }` or `await ic.withLock("balances", async () => { ... })`.
+// Serializes handlers that read, await and write shared state, so that other
+// messages cannot interleave at the await. Waiters get the lock in the order
+// of arrival. If waiting fails, e.g. after a timeout, it throws an error with
+// `code: "locked"` and the caller should retry later. The release function is
+// idempotent.
+ic.lock = async function (name) {
+	const acquired = ic.locks.acquire(name);
+	if (acquired !== true) {
+		try {
+			await acquired;
+		} catch (cause) {
+			const error = new Error(`${cause.message ?? cause}, retry later`);
+			error.code = "locked";
+			throw error;
+		}
+	}
+	let released = false;
+	return () => {
+		if (!released) {
+			released = true;
+			ic.locks.release(name);
+		}
+	};
+};
+
+ic.withLock = async function (name, fn) {
+	const release = await ic.lock(name);
+	try {
+		return await fn();
+	} finally {
+		release();
+	}
+};
+
+// The error of all functions in `ic.api`. `binding` names the failed function,
+// e.g. "management.raw_rand", and `cause` keeps the original error.
+ic.IcError = class IcError extends Error {
+	constructor(binding, cause) {
+		super(cause instanceof Error ? cause.message : String(cause));
+		this.name = "IcError";
+		this.binding = binding;
+		this.cause = cause;
+	}
+};
+
+// Groups the linked functions in a namespace where every function returns a
+// promise and fails with an `ic.IcError`, whether the underlying binding is
+// synchronous, throws, or rejects with a string. The functions look up the
+// binding on every call, so tests can replace either the binding or the
+// function in `ic.api` with a mock. The promises are new objects, so pass the
+// promise of the binding itself to `ic.cyclesRefunded()`.
+ic.api = (function () {
+	function wrap(group, target, names = Object.keys(target)) {
+		const api = {};
+		for (const name of names) {
+			if (typeof target[name] !== "function") {
+				continue;
+			}
+			api[name] = async (...args) => {
+				try {
+					return await target[name](...args);
+				} catch (e) {
+					throw e instanceof ic.IcError ? e : new ic.IcError(`${group}.${name}`, e);
+				}
+			};
+		}
+		return api;
+	}
+
+	return {
+		system: wrap("system", ic0),
+		management: wrap("management", managementCanister),
+		canisters: wrap("canisters", ic, ["call", "callRaw", "multicall"]),
+	};
+})();
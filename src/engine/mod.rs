@@ -1,12 +1,23 @@
 use anyhow::Error;
 use ic_cdk::api::call::ManualReply;
-use quickjs_wasm_rs::{JSContextRef, JSValueRef};
-use std::{cell::RefCell, collections::BTreeMap};
+use quickjs_wasm_rs::{JSContextRef, JSValue, JSValueRef};
+use sha2::{Digest, Sha256};
+use std::{
+    cell::{Cell, RefCell},
+    collections::BTreeMap,
+    future::Future,
+    rc::Rc,
+};
+
+use crate::{call_metrics, call_policy, locks, recorder, source_maps, system_api};
 
 // The name and contents of the JS engine script.
 const ENGINE_FILE: &str = "engine.js";
 const ENGINE_SCRIPT: &[u8] = include_bytes!("engine.js");
 
+// The name of the global object that groups the APIs available to JS code.
+const NAMESPACE: &str = "ic";
+
 // Keep these field and method names in sync with engine.js.
 const ENGINE: &str = "__engine__";
 const ID: &str = "id";
@@ -16,8 +27,22 @@ const EXECUTE_ENDPOINT: &str = "executeEndpoint";
 const EXECUTE_REPLY_CALLBACK: &str = "executeReplyCallback";
 const EXECUTE_REJECT_CALLBACK: &str = "executeRejectCallback";
 const CREATE_CALLBACK: &str = "createCallback";
+const CHARGE_CALL: &str = "chargeCall";
+const CREATE_CALL_CALLBACK: &str = "createCallCallback";
 const REMOVE_CALLBACK: &str = "removeCallback";
+const LIST_CALLBACKS: &str = "listCallbacks";
+const RESERVE_CALLBACKS: &str = "reserveCallbacks";
+const TAKE_READY_CALLS: &str = "takeReadyCalls";
+const SET_CYCLES_REFUNDED: &str = "setCyclesRefunded";
 const GET_ENTERED_CALL_CONTEXT: &str = "getEnteredCallContext";
+const GET_CALLBACK_CALL_CONTEXT: &str = "getCallbackCallContext";
+const GET_LIMIT: &str = "getLimit";
+// The field of the engine object that stores the hash of the user JS script.
+const SCRIPT_HASH: &str = "scriptHash";
+const MAX_ARGUMENT_BYTES: &str = "maxArgumentBytes";
+
+// Keep this name in sync with endpoints/endpoints.js.
+const ENDPOINTS: &str = "__endpoints__";
 
 /// A function that returns the JS arguments for a public endpoint.
 /// Usually this function converts the input arguments of the endpoint from
@@ -28,6 +53,13 @@ pub trait Arguments: FnOnce(&JSContextRef) -> Result<Vec<JSValueRef>, Error> {}
 /// the endpoint.
 pub trait Replier<R>: FnOnce(&JSContextRef, Result<JSValueRef, Error>) -> ManualReply<R> {}
 
+/// A function that consumes the result of execution of a JS task that does not
+/// have a caller to reply to, e.g. a background job or a timer.
+pub trait Completion: FnOnce(&JSContextRef, Result<JSValueRef, Error>) {}
+
+/// A function that produces the value of a promise created by `promise()`.
+pub trait Settler: FnOnce(&JSContextRef) -> Result<JSValueRef, Error> {}
+
 // The internal representation of `Replier` with the result type erased such
 // that it is possible to store the replier in a collection.
 trait StoredReplier: FnOnce(&JSContextRef, Result<JSValueRef, Error>) -> () {}
@@ -39,13 +71,13 @@ pub trait CallResultDeserializer:
 {
 }
 
-// The unique ID of a call context.
-//
-// A call context represents an execution of a public endpoint. The
-// execution starts when the public endpoint is invoked and finishes when all
-// outgoing calls made by the endpoint finish.
-#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
-struct CallContextId(i32);
+/// The unique ID of a call context.
+///
+/// A call context represents an execution of a public endpoint. The
+/// execution starts when the public endpoint is invoked and finishes when all
+/// outgoing calls made by the endpoint finish.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct CallContextId(i32);
 
 // The unique ID of a callback of an outgoing call.
 //
@@ -54,10 +86,38 @@ struct CallContextId(i32);
 #[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
 struct CallbackId(i32);
 
+// An outgoing call that is not performed yet.
+struct QueuedCall {
+    canister_id: ic_cdk::export::Principal,
+    method: String,
+    args: Vec<u8>,
+    cycles: u128,
+}
+
+// The replier of a call context of a replaced context, taken by the first of
+// its callbacks that completes.
+type RetiredReplier = Rc<RefCell<Option<Box<dyn StoredReplier>>>>;
+
+// The error of executions whose context was replaced by `promote()` or
+// `rollback()` while they awaited calls or promises.
+const SCRIPT_RELOADED: &str = "The script was reloaded while the execution was pending";
+
+/// The unique ID of a JS promise that is settled by Rust code.
+///
+/// Internally, such a promise is a callback that is not associated with an
+/// outgoing call made by the engine.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub struct PromiseId(CallbackId);
+
 thread_local! {
     // The JS context in which all JS code is executed.
     static CONTEXT: RefCell<Option<JSContextRef>> = RefCell::new(None);
 
+    // The context with a new version of the script that is checked before it
+    // becomes active and the previously active context kept for a rollback.
+    static STAGED: RefCell<Option<JSContextRef>> = RefCell::new(None);
+    static STANDBY: RefCell<Option<JSContextRef>> = RefCell::new(None);
+
     // For each pending execution (call context), there is one replier that
     // produces an actual reply from the result of execution.
     static REPLIERS: RefCell<BTreeMap<CallContextId, Box<dyn StoredReplier>>> = RefCell::new(Default::default());
@@ -65,6 +125,26 @@ thread_local! {
     // For each pending outgoing call, there is a deserializer that converts
     // the result of the call into a JS value.
     static DESERIALIZERS: RefCell<BTreeMap<CallbackId, Box<dyn CallResultDeserializer>>> = RefCell::new(Default::default());
+
+    // The outgoing calls that wait for a free slot in their call context.
+    static QUEUED_CALLS: RefCell<BTreeMap<CallbackId, QueuedCall>> = RefCell::new(Default::default());
+
+    // Whether new executions of public endpoints are rejected, so that the
+    // pending ones can complete before an upgrade.
+    static DRAINING: Cell<bool> = Cell::new(false);
+
+    // Whether the current execution runs in a query call, see
+    // `execute_query()`.
+    static READ_ONLY: Cell<bool> = Cell::new(false);
+
+    // The pending callbacks of replaced contexts. They share the replier of
+    // their call context, which rejects the caller when the first of them
+    // completes.
+    static RETIRED: RefCell<BTreeMap<CallbackId, RetiredReplier>> = RefCell::new(Default::default());
+
+    // Promises that were settled while JS code was running. They are processed
+    // as soon as the JS code returns control to the engine.
+    static DEFERRED_SETTLERS: RefCell<Vec<(PromiseId, Box<dyn Settler>)>> = RefCell::new(Default::default());
 }
 
 /// The embedders must call this function to initialize the engine.
@@ -78,17 +158,232 @@ pub fn init(
     script_name: &str,
     script: &str,
 ) -> Result<(), Error> {
+    let context = load(linker, script_name, script)?;
+    CONTEXT.with(|ctx| {
+        let mut ctx = ctx.borrow_mut();
+        *ctx = Some(context);
+    });
+    Ok(())
+}
+
+fn load(
+    linker: impl FnOnce(&JSContextRef) -> Result<(), Error>,
+    script_name: &str,
+    script: &str,
+) -> Result<JSContextRef, Error> {
     let context = JSContextRef::default();
     linker(&context)?;
     context.eval_global(ENGINE_FILE, std::str::from_utf8(ENGINE_SCRIPT).unwrap())?;
     context.eval_global(script_name, script)?;
-    CONTEXT.with(|ctx| {
-        let mut ctx = ctx.borrow_mut();
-        *ctx = Some(context);
+    let hash = hex::encode(Sha256::digest(script.as_bytes()));
+    let engine = context.global_object()?.get_property(ENGINE)?;
+    engine.set_property(SCRIPT_HASH, context.value_from_str(&hash)?)?;
+    Ok(context)
+}
+
+/// Returns the hex-encoded SHA-256 hash of the user JS script of the active
+/// context.
+pub fn script_hash() -> String {
+    CONTEXT.with(|context| {
+        let context = context.borrow();
+        let context = context.as_ref().unwrap();
+        let engine = context
+            .global_object()
+            .unwrap()
+            .get_property(ENGINE)
+            .unwrap();
+        engine
+            .get_property(SCRIPT_HASH)
+            .unwrap()
+            .as_str()
+            .unwrap()
+            .to_string()
+    })
+}
+
+/// Loads a new version of the user JS script into a second context that does
+/// not serve traffic until `promote()` is called. The state of the linked Rust
+/// modules is shared by both contexts, so registrations made by the new script,
+/// e.g. schemas, apply immediately.
+pub fn stage(
+    linker: impl FnOnce(&JSContextRef) -> Result<(), Error>,
+    script_name: &str,
+    script: &str,
+) -> Result<(), Error> {
+    let context = load(linker, script_name, script)?;
+    STAGED.with(|staged| *staged.borrow_mut() = Some(context));
+    Ok(())
+}
+
+/// Executes the given JS method in the staged context like `execute_sync()`.
+/// This is useful for smoke checks of a new version of the script.
+pub fn execute_staged_sync<T>(
+    method: &str,
+    arguments: impl Arguments,
+    handler: impl FnOnce(&JSContextRef, JSValueRef) -> Result<T, Error>,
+) -> Result<T, Error> {
+    let staged = STAGED
+        .with(|staged| staged.borrow_mut().take())
+        .ok_or_else(|| anyhow::anyhow!("No script is staged"))?;
+    let active = CONTEXT.with(|context| context.borrow_mut().replace(staged));
+    let result = execute_sync(method, arguments, handler);
+    let staged = CONTEXT.with(|context| std::mem::replace(&mut *context.borrow_mut(), active));
+    STAGED.with(|cell| *cell.borrow_mut() = staged);
+    result
+}
+
+/// Makes the staged context active and keeps the previously active one for
+/// `rollback()`. Pending executions have callbacks in the previously active
+/// context, so they are rejected when their calls or promises complete.
+pub fn promote() -> Result<(), Error> {
+    let staged = STAGED
+        .with(|staged| staged.borrow_mut().take())
+        .ok_or_else(|| anyhow::anyhow!("No script is staged"))?;
+    retire(&staged)?;
+    let active = CONTEXT.with(|context| context.borrow_mut().replace(staged));
+    STANDBY.with(|standby| *standby.borrow_mut() = active);
+    Ok(())
+}
+
+/// Makes the previously active context active again. The rolled back context
+/// becomes staged, so it can be promoted again. Pending executions are rejected
+/// like in `promote()`.
+pub fn rollback() -> Result<(), Error> {
+    let standby = STANDBY
+        .with(|standby| standby.borrow_mut().take())
+        .ok_or_else(|| anyhow::anyhow!("There is no previous script to roll back to"))?;
+    retire(&standby)?;
+    let active = CONTEXT.with(|context| context.borrow_mut().replace(standby));
+    STAGED.with(|staged| *staged.borrow_mut() = active);
+    Ok(())
+}
+
+// Moves the pending callbacks of the active context to `RETIRED` before the
+// given context replaces it. Their ids stay reserved in the next context
+// because the replies of the calls carry them. Queued calls are dropped.
+fn retire(next: &JSContextRef) -> Result<(), Error> {
+    let pending = CONTEXT.with(
+        |context| -> Result<Vec<(CallbackId, CallContextId)>, Error> {
+            let context = context.borrow();
+            let context = context.as_ref().unwrap();
+            let engine = context.global_object()?.get_property(ENGINE)?;
+            let list = engine.get_property(LIST_CALLBACKS)?.call(&engine, &[])?;
+            let length = list.get_property("length")?.try_as_integer()?;
+            (0..length)
+                .map(|i| {
+                    let pair = list.get_indexed_property(i as u32)?;
+                    Ok((
+                        CallbackId(pair.get_indexed_property(0)?.try_as_integer()?),
+                        CallContextId(pair.get_indexed_property(1)?.try_as_integer()?),
+                    ))
+                })
+                .collect()
+        },
+    )?;
+    let mut repliers = REPLIERS.with(|repliers| std::mem::take(&mut *repliers.borrow_mut()));
+    let mut shared: BTreeMap<CallContextId, RetiredReplier> = BTreeMap::new();
+    let queued = QUEUED_CALLS.with(|queued| std::mem::take(&mut *queued.borrow_mut()));
+    DESERIALIZERS.with(|deserializers| deserializers.borrow_mut().clear());
+    RETIRED.with(|retired| {
+        let mut retired = retired.borrow_mut();
+        for (callback_id, call_context_id) in pending {
+            if queued.contains_key(&callback_id) {
+                continue;
+            }
+            let replier = shared
+                .entry(call_context_id)
+                .or_insert_with(|| Rc::new(RefCell::new(repliers.remove(&call_context_id))));
+            retired.insert(callback_id, replier.clone());
+        }
     });
+    let ids = RETIRED.with(|retired| {
+        let array = next.array_value()?;
+        for callback_id in retired.borrow().keys() {
+            array.append_property(next.value_from_i32(callback_id.0)?)?;
+        }
+        Ok::<_, Error>(array)
+    })?;
+    let engine = next.global_object()?.get_property(ENGINE)?;
+    engine
+        .get_property(RESERVE_CALLBACKS)?
+        .call(&engine, &[ids])?;
     Ok(())
 }
 
+// Rejects the execution of the given retired callback unless another callback
+// of its call context did it already. Returns false if the callback belongs to
+// the active context.
+fn complete_retired(context: &JSContextRef, callback_id: CallbackId) -> bool {
+    let Some(replier) = RETIRED.with(|retired| retired.borrow_mut().remove(&callback_id)) else {
+        return false;
+    };
+    let engine = context
+        .global_object()
+        .unwrap()
+        .get_property(ENGINE)
+        .unwrap();
+    let remove = engine.get_property(REMOVE_CALLBACK).unwrap();
+    remove
+        .call(&engine, &[context.value_from_i32(callback_id.0).unwrap()])
+        .unwrap();
+    let replier = replier.borrow_mut().take();
+    if let Some(replier) = replier {
+        replier(context, Err(anyhow::anyhow!(SCRIPT_RELOADED)));
+    }
+    true
+}
+
+/// Starts or stops rejecting new executions of public endpoints with `execute()`.
+/// Pending executions and their outgoing calls continue, so the engine becomes
+/// idle eventually. Background executions started with `spawn()`, e.g. by
+/// timers, are not affected. The flag is not persisted, so an upgrade ends
+/// draining.
+pub fn set_draining(draining: bool) {
+    DRAINING.with(|cell| cell.set(draining));
+}
+
+/// The status of the engine for debugging tools.
+pub struct Status {
+    pub pending_executions: usize,
+    pub pending_calls: usize,
+    pub has_staged: bool,
+    pub has_standby: bool,
+    pub draining: bool,
+}
+
+impl Status {
+    /// Returns true if no executions or outgoing calls are pending.
+    pub fn is_idle(&self) -> bool {
+        self.pending_executions == 0 && self.pending_calls == 0
+    }
+}
+
+pub fn status() -> Status {
+    Status {
+        pending_executions: REPLIERS.with(|repliers| repliers.borrow().len()),
+        pending_calls: DESERIALIZERS.with(|deserializers| deserializers.borrow().len())
+            + RETIRED.with(|retired| retired.borrow().len()),
+        has_staged: STAGED.with(|staged| staged.borrow().is_some()),
+        has_standby: STANDBY.with(|standby| standby.borrow().is_some()),
+        draining: DRAINING.with(|draining| draining.get()),
+    }
+}
+
+/// Evaluates the given code in the global scope of the active context and
+/// returns its result. This is meant for debugging tools: the code runs
+/// outside of any call context, so it must not make outgoing calls.
+pub fn eval(name: &str, code: &str) -> Result<JSValue, Error> {
+    CONTEXT.with(|context| {
+        let context = context.borrow();
+        let context = context.as_ref().unwrap();
+        let value = context.eval_global(name, code)?;
+        let value = quickjs_wasm_rs::from_qjs_value(&value)?;
+        context.execute_pending()?;
+        settle_deferred(context);
+        Ok(value)
+    })
+}
+
 /// This helper starts execution of a public endpoint of the canister with the
 /// given JS method name.
 ///
@@ -104,7 +399,10 @@ pub fn execute<R>(
     CONTEXT.with(|context| {
         let mut context = context.borrow_mut();
         let context = context.as_mut().unwrap();
-        match execute_js_endpoint(context, method, arguments) {
+        let result = check_draining(method)
+            .and_then(|()| check_argument_size(context, method))
+            .and_then(|()| execute_js_endpoint(context, method, arguments));
+        let reply = match result {
             Ok((_id, Some(value))) => replier(context, Ok(value)),
             Ok((id, None)) => {
                 put_replier(id, |context, result| {
@@ -113,14 +411,220 @@ pub fn execute<R>(
                 ManualReply::empty()
             }
             Err(err) => replier(context, Err(err)),
+        };
+        settle_deferred(context);
+        reply
+    })
+}
+
+/// This helper executes a public endpoint in a query call. The JS method must
+/// complete without awaiting: outgoing calls and writes of persisted state fail
+/// because a query cannot make calls and its changes are discarded.
+pub fn execute_query<R>(
+    method: &str,
+    arguments: impl Arguments,
+    replier: impl Replier<R> + 'static,
+) -> ManualReply<R> {
+    READ_ONLY.with(|read_only| read_only.set(true));
+    let reply = CONTEXT.with(|context| {
+        let mut context = context.borrow_mut();
+        let context = context.as_mut().unwrap();
+        let result = check_argument_size(context, method)
+            .and_then(|()| execute_js_endpoint(context, method, arguments));
+        let reply = match result {
+            Ok((_id, Some(value))) => replier(context, Ok(value)),
+            Ok((_id, None)) => replier(
+                context,
+                Err(anyhow::anyhow!(
+                    "The query {} must complete without awaiting",
+                    method
+                )),
+            ),
+            Err(err) => replier(context, Err(err)),
+        };
+        settle_deferred(context);
+        reply
+    });
+    READ_ONLY.with(|read_only| read_only.set(false));
+    reply
+}
+
+/// Fails if the current execution runs in a query call. Linked functions that
+/// write persisted state call it first.
+pub fn ensure_writable(operation: &str) -> Result<(), Error> {
+    if READ_ONLY.with(|read_only| read_only.get()) {
+        anyhow::bail!("{} is not allowed in a query", operation);
+    }
+    Ok(())
+}
+
+// Fails with a retry hint if the engine is draining.
+fn check_draining(method: &str) -> Result<(), Error> {
+    if DRAINING.with(|draining| draining.get()) {
+        return Err(anyhow::anyhow!(
+            "The canister is draining before an upgrade and does not accept {} now, retry after the upgrade",
+            method
+        ));
+    }
+    Ok(())
+}
+
+// Fails with an error naming the endpoint if the arguments of the incoming
+// message exceed the configured limit.
+fn check_argument_size(context: &JSContextRef, method: &str) -> Result<(), Error> {
+    let size = ic_cdk::api::call::arg_data_raw_size();
+    let global = context.global_object()?;
+    let engine = global.get_property(ENGINE)?;
+    let get_limit = engine.get_property(GET_LIMIT)?;
+    let limit = get_limit
+        .call(&engine, &[context.value_from_str(MAX_ARGUMENT_BYTES)?])?
+        .as_f64()?;
+    if size as f64 > limit {
+        return Err(anyhow::anyhow!(
+            "The arguments of {} have {} bytes, the limit is {}",
+            method,
+            size,
+            limit
+        ));
+    }
+    Ok(())
+}
+
+/// This helper starts execution of the given JS method in the background, i.e.
+/// outside of a public endpoint.
+///
+/// The arguments to the JS method are produced by the `arguments` function.
+///
+/// When the result of execution is ready, then the given `completion` function
+/// will be invoked with the JS result.
+pub fn spawn(method: &str, arguments: impl Arguments, completion: impl Completion + 'static) {
+    CONTEXT.with(|context| {
+        let mut context = context.borrow_mut();
+        let context = context.as_mut().unwrap();
+        match execute_js_endpoint(context, method, arguments) {
+            Ok((_id, Some(value))) => completion(context, Ok(value)),
+            Ok((id, None)) => put_replier(id, completion),
+            Err(err) => completion(context, Err(err)),
+        }
+        settle_deferred(context);
+    })
+}
+
+/// This helper executes the given JS method and expects the execution to
+/// complete synchronously, i.e. without pending outgoing calls. This is useful
+/// in contexts that cannot make calls such as `post_upgrade`.
+///
+/// The given `handler` function converts the JS result into a Rust value.
+pub fn execute_sync<T>(
+    method: &str,
+    arguments: impl Arguments,
+    handler: impl FnOnce(&JSContextRef, JSValueRef) -> Result<T, Error>,
+) -> Result<T, Error> {
+    CONTEXT.with(|context| {
+        let mut context = context.borrow_mut();
+        let context = context.as_mut().unwrap();
+        match execute_js_endpoint(context, method, arguments)? {
+            (_id, Some(value)) => handler(context, value),
+            (_id, None) => Err(anyhow::anyhow!(
+                "Expected {} to complete without awaiting outgoing calls",
+                method
+            )),
         }
     })
 }
 
+/// This helper creates a JS promise that is settled later by Rust code using
+/// `settle()`. This allows linked functions to perform asynchronous Rust work,
+/// e.g. in a future started by `ic_cdk::spawn()`, and deliver its result to JS.
+///
+/// Similar to an outgoing call, the promise keeps the current call context
+/// alive until it is settled.
+pub fn promise<'a>(context: &'a JSContextRef) -> Result<(PromiseId, JSValueRef<'a>), Error> {
+    let global = context.global_object()?;
+    let (callback_id, promise) = create_js_callback(&global)?;
+    Ok((PromiseId(callback_id), promise))
+}
+
+/// A shorthand for `promise()` that runs the given future with
+/// `ic_cdk::spawn()` and settles the promise with its result.
+pub fn promise_for<'a>(
+    context: &'a JSContextRef,
+    future: impl Future<Output = Result<JSValue, String>> + 'static,
+) -> Result<JSValueRef<'a>, Error> {
+    let (id, promise) = promise(context)?;
+    ic_cdk::spawn(async move {
+        let result = future.await;
+        settle(id, move |context| match result {
+            Ok(value) => quickjs_wasm_rs::to_qjs_value(context, &value),
+            Err(err) => Err(anyhow::anyhow!(err)),
+        });
+    });
+    Ok(promise)
+}
+
+/// Settles the given promise created by `promise()`. The promise is resolved
+/// with the value returned by the `settler` function or rejected with its
+/// error.
+pub fn settle(id: PromiseId, settler: impl Settler + 'static) {
+    let is_running = CONTEXT.with(|context| context.try_borrow_mut().is_err());
+    if is_running {
+        // This happens if the asynchronous work completed synchronously
+        // while the JS code that started it is still running.
+        DEFERRED_SETTLERS.with(|settlers| settlers.borrow_mut().push((id, Box::new(settler))));
+        return;
+    }
+    CONTEXT.with(|context| {
+        let mut context = context.borrow_mut();
+        let context = context.as_mut().unwrap();
+        settle_promise(context, id, settler);
+        settle_deferred(context);
+    });
+}
+
+/// Returns the `ic` namespace object that groups the APIs available to the
+/// user JS code. The object is created on first use.
+pub fn namespace<'a>(context: &'a JSContextRef) -> Result<JSValueRef<'a>, Error> {
+    let global = context.global_object()?;
+    if global.get_property(NAMESPACE)?.is_undefined() {
+        global.set_property(NAMESPACE, context.object_value()?)?;
+    }
+    global.get_property(NAMESPACE)
+}
+
+/// Returns true if the user JS script defines a global function or registers
+/// an endpoint handler with the given name. This is useful for optional hooks
+/// that the script may implement.
+pub fn is_function_defined(name: &str) -> bool {
+    CONTEXT.with(|context| {
+        let context = context.borrow();
+        let context = context.as_ref().unwrap();
+        context
+            .global_object()
+            .and_then(|global| lookup_function(&global, name))
+            .map(|value| value.is_function())
+            .unwrap_or(false)
+    })
+}
+
+// An internal helper that finds the JS function with the given name. Handlers
+// registered with `ic.registerUpdate()` and `ic.registerQuery()` take
+// precedence over global functions.
+fn lookup_function<'a>(global: &JSValueRef<'a>, name: &str) -> Result<JSValueRef<'a>, Error> {
+    let endpoints = global.get_property(ENDPOINTS)?;
+    if !endpoints.is_undefined() {
+        let handler = endpoints.get_property(name)?;
+        if handler.is_function() {
+            return Ok(handler);
+        }
+    }
+    global.get_property(name)
+}
+
 /// This helper starts an outgoing call the given method of another canister.
 /// The arguments should be already in the serialized wire format (e.g. Candid).
 /// When the call completes, the result of the call will be deserialized and
 /// converted to a JS value using the given `call_result_deserializer` function.
+/// Calls that the call policy does not allow fail without being performed.
 pub fn call<'a>(
     context: &'a JSContextRef,
     canister_id: ic_cdk::export::Principal,
@@ -128,12 +632,88 @@ pub fn call<'a>(
     serialized_args: &[u8],
     call_result_deserializer: impl CallResultDeserializer + 'static,
 ) -> Result<JSValueRef<'a>, Error> {
+    call_with_cycles(
+        context,
+        canister_id,
+        method,
+        serialized_args,
+        0,
+        call_result_deserializer,
+    )
+}
+
+/// Returns the call context of the running JS code.
+pub fn entered_call_context(context: &JSContextRef) -> Result<CallContextId, Error> {
+    let engine = context.global_object()?.get_property(ENGINE)?;
+    let method = engine.get_property(GET_ENTERED_CALL_CONTEXT)?;
+    let entered_call_context = method.call(&engine, &[])?;
+    Ok(CallContextId(
+        entered_call_context.get_property(ID)?.try_as_integer()?,
+    ))
+}
+
+/// Charges a call that is not made by `call()`, e.g. a call deferred by the
+/// outbox or made by a linked module, with the given cycles attached against
+/// the budget of the current call context.
+pub fn charge_call(context: &JSContextRef, cycles: u128) -> Result<(), Error> {
+    let engine = context.global_object()?.get_property(ENGINE)?;
+    let method = engine.get_property(CHARGE_CALL)?;
+    method.call(&engine, &[context.value_from_f64(cycles as f64)?])?;
+    Ok(())
+}
+
+/// Like `call()`, but attaches the given cycles to the call. The calls and
+/// cycles of a call context count against the budget set by
+/// `ic.limits.configure()`.
+pub fn call_with_cycles<'a>(
+    context: &'a JSContextRef,
+    canister_id: ic_cdk::export::Principal,
+    method: &str,
+    serialized_args: &[u8],
+    cycles: u128,
+    call_result_deserializer: impl CallResultDeserializer + 'static,
+) -> Result<JSValueRef<'a>, Error> {
+    ensure_writable(&format!("Calling {}", method))?;
+    call_policy::check(&canister_id, method).map_err(Error::msg)?;
     let global = context.global_object()?;
-    let (callback_id, promise) = create_js_callback(&global)?;
+    let (callback_id, promise, is_ready) = create_js_call_callback(context, &global, cycles)?;
     put_deserializer(callback_id, call_result_deserializer);
 
-    let canister_id = canister_id.as_slice();
-    let method = method.as_bytes();
+    recorder::record(recorder::Event::Call {
+        callback_id: callback_id.0,
+        canister_id,
+        method: method.to_string(),
+        args: serialized_args.to_vec(),
+    });
+
+    let call = QueuedCall {
+        canister_id,
+        method: method.to_string(),
+        args: serialized_args.to_vec(),
+        cycles,
+    };
+    if is_ready {
+        perform_call(context, callback_id, call);
+        perform_ready_calls(context);
+    } else {
+        // The call context has the maximum number of calls in flight, so the
+        // call is performed when one of them completes.
+        QUEUED_CALLS.with(|queued| queued.borrow_mut().insert(callback_id, call));
+    }
+    Ok(promise)
+}
+
+// An internal helper that performs the given call or rejects its promise if
+// that fails.
+fn perform_call(context: &JSContextRef, callback_id: CallbackId, call: QueuedCall) {
+    #[cfg(feature = "fault-injection")]
+    if let Some(err) = crate::faults::inject(&call.canister_id) {
+        reject_immediately(context, callback_id, &err);
+        return;
+    }
+
+    let canister_id = call.canister_id.as_slice();
+    let method = call.method.as_bytes();
 
     let err = unsafe {
         ic0::call_new(
@@ -146,20 +726,81 @@ pub fn call<'a>(
             handle_call_reject as usize as i32,
             callback_id.0,
         );
-        ic0::call_data_append(
-            serialized_args.as_ptr() as i32,
-            serialized_args.len() as i32,
-        );
+        ic0::call_data_append(call.args.as_ptr() as i32, call.args.len() as i32);
+        if call.cycles > 0 {
+            ic0::call_cycles_add128((call.cycles >> 64) as i64, call.cycles as u64 as i64);
+        }
         ic0::call_on_cleanup(remove_js_callback as usize as i32, callback_id.0);
         ic0::call_perform()
     };
 
     if err != 0 {
-        let err = format!("Failed to make a call, error code: {}", err);
-        let err = context.value_from_str(&err).unwrap();
-        execute_js_callback(context, EXECUTE_REJECT_CALLBACK, callback_id, err);
+        reject_immediately(
+            context,
+            callback_id,
+            &format!("Failed to make a call, error code: {}", err),
+        );
+    } else {
+        call_metrics::started(callback_id.0, call.canister_id, call.cycles);
     }
-    Ok(promise)
+}
+
+// An internal helper that performs the queued calls that got a free slot
+// because other calls of their call context completed. Rejecting a call frees
+// a slot too, so this repeats until no more calls are ready.
+fn perform_ready_calls(context: &JSContextRef) {
+    loop {
+        let global = context.global_object().unwrap();
+        let engine = global.get_property(ENGINE).unwrap();
+        let method = engine.get_property(TAKE_READY_CALLS).unwrap();
+        let ready = method.call(&engine, &[]).unwrap();
+        let length = ready
+            .get_property("length")
+            .unwrap()
+            .try_as_integer()
+            .unwrap();
+        if length == 0 {
+            break;
+        }
+        for i in 0..length {
+            let callback_id = CallbackId(
+                ready
+                    .get_indexed_property(i as u32)
+                    .unwrap()
+                    .try_as_integer()
+                    .unwrap(),
+            );
+            if let Some(call) = QUEUED_CALLS.with(|queued| queued.borrow_mut().remove(&callback_id))
+            {
+                perform_call(context, callback_id, call);
+            }
+        }
+    }
+}
+
+// Rejects the promise of a call that was not performed.
+fn reject_immediately(context: &JSContextRef, callback_id: CallbackId, err: &str) {
+    recorder::record(recorder::Event::Reject {
+        callback_id: callback_id.0,
+        message: err.to_string(),
+    });
+    let err = context.value_from_str(err).unwrap();
+    let _ignore = get_deserializer(callback_id);
+    execute_js_callback(context, EXECUTE_REJECT_CALLBACK, callback_id, err);
+}
+
+// Makes the cycles refunded by the completed call available to JS via
+// `ic.cyclesRefunded(promise)`.
+fn set_cycles_refunded(context: &JSContextRef, callback_id: CallbackId) {
+    let refunded = ic_cdk::api::call::msg_cycles_refunded128();
+    let global = context.global_object().unwrap();
+    let engine = global.get_property(ENGINE).unwrap();
+    let method = engine.get_property(SET_CYCLES_REFUNDED).unwrap();
+    let args = &[
+        context.value_from_i32(callback_id.0).unwrap(),
+        system_api::cycles_value(context, refunded).unwrap(),
+    ];
+    method.call(&engine, args).unwrap();
 }
 
 // The reply callback of an outgoing call. It is marked as `extern "C"` because
@@ -171,6 +812,19 @@ extern "C" fn handle_call_reply(callback_id: i32) {
         let mut context = context.borrow_mut();
         let context = context.as_mut().unwrap();
         let result = ic_cdk::api::call::arg_data_raw();
+        recorder::record(recorder::Event::Reply {
+            callback_id: callback_id.0,
+            data: result.clone(),
+        });
+        call_metrics::completed(
+            callback_id.0,
+            ic_cdk::api::call::msg_cycles_refunded128(),
+            false,
+        );
+        if complete_retired(context, callback_id) {
+            return;
+        }
+        set_cycles_refunded(context, callback_id);
         let deserialize_call_result_fn = get_deserializer(callback_id).unwrap();
         match deserialize_call_result_fn(context, result) {
             Ok(result) => execute_js_callback(context, EXECUTE_REPLY_CALLBACK, callback_id, result),
@@ -179,6 +833,8 @@ extern "C" fn handle_call_reply(callback_id: i32) {
                 execute_js_callback(context, EXECUTE_REJECT_CALLBACK, callback_id, err)
             }
         }
+        perform_ready_calls(context);
+        settle_deferred(context);
     });
 }
 
@@ -191,9 +847,24 @@ extern "C" fn handle_call_reject(callback_id: i32) {
         let mut context = context.borrow_mut();
         let context = context.as_mut().unwrap();
         let err = ic_cdk::api::call::reject_message();
+        recorder::record(recorder::Event::Reject {
+            callback_id: callback_id.0,
+            message: err.clone(),
+        });
+        call_metrics::completed(
+            callback_id.0,
+            ic_cdk::api::call::msg_cycles_refunded128(),
+            true,
+        );
+        if complete_retired(context, callback_id) {
+            return;
+        }
         let err = context.value_from_str(&err.to_string()).unwrap();
         let _ignore = get_deserializer(callback_id);
-        execute_js_callback(context, EXECUTE_REJECT_CALLBACK, callback_id, err)
+        set_cycles_refunded(context, callback_id);
+        execute_js_callback(context, EXECUTE_REJECT_CALLBACK, callback_id, err);
+        perform_ready_calls(context);
+        settle_deferred(context);
     });
 }
 
@@ -202,14 +873,44 @@ extern "C" fn handle_call_reject(callback_id: i32) {
 #[no_mangle]
 extern "C" fn remove_js_callback(callback_id: i32) {
     let callback_id = CallbackId(callback_id);
+    call_metrics::forget(callback_id.0);
+    RETIRED.with(|retired| retired.borrow_mut().remove(&callback_id));
     CONTEXT.with(|context| {
         let mut context = context.borrow_mut();
         let context = context.as_mut().unwrap();
         let _ignore = get_deserializer(callback_id);
         let global = context.global_object().unwrap();
         let engine = global.get_property(ENGINE).unwrap();
+        // The call context of a trapped callback never resumes, so it cannot
+        // release its locks.
+        let call_context = engine
+            .get_property(GET_CALLBACK_CALL_CONTEXT)
+            .unwrap()
+            .call(&engine, &[context.value_from_i32(callback_id.0).unwrap()])
+            .unwrap();
+        if !call_context.is_null_or_undefined() {
+            locks::release_all(CallContextId(call_context.try_as_integer().unwrap()));
+        }
+        let callback_id = context.value_from_i32(callback_id.0).unwrap();
         let cleanup_method = engine.get_property(REMOVE_CALLBACK).unwrap();
-        let _ignore = cleanup_method.call(&engine, &[]).unwrap();
+        // The queued calls of the call context are dropped with it.
+        let dropped = cleanup_method.call(&engine, &[callback_id]).unwrap();
+        let length = dropped
+            .get_property("length")
+            .unwrap()
+            .try_as_integer()
+            .unwrap();
+        for i in 0..length {
+            let callback_id = CallbackId(
+                dropped
+                    .get_indexed_property(i as u32)
+                    .unwrap()
+                    .try_as_integer()
+                    .unwrap(),
+            );
+            let _ignore = get_deserializer(callback_id);
+            QUEUED_CALLS.with(|queued| queued.borrow_mut().remove(&callback_id));
+        }
     });
 }
 
@@ -222,8 +923,19 @@ fn execute_js_endpoint<'a>(
     let global = context.global_object()?;
     let engine = global.get_property(ENGINE)?;
     let execute_method = engine.get_property(EXECUTE_ENDPOINT)?;
-    let js_endpoint = global.get_property(method)?;
+    let js_endpoint = lookup_function(&global, method)?;
     let args = arguments(context)?;
+    if recorder::is_enabled() {
+        let json = args
+            .iter()
+            .map(|arg| Ok(crate::json::to_json(&quickjs_wasm_rs::from_qjs_value(arg)?)))
+            .collect::<Result<Vec<_>, Error>>()?;
+        recorder::record(recorder::Event::Execution {
+            method: method.to_string(),
+            caller: ic_cdk::caller(),
+            args: serde_json::Value::Array(json).to_string(),
+        });
+    }
     let args = [&[js_endpoint], args.as_slice()].concat();
     execute_js_task(context, &engine, &execute_method, &args)
 }
@@ -287,12 +999,41 @@ fn execute_js_task<'a>(
         (true, false) => {
             let exception = quickjs_wasm_rs::Exception::from(rejected)?;
             let err = exception.into_error();
-            Err(err)
+            Err(anyhow::anyhow!(source_maps::translate(&err.to_string())))
         }
         (false, false) => unreachable!("The result cannot be both replied and rejected."),
     }
 }
 
+// An internal helper that resolves or rejects the given promise.
+fn settle_promise(context: &JSContextRef, id: PromiseId, settler: impl Settler) {
+    if complete_retired(context, id.0) {
+        return;
+    }
+    match settler(context) {
+        Ok(value) => execute_js_callback(context, EXECUTE_REPLY_CALLBACK, id.0, value),
+        Err(err) => {
+            let err = context.value_from_str(&err.to_string()).unwrap();
+            execute_js_callback(context, EXECUTE_REJECT_CALLBACK, id.0, err)
+        }
+    }
+}
+
+// An internal helper that settles the promises deferred while JS code was
+// running. Settling a promise runs JS code, which may defer more promises.
+fn settle_deferred(context: &JSContextRef) {
+    loop {
+        let deferred =
+            DEFERRED_SETTLERS.with(|settlers| std::mem::take(&mut *settlers.borrow_mut()));
+        if deferred.is_empty() {
+            break;
+        }
+        for (id, settler) in deferred {
+            settle_promise(context, id, settler);
+        }
+    }
+}
+
 // An internal helper that creates a JS callback for an outgoing call.
 fn create_js_callback<'a>(global: &JSValueRef<'a>) -> Result<(CallbackId, JSValueRef<'a>), Error> {
     let engine = global.get_property(ENGINE)?;
@@ -304,6 +1045,22 @@ fn create_js_callback<'a>(global: &JSValueRef<'a>) -> Result<(CallbackId, JSValu
     Ok((CallbackId(callback_id), promise))
 }
 
+// Like `create_js_callback()` but for an outgoing call. Also returns whether
+// the call may be performed now or must wait in the queue of its call context.
+fn create_js_call_callback<'a>(
+    context: &'a JSContextRef,
+    global: &JSValueRef<'a>,
+    cycles: u128,
+) -> Result<(CallbackId, JSValueRef<'a>, bool), Error> {
+    let engine = global.get_property(ENGINE)?;
+    let callback_method = engine.get_property(CREATE_CALL_CALLBACK)?;
+    let result = callback_method.call(&engine, &[context.value_from_f64(cycles as f64)?])?;
+    let callback_id = result.get_indexed_property(0)?.try_as_integer()?;
+    let promise = result.get_indexed_property(1)?;
+    let is_ready = result.get_indexed_property(2)?.as_bool()?;
+    Ok((CallbackId(callback_id), promise, is_ready))
+}
+
 // An internal helper that saves the given replier function.
 fn put_replier(id: CallContextId, replier: impl StoredReplier + 'static) {
     REPLIERS.with(|store| {
@@ -339,5 +1096,7 @@ fn get_deserializer(id: CallbackId) -> Option<impl CallResultDeserializer> {
 // Boilerplate for the function traits.
 impl<F: FnOnce(&JSContextRef) -> Result<Vec<JSValueRef>, Error>> Arguments for F {}
 impl<R, F: FnOnce(&JSContextRef, Result<JSValueRef, Error>) -> ManualReply<R>> Replier<R> for F {}
+impl<F: FnOnce(&JSContextRef, Result<JSValueRef, Error>)> Completion for F {}
 impl<F: FnOnce(&JSContextRef, Result<JSValueRef, Error>) -> ()> StoredReplier for F {}
 impl<F: FnOnce(&JSContextRef, Vec<u8>) -> Result<JSValueRef, Error>> CallResultDeserializer for F {}
+impl<F: FnOnce(&JSContextRef) -> Result<JSValueRef, Error>> Settler for F {}
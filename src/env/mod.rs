@@ -0,0 +1,79 @@
+// Named values passed at install time exposed to JS as `ic.env` and
+// `process.env`.
+//
+// Pass `(opt record { env = opt vec { record { "LEDGER"; "ryjl3-..." } } })`
+// as the init or upgrade argument. The values are stored in stable memory, so
+// an upgrade without `env` keeps them, while an upgrade with `env` replaces all
+// of them. Stable memory is not encrypted, so the values are visible to the
+// nodes of the subnet.
+use std::cell::RefCell;
+
+use candid::{CandidType, Deserialize};
+use ic_stable_structures::StableBTreeMap;
+use quickjs_wasm_rs::JSContextRef;
+
+use crate::{
+    call_policy, engine, redaction,
+    stable::{self, Memory},
+};
+
+/// The argument of `init` and `post_upgrade`.
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct InstallArgs {
+    pub env: Option<Vec<(String, String)>>,
+    /// Replaces the policy of outgoing calls if present.
+    pub call_policy: Option<call_policy::CallPolicy>,
+    /// Replaces the detail of errors shown to callers if present.
+    pub error_detail: Option<redaction::ErrorDetail>,
+}
+
+thread_local! {
+    static ENV: RefCell<StableBTreeMap<String, String, Memory>> =
+        RefCell::new(StableBTreeMap::init(stable::memory(stable::ENV)));
+}
+
+/// Replaces the stored values, the call policy and the error detail if the
+/// argument contains them.
+pub fn install(args: Option<InstallArgs>) {
+    let Some(args) = args else {
+        return;
+    };
+    if let Some(policy) = args.call_policy {
+        call_policy::set(Some(policy));
+    }
+    if let Some(detail) = args.error_detail {
+        redaction::set(detail);
+    }
+    let Some(env) = args.env else {
+        return;
+    };
+    ENV.with(|stored| {
+        let mut stored = stored.borrow_mut();
+        let keys: Vec<String> = stored.iter().map(|(key, _)| key).collect();
+        for key in keys {
+            stored.remove(&key);
+        }
+        for (key, value) in env {
+            stored.insert(key, value);
+        }
+    });
+}
+
+pub fn link(context: &JSContextRef) -> Result<(), anyhow::Error> {
+    let env = context.object_value()?;
+    ENV.with(|stored| {
+        for (key, value) in stored.borrow().iter() {
+            env.set_property(key.as_str(), context.value_from_str(&value)?)?;
+        }
+        Ok::<_, anyhow::Error>(())
+    })?;
+    engine::namespace(context)?.set_property("env", env)?;
+
+    let global = context.global_object()?;
+    if global.get_property("process")?.is_undefined() {
+        let process = context.object_value()?;
+        process.set_property("env", engine::namespace(context)?.get_property("env")?)?;
+        global.set_property("process", process)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,205 @@
+// Ethereum ABI encoding of JS values.
+//
+// Integers are passed as JS numbers or as decimal or `0x`-prefixed hex strings
+// and are returned as decimal strings, so JS can convert them with `BigInt()`.
+// Addresses are hex strings (checksummed on output), byte values are ArrayBuffers or hex strings and
+// tuples are arrays.
+use anyhow::{anyhow, bail};
+use ethabi::{ethereum_types::U256, param_type::Reader, Address, ParamType, Token};
+use quickjs_wasm_rs::JSValue;
+
+use super::address;
+use crate::crypto;
+
+pub fn encode(types: &[String], values: &[JSValue]) -> Result<Vec<u8>, anyhow::Error> {
+    let types = parse_types(types)?;
+    if types.len() != values.len() {
+        bail!("Expected {} values, got {}", types.len(), values.len());
+    }
+    let tokens = types
+        .iter()
+        .zip(values)
+        .map(|(param, value)| to_token(param, value))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(ethabi::encode(&tokens))
+}
+
+pub fn decode(types: &[String], data: &[u8]) -> Result<Vec<JSValue>, anyhow::Error> {
+    let types = parse_types(types)?;
+    let tokens = ethabi::decode(&types, data)?;
+    Ok(tokens.into_iter().map(from_token).collect())
+}
+
+/// Encodes a call of the function with the given signature, e.g.
+/// `transfer(address,uint256)`: the 4-byte selector followed by the arguments.
+pub fn encode_function_call(signature: &str, values: &[JSValue]) -> Result<Vec<u8>, anyhow::Error> {
+    let start = signature
+        .find('(')
+        .ok_or_else(|| anyhow!("Invalid function signature {}", signature))?;
+    let ParamType::Tuple(types) = Reader::read(&signature[start..])? else {
+        bail!("Invalid function signature {}", signature);
+    };
+    if types.len() != values.len() {
+        bail!("Expected {} values, got {}", types.len(), values.len());
+    }
+    let tokens = types
+        .iter()
+        .zip(values)
+        .map(|(param, value)| to_token(param, value))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut result = crypto::keccak256(signature.as_bytes())[0..4].to_vec();
+    result.extend(ethabi::encode(&tokens));
+    Ok(result)
+}
+
+fn parse_types(types: &[String]) -> Result<Vec<ParamType>, anyhow::Error> {
+    types
+        .iter()
+        .map(|name| Reader::read(name).map_err(|err| anyhow!("Invalid ABI type {}: {}", name, err)))
+        .collect()
+}
+
+fn to_token(param: &ParamType, value: &JSValue) -> Result<Token, anyhow::Error> {
+    Ok(match param {
+        ParamType::Address => Token::Address(address(value)?),
+        ParamType::Bytes => Token::Bytes(bytes(value)?),
+        ParamType::FixedBytes(len) => {
+            let bytes = bytes(value)?;
+            if bytes.len() != *len {
+                bail!("Expected {} bytes, got {}", len, bytes.len());
+            }
+            Token::FixedBytes(bytes)
+        }
+        ParamType::Int(_) => Token::Int(int(value)?),
+        ParamType::Uint(_) => Token::Uint(uint(value)?),
+        ParamType::Bool => match value {
+            JSValue::Bool(value) => Token::Bool(*value),
+            _ => bail!("Expected a boolean"),
+        },
+        ParamType::String => match value {
+            JSValue::String(value) => Token::String(value.clone()),
+            _ => bail!("Expected a string"),
+        },
+        ParamType::Array(param) => Token::Array(to_tokens(param, array(value)?)?),
+        ParamType::FixedArray(param, len) => {
+            let values = array(value)?;
+            if values.len() != *len {
+                bail!(
+                    "Expected an array of {} elements, got {}",
+                    len,
+                    values.len()
+                );
+            }
+            Token::FixedArray(to_tokens(param, values)?)
+        }
+        ParamType::Tuple(params) => {
+            let values = array(value)?;
+            if values.len() != params.len() {
+                bail!(
+                    "Expected a tuple of {} elements, got {}",
+                    params.len(),
+                    values.len()
+                );
+            }
+            Token::Tuple(
+                params
+                    .iter()
+                    .zip(values)
+                    .map(|(param, value)| to_token(param, value))
+                    .collect::<Result<_, _>>()?,
+            )
+        }
+    })
+}
+
+fn to_tokens(param: &ParamType, values: &[JSValue]) -> Result<Vec<Token>, anyhow::Error> {
+    values.iter().map(|value| to_token(param, value)).collect()
+}
+
+fn from_token(token: Token) -> JSValue {
+    match token {
+        Token::Address(address) => JSValue::String(address::to_checksum(&address)),
+        Token::Bytes(bytes) | Token::FixedBytes(bytes) => JSValue::ArrayBuffer(bytes),
+        Token::Uint(value) => JSValue::String(value.to_string()),
+        Token::Int(value) => {
+            if value.bit(255) {
+                JSValue::String(format!("-{}", (!value).overflowing_add(U256::one()).0))
+            } else {
+                JSValue::String(value.to_string())
+            }
+        }
+        Token::Bool(value) => JSValue::Bool(value),
+        Token::String(value) => JSValue::String(value),
+        Token::Array(tokens) | Token::FixedArray(tokens) | Token::Tuple(tokens) => {
+            JSValue::Array(tokens.into_iter().map(from_token).collect())
+        }
+    }
+}
+
+fn array(value: &JSValue) -> Result<&[JSValue], anyhow::Error> {
+    match value {
+        JSValue::Array(values) => Ok(values),
+        _ => bail!("Expected an array"),
+    }
+}
+
+/// Parses a 20-byte address from a hex string.
+pub fn address(value: &JSValue) -> Result<Address, anyhow::Error> {
+    let bytes = bytes(value)?;
+    if bytes.len() != 20 {
+        bail!("Expected a 20-byte address, got {} bytes", bytes.len());
+    }
+    Ok(Address::from_slice(&bytes))
+}
+
+/// Returns the bytes of an ArrayBuffer or a `0x`-prefixed hex string.
+pub fn bytes(value: &JSValue) -> Result<Vec<u8>, anyhow::Error> {
+    match value {
+        JSValue::ArrayBuffer(bytes) => Ok(bytes.clone()),
+        JSValue::String(string) => {
+            let hex = string
+                .strip_prefix("0x")
+                .ok_or_else(|| anyhow!("Expected a 0x-prefixed hex string, got {}", string))?;
+            Ok(hex::decode(hex)?)
+        }
+        _ => bail!("Expected an ArrayBuffer or a hex string"),
+    }
+}
+
+/// Parses an unsigned 256-bit integer from a JS number or a decimal or hex
+/// string.
+pub fn uint(value: &JSValue) -> Result<U256, anyhow::Error> {
+    match value {
+        JSValue::Int(value) if *value >= 0 => Ok(U256::from(*value as u64)),
+        JSValue::Float(value)
+            if *value >= 0.0 && value.fract() == 0.0 && *value <= 2f64.powi(53) =>
+        {
+            Ok(U256::from(*value as u64))
+        }
+        JSValue::String(string) => match string.strip_prefix("0x") {
+            Some(hex) => U256::from_str_radix(hex, 16)
+                .map_err(|err| anyhow!("Invalid integer {}: {:?}", string, err)),
+            None => U256::from_dec_str(string)
+                .map_err(|err| anyhow!("Invalid integer {}: {:?}", string, err)),
+        },
+        _ => bail!("Expected a non-negative integer as a number or a string"),
+    }
+}
+
+// Parses a signed integer into its two's complement representation.
+fn int(value: &JSValue) -> Result<U256, anyhow::Error> {
+    let (negative, magnitude) = match value {
+        JSValue::Int(value) => (*value < 0, U256::from(value.unsigned_abs())),
+        JSValue::Float(value) => (*value < 0.0, uint(&JSValue::Float(value.abs()))?),
+        JSValue::String(string) => match string.strip_prefix('-') {
+            Some(string) => (true, uint(&JSValue::String(string.to_string()))?),
+            None => (false, uint(value)?),
+        },
+        _ => bail!("Expected an integer as a number or a string"),
+    };
+    if negative {
+        Ok((!magnitude).overflowing_add(U256::one()).0)
+    } else {
+        Ok(magnitude)
+    }
+}
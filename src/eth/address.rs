@@ -0,0 +1,76 @@
+// Ethereum addresses of threshold ECDSA keys.
+//
+// The address is the last 20 bytes of `keccak256` of the uncompressed public
+// key without its prefix byte. Public keys of a canister never change, so the
+// addresses are cached.
+use std::{cell::RefCell, collections::HashMap};
+
+use ethabi::Address;
+use ic_cdk::api::management_canister::ecdsa::{
+    ecdsa_public_key, EcdsaCurve, EcdsaKeyId, EcdsaPublicKeyArgument,
+};
+use k256::elliptic_curve::sec1::ToEncodedPoint;
+
+use crate::crypto;
+
+/// The threshold ECDSA key used when JS does not specify one.
+pub const DEFAULT_KEY_NAME: &str = "key_1";
+
+thread_local! {
+    // Maps a key name and a derivation path to the address.
+    static ADDRESSES: RefCell<HashMap<(String, Vec<Vec<u8>>), Address>> = RefCell::new(HashMap::new());
+}
+
+/// Returns the address of the threshold ECDSA key of this canister with the
+/// given name and derivation path.
+pub async fn address_for(
+    key_name: String,
+    derivation_path: Vec<Vec<u8>>,
+) -> Result<Address, String> {
+    let cache_key = (key_name.clone(), derivation_path.clone());
+    if let Some(address) = ADDRESSES.with(|addresses| addresses.borrow().get(&cache_key).copied()) {
+        return Ok(address);
+    }
+    let (response,) = ecdsa_public_key(EcdsaPublicKeyArgument {
+        canister_id: None,
+        derivation_path,
+        key_id: EcdsaKeyId {
+            curve: EcdsaCurve::Secp256k1,
+            name: key_name,
+        },
+    })
+    .await
+    .map_err(|(code, message)| {
+        format!("ecdsa_public_key failed with code {:?}: {}", code, message)
+    })?;
+    let address = from_public_key(&response.public_key).map_err(|err| err.to_string())?;
+    ADDRESSES.with(|addresses| addresses.borrow_mut().insert(cache_key, address));
+    Ok(address)
+}
+
+/// Returns the address of the given SEC1-encoded secp256k1 public key.
+pub fn from_public_key(public_key: &[u8]) -> Result<Address, anyhow::Error> {
+    let public_key = k256::PublicKey::from_sec1_bytes(public_key)?;
+    let point = public_key.to_encoded_point(false);
+    let hash = crypto::keccak256(&point.as_bytes()[1..]);
+    Ok(Address::from_slice(&hash[12..]))
+}
+
+/// Returns the EIP-55 mixed-case checksum encoding of the address.
+pub fn to_checksum(address: &Address) -> String {
+    let hex = hex::encode(address.as_bytes());
+    let hash = crypto::keccak256(hex.as_bytes());
+    let checksummed: String = hex
+        .chars()
+        .enumerate()
+        .map(|(i, c)| {
+            let nibble = (hash[i / 2] >> (if i % 2 == 0 { 4 } else { 0 })) & 0x0f;
+            if nibble >= 8 {
+                c.to_ascii_uppercase()
+            } else {
+                c
+            }
+        })
+        .collect();
+    format!("0x{}", checksummed)
+}
@@ -0,0 +1,381 @@
+// Ethereum helpers exposed to JS as `ic.eth`: addresses of threshold ECDSA
+// keys, ABI and RLP encoding and serialization of EIP-1559 transactions.
+//
+// A transaction is signed with threshold ECDSA in three steps: serialize the
+// unsigned transaction, sign `keccak256` of it and serialize the transaction
+// again with the signature. The result is sent with `eth_sendRawTransaction`.
+use anyhow::bail;
+use ethabi::{ethereum_types::U256, Address};
+use quickjs_wasm_rs::{CallbackArg, JSContextRef, JSError, JSValue, JSValueRef};
+
+use crate::{crypto, engine};
+
+pub mod abi;
+pub mod address;
+pub mod rlp;
+
+use rlp::Item;
+
+// The type of EIP-1559 transactions in the typed transaction envelope.
+const EIP1559_TRANSACTION_TYPE: u8 = 0x02;
+
+/// An EIP-1559 transaction.
+#[derive(Clone, Debug)]
+pub struct Transaction {
+    pub chain_id: U256,
+    pub nonce: U256,
+    pub max_priority_fee_per_gas: U256,
+    pub max_fee_per_gas: U256,
+    pub gas_limit: U256,
+    /// `None` for contract creation.
+    pub to: Option<Address>,
+    pub value: U256,
+    pub data: Vec<u8>,
+    pub access_list: Vec<(Address, Vec<Vec<u8>>)>,
+}
+
+/// An ECDSA signature with the parity of the y coordinate of the curve point.
+#[derive(Clone, Debug)]
+pub struct Signature {
+    pub y_parity: bool,
+    pub r: U256,
+    pub s: U256,
+}
+
+impl Transaction {
+    /// Parses `{chainId, nonce, maxPriorityFeePerGas, maxFeePerGas, gasLimit,
+    /// to, value, data, accessList}`. All fields except `chainId`, `nonce` and
+    /// the fees are optional.
+    pub fn from_js(value: &JSValue) -> Result<Self, anyhow::Error> {
+        let JSValue::Object(fields) = value else {
+            bail!("Expected a transaction object");
+        };
+        let required = |name: &str| match fields.get(name) {
+            Some(value) => {
+                abi::uint(value).map_err(|err| anyhow::anyhow!("Invalid {}: {}", name, err))
+            }
+            None => bail!("Missing transaction field {}", name),
+        };
+        let optional = |name: &str| match fields.get(name) {
+            Some(JSValue::Undefined | JSValue::Null) | None => Ok(U256::zero()),
+            Some(value) => {
+                abi::uint(value).map_err(|err| anyhow::anyhow!("Invalid {}: {}", name, err))
+            }
+        };
+        let to = match fields.get("to") {
+            Some(JSValue::Undefined | JSValue::Null) | None => None,
+            Some(value) => Some(abi::address(value)?),
+        };
+        let data = match fields.get("data") {
+            Some(JSValue::Undefined | JSValue::Null) | None => vec![],
+            Some(value) => abi::bytes(value)?,
+        };
+        let access_list = match fields.get("accessList") {
+            Some(JSValue::Array(entries)) => entries
+                .iter()
+                .map(|entry| {
+                    let JSValue::Object(entry) = entry else {
+                        bail!("Expected an access list entry {{address, storageKeys}}");
+                    };
+                    let address =
+                        abi::address(entry.get("address").unwrap_or(&JSValue::Undefined))?;
+                    let keys = match entry.get("storageKeys") {
+                        Some(JSValue::Array(keys)) => {
+                            keys.iter().map(abi::bytes).collect::<Result<_, _>>()?
+                        }
+                        _ => vec![],
+                    };
+                    Ok((address, keys))
+                })
+                .collect::<Result<_, _>>()?,
+            _ => vec![],
+        };
+        Ok(Self {
+            chain_id: required("chainId")?,
+            nonce: required("nonce")?,
+            max_priority_fee_per_gas: required("maxPriorityFeePerGas")?,
+            max_fee_per_gas: required("maxFeePerGas")?,
+            gas_limit: optional("gasLimit")?,
+            to,
+            value: optional("value")?,
+            data,
+            access_list,
+        })
+    }
+
+    /// Returns the typed transaction envelope: the type byte followed by the
+    /// RLP-encoded fields and the optional signature.
+    pub fn serialize(&self, signature: Option<&Signature>) -> Vec<u8> {
+        let mut items = vec![
+            uint_item(self.chain_id),
+            uint_item(self.nonce),
+            uint_item(self.max_priority_fee_per_gas),
+            uint_item(self.max_fee_per_gas),
+            uint_item(self.gas_limit),
+            Item::Bytes(self.to.map(|to| to.as_bytes().to_vec()).unwrap_or_default()),
+            uint_item(self.value),
+            Item::Bytes(self.data.clone()),
+            Item::List(
+                self.access_list
+                    .iter()
+                    .map(|(address, keys)| {
+                        Item::List(vec![
+                            Item::Bytes(address.as_bytes().to_vec()),
+                            Item::List(keys.iter().cloned().map(Item::Bytes).collect()),
+                        ])
+                    })
+                    .collect(),
+            ),
+        ];
+        if let Some(signature) = signature {
+            items.push(uint_item(U256::from(signature.y_parity as u8)));
+            items.push(uint_item(signature.r));
+            items.push(uint_item(signature.s));
+        }
+        let mut result = vec![EIP1559_TRANSACTION_TYPE];
+        result.extend(rlp::encode(&Item::List(items)));
+        result
+    }
+
+    /// Returns the hash that must be signed.
+    pub fn signing_hash(&self) -> [u8; 32] {
+        crypto::keccak256(&self.serialize(None))
+    }
+}
+
+impl Signature {
+    /// Parses `{yParity, r, s}` where `r` and `s` are 32-byte ArrayBuffers or
+    /// integers.
+    pub fn from_js(value: &JSValue) -> Result<Self, anyhow::Error> {
+        let JSValue::Object(fields) = value else {
+            bail!("Expected a signature object {{yParity, r, s}}");
+        };
+        let y_parity = match fields.get("yParity") {
+            Some(JSValue::Bool(value)) => *value,
+            Some(JSValue::Int(value)) if *value == 0 || *value == 1 => *value == 1,
+            _ => bail!("Expected yParity to be 0 or 1"),
+        };
+        let component = |name: &str| match fields.get(name) {
+            Some(JSValue::ArrayBuffer(bytes)) if bytes.len() == 32 => {
+                Ok(U256::from_big_endian(bytes))
+            }
+            Some(value) => abi::uint(value),
+            None => bail!("Missing signature field {}", name),
+        };
+        Ok(Self {
+            y_parity,
+            r: component("r")?,
+            s: component("s")?,
+        })
+    }
+}
+
+fn uint_item(value: U256) -> Item {
+    let mut bytes = [0; 32];
+    value.to_big_endian(&mut bytes);
+    Item::Bytes(bytes.into_iter().skip_while(|b| *b == 0).collect())
+}
+
+/// Converts a JS value into an RLP item: ArrayBuffers and hex strings become
+/// byte strings, numbers and decimal strings become integers and arrays become
+/// lists.
+pub fn to_rlp(value: &JSValue) -> Result<Item, anyhow::Error> {
+    match value {
+        JSValue::Undefined | JSValue::Null => Ok(Item::Bytes(vec![])),
+        JSValue::ArrayBuffer(bytes) => Ok(Item::Bytes(bytes.clone())),
+        JSValue::String(string) if string.starts_with("0x") => Ok(Item::Bytes(abi::bytes(value)?)),
+        JSValue::Int(_) | JSValue::Float(_) | JSValue::String(_) => {
+            Ok(uint_item(abi::uint(value)?))
+        }
+        JSValue::Array(values) => Ok(Item::List(
+            values.iter().map(to_rlp).collect::<Result<_, _>>()?,
+        )),
+        _ => bail!("Expected an ArrayBuffer, a hex string, an integer or an array"),
+    }
+}
+
+/// Converts an RLP item into a JS value: byte strings become ArrayBuffers and
+/// lists become arrays.
+pub fn from_rlp(item: Item) -> JSValue {
+    match item {
+        Item::Bytes(bytes) => JSValue::ArrayBuffer(bytes),
+        Item::List(items) => JSValue::Array(items.into_iter().map(from_rlp).collect()),
+    }
+}
+
+fn strings(value: &JSValue) -> Result<Vec<String>, anyhow::Error> {
+    match value {
+        JSValue::Array(values) => values
+            .iter()
+            .map(|value| match value {
+                JSValue::String(value) => Ok(value.clone()),
+                _ => Err(JSError::Type("Expected an array of ABI types".to_string()).into()),
+            })
+            .collect(),
+        _ => Err(JSError::Type("Expected an array of ABI types".to_string()).into()),
+    }
+}
+
+/// Parses a derivation path given as an array of ArrayBuffers or strings.
+pub fn derivation_path(value: &JSValue) -> Result<Vec<Vec<u8>>, anyhow::Error> {
+    match value {
+        JSValue::Undefined => Ok(vec![]),
+        JSValue::Array(values) => values
+            .iter()
+            .map(|value| match value {
+                JSValue::ArrayBuffer(bytes) => Ok(bytes.clone()),
+                JSValue::String(string) => Ok(string.as_bytes().to_vec()),
+                _ => Err(JSError::Type(
+                    "Expected the derivation path to contain ArrayBuffers or strings".to_string(),
+                )
+                .into()),
+            })
+            .collect(),
+        _ => Err(JSError::Type("Expected the derivation path to be an array".to_string()).into()),
+    }
+}
+
+fn values(value: &JSValue) -> Result<Vec<JSValue>, anyhow::Error> {
+    match value {
+        JSValue::Array(values) => Ok(values.clone()),
+        _ => Err(JSError::Type("Expected an array of values".to_string()).into()),
+    }
+}
+
+pub fn link(context: &JSContextRef) -> Result<(), anyhow::Error> {
+    // Usage: `ic.eth.abiEncode(['address', 'uint256'], [to, amount])`.
+    fn abi_encode<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 2 {
+            return Err(JSError::Type(format!("Expected 2 arguments, got {}", args.len())).into());
+        }
+        let types = strings(&args[0].to_js_value()?)?;
+        let values = values(&args[1].to_js_value()?)?;
+        context.array_buffer_value(&abi::encode(&types, &values)?)
+    }
+
+    fn abi_decode<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 2 {
+            return Err(JSError::Type(format!("Expected 2 arguments, got {}", args.len())).into());
+        }
+        let types = strings(&args[0].to_js_value()?)?;
+        let data = abi::bytes(&args[1].to_js_value()?)?;
+        quickjs_wasm_rs::to_qjs_value(context, &JSValue::Array(abi::decode(&types, &data)?))
+    }
+
+    // Usage: `ic.eth.encodeFunctionCall('transfer(address,uint256)', [to, amount])`.
+    fn encode_function_call<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 2 {
+            return Err(JSError::Type(format!("Expected 2 arguments, got {}", args.len())).into());
+        }
+        let signature: String = args[0].try_into()?;
+        let values = values(&args[1].to_js_value()?)?;
+        context.array_buffer_value(&abi::encode_function_call(&signature, &values)?)
+    }
+
+    fn rlp_encode<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 1 {
+            return Err(JSError::Type(format!("Expected 1 argument, got {}", args.len())).into());
+        }
+        context.array_buffer_value(&rlp::encode(&to_rlp(&args[0].to_js_value()?)?))
+    }
+
+    fn rlp_decode<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 1 {
+            return Err(JSError::Type(format!("Expected 1 argument, got {}", args.len())).into());
+        }
+        let bytes = abi::bytes(&args[0].to_js_value()?)?;
+        quickjs_wasm_rs::to_qjs_value(context, &from_rlp(rlp::decode(&bytes)?))
+    }
+
+    // Usage: `ic.eth.serializeTransaction(tx)` for the unsigned transaction
+    // and `ic.eth.serializeTransaction(tx, {yParity, r, s})` for the signed one.
+    fn serialize_transaction<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.is_empty() || args.len() > 2 {
+            return Err(
+                JSError::Type(format!("Expected 1 or 2 arguments, got {}", args.len())).into(),
+            );
+        }
+        let transaction = Transaction::from_js(&args[0].to_js_value()?)?;
+        let signature = match args.get(1) {
+            Some(arg) => Some(Signature::from_js(&arg.to_js_value()?)?),
+            None => None,
+        };
+        context.array_buffer_value(&transaction.serialize(signature.as_ref()))
+    }
+
+    // Usage: `await ic.eth.addressFor(derivationPath, {keyName})`. Returns the
+    // checksummed address of the threshold ECDSA key.
+    fn address_for<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.is_empty() || args.len() > 2 {
+            return Err(
+                JSError::Type(format!("Expected 1 or 2 arguments, got {}", args.len())).into(),
+            );
+        }
+        let derivation_path = derivation_path(&args[0].to_js_value()?)?;
+        let key_name = match args.get(1).map(|arg| arg.to_js_value()).transpose()? {
+            Some(JSValue::Object(options)) => match options.get("keyName") {
+                Some(JSValue::String(name)) => name.clone(),
+                Some(_) => {
+                    return Err(JSError::Type("Expected keyName to be a string".to_string()).into())
+                }
+                None => address::DEFAULT_KEY_NAME.to_string(),
+            },
+            _ => address::DEFAULT_KEY_NAME.to_string(),
+        };
+        let (id, promise) = engine::promise(context)?;
+        ic_cdk::spawn(async move {
+            let result = address::address_for(key_name, derivation_path).await;
+            engine::settle(id, move |context| match result {
+                Ok(address) => context.value_from_str(&address::to_checksum(&address)),
+                Err(err) => Err(anyhow::anyhow!(err)),
+            });
+        });
+        Ok(promise)
+    }
+
+    let eth = context.object_value()?;
+    eth.set_property("addressFor", context.wrap_callback2(address_for)?)?;
+    eth.set_property("abiEncode", context.wrap_callback2(abi_encode)?)?;
+    eth.set_property("abiDecode", context.wrap_callback2(abi_decode)?)?;
+    eth.set_property(
+        "encodeFunctionCall",
+        context.wrap_callback2(encode_function_call)?,
+    )?;
+    eth.set_property("rlpEncode", context.wrap_callback2(rlp_encode)?)?;
+    eth.set_property("rlpDecode", context.wrap_callback2(rlp_decode)?)?;
+    eth.set_property(
+        "serializeTransaction",
+        context.wrap_callback2(serialize_transaction)?,
+    )?;
+
+    engine::namespace(context)?.set_property("eth", eth)?;
+    Ok(())
+}
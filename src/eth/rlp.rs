@@ -0,0 +1,100 @@
+// Recursive Length Prefix (RLP) encoding used by Ethereum.
+use anyhow::bail;
+
+/// An RLP item: a byte string or a list of items.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Item {
+    Bytes(Vec<u8>),
+    List(Vec<Item>),
+}
+
+pub fn encode(item: &Item) -> Vec<u8> {
+    match item {
+        Item::Bytes(bytes) if bytes.len() == 1 && bytes[0] < 0x80 => bytes.clone(),
+        Item::Bytes(bytes) => {
+            let mut result = header(0x80, bytes.len());
+            result.extend_from_slice(bytes);
+            result
+        }
+        Item::List(items) => {
+            let payload: Vec<u8> = items.iter().flat_map(encode).collect();
+            let mut result = header(0xc0, payload.len());
+            result.extend(payload);
+            result
+        }
+    }
+}
+
+fn header(offset: u8, len: usize) -> Vec<u8> {
+    if len <= 55 {
+        return vec![offset + len as u8];
+    }
+    let len = minimal_be_bytes(len as u64);
+    let mut result = vec![offset + 55 + len.len() as u8];
+    result.extend(len);
+    result
+}
+
+// Returns the big-endian bytes of the number without leading zeros.
+fn minimal_be_bytes(value: u64) -> Vec<u8> {
+    value
+        .to_be_bytes()
+        .into_iter()
+        .skip_while(|b| *b == 0)
+        .collect()
+}
+
+/// Decodes a single item that must span all the given bytes.
+pub fn decode(bytes: &[u8]) -> Result<Item, anyhow::Error> {
+    let (item, rest) = decode_prefix(bytes)?;
+    if !rest.is_empty() {
+        bail!("Unexpected {} bytes after the RLP item", rest.len());
+    }
+    Ok(item)
+}
+
+fn decode_prefix(bytes: &[u8]) -> Result<(Item, &[u8]), anyhow::Error> {
+    let Some(&first) = bytes.first() else {
+        bail!("Unexpected end of RLP input");
+    };
+    let (is_list, offset, len) = match first {
+        0x00..=0x7f => return Ok((Item::Bytes(vec![first]), &bytes[1..])),
+        0x80..=0xb7 => (false, 1, (first - 0x80) as usize),
+        0xb8..=0xbf => {
+            let len_of_len = (first - 0xb7) as usize;
+            (false, 1 + len_of_len, read_len(bytes, len_of_len)?)
+        }
+        0xc0..=0xf7 => (true, 1, (first - 0xc0) as usize),
+        0xf8..=0xff => {
+            let len_of_len = (first - 0xf7) as usize;
+            (true, 1 + len_of_len, read_len(bytes, len_of_len)?)
+        }
+    };
+    let end = offset
+        .checked_add(len)
+        .filter(|end| *end <= bytes.len())
+        .ok_or_else(|| anyhow::anyhow!("Unexpected end of RLP input"))?;
+    let payload = &bytes[offset..end];
+    let item = if is_list {
+        let mut items = vec![];
+        let mut rest = payload;
+        while !rest.is_empty() {
+            let (item, next) = decode_prefix(rest)?;
+            items.push(item);
+            rest = next;
+        }
+        Item::List(items)
+    } else {
+        Item::Bytes(payload.to_vec())
+    };
+    Ok((item, &bytes[end..]))
+}
+
+fn read_len(bytes: &[u8], len_of_len: usize) -> Result<usize, anyhow::Error> {
+    if len_of_len > 8 || bytes.len() < 1 + len_of_len {
+        bail!("Invalid RLP length prefix");
+    }
+    Ok(bytes[1..1 + len_of_len]
+        .iter()
+        .fold(0_u64, |len, b| (len << 8) | *b as u64) as usize)
+}
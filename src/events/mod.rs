@@ -0,0 +1,146 @@
+// A buffer of server-push events for HTTP clients exposed to JS as
+// `ic.events.publish(topic, data)`.
+//
+// Clients poll `GET /events?since=<seq>` to fetch the events published after
+// the given sequence number. The endpoint is served by the `http_request`
+// query, so polling is cheap. The hash of each buffered event is certified,
+// and the response carries the certificate and a witness for the returned
+// range of events. The leaf of an event is the SHA-256 hash of its compact JSON
+// encoding with sorted keys under the path `events/<seq as 8 big-endian bytes>`.
+//
+// The buffer is kept on the heap and does not survive upgrades. Responses
+// include the epoch of the buffer, so clients detect a reset and start over.
+use std::{cell::RefCell, collections::VecDeque};
+
+use quickjs_wasm_rs::{CallbackArg, JSContextRef, JSError, JSValueRef};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    certification, engine,
+    http::{HttpRequest, HttpResponse},
+    json,
+};
+
+/// The HTTP path of the polling endpoint.
+pub const PATH: &str = "/events";
+
+// The label of the subtree of events in the certified state.
+const LABEL: &str = "events";
+
+// The maximum number of buffered events. Older events are dropped.
+const MAX_BUFFERED_EVENTS: usize = 1000;
+
+// The maximum number of events returned in one response.
+const MAX_EVENTS_PER_RESPONSE: usize = 100;
+
+struct Buffer {
+    // The time of the first publication after the canister was set up.
+    epoch: u64,
+    next_seq: u64,
+    events: VecDeque<(u64, Value)>,
+}
+
+thread_local! {
+    static BUFFER: RefCell<Buffer> = RefCell::new(Buffer {
+        epoch: 0,
+        next_seq: 1,
+        events: VecDeque::new(),
+    });
+}
+
+/// Appends an event to the buffer and returns its sequence number.
+pub fn publish(topic: String, data: Value) -> u64 {
+    BUFFER.with(|buffer| {
+        let mut buffer = buffer.borrow_mut();
+        if buffer.epoch == 0 {
+            buffer.epoch = ic_cdk::api::time();
+        }
+        let seq = buffer.next_seq;
+        buffer.next_seq += 1;
+        let event = json!({"seq": seq, "topic": topic, "data": data, "time": ic_cdk::api::time()});
+        certification::insert(LABEL, seq.to_be_bytes().to_vec(), hash(&event));
+        buffer.events.push_back((seq, event));
+        while buffer.events.len() > MAX_BUFFERED_EVENTS {
+            if let Some((seq, _)) = buffer.events.pop_front() {
+                certification::remove(LABEL, &seq.to_be_bytes());
+            }
+        }
+        seq
+    })
+}
+
+fn hash(event: &Value) -> [u8; 32] {
+    Sha256::digest(event.to_string().as_bytes()).into()
+}
+
+/// Handles a polling request. The certificate is available only when this is
+/// called in the `http_request` query.
+pub fn handle(request: &HttpRequest) -> HttpResponse {
+    if request.method.to_uppercase() != "GET" {
+        return HttpResponse::error(405, "method_not_allowed", "Events must be fetched with GET");
+    }
+    let since = match request
+        .query()
+        .get("since")
+        .map(|since| since.parse::<u64>())
+    {
+        None => 0,
+        Some(Ok(since)) => since,
+        Some(Err(_)) => {
+            return HttpResponse::error(
+                400,
+                "invalid_request",
+                "The since parameter must be a number",
+            )
+        }
+    };
+    BUFFER.with(|buffer| {
+        let buffer = buffer.borrow();
+        let events: Vec<Value> = buffer
+            .events
+            .iter()
+            .filter(|(seq, _)| *seq > since)
+            .take(MAX_EVENTS_PER_RESPONSE)
+            .map(|(_, event)| event.clone())
+            .collect();
+        let first = since.saturating_add(1);
+        let last = first.max(since.saturating_add(events.len() as u64));
+        let witness =
+            certification::range_witness(LABEL, &first.to_be_bytes(), &last.to_be_bytes());
+        let certificate = certification::certificate().ok().map(hex::encode);
+        HttpResponse::json(
+            200,
+            &json!({
+                "epoch": buffer.epoch,
+                "events": events,
+                "next": since.max(buffer.next_seq - 1),
+                "certificate": certificate,
+                "tree": hex::encode(certification::encode(&witness)),
+            }),
+        )
+    })
+}
+
+pub fn link(context: &JSContextRef) -> Result<(), anyhow::Error> {
+    // Returns the sequence number of the published event.
+    fn publish<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 2 {
+            return Err(JSError::Type(format!("Expected 2 arguments, got {}", args.len())).into());
+        }
+        engine::ensure_writable("ic.events.publish()")?;
+        let topic: String = args[0].try_into()?;
+        let data = json::to_json(&args[1].to_js_value()?);
+        context.value_from_f64(self::publish(topic, data) as f64)
+    }
+
+    let events = context.object_value()?;
+    events.set_property("publish", context.wrap_callback2(publish)?)?;
+
+    engine::namespace(context)?.set_property("events", events)?;
+    Ok(())
+}
@@ -0,0 +1,178 @@
+// A binding of the EVM RPC canister exposed to JS as `ic.evmRpc`.
+//
+// JSON-RPC requests are sent through the `request` method of the EVM RPC
+// canister, which forwards them to the configured RPC provider with HTTPS
+// outcalls. Configure it with `ic.evmRpc.configure({rpcUrl})` or
+// `ic.evmRpc.configure({providerId})` when the script is loaded.
+use std::cell::RefCell;
+
+use candid::{parser::value::IDLValue, CandidType, Deserialize, Principal};
+use quickjs_wasm_rs::{CallbackArg, JSContextRef, JSError, JSValue, JSValueRef};
+
+use crate::{call_policy, engine, jobs, json};
+
+/// The EVM RPC canister on the fiduciary subnet.
+pub const EVM_RPC_CANISTER_ID: &str = "7hfb6-caaaa-aaaar-qadga-cai";
+
+// The defaults for the cycles attached to a request and the maximum size of
+// the response. Unused cycles are refunded.
+const DEFAULT_CYCLES: u128 = 10_000_000_000;
+const DEFAULT_MAX_RESPONSE_BYTES: u64 = 8 * 1024;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct HttpHeader {
+    name: String,
+    value: String,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct RpcApi {
+    url: String,
+    headers: Option<Vec<HttpHeader>>,
+}
+
+// The subset of `RpcService` of the EVM RPC canister used here.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+enum RpcService {
+    Provider(u64),
+    Custom(RpcApi),
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+enum RequestResult {
+    Ok(String),
+    Err(IDLValue),
+}
+
+#[derive(Clone, Debug)]
+struct Config {
+    canister_id: Principal,
+    service: Option<RpcService>,
+    cycles: u128,
+    max_response_bytes: u64,
+}
+
+thread_local! {
+    static CONFIG: RefCell<Config> = RefCell::new(Config {
+        canister_id: Principal::from_text(EVM_RPC_CANISTER_ID).unwrap(),
+        service: None,
+        cycles: DEFAULT_CYCLES,
+        max_response_bytes: DEFAULT_MAX_RESPONSE_BYTES,
+    });
+}
+
+/// Sends a JSON-RPC request and returns its result.
+pub async fn request(method: &str, params: serde_json::Value) -> Result<serde_json::Value, String> {
+    let config = CONFIG.with(|config| config.borrow().clone());
+    let service = config
+        .service
+        .ok_or_else(|| "The EVM RPC service is not configured".to_string())?;
+    let payload = serde_json::json!({
+        "jsonrpc": "2.0",
+        "id": 1,
+        "method": method,
+        "params": params,
+    })
+    .to_string();
+    call_policy::check(&config.canister_id, "request")?;
+    let (result,): (RequestResult,) = ic_cdk::api::call::call_with_payment128(
+        config.canister_id,
+        "request",
+        (service, payload, config.max_response_bytes),
+        config.cycles,
+    )
+    .await
+    .map_err(|(code, message)| format!("EVM RPC call failed with code {:?}: {}", code, message))?;
+    let response = match result {
+        RequestResult::Ok(response) => response,
+        RequestResult::Err(err) => return Err(format!("{} failed: {}", method, err)),
+    };
+    let mut response: serde_json::Value = serde_json::from_str(&response)
+        .map_err(|err| format!("Invalid JSON-RPC response: {}", err))?;
+    if let Some(error) = response.get("error") {
+        return Err(format!("{} failed: {}", method, error));
+    }
+    Ok(response["result"].take())
+}
+
+fn configure(options: &JSValue) -> Result<(), anyhow::Error> {
+    let JSValue::Object(fields) = options else {
+        return Err(JSError::Type("Expected an options object".to_string()).into());
+    };
+    CONFIG.with(|config| {
+        let mut config = config.borrow_mut();
+        if let Some(JSValue::String(canister_id)) = fields.get("canisterId") {
+            config.canister_id = Principal::from_text(canister_id)?;
+        }
+        match (fields.get("rpcUrl"), fields.get("providerId")) {
+            (Some(JSValue::String(url)), None) => {
+                config.service = Some(RpcService::Custom(RpcApi {
+                    url: url.clone(),
+                    headers: None,
+                }))
+            }
+            (None, Some(id)) => {
+                config.service = Some(RpcService::Provider(jobs::number(id, "providerId")? as u64))
+            }
+            (None, None) => {}
+            _ => {
+                return Err(
+                    JSError::Type("Expected either rpcUrl or providerId".to_string()).into(),
+                )
+            }
+        }
+        if let Some(cycles) = fields.get("cycles") {
+            config.cycles = jobs::number(cycles, "cycles")? as u128;
+        }
+        if let Some(bytes) = fields.get("maxResponseBytes") {
+            config.max_response_bytes = jobs::number(bytes, "maxResponseBytes")? as u64;
+        }
+        Ok(())
+    })
+}
+
+pub fn link(context: &JSContextRef) -> Result<(), anyhow::Error> {
+    // Usage: `ic.evmRpc.configure({rpcUrl, providerId, canisterId, cycles,
+    // maxResponseBytes})`.
+    fn configure<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 1 {
+            return Err(JSError::Type(format!("Expected 1 argument, got {}", args.len())).into());
+        }
+        self::configure(&args[0].to_js_value()?)?;
+        context.undefined_value()
+    }
+
+    // Usage: `await ic.evmRpc.request('eth_blockNumber', [])`.
+    fn request<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 2 {
+            return Err(JSError::Type(format!("Expected 2 arguments, got {}", args.len())).into());
+        }
+        let method: String = args[0].try_into()?;
+        let params = json::to_json(&args[1].to_js_value()?);
+        engine::charge_call(context, CONFIG.with(|config| config.borrow().cycles))?;
+        let (id, promise) = engine::promise(context)?;
+        ic_cdk::spawn(async move {
+            let result = self::request(&method, params).await;
+            engine::settle(id, move |context| match result {
+                Ok(value) => quickjs_wasm_rs::to_qjs_value(context, &json::from_json(&value)),
+                Err(err) => Err(anyhow::anyhow!(err)),
+            });
+        });
+        Ok(promise)
+    }
+
+    let evm_rpc = context.object_value()?;
+    evm_rpc.set_property("configure", context.wrap_callback2(configure)?)?;
+    evm_rpc.set_property("request", context.wrap_callback2(request)?)?;
+
+    engine::namespace(context)?.set_property("evmRpc", evm_rpc)?;
+    Ok(())
+}
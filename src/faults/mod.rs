@@ -0,0 +1,67 @@
+// Fault injection for outgoing calls, e.g. to exercise retry and saga logic of
+// the JS script on a local replica.
+//
+// Only built with the `fault-injection` feature. Controllers configure faults
+// per target canister with `set_call_faults(target, faults)`. A faulty call is
+// rejected right away without reaching the target, the same way as a call
+// that fails to be enqueued. Latency cannot be injected because the call
+// context must stay in the same message.
+use std::{cell::RefCell, collections::HashMap};
+
+use candid::{CandidType, Deserialize, Principal};
+
+use crate::crypto;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct CallFaults {
+    /// The percentage of calls to reject from 0 to 100.
+    pub reject_percent: u8,
+    /// The reject code in the error message of a rejected call.
+    pub reject_code: u32,
+    pub reject_message: Option<String>,
+}
+
+thread_local! {
+    static FAULTS: RefCell<HashMap<Principal, CallFaults>> = RefCell::new(HashMap::new());
+
+    // Together with the time, seeds the pseudo-random choice of faulty calls.
+    static COUNTER: RefCell<u64> = RefCell::new(0);
+}
+
+pub fn set(target: Principal, faults: Option<CallFaults>) -> Result<(), String> {
+    if faults
+        .as_ref()
+        .is_some_and(|faults| faults.reject_percent > 100)
+    {
+        return Err("Expected reject_percent from 0 to 100".to_string());
+    }
+    FAULTS.with(|all| match faults {
+        Some(faults) => all.borrow_mut().insert(target, faults),
+        None => all.borrow_mut().remove(&target),
+    });
+    Ok(())
+}
+
+/// Returns the error message if a call to the given canister should fail.
+pub fn inject(target: &Principal) -> Option<String> {
+    let faults = FAULTS.with(|all| all.borrow().get(target).cloned())?;
+    let counter = COUNTER.with(|counter| {
+        let mut counter = counter.borrow_mut();
+        *counter += 1;
+        *counter
+    });
+    let mut seed = ic_cdk::api::time().to_be_bytes().to_vec();
+    seed.extend_from_slice(&counter.to_be_bytes());
+    let roll = crypto::sha256(&seed)[0] as u32 * 100 / 256;
+    if roll >= u32::from(faults.reject_percent) {
+        return None;
+    }
+    Some(format!(
+        "Injected fault: reject code {}: {}",
+        faults.reject_code,
+        faults
+            .reject_message
+            .as_deref()
+            .unwrap_or("the call was rejected")
+    ))
+}
@@ -0,0 +1,102 @@
+// Feature flags exposed to JS as `ic.flags`.
+//
+// Controllers set flags at runtime with `set_flag(name, flag)`, where a flag is
+// either a boolean or a percentage of keys for which it is enabled. JS reads
+// them synchronously with `ic.flags.isEnabled(name, key)`. A percentage flag
+// is enabled for a key if the hash of the flag name and the key falls into the
+// percentage, so the same key always gets the same answer and raising the
+// percentage only adds keys. The key defaults to the caller. Unknown flags are
+// disabled.
+//
+// Flags are stored in stable memory, so they survive upgrades and redeploys of
+// the script.
+use std::cell::RefCell;
+
+use candid::{CandidType, Deserialize};
+use ic_stable_structures::StableBTreeMap;
+use quickjs_wasm_rs::{CallbackArg, JSContextRef, JSError, JSValueRef};
+
+use crate::{
+    crypto, engine,
+    stable::{self, Candid, Memory},
+};
+
+#[derive(CandidType, Deserialize, Copy, Clone, Debug)]
+pub enum Flag {
+    Boolean(bool),
+    /// Enabled for the given percentage of keys from 0 to 100.
+    Percentage(u8),
+}
+
+thread_local! {
+    static FLAGS: RefCell<StableBTreeMap<String, Candid<Flag>, Memory>> =
+        RefCell::new(StableBTreeMap::init(stable::memory(stable::FLAGS)));
+}
+
+pub fn set(name: String, flag: Flag) -> Result<(), String> {
+    if let Flag::Percentage(percentage) = flag {
+        if percentage > 100 {
+            return Err(format!(
+                "Expected a percentage from 0 to 100, got {}",
+                percentage
+            ));
+        }
+    }
+    FLAGS.with(|flags| flags.borrow_mut().insert(name, Candid(flag)));
+    Ok(())
+}
+
+pub fn remove(name: String) {
+    FLAGS.with(|flags| flags.borrow_mut().remove(&name));
+}
+
+pub fn list() -> Vec<(String, Flag)> {
+    FLAGS.with(|flags| {
+        flags
+            .borrow()
+            .iter()
+            .map(|(name, flag)| (name, flag.0))
+            .collect()
+    })
+}
+
+/// Returns true if the flag with the given name is enabled for the given key.
+pub fn is_enabled(name: &str, key: &str) -> bool {
+    let flag = FLAGS.with(|flags| flags.borrow().get(&name.to_string()));
+    match flag.map(|flag| flag.0) {
+        None => false,
+        Some(Flag::Boolean(enabled)) => enabled,
+        Some(Flag::Percentage(percentage)) => {
+            let hash = crypto::sha256(format!("{}/{}", name, key).as_bytes());
+            let bucket = u64::from_be_bytes(hash[..8].try_into().unwrap()) % 100;
+            bucket < u64::from(percentage)
+        }
+    }
+}
+
+pub fn link(context: &JSContextRef) -> Result<(), anyhow::Error> {
+    // Usage: `ic.flags.isEnabled(name)` or `ic.flags.isEnabled(name, key)`.
+    fn is_enabled<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.is_empty() || args.len() > 2 {
+            return Err(
+                JSError::Type(format!("Expected 1 or 2 arguments, got {}", args.len())).into(),
+            );
+        }
+        let name: String = args[0].try_into()?;
+        let key: String = match args.get(1) {
+            Some(key) => key.try_into()?,
+            None => ic_cdk::caller().to_text(),
+        };
+        context.value_from_bool(self::is_enabled(&name, &key))
+    }
+
+    let flags = context.object_value()?;
+    flags.set_property("isEnabled", context.wrap_callback2(is_enabled)?)?;
+
+    engine::namespace(context)?.set_property("flags", flags)?;
+    Ok(())
+}
@@ -0,0 +1,70 @@
+// Executes GraphQL plans produced by graphql/mod.rs with the resolvers in the
+// global `graphqlResolvers` object.
+//
+// Resolvers of root fields are called as `resolver(args)` and return the value
+// of the field. Resolvers of nested fields are batched: they are called once
+// per level as `resolver(parents, args)` with all parent objects and return an
+// array with one value per parent. Fields without a resolver are read from
+// the parent object. Resolvers may return promises.
+Object.defineProperty(globalThis, "__graphqlExecute__", {
+	enumerable: false,
+	value: (function () {
+		async function resolveField(typeName, field, parents, isRoot) {
+			if (field.name === "__typename") {
+				return parents.map(() => typeName);
+			}
+			const resolvers = (globalThis.graphqlResolvers || {})[typeName] || {};
+			const resolver = resolvers[field.name];
+			if (!resolver) {
+				return parents.map((parent) => parent == null ? null : parent[field.name]);
+			}
+			if (isRoot) {
+				return [await resolver(field.args)];
+			}
+			const values = await resolver(parents, field.args);
+			if (!Array.isArray(values) || values.length !== parents.length) {
+				throw new Error(`Resolver ${typeName}.${field.name} must return one value per parent`);
+			}
+			return Promise.all(values);
+		}
+
+		// Returns one result object per parent.
+		async function executeSelection(selection, parents, isRoot) {
+			const results = parents.map(() => ({}));
+			for (const field of selection.fields) {
+				let values = await resolveField(selection.type, field, parents, isRoot);
+				if (field.selection) {
+					// Resolve the children of all parents in one batch.
+					const children = [];
+					for (const value of values) {
+						if (value == null) continue;
+						if (field.list) children.push(...value); else children.push(value);
+					}
+					const childResults = await executeSelection(field.selection, children, false);
+					let index = 0;
+					values = values.map((value) => {
+						if (value == null) return null;
+						if (field.list) return value.map(() => childResults[index++]);
+						return childResults[index++];
+					});
+				}
+				values.forEach((value, i) => results[i][field.alias] = value === undefined ? null : value);
+			}
+			return results;
+		}
+
+		async function execute(plan) {
+			try {
+				const [data] = await executeSelection(plan, [{}], true);
+				return { data };
+			} catch (e) {
+				return { data: null, errors: [{ message: String(e && e.message || e) }] };
+			}
+		}
+
+		// Executes a single plan or a batch of plans.
+		return function (plans) {
+			return Array.isArray(plans) ? Promise.all(plans.map(execute)) : execute(plans);
+		};
+	})()
+});
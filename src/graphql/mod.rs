@@ -0,0 +1,363 @@
+// A GraphQL API served at `/graphql`.
+//
+// The script defines the schema in SDL with `ic.graphql.define(sdl)` and the
+// resolvers in the global `graphqlResolvers` object, e.g.
+// `{Query: {user: (args) => ...}, User: {posts: (users, args) => ...}}`.
+// Rust parses and validates queries against the schema, resolves fragments,
+// directives and variables and produces an execution plan. The plan is
+// executed by graphql.js, which calls the resolvers of nested fields in
+// batches, one call per field and level. A POST body may also contain an array
+// of requests, which are executed in one message.
+use std::{cell::RefCell, collections::HashMap};
+
+use graphql_parser::{
+    query::{self, Definition, OperationDefinition, Selection, SelectionSet},
+    schema::{self, Type, TypeDefinition},
+};
+use ic_cdk::api::call::ManualReply;
+use quickjs_wasm_rs::{CallbackArg, JSContextRef, JSError, JSValueRef};
+use serde_json::{json, Map, Value};
+
+use crate::{
+    engine,
+    http::{HttpRequest, HttpResponse},
+    json, redaction,
+};
+
+/// The HTTP path of the GraphQL endpoint.
+pub const PATH: &str = "/graphql";
+
+// The executor of plans defined in graphql.js.
+const EXECUTOR_FILE: &str = "graphql.js";
+const EXECUTOR_SCRIPT: &str = include_str!("graphql.js");
+const EXECUTE: &str = "__graphqlExecute__";
+
+// The type of a field: the name of the named type and whether it is a list.
+#[derive(Clone, Debug)]
+struct FieldType {
+    name: String,
+    is_list: bool,
+}
+
+// Maps object type names to their fields.
+type Schema = HashMap<String, HashMap<String, FieldType>>;
+
+thread_local! {
+    static SCHEMA: RefCell<Option<Schema>> = RefCell::new(None);
+}
+
+pub fn is_defined() -> bool {
+    SCHEMA.with(|schema| schema.borrow().is_some())
+}
+
+pub fn define(sdl: &str) -> Result<(), anyhow::Error> {
+    let document = schema::parse_schema::<String>(sdl)?;
+    let mut types = Schema::new();
+    for definition in document.definitions {
+        let (name, fields) = match definition {
+            schema::Definition::TypeDefinition(TypeDefinition::Object(object)) => {
+                (object.name, object.fields)
+            }
+            schema::Definition::TypeDefinition(TypeDefinition::Interface(interface)) => {
+                (interface.name, interface.fields)
+            }
+            _ => continue,
+        };
+        let fields = fields
+            .into_iter()
+            .map(|field| (field.name, field_type(&field.field_type)))
+            .collect();
+        types.insert(name, fields);
+    }
+    if !types.contains_key("Query") {
+        anyhow::bail!("The GraphQL schema must define the Query type");
+    }
+    SCHEMA.with(|schema| *schema.borrow_mut() = Some(types));
+    Ok(())
+}
+
+fn field_type(field_type: &Type<String>) -> FieldType {
+    match field_type {
+        Type::NamedType(name) => FieldType {
+            name: name.clone(),
+            is_list: false,
+        },
+        Type::ListType(inner) => FieldType {
+            is_list: true,
+            ..field_type(inner)
+        },
+        Type::NonNullType(inner) => field_type(inner),
+    }
+}
+
+/// Handles a GraphQL request over HTTP.
+pub fn handle(request: &HttpRequest) -> ManualReply<HttpResponse> {
+    let body = match request.method.to_uppercase().as_str() {
+        "GET" => {
+            let query = request.query();
+            let variables = query
+                .get("variables")
+                .and_then(|variables| serde_json::from_str(variables).ok())
+                .unwrap_or(Value::Null);
+            Ok(json!({
+                "query": query.get("query"),
+                "variables": variables,
+                "operationName": query.get("operationName"),
+            }))
+        }
+        "POST" => serde_json::from_slice::<Value>(&request.body).map_err(|err| err.to_string()),
+        _ => Err("GraphQL requests must use GET or POST".to_string()),
+    };
+    let plans = body.and_then(|body| match body {
+        Value::Array(requests) => requests
+            .iter()
+            .map(plan_request)
+            .collect::<Result<Vec<_>, _>>()
+            .map(Value::Array),
+        body => plan_request(&body),
+    });
+    let plans = match plans {
+        Ok(plans) => plans,
+        Err(err) => {
+            return ManualReply::one(HttpResponse::json(
+                400,
+                &json!({"errors": [{"message": err}]}),
+            ));
+        }
+    };
+    engine::execute(
+        EXECUTE,
+        move |context| {
+            Ok(vec![quickjs_wasm_rs::to_qjs_value(
+                context,
+                &json::from_json(&plans),
+            )?])
+        },
+        |_context, result| {
+            let result = result
+                .and_then(|value| Ok(json::to_json(&quickjs_wasm_rs::from_qjs_value(&value)?)));
+            match result {
+                Ok(result) => ManualReply::one(HttpResponse::json(200, &result)),
+                Err(err) => ManualReply::one(HttpResponse::json(
+                    500,
+                    &json!({"data": null, "errors": [{"message": redaction::redact(&err.to_string())}]}),
+                )),
+            }
+        },
+    )
+}
+
+// Produces the execution plan of a request `{query, variables, operationName}`.
+fn plan_request(request: &Value) -> Result<Value, String> {
+    let query = request["query"]
+        .as_str()
+        .ok_or_else(|| "The request must contain a query".to_string())?;
+    let variables = request["variables"]
+        .as_object()
+        .cloned()
+        .unwrap_or_default();
+    let operation_name = request["operationName"].as_str();
+    let document = query::parse_query::<String>(query).map_err(|err| err.to_string())?;
+
+    let mut fragments = HashMap::new();
+    let mut operations = vec![];
+    for definition in document.definitions.iter() {
+        match definition {
+            Definition::Fragment(fragment) => {
+                fragments.insert(fragment.name.clone(), &fragment.selection_set);
+            }
+            Definition::Operation(operation) => operations.push(operation),
+        }
+    }
+    let operation = match operation_name {
+        Some(name) => operations
+            .into_iter()
+            .find(|operation| self::operation_name(operation) == Some(name))
+            .ok_or_else(|| format!("Unknown operation {}", name))?,
+        None if operations.len() == 1 => operations[0],
+        None => {
+            return Err(
+                "The operationName is required for documents with many operations".to_string(),
+            )
+        }
+    };
+    let (root, selection_set) = match operation {
+        OperationDefinition::SelectionSet(selection_set) => ("Query", selection_set),
+        OperationDefinition::Query(query) => ("Query", &query.selection_set),
+        OperationDefinition::Mutation(mutation) => ("Mutation", &mutation.selection_set),
+        OperationDefinition::Subscription(_) => {
+            return Err("Subscriptions are not supported".to_string())
+        }
+    };
+    SCHEMA.with(|schema| {
+        let schema = schema.borrow();
+        let schema = schema
+            .as_ref()
+            .ok_or_else(|| "The GraphQL schema is not defined".to_string())?;
+        let planner = Planner {
+            schema,
+            fragments: &fragments,
+            variables: &variables,
+        };
+        planner.plan(root, selection_set)
+    })
+}
+
+fn operation_name<'a>(operation: &'a OperationDefinition<String>) -> Option<&'a str> {
+    match operation {
+        OperationDefinition::SelectionSet(_) => None,
+        OperationDefinition::Query(query) => query.name.as_deref(),
+        OperationDefinition::Mutation(mutation) => mutation.name.as_deref(),
+        OperationDefinition::Subscription(subscription) => subscription.name.as_deref(),
+    }
+}
+
+struct Planner<'a> {
+    schema: &'a Schema,
+    fragments: &'a HashMap<String, &'a SelectionSet<'a, String>>,
+    variables: &'a Map<String, Value>,
+}
+
+impl<'a> Planner<'a> {
+    // Returns `{type, fields: [{alias, name, args, list, selection}]}`.
+    fn plan(&self, type_name: &str, selection_set: &SelectionSet<String>) -> Result<Value, String> {
+        let fields = self
+            .schema
+            .get(type_name)
+            .ok_or_else(|| format!("Unknown type {}", type_name))?;
+        let mut planned = vec![];
+        self.plan_fields(type_name, fields, selection_set, &mut planned)?;
+        Ok(json!({"type": type_name, "fields": planned}))
+    }
+
+    fn plan_fields(
+        &self,
+        type_name: &str,
+        fields: &HashMap<String, FieldType>,
+        selection_set: &SelectionSet<String>,
+        planned: &mut Vec<Value>,
+    ) -> Result<(), String> {
+        for selection in selection_set.items.iter() {
+            match selection {
+                Selection::Field(field) => {
+                    if !self.is_included(&field.directives)? {
+                        continue;
+                    }
+                    let alias = field.alias.as_ref().unwrap_or(&field.name);
+                    if field.name == "__typename" {
+                        planned.push(json!({"alias": alias, "name": field.name, "args": {}}));
+                        continue;
+                    }
+                    let field_type = fields
+                        .get(&field.name)
+                        .ok_or_else(|| format!("Unknown field {}.{}", type_name, field.name))?;
+                    let args: Map<String, Value> = field
+                        .arguments
+                        .iter()
+                        .map(|(name, value)| Ok((name.clone(), self.value(value)?)))
+                        .collect::<Result<_, String>>()?;
+                    let selection = if field.selection_set.items.is_empty() {
+                        Value::Null
+                    } else {
+                        self.plan(&field_type.name, &field.selection_set)?
+                    };
+                    planned.push(json!({
+                        "alias": alias,
+                        "name": field.name,
+                        "args": args,
+                        "list": field_type.is_list,
+                        "selection": selection,
+                    }));
+                }
+                Selection::FragmentSpread(spread) => {
+                    if !self.is_included(&spread.directives)? {
+                        continue;
+                    }
+                    let fragment = self
+                        .fragments
+                        .get(&spread.fragment_name)
+                        .ok_or_else(|| format!("Unknown fragment {}", spread.fragment_name))?;
+                    self.plan_fields(type_name, fields, fragment, planned)?;
+                }
+                Selection::InlineFragment(fragment) => {
+                    if !self.is_included(&fragment.directives)? {
+                        continue;
+                    }
+                    self.plan_fields(type_name, fields, &fragment.selection_set, planned)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Evaluates the `@skip(if:)` and `@include(if:)` directives.
+    fn is_included(&self, directives: &[query::Directive<String>]) -> Result<bool, String> {
+        for directive in directives {
+            let condition = directive
+                .arguments
+                .iter()
+                .find(|(name, _)| name == "if")
+                .map(|(_, value)| self.value(value))
+                .transpose()?;
+            match (directive.name.as_str(), condition) {
+                ("skip", Some(Value::Bool(true))) | ("include", Some(Value::Bool(false))) => {
+                    return Ok(false)
+                }
+                _ => {}
+            }
+        }
+        Ok(true)
+    }
+
+    fn value(&self, value: &query::Value<String>) -> Result<Value, String> {
+        Ok(match value {
+            query::Value::Variable(name) => {
+                self.variables.get(name).cloned().unwrap_or(Value::Null)
+            }
+            query::Value::Int(number) => number
+                .as_i64()
+                .map(Value::from)
+                .ok_or_else(|| "Invalid integer".to_string())?,
+            query::Value::Float(number) => json!(number),
+            query::Value::String(string) => Value::String(string.clone()),
+            query::Value::Boolean(boolean) => Value::Bool(*boolean),
+            query::Value::Null => Value::Null,
+            query::Value::Enum(name) => Value::String(name.clone()),
+            query::Value::List(values) => Value::Array(
+                values
+                    .iter()
+                    .map(|value| self.value(value))
+                    .collect::<Result<_, _>>()?,
+            ),
+            query::Value::Object(fields) => Value::Object(
+                fields
+                    .iter()
+                    .map(|(name, value)| Ok((name.clone(), self.value(value)?)))
+                    .collect::<Result<_, String>>()?,
+            ),
+        })
+    }
+}
+
+pub fn link(context: &JSContextRef) -> Result<(), anyhow::Error> {
+    fn define<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 1 {
+            return Err(JSError::Type(format!("Expected 1 argument, got {}", args.len())).into());
+        }
+        let sdl: String = args[0].try_into()?;
+        self::define(&sdl)?;
+        context.undefined_value()
+    }
+
+    context.eval_global(EXECUTOR_FILE, EXECUTOR_SCRIPT)?;
+
+    let graphql = context.object_value()?;
+    graphql.set_property("define", context.wrap_callback2(define)?)?;
+
+    engine::namespace(context)?.set_property("graphql", graphql)?;
+    Ok(())
+}
@@ -0,0 +1,538 @@
+// Serving HTTP requests with JS.
+//
+// The HTTP gateway calls the `http_request` query first. Responses of JS
+// handlers are not certified, so the query asks the gateway to upgrade the
+// request to the `http_request_update` update call. The update call validates
+// the request and runs the global `httpRequest(request)` JS function.
+//
+// JSON-RPC requests to `/rpc` that call only query endpoints, polling
+// requests to `/events` and `GET /metrics` are answered by the query directly.
+//
+// The request object is `{method, url, path, query, headers, body}` where
+// header names are lowercase and the body is an ArrayBuffer. The handler
+// returns `{statusCode, headers, body}` where the body is a string, an
+// ArrayBuffer or a JSON-serializable value. The request also carries the
+// `identity` of the sender resolved from its bearer token (see auth).
+//
+// Bodies larger than a reply can hold are streamed: the response carries the
+// first chunk and a callback strategy, and the gateway fetches the other
+// chunks with the `http_request_streaming_callback` query. The update call
+// keeps the chunks on the heap because queries cannot store state; streams
+// older than `STREAM_TTL` are dropped when the next stream starts, and the
+// oldest streams are dropped when the chunks exceed `MAX_STREAMED_BYTES`.
+// The callback is a public query, so the tokens carry an HMAC of the stream id
+// with a random secret and other callers cannot fetch the chunks.
+use std::{cell::RefCell, collections::HashMap, time::Duration};
+
+use candid::{CandidType, Deserialize, Func};
+use hmac::{Hmac, Mac};
+use ic_cdk::api::call::ManualReply;
+use quickjs_wasm_rs::{JSContextRef, JSValue, JSValueRef};
+use serde_json::{json, Value};
+use sha2::Sha256;
+
+#[cfg(feature = "dev-repl")]
+use crate::dev;
+use crate::{
+    auth::{self, Identity},
+    call_metrics, compress, engine, events, graphql, json, jsonrpc, quotas, redaction, schemas,
+    system_api::{self, Level},
+};
+
+/// The name of the JS function that handles HTTP requests.
+pub const HTTP_REQUEST: &str = "httpRequest";
+
+// Responses smaller than this are not compressed.
+const MIN_COMPRESSED_SIZE: usize = 1024;
+
+/// The query that returns the chunks of streamed bodies.
+pub const STREAMING_CALLBACK: &str = "http_request_streaming_callback";
+
+// The size of the chunks of streamed bodies, well below the limit of a reply.
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+// The time in nanoseconds that the chunks of a stream are kept.
+const STREAM_TTL: u64 = 10 * 60 * 1_000_000_000;
+
+// The maximum total size of the chunks kept for streaming.
+const MAX_STREAMED_BYTES: usize = 64 * 1024 * 1024;
+
+struct Stream {
+    chunks: Vec<Vec<u8>>,
+    created_at: u64,
+}
+
+impl Stream {
+    fn size(&self) -> usize {
+        self.chunks.iter().map(|chunk| chunk.len()).sum()
+    }
+}
+
+thread_local! {
+    static STREAMS: RefCell<HashMap<u64, Stream>> = RefCell::new(HashMap::new());
+    static NEXT_STREAM_ID: RefCell<u64> = RefCell::new(0);
+
+    // The secret key of the signatures of stream tokens. Empty until
+    // `raw_rand` returns after the installation or upgrade.
+    static STREAM_SECRET: RefCell<Vec<u8>> = RefCell::new(vec![]);
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct HttpRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct HttpResponse {
+    pub status_code: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    pub upgrade: Option<bool>,
+    pub streaming_strategy: Option<StreamingStrategy>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct StreamingToken {
+    pub id: u64,
+    pub index: u64,
+    /// The HMAC-SHA256 of the id.
+    pub signature: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum StreamingStrategy {
+    Callback {
+        callback: Func,
+        token: StreamingToken,
+    },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct StreamingCallbackHttpResponse {
+    pub body: Vec<u8>,
+    pub token: Option<StreamingToken>,
+}
+
+impl HttpRequest {
+    pub fn path(&self) -> &str {
+        self.url.split('?').next().unwrap_or_default()
+    }
+
+    /// Returns the decoded query parameters.
+    pub fn query(&self) -> HashMap<String, String> {
+        let Some((_, query)) = self.url.split_once('?') else {
+            return HashMap::new();
+        };
+        query
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+                (percent_decode(name), percent_decode(value))
+            })
+            .collect()
+    }
+
+    /// Returns the value of the given header. Header names are case-insensitive.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Returns the route of the request, e.g. `POST /orders`.
+    pub fn route(&self) -> String {
+        format!("{} {}", self.method.to_uppercase(), self.path())
+    }
+}
+
+impl HttpResponse {
+    pub fn json(status_code: u16, body: &Value) -> Self {
+        Self {
+            status_code,
+            headers: vec![("content-type".to_string(), "application/json".to_string())],
+            body: body.to_string().into_bytes(),
+            upgrade: None,
+            streaming_strategy: None,
+        }
+    }
+
+    /// A response to the `http_request` query that asks the gateway to retry
+    /// the request as an update call.
+    pub fn upgrade() -> Self {
+        Self {
+            status_code: 200,
+            headers: vec![],
+            body: vec![],
+            upgrade: Some(true),
+            streaming_strategy: None,
+        }
+    }
+
+    pub fn error(status_code: u16, error: &str, message: &str) -> Self {
+        Self::json(status_code, &json!({"error": error, "message": message}))
+    }
+}
+
+fn percent_decode(input: &str) -> String {
+    let input = input.replace('+', " ");
+    let bytes = input.as_bytes();
+    let mut result = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let byte = std::str::from_utf8(&bytes[i + 1..i + 3])
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+            if let Some(byte) = byte {
+                result.push(byte);
+                i += 3;
+                continue;
+            }
+        }
+        result.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&result).into_owned()
+}
+
+/// Handles the `http_request` query.
+pub fn handle_query(request: HttpRequest) -> ManualReply<HttpResponse> {
+    #[cfg(feature = "dev-repl")]
+    if request.method.eq_ignore_ascii_case("GET") && request.path() == dev::PATH {
+        return ManualReply::one(dev::page());
+    }
+    let is_events = request.path() == events::PATH;
+    let is_metrics =
+        request.method.eq_ignore_ascii_case("GET") && request.path() == call_metrics::PATH;
+    let is_read_only_rpc = request.path() == jsonrpc::PATH && jsonrpc::is_read_only(&request);
+    if (!is_events && !is_metrics && !is_read_only_rpc) || quotas::is_limited(&request.route()) {
+        return ManualReply::one(HttpResponse::upgrade());
+    }
+    let identity = match authenticate(&request) {
+        Ok(identity) => identity,
+        Err(response) => return ManualReply::one(response),
+    };
+    if is_events {
+        return ManualReply::one(events::handle(&request));
+    }
+    if is_metrics {
+        return ManualReply::one(HttpResponse {
+            status_code: 200,
+            headers: vec![(
+                "content-type".to_string(),
+                "text/plain; version=0.0.4".to_string(),
+            )],
+            body: call_metrics::prometheus().into_bytes(),
+            upgrade: None,
+            streaming_strategy: None,
+        });
+    }
+    jsonrpc::handle(&request, &identity)
+}
+
+/// Handles the `http_request_update` update call.
+pub fn handle_update(request: HttpRequest) -> ManualReply<HttpResponse> {
+    let identity = match authenticate(&request) {
+        Ok(identity) => identity,
+        Err(response) => return ManualReply::one(response),
+    };
+    if let Err(err) = quotas::consume(&request.route(), &identity) {
+        let mut response = HttpResponse::error(429, "too_many_requests", &err.to_string());
+        response
+            .headers
+            .push(("retry-after".to_string(), err.retry_after.to_string()));
+        return ManualReply::one(response);
+    }
+    if let Err(response) = validate(&request) {
+        return ManualReply::one(response);
+    }
+    if request.path() == graphql::PATH && graphql::is_defined() {
+        return graphql::handle(&request);
+    }
+    if request.path() == events::PATH {
+        return ManualReply::one(events::handle(&request));
+    }
+    if request.path() == jsonrpc::PATH {
+        return jsonrpc::handle(&request, &identity);
+    }
+    #[cfg(feature = "dev-repl")]
+    if dev::is_dev_path(request.path()) {
+        return ManualReply::one(dev::handle(&request, &identity));
+    }
+    if !engine::is_function_defined(HTTP_REQUEST) {
+        return ManualReply::one(HttpResponse::error(
+            404,
+            "not_found",
+            "No HTTP handler is defined",
+        ));
+    }
+    let accepts_gzip = request
+        .header("accept-encoding")
+        .is_some_and(|encodings| encodings.contains("gzip"));
+    engine::execute(
+        HTTP_REQUEST,
+        move |context| Ok(vec![to_js_request(context, &request, &identity)?]),
+        move |_context, result| {
+            let response = result.and_then(|value| from_js_response(&value));
+            match response {
+                Ok(response) if accepts_gzip => ManualReply::one(stream(gzip(response))),
+                Ok(response) => ManualReply::one(stream(response)),
+                Err(err) => ManualReply::one(HttpResponse::error(
+                    500,
+                    "internal_error",
+                    &redaction::redact(&err.to_string()),
+                )),
+            }
+        },
+    )
+}
+
+// Resolves the identity of the sender and rejects invalid tokens and anonymous
+// senders of routes that require authentication with a 401 response.
+fn authenticate(request: &HttpRequest) -> Result<Identity, HttpResponse> {
+    let identity = auth::middleware::http_identity(request.header("authorization"))
+        .map_err(|err| HttpResponse::error(401, "unauthorized", &err))?;
+    auth::middleware::check(&request.route(), &identity)
+        .map_err(|err| HttpResponse::error(401, "unauthorized", &err))?;
+    Ok(identity)
+}
+
+// Rejects requests whose JSON body does not match the schema of their route
+// with a structured 400 response.
+fn validate(request: &HttpRequest) -> Result<(), HttpResponse> {
+    schemas::check_body(&request.route(), &request.body).map_err(|issues| {
+        let issues: Vec<_> = issues
+            .into_iter()
+            .map(|issue| json!({"path": issue.path, "message": issue.message}))
+            .collect();
+        HttpResponse::json(400, &json!({"error": "invalid_request", "issues": issues}))
+    })
+}
+
+fn gzip(mut response: HttpResponse) -> HttpResponse {
+    let is_encoded = response
+        .headers
+        .iter()
+        .any(|(name, _)| name.eq_ignore_ascii_case("content-encoding"));
+    if is_encoded || response.body.len() < MIN_COMPRESSED_SIZE {
+        return response;
+    }
+    response.body = compress::gzip(&response.body);
+    response
+        .headers
+        .push(("content-encoding".to_string(), "gzip".to_string()));
+    response
+}
+
+/// Fetches the secret key of the stream tokens. Must be called after the
+/// installation and upgrades.
+pub fn init() {
+    ic_cdk_timers::set_timer(Duration::ZERO, || {
+        ic_cdk::spawn(async {
+            match ic_cdk::api::management_canister::main::raw_rand().await {
+                Ok((secret,)) => STREAM_SECRET.with(|cell| *cell.borrow_mut() = secret),
+                Err((code, message)) => {
+                    system_api::log(
+                        Level::Error,
+                        Some("http"),
+                        &[format!("raw_rand failed with code {:?}: {}", code, message).into()],
+                    );
+                    init();
+                }
+            }
+        })
+    });
+}
+
+// Returns the HMAC of the stream id or `None` before the secret is known.
+fn sign(id: u64) -> Option<Hmac<Sha256>> {
+    STREAM_SECRET.with(|secret| {
+        let secret = secret.borrow();
+        if secret.is_empty() {
+            return None;
+        }
+        let mut mac = Hmac::<Sha256>::new_from_slice(&secret).unwrap();
+        mac.update(&id.to_be_bytes());
+        Some(mac)
+    })
+}
+
+// Keeps all but the first chunk of a large body for the streaming callback.
+fn stream(mut response: HttpResponse) -> HttpResponse {
+    if response.body.len() <= CHUNK_SIZE {
+        return response;
+    }
+    if response.body.len() - CHUNK_SIZE > MAX_STREAMED_BYTES {
+        return HttpResponse::error(
+            500,
+            "internal_error",
+            "The response is too large to be streamed",
+        );
+    }
+    let now = ic_cdk::api::time();
+    let mut chunks: Vec<Vec<u8>> = response
+        .body
+        .chunks(CHUNK_SIZE)
+        .map(|chunk| chunk.to_vec())
+        .collect();
+    response.body = chunks.remove(0);
+    let id = NEXT_STREAM_ID.with(|next| {
+        let mut next = next.borrow_mut();
+        *next += 1;
+        *next
+    });
+    let Some(mac) = sign(id) else {
+        let mut response =
+            HttpResponse::error(503, "unavailable", "The canister is starting, retry later");
+        response
+            .headers
+            .push(("retry-after".to_string(), "1".to_string()));
+        return response;
+    };
+    let size: usize = chunks.iter().map(|chunk| chunk.len()).sum();
+    STREAMS.with(|streams| {
+        let mut streams = streams.borrow_mut();
+        streams.retain(|_, stream| now.saturating_sub(stream.created_at) < STREAM_TTL);
+        let mut total: usize = streams.values().map(Stream::size).sum();
+        while total + size > MAX_STREAMED_BYTES {
+            let oldest = streams
+                .iter()
+                .min_by_key(|(id, stream)| (stream.created_at, **id))
+                .map(|(id, _)| *id)
+                .unwrap();
+            total -= streams.remove(&oldest).unwrap().size();
+        }
+        streams.insert(
+            id,
+            Stream {
+                chunks,
+                created_at: now,
+            },
+        );
+    });
+    response.streaming_strategy = Some(StreamingStrategy::Callback {
+        callback: Func {
+            principal: ic_cdk::id(),
+            method: STREAMING_CALLBACK.to_string(),
+        },
+        token: StreamingToken {
+            id,
+            index: 0,
+            signature: mac.finalize().into_bytes().to_vec(),
+        },
+    });
+    response
+}
+
+/// Handles the `http_request_streaming_callback` query.
+pub fn streaming_callback(token: StreamingToken) -> Result<StreamingCallbackHttpResponse, String> {
+    let is_valid = sign(token.id).is_some_and(|mac| mac.verify_slice(&token.signature).is_ok());
+    if !is_valid {
+        return Err("Invalid streaming token".to_string());
+    }
+    STREAMS.with(|streams| {
+        let streams = streams.borrow();
+        let stream = streams
+            .get(&token.id)
+            .ok_or_else(|| format!("The stream {} has expired", token.id))?;
+        let body = stream
+            .chunks
+            .get(token.index as usize)
+            .ok_or_else(|| format!("The stream {} has no chunk {}", token.id, token.index))?
+            .clone();
+        let next = token.index + 1;
+        Ok(StreamingCallbackHttpResponse {
+            body,
+            token: (next < stream.chunks.len() as u64).then_some(StreamingToken {
+                id: token.id,
+                index: next,
+                signature: token.signature,
+            }),
+        })
+    })
+}
+
+fn to_js_request<'a>(
+    context: &'a JSContextRef,
+    request: &HttpRequest,
+    identity: &Identity,
+) -> Result<JSValueRef<'a>, anyhow::Error> {
+    let js = context.object_value()?;
+    js.set_property("method", context.value_from_str(&request.method)?)?;
+    js.set_property("url", context.value_from_str(&request.url)?)?;
+    js.set_property("path", context.value_from_str(request.path())?)?;
+    let query = context.object_value()?;
+    for (name, value) in request.query() {
+        query.set_property(name.as_str(), context.value_from_str(&value)?)?;
+    }
+    js.set_property("query", query)?;
+    let headers = context.object_value()?;
+    for (name, value) in request.headers.iter() {
+        headers.set_property(name.to_lowercase().as_str(), context.value_from_str(value)?)?;
+    }
+    js.set_property("headers", headers)?;
+    js.set_property("body", context.array_buffer_value(&request.body)?)?;
+    // Parsing in Rust is much cheaper than `JSON.parse()` in JS.
+    let is_json = request
+        .header("content-type")
+        .is_some_and(|content_type| content_type.contains("json"));
+    if is_json {
+        if let Ok(body) = json::parse(context, &request.body) {
+            js.set_property("json", body)?;
+        }
+    }
+    js.set_property("identity", identity.to_js(context)?)?;
+    Ok(js)
+}
+
+fn from_js_response(value: &JSValueRef) -> Result<HttpResponse, anyhow::Error> {
+    let fields = match quickjs_wasm_rs::from_qjs_value(value)? {
+        JSValue::Object(fields) => fields,
+        JSValue::String(body) => HashMap::from([("body".to_string(), JSValue::String(body))]),
+        _ => anyhow::bail!("Expected {}() to return a response object", HTTP_REQUEST),
+    };
+    let status_code = match fields.get("statusCode") {
+        Some(JSValue::Int(code)) => u16::try_from(*code)?,
+        Some(JSValue::Float(code)) => *code as u16,
+        Some(_) => anyhow::bail!("Expected statusCode to be a number"),
+        None => 200,
+    };
+    let mut headers = vec![];
+    if let Some(JSValue::Object(fields)) = fields.get("headers") {
+        for (name, value) in fields {
+            match value {
+                JSValue::String(value) => headers.push((name.clone(), value.clone())),
+                _ => anyhow::bail!("Expected the value of header {} to be a string", name),
+            }
+        }
+    }
+    let (body, content_type) = match fields.get("body") {
+        None | Some(JSValue::Undefined | JSValue::Null) => (vec![], None),
+        Some(JSValue::String(body)) => {
+            (body.clone().into_bytes(), Some("text/plain; charset=utf-8"))
+        }
+        Some(JSValue::ArrayBuffer(body)) => (body.clone(), Some("application/octet-stream")),
+        Some(body) => (
+            json::to_json(body).to_string().into_bytes(),
+            Some("application/json"),
+        ),
+    };
+    let has_content_type = headers
+        .iter()
+        .any(|(name, _)| name.eq_ignore_ascii_case("content-type"));
+    if let (Some(content_type), false) = (content_type, has_content_type) {
+        headers.push(("content-type".to_string(), content_type.to_string()));
+    }
+    Ok(HttpResponse {
+        status_code,
+        headers,
+        body,
+        upgrade: None,
+        streaming_strategy: None,
+    })
+}
@@ -0,0 +1,457 @@
+// HTTPS outcalls exposed to JS as `fetch(url, options)` and the preparation
+// of outcalls as `ic.outcalls.prepare(url, options)`.
+//
+// `fetch()` sends the prepared request with the `http_request` method of the
+// management canister and resolves with `{status, headers, body}` where the
+// headers are `[name, value]` pairs and the body is an ArrayBuffer. Only GET,
+// HEAD and POST are supported. The outcall is paid with the estimated cycles
+// for a subnet of `subnetSize` nodes unless `cycles` is given; unused cycles
+// are refunded.
+//
+// Every node of the subnet performs an outcall, so a request that changes
+// state on the server, e.g. a payment, reaches it several times. Servers that
+// honor the `Idempotency-Key` header apply such requests once if all copies
+// carry the same key. `{idempotencyKey: true}` derives a key from the request
+// and a counter, which is the same on all nodes because they execute the same
+// messages. A string is used as the key as is.
+//
+// The helpers also normalize the request so that its cost is predictable:
+// - The URL must use HTTPS without credentials. IPv6 literals are rewritten in
+//   their canonical form and loopback, private and link-local addresses are
+//   rejected because the nodes cannot reach them.
+// - Header names are lowercased, values are trimmed and later duplicates
+//   replace earlier ones. Hop-by-hop headers and `host` are dropped because
+//   the nodes set them. Values with line breaks are rejected.
+// - `maxResponseBytes` defaults to 64KiB instead of the 2MB of the system API
+//   because the outcall is charged for it. The estimated cycles for a subnet
+//   of `subnetSize` nodes are returned as `cycles`.
+// - `transform` selects the transforms of transforms.rs that make the
+//   responses of the nodes equal.
+use std::{
+    cell::Cell,
+    net::{Ipv4Addr, Ipv6Addr},
+};
+
+use candid::{decode_args, encode_args, Principal};
+use ic_cdk::api::management_canister::http_request::{
+    CanisterHttpRequestArgument, HttpHeader, HttpMethod, HttpResponse,
+};
+use quickjs_wasm_rs::{CallbackArg, JSContextRef, JSError, JSValue, JSValueRef};
+
+use crate::{costs, crypto, engine, jobs};
+
+pub mod transforms;
+
+/// The default limit of the response size of an outcall.
+pub const DEFAULT_MAX_RESPONSE_BYTES: u64 = 64 * 1024;
+
+// The subnet size for the cycles estimate if the options do not set one.
+const DEFAULT_SUBNET_SIZE: u64 = 13;
+
+const IDEMPOTENCY_KEY: &str = "idempotency-key";
+
+// Headers that apply to a single connection and are set by the nodes.
+const DROPPED_HEADERS: &[&str] = &[
+    "connection",
+    "content-length",
+    "host",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+thread_local! {
+    // Makes the derived idempotency keys of identical requests distinct.
+    static NEXT_IDEMPOTENCY_NONCE: Cell<u64> = Cell::new(0);
+}
+
+/// A normalized outcall.
+pub struct Outcall {
+    pub url: String,
+    pub method: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    pub max_response_bytes: u64,
+    pub transforms: Vec<transforms::Transform>,
+}
+
+/// Checks the URL of an outcall and returns it with a canonical host.
+pub fn normalize_url(url: &str) -> Result<String, String> {
+    let rest = url
+        .strip_prefix("https://")
+        .ok_or_else(|| format!("Expected an https:// URL, got {}", url))?;
+    let end = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+    let (authority, path) = rest.split_at(end);
+    if authority.contains('@') {
+        return Err("URLs with credentials are not allowed".to_string());
+    }
+    let (host, port) = if let Some(literal) = authority.strip_prefix('[') {
+        let (address, port) = literal
+            .split_once(']')
+            .ok_or_else(|| format!("Invalid IPv6 address in {}", url))?;
+        let address: Ipv6Addr = address
+            .parse()
+            .map_err(|_| format!("Invalid IPv6 address in {}", url))?;
+        if address.is_loopback() || address.is_unspecified() || is_local_ipv6(&address) {
+            return Err(format!(
+                "The address {} is not reachable by outcalls",
+                address
+            ));
+        }
+        (format!("[{}]", address), port)
+    } else {
+        let (host, port) = match authority.find(':') {
+            Some(i) => authority.split_at(i),
+            None => (authority, ""),
+        };
+        if host.is_empty() {
+            return Err(format!("The URL {} has no host", url));
+        }
+        if let Ok(address) = host.parse::<Ipv4Addr>() {
+            if address.is_loopback()
+                || address.is_private()
+                || address.is_link_local()
+                || address.is_unspecified()
+            {
+                return Err(format!(
+                    "The address {} is not reachable by outcalls",
+                    address
+                ));
+            }
+        }
+        (host.to_ascii_lowercase(), port)
+    };
+    if !port.is_empty() && port[1..].parse::<u16>().is_err() {
+        return Err(format!("Invalid port in {}", url));
+    }
+    Ok(format!("https://{}{}{}", host, port, path))
+}
+
+// Unique local (fc00::/7) and link-local (fe80::/10) addresses.
+fn is_local_ipv6(address: &Ipv6Addr) -> bool {
+    let first = address.segments()[0];
+    (first & 0xfe00) == 0xfc00 || (first & 0xffc0) == 0xfe80
+}
+
+/// Lowercases, trims and deduplicates the headers and drops the ones set by
+/// the nodes.
+pub fn normalize_headers(headers: Vec<(String, String)>) -> Result<Vec<(String, String)>, String> {
+    let mut normalized: Vec<(String, String)> = vec![];
+    for (name, value) in headers {
+        let name = name.trim().to_ascii_lowercase();
+        let value = value.trim().to_string();
+        if name.is_empty() || name.contains(['\r', '\n', ':']) || value.contains(['\r', '\n']) {
+            return Err(format!("Invalid header {}", name));
+        }
+        if DROPPED_HEADERS.contains(&name.as_str()) || name.starts_with("proxy-") {
+            continue;
+        }
+        normalized.retain(|(existing, _)| *existing != name);
+        normalized.push((name, value));
+    }
+    Ok(normalized)
+}
+
+/// Derives an idempotency key that is the same on all nodes of the subnet.
+pub fn derive_idempotency_key(method: &str, url: &str, body: &[u8]) -> String {
+    let nonce = NEXT_IDEMPOTENCY_NONCE.with(|next| {
+        let nonce = next.get();
+        next.set(nonce + 1);
+        nonce
+    });
+    let mut bytes = ic_cdk::id().as_slice().to_vec();
+    bytes.extend_from_slice(&ic_cdk::api::time().to_be_bytes());
+    bytes.extend_from_slice(&nonce.to_be_bytes());
+    bytes.extend_from_slice(method.as_bytes());
+    bytes.extend_from_slice(url.as_bytes());
+    bytes.extend_from_slice(body);
+    hex::encode(&crypto::sha256(&bytes)[..16])
+}
+
+/// Normalizes an outcall from the JS options `{method, headers, body,
+/// maxResponseBytes, idempotencyKey, transform}` where the headers are an object or a
+/// list of `[name, value]` pairs.
+pub fn prepare(url: &str, options: &JSValue) -> Result<Outcall, anyhow::Error> {
+    let empty = Default::default();
+    let options = match options {
+        JSValue::Undefined | JSValue::Null => &empty,
+        JSValue::Object(options) => options,
+        _ => return Err(JSError::Type("Expected an options object".to_string()).into()),
+    };
+    let url = normalize_url(url).map_err(anyhow::Error::msg)?;
+    let method = match options.get("method") {
+        None | Some(JSValue::Undefined) => "GET".to_string(),
+        Some(JSValue::String(method)) => method.to_ascii_uppercase(),
+        Some(_) => return Err(JSError::Type("Expected method to be a string".to_string()).into()),
+    };
+    let headers = match options.get("headers") {
+        None | Some(JSValue::Undefined | JSValue::Null) => vec![],
+        Some(JSValue::Object(headers)) => headers
+            .iter()
+            .map(|(name, value)| match value {
+                JSValue::String(value) => Ok((name.clone(), value.clone())),
+                _ => Err(JSError::Type(format!("Expected header {} to be a string", name)).into()),
+            })
+            .collect::<Result<_, anyhow::Error>>()?,
+        Some(JSValue::Array(headers)) => headers
+            .iter()
+            .map(|header| match header {
+                JSValue::Array(pair) => match pair.as_slice() {
+                    [JSValue::String(name), JSValue::String(value)] => {
+                        Ok((name.clone(), value.clone()))
+                    }
+                    _ => Err(JSError::Type(
+                        "Expected a header to be a [name, value] pair".to_string(),
+                    )
+                    .into()),
+                },
+                _ => Err(
+                    JSError::Type("Expected a header to be a [name, value] pair".to_string())
+                        .into(),
+                ),
+            })
+            .collect::<Result<_, anyhow::Error>>()?,
+        Some(_) => {
+            return Err(
+                JSError::Type("Expected headers to be an object or an array".to_string()).into(),
+            )
+        }
+    };
+    let mut headers = normalize_headers(headers).map_err(anyhow::Error::msg)?;
+    let body = match options.get("body") {
+        None | Some(JSValue::Undefined | JSValue::Null) => vec![],
+        Some(JSValue::String(body)) => body.as_bytes().to_vec(),
+        Some(JSValue::ArrayBuffer(body)) => body.clone(),
+        Some(_) => {
+            return Err(
+                JSError::Type("Expected body to be a string or an ArrayBuffer".to_string()).into(),
+            )
+        }
+    };
+    let max_response_bytes = match options.get("maxResponseBytes") {
+        None | Some(JSValue::Undefined | JSValue::Null) => DEFAULT_MAX_RESPONSE_BYTES,
+        Some(value) => jobs::number(value, "maxResponseBytes")? as u64,
+    };
+    if max_response_bytes > costs::HTTP_DEFAULT_MAX_RESPONSE_BYTES {
+        return Err(JSError::Range(format!(
+            "maxResponseBytes is {}, the limit is {}",
+            max_response_bytes,
+            costs::HTTP_DEFAULT_MAX_RESPONSE_BYTES
+        ))
+        .into());
+    }
+    let key = match options.get("idempotencyKey") {
+        None | Some(JSValue::Undefined | JSValue::Null | JSValue::Bool(false)) => None,
+        Some(JSValue::Bool(true)) => Some(derive_idempotency_key(&method, &url, &body)),
+        Some(JSValue::String(key)) => Some(key.clone()),
+        Some(_) => {
+            return Err(JSError::Type(
+                "Expected idempotencyKey to be a boolean or a string".to_string(),
+            )
+            .into())
+        }
+    };
+    if let Some(key) = key {
+        headers = normalize_headers([headers, vec![(IDEMPOTENCY_KEY.to_string(), key)]].concat())
+            .map_err(anyhow::Error::msg)?;
+    }
+    let transforms = match options.get("transform") {
+        Some(value) => transforms::parse(value)?,
+        None => vec![],
+    };
+    Ok(Outcall {
+        url,
+        method,
+        headers,
+        body,
+        max_response_bytes,
+        transforms,
+    })
+}
+
+impl Outcall {
+    /// The size of the request that the outcall is charged for.
+    pub fn request_bytes(&self) -> u64 {
+        let headers: usize = self
+            .headers
+            .iter()
+            .map(|(name, value)| name.len() + value.len())
+            .sum();
+        let transform = transforms::context(&self.transforms).map_or(0, |transform| {
+            transform.context.len() + transform.function.0.method.len()
+        });
+        (self.url.len() + headers + self.body.len() + transform) as u64
+    }
+
+    /// The argument of the `http_request` method of the management canister.
+    pub fn to_argument(&self) -> Result<CanisterHttpRequestArgument, String> {
+        let method = match self.method.as_str() {
+            "GET" => HttpMethod::GET,
+            "HEAD" => HttpMethod::HEAD,
+            "POST" => HttpMethod::POST,
+            method => {
+                return Err(format!(
+                    "The method {} is not supported by outcalls",
+                    method
+                ))
+            }
+        };
+        Ok(CanisterHttpRequestArgument {
+            url: self.url.clone(),
+            max_response_bytes: Some(self.max_response_bytes),
+            method,
+            headers: self
+                .headers
+                .iter()
+                .map(|(name, value)| HttpHeader {
+                    name: name.clone(),
+                    value: value.clone(),
+                })
+                .collect(),
+            body: if self.body.is_empty() {
+                None
+            } else {
+                Some(self.body.clone())
+            },
+            transform: transforms::context(&self.transforms),
+        })
+    }
+}
+
+// Reads `subnetSize` from the options of `prepare()` and `fetch()`.
+fn subnet_size(options: &JSValue) -> Result<u64, anyhow::Error> {
+    match options {
+        JSValue::Object(options) => match options.get("subnetSize") {
+            None | Some(JSValue::Undefined | JSValue::Null) => Ok(DEFAULT_SUBNET_SIZE),
+            Some(value) => Ok(jobs::number(value, "subnetSize")? as u64),
+        },
+        _ => Ok(DEFAULT_SUBNET_SIZE),
+    }
+}
+
+fn response_to_js(
+    context: &JSContextRef,
+    response: HttpResponse,
+) -> Result<JSValueRef, anyhow::Error> {
+    let status: u16 = response.status.0.to_string().parse()?;
+    let js = context.object_value()?;
+    js.set_property("status", context.value_from_i32(status as i32)?)?;
+    let headers = context.array_value()?;
+    for header in response.headers {
+        let pair = context.array_value()?;
+        pair.append_property(context.value_from_str(&header.name)?)?;
+        pair.append_property(context.value_from_str(&header.value)?)?;
+        headers.append_property(pair)?;
+    }
+    js.set_property("headers", headers)?;
+    js.set_property("body", context.array_buffer_value(&response.body)?)?;
+    Ok(js)
+}
+
+pub fn link(context: &JSContextRef) -> Result<(), anyhow::Error> {
+    // Usage: `ic.outcalls.prepare(url, {method, headers, body,
+    // maxResponseBytes, idempotencyKey, transform, subnetSize})`. Returns `{url, method,
+    // headers, body, maxResponseBytes, cycles}` with the headers as a list of
+    // `[name, value]` pairs.
+    fn prepare<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.is_empty() || args.len() > 2 {
+            return Err(
+                JSError::Type(format!("Expected 1 or 2 arguments, got {}", args.len())).into(),
+            );
+        }
+        let url: String = args[0].try_into()?;
+        let options = match args.get(1) {
+            Some(arg) => arg.to_js_value()?,
+            None => JSValue::Undefined,
+        };
+        let subnet_size = subnet_size(&options)?;
+        let outcall = self::prepare(&url, &options)?;
+        let cycles = costs::http_outcall(
+            outcall.request_bytes(),
+            outcall.max_response_bytes,
+            subnet_size as u128,
+        );
+
+        let js = context.object_value()?;
+        js.set_property("url", context.value_from_str(&outcall.url)?)?;
+        js.set_property("method", context.value_from_str(&outcall.method)?)?;
+        let headers = context.array_value()?;
+        for (name, value) in outcall.headers.iter() {
+            let header = context.array_value()?;
+            header.append_property(context.value_from_str(name)?)?;
+            header.append_property(context.value_from_str(value)?)?;
+            headers.append_property(header)?;
+        }
+        js.set_property("headers", headers)?;
+        js.set_property("body", context.array_buffer_value(&outcall.body)?)?;
+        js.set_property(
+            "maxResponseBytes",
+            context.value_from_f64(outcall.max_response_bytes as f64)?,
+        )?;
+        js.set_property("cycles", context.value_from_f64(cycles as f64)?)?;
+        Ok(js)
+    }
+
+    // Usage: `await fetch(url, {method, headers, body, maxResponseBytes,
+    // idempotencyKey, transform, subnetSize, cycles})`.
+    fn fetch<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.is_empty() || args.len() > 2 {
+            return Err(
+                JSError::Type(format!("Expected 1 or 2 arguments, got {}", args.len())).into(),
+            );
+        }
+        let url: String = args[0].try_into()?;
+        let options = match args.get(1) {
+            Some(arg) => arg.to_js_value()?,
+            None => JSValue::Undefined,
+        };
+        let outcall = self::prepare(&url, &options)?;
+        let cycles = match &options {
+            JSValue::Object(fields) if fields.contains_key("cycles") => {
+                jobs::number(&fields["cycles"], "cycles")? as u128
+            }
+            _ => {
+                let subnet_size = subnet_size(&options)? as u128;
+                costs::http_outcall(
+                    outcall.request_bytes(),
+                    outcall.max_response_bytes,
+                    subnet_size,
+                )
+            }
+        };
+        let argument = outcall.to_argument().map_err(anyhow::Error::msg)?;
+        let args = encode_args((argument,))?;
+        engine::call_with_cycles(
+            context,
+            Principal::management_canister(),
+            "http_request",
+            &args,
+            cycles,
+            |context, bytes| {
+                let (response,) = decode_args::<(HttpResponse,)>(&bytes)?;
+                response_to_js(context, response)
+            },
+        )
+    }
+
+    let outcalls = context.object_value()?;
+    outcalls.set_property("prepare", context.wrap_callback2(prepare)?)?;
+    engine::namespace(context)?.set_property("outcalls", outcalls)?;
+    context
+        .global_object()?
+        .set_property("fetch", context.wrap_callback2(fetch)?)?;
+    Ok(())
+}
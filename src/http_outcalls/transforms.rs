@@ -0,0 +1,161 @@
+// Transforms of outcall responses that make the responses of all nodes equal.
+//
+// The nodes of a subnet reach consensus on a response only if their copies
+// are identical, which is rarely the case for APIs that add dates, request
+// ids or timestamps. The transforms are applied in order by the
+// `transform_outcall` query before consensus. They are selected in JS with
+// `transform: ["sortKeys", {stripHeaders: ["date"]}, {extractJson: "/data"},
+// {normalizeTimestamps: {keys: ["updated_at"], granularity: 60}}]` and passed
+// to the query as the Candid-encoded context of the outcall.
+use candid::{CandidType, Deserialize, Func};
+use ic_cdk::api::management_canister::http_request::{
+    HttpResponse, TransformArgs, TransformContext, TransformFunc,
+};
+use quickjs_wasm_rs::{JSError, JSValue};
+use serde_json::Value;
+
+use crate::jobs;
+
+// The query of lib.rs that applies the transforms.
+const TRANSFORM_METHOD: &str = "transform_outcall";
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum Transform {
+    /// Drops the headers with the given names or all headers if none are
+    /// given.
+    StripHeaders(Option<Vec<String>>),
+    /// Replaces a JSON body with the value at the given JSON pointer.
+    ExtractJson(String),
+    /// Rounds the numbers of the given keys of a JSON body down to a multiple
+    /// of the granularity and replaces other values of these keys with
+    /// `null`.
+    NormalizeTimestamps { keys: Vec<String>, granularity: u64 },
+    /// Serializes a JSON body with sorted keys and without whitespace.
+    SortKeys,
+}
+
+/// Parses the transforms from the `transform` option of an outcall.
+pub fn parse(value: &JSValue) -> Result<Vec<Transform>, anyhow::Error> {
+    match value {
+        JSValue::Undefined | JSValue::Null => Ok(vec![]),
+        JSValue::Array(values) => values.iter().map(parse_one).collect(),
+        value => Ok(vec![parse_one(value)?]),
+    }
+}
+
+fn parse_one(value: &JSValue) -> Result<Transform, anyhow::Error> {
+    match value {
+        JSValue::String(name) if name == "stripHeaders" => Ok(Transform::StripHeaders(None)),
+        JSValue::String(name) if name == "sortKeys" => Ok(Transform::SortKeys),
+        JSValue::Object(fields) if fields.len() == 1 => {
+            let (name, value) = fields.iter().next().unwrap();
+            match (name.as_str(), value) {
+                ("stripHeaders", JSValue::Array(names)) => Ok(Transform::StripHeaders(Some(
+                    names
+                        .iter()
+                        .map(|name| match name {
+                            JSValue::String(name) => Ok(name.to_ascii_lowercase()),
+                            _ => Err(JSError::Type("Expected header names to be strings".to_string()).into()),
+                        })
+                        .collect::<Result<_, anyhow::Error>>()?,
+                ))),
+                ("extractJson", JSValue::String(pointer)) => Ok(Transform::ExtractJson(pointer.clone())),
+                ("normalizeTimestamps", JSValue::Object(options)) => {
+                    let keys = match options.get("keys") {
+                        Some(JSValue::Array(keys)) => keys
+                            .iter()
+                            .map(|key| match key {
+                                JSValue::String(key) => Ok(key.clone()),
+                                _ => Err(JSError::Type("Expected keys to be strings".to_string()).into()),
+                            })
+                            .collect::<Result<_, anyhow::Error>>()?,
+                        _ => return Err(JSError::Type("Expected keys to be an array".to_string()).into()),
+                    };
+                    let granularity = match options.get("granularity") {
+                        None | Some(JSValue::Undefined) => 1,
+                        Some(value) => (jobs::number(value, "granularity")? as u64).max(1),
+                    };
+                    Ok(Transform::NormalizeTimestamps { keys, granularity })
+                }
+                _ => Err(JSError::Type(format!("Invalid options of the transform {}", name)).into()),
+            }
+        }
+        _ => Err(JSError::Type(
+            "Expected a transform like \"sortKeys\" or {stripHeaders: [...]}, {extractJson: pointer} or \
+             {normalizeTimestamps: {keys, granularity}}"
+                .to_string(),
+        )
+        .into()),
+    }
+}
+
+/// The transform context of an outcall with the given transforms.
+pub fn context(transforms: &[Transform]) -> Option<TransformContext> {
+    if transforms.is_empty() {
+        return None;
+    }
+    Some(TransformContext {
+        function: TransformFunc(Func {
+            principal: ic_cdk::id(),
+            method: TRANSFORM_METHOD.to_string(),
+        }),
+        context: candid::encode_one(transforms).unwrap(),
+    })
+}
+
+/// Applies the transforms of the context to the response.
+pub fn apply(args: TransformArgs) -> Result<HttpResponse, String> {
+    let transforms: Vec<Transform> = candid::decode_one(&args.context)
+        .map_err(|err| format!("Invalid transform context: {}", err))?;
+    let mut response = args.response;
+    for transform in transforms {
+        match transform {
+            Transform::StripHeaders(None) => response.headers.clear(),
+            Transform::StripHeaders(Some(names)) => response
+                .headers
+                .retain(|header| !names.contains(&header.name.to_ascii_lowercase())),
+            Transform::ExtractJson(pointer) => {
+                let body = parse_body(&response.body)?;
+                let value = body
+                    .pointer(&pointer)
+                    .ok_or_else(|| format!("The response has no value at {}", pointer))?;
+                response.body = value.to_string().into_bytes();
+            }
+            Transform::NormalizeTimestamps { keys, granularity } => {
+                let mut body = parse_body(&response.body)?;
+                normalize_timestamps(&mut body, &keys, granularity);
+                response.body = body.to_string().into_bytes();
+            }
+            // The objects of serde_json are ordered by key.
+            Transform::SortKeys => {
+                response.body = parse_body(&response.body)?.to_string().into_bytes()
+            }
+        }
+    }
+    Ok(response)
+}
+
+fn parse_body(body: &[u8]) -> Result<Value, String> {
+    serde_json::from_slice(body).map_err(|err| format!("The response is not JSON: {}", err))
+}
+
+fn normalize_timestamps(value: &mut Value, keys: &[String], granularity: u64) {
+    match value {
+        Value::Object(fields) => {
+            for (key, value) in fields.iter_mut() {
+                if keys.contains(key) {
+                    *value = match value.as_u64() {
+                        Some(timestamp) => Value::from(timestamp - timestamp % granularity),
+                        None => Value::Null,
+                    };
+                } else {
+                    normalize_timestamps(value, keys, granularity);
+                }
+            }
+        }
+        Value::Array(values) => values
+            .iter_mut()
+            .for_each(|value| normalize_timestamps(value, keys, granularity)),
+        _ => {}
+    }
+}
@@ -2,3 +2,7 @@ async function query(n) {
     ic0.debug_print(await managementCanister.canister_status(ic0.canister_self()));
     return "test";
 }
+
+function read(key) {
+    return ic.kv.get(key) ?? null;
+}
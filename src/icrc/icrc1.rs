@@ -0,0 +1,34 @@
+// The ICRC-1 `icrc1_transfer` endpoint of token ledgers.
+use candid::{parser::value::IDLValue, CandidType, Deserialize, Nat, Principal};
+
+use super::Account;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TransferArg {
+    pub from_subaccount: Option<Vec<u8>>,
+    pub to: Account,
+    pub amount: Nat,
+    pub fee: Option<Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+// The errors are decoded generically because only their text is reported.
+#[derive(CandidType, Deserialize, Debug)]
+enum TransferResult {
+    Ok(Nat),
+    Err(IDLValue),
+}
+
+/// Transfers tokens and returns the index of the block of the transfer.
+pub async fn transfer(ledger: Principal, arg: TransferArg) -> Result<Nat, String> {
+    let (result,): (TransferResult,) = ic_cdk::call(ledger, "icrc1_transfer", (arg,))
+        .await
+        .map_err(|(code, message)| {
+            format!("icrc1_transfer failed with code {:?}: {}", code, message)
+        })?;
+    match result {
+        TransferResult::Ok(index) => Ok(index),
+        TransferResult::Err(err) => Err(format!("icrc1_transfer failed: {}", err)),
+    }
+}
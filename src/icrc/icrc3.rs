@@ -0,0 +1,84 @@
+// The ICRC-3 `icrc3_get_blocks` endpoint of ledgers and their archives.
+use candid::{types::reference::Func, CandidType, Deserialize, Nat, Principal};
+
+use super::Value;
+use crate::call_policy;
+
+// The maximum number of archive callbacks followed by one request. Archives
+// return the blocks of all callbacks at once, so this is a safety net.
+const MAX_ARCHIVE_CALLS: usize = 64;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct GetBlocksArgs {
+    pub start: Nat,
+    pub length: Nat,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Block {
+    pub id: Nat,
+    pub block: Value,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ArchivedBlocks {
+    pub args: Vec<GetBlocksArgs>,
+    pub callback: Func,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct GetBlocksResult {
+    pub log_length: Nat,
+    pub blocks: Vec<Block>,
+    pub archived_blocks: Vec<ArchivedBlocks>,
+}
+
+async fn call(
+    canister: Principal,
+    method: &str,
+    args: Vec<GetBlocksArgs>,
+) -> Result<GetBlocksResult, String> {
+    call_policy::check(&canister, method)?;
+    let (result,): (GetBlocksResult,) =
+        ic_cdk::call(canister, method, (args,))
+            .await
+            .map_err(|(code, message)| {
+                format!("{} failed with code {:?}: {}", method, code, message)
+            })?;
+    Ok(result)
+}
+
+/// Returns the length of the log and the blocks of the given range including
+/// the archived ones ordered by id.
+pub async fn get_blocks(
+    ledger: Principal,
+    start: u64,
+    length: u64,
+) -> Result<(Nat, Vec<Block>), String> {
+    let args = vec![GetBlocksArgs {
+        start: Nat::from(start),
+        length: Nat::from(length),
+    }];
+    let GetBlocksResult {
+        log_length,
+        mut blocks,
+        archived_blocks: mut pending,
+    } = call(ledger, "icrc3_get_blocks", args).await?;
+    let mut calls = 0;
+    while let Some(archived) = pending.pop() {
+        calls += 1;
+        if calls > MAX_ARCHIVE_CALLS {
+            return Err("Too many archive callbacks".to_string());
+        }
+        let result = call(
+            archived.callback.principal,
+            &archived.callback.method,
+            archived.args,
+        )
+        .await?;
+        blocks.extend(result.blocks);
+        pending.extend(result.archived_blocks);
+    }
+    blocks.sort_by(|a, b| a.id.cmp(&b.id));
+    Ok((log_length, blocks))
+}
@@ -0,0 +1,161 @@
+// The ICRC-7 and ICRC-37 endpoints of NFT collections.
+//
+// Errors of transfers and approvals are decoded generically because the
+// error variants differ between versions of the standards.
+use candid::{parser::value::IDLValue, CandidType, Deserialize, Nat, Principal};
+
+use super::{Account, Value};
+use crate::call_policy;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TransferArg {
+    pub from_subaccount: Option<Vec<u8>>,
+    pub to: Account,
+    pub token_id: Nat,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ApprovalInfo {
+    pub spender: Account,
+    pub from_subaccount: Option<Vec<u8>>,
+    pub expires_at: Option<u64>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ApproveTokenArg {
+    pub token_id: Nat,
+    pub approval_info: ApprovalInfo,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TransferFromArg {
+    pub spender_subaccount: Option<Vec<u8>>,
+    pub from: Account,
+    pub to: Account,
+    pub token_id: Nat,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct IsApprovedArg {
+    pub spender: Account,
+    pub from_subaccount: Option<Vec<u8>>,
+    pub token_id: Nat,
+}
+
+/// The result of a transfer or an approval: the transaction index or an error.
+#[derive(CandidType, Deserialize, Debug)]
+pub enum TxResult {
+    Ok(Nat),
+    Err(IDLValue),
+}
+
+async fn call<A: CandidType, R: for<'de> Deserialize<'de> + CandidType>(
+    collection: Principal,
+    method: &str,
+    args: A,
+) -> Result<R, String> {
+    call_policy::check(&collection, method)?;
+    let (result,): (R,) =
+        ic_cdk::call(collection, method, (args,))
+            .await
+            .map_err(|(code, message)| {
+                format!("{} failed with code {:?}: {}", method, code, message)
+            })?;
+    Ok(result)
+}
+
+// Returns the only result of a batch method called with one argument.
+fn single<T>(method: &str, results: Vec<Option<T>>) -> Result<Option<T>, String> {
+    results
+        .into_iter()
+        .next()
+        .ok_or_else(|| format!("{} returned no results", method))
+}
+
+fn tx_index(method: &str, result: Option<TxResult>) -> Result<Nat, String> {
+    match result {
+        Some(TxResult::Ok(index)) => Ok(index),
+        Some(TxResult::Err(err)) => Err(format!("{} failed: {}", method, err)),
+        None => Err(format!("{} was not processed", method)),
+    }
+}
+
+pub async fn owner_of(collection: Principal, token_id: Nat) -> Result<Option<Account>, String> {
+    let results: Vec<Option<Account>> = call(collection, "icrc7_owner_of", vec![token_id]).await?;
+    single("icrc7_owner_of", results)
+}
+
+pub async fn tokens_of(
+    collection: Principal,
+    account: Account,
+    prev: Option<Nat>,
+    take: Option<Nat>,
+) -> Result<Vec<Nat>, String> {
+    call_policy::check(&collection, "icrc7_tokens_of")?;
+    let (tokens,): (Vec<Nat>,) = ic_cdk::call(collection, "icrc7_tokens_of", (account, prev, take))
+        .await
+        .map_err(|(code, message)| {
+            format!("icrc7_tokens_of failed with code {:?}: {}", code, message)
+        })?;
+    Ok(tokens)
+}
+
+pub async fn token_metadata(
+    collection: Principal,
+    token_id: Nat,
+) -> Result<Option<Vec<(String, Value)>>, String> {
+    let results: Vec<Option<Vec<(String, Value)>>> =
+        call(collection, "icrc7_token_metadata", vec![token_id]).await?;
+    single("icrc7_token_metadata", results)
+}
+
+pub async fn collection_metadata(collection: Principal) -> Result<Vec<(String, Value)>, String> {
+    call_policy::check(&collection, "icrc7_collection_metadata")?;
+    let (metadata,): (Vec<(String, Value)>,) =
+        ic_cdk::call(collection, "icrc7_collection_metadata", ())
+            .await
+            .map_err(|(code, message)| {
+                format!(
+                    "icrc7_collection_metadata failed with code {:?}: {}",
+                    code, message
+                )
+            })?;
+    Ok(metadata)
+}
+
+pub async fn transfer(collection: Principal, arg: TransferArg) -> Result<Nat, String> {
+    let results: Vec<Option<TxResult>> = call(collection, "icrc7_transfer", vec![arg]).await?;
+    tx_index("icrc7_transfer", single("icrc7_transfer", results)?)
+}
+
+pub async fn approve(collection: Principal, arg: ApproveTokenArg) -> Result<Nat, String> {
+    let results: Vec<Option<TxResult>> =
+        call(collection, "icrc37_approve_tokens", vec![arg]).await?;
+    tx_index(
+        "icrc37_approve_tokens",
+        single("icrc37_approve_tokens", results)?,
+    )
+}
+
+pub async fn transfer_from(collection: Principal, arg: TransferFromArg) -> Result<Nat, String> {
+    let results: Vec<Option<TxResult>> =
+        call(collection, "icrc37_transfer_from", vec![arg]).await?;
+    tx_index(
+        "icrc37_transfer_from",
+        single("icrc37_transfer_from", results)?,
+    )
+}
+
+pub async fn is_approved(collection: Principal, arg: IsApprovedArg) -> Result<bool, String> {
+    let results: Vec<bool> = call(collection, "icrc37_is_approved", vec![arg]).await?;
+    results
+        .into_iter()
+        .next()
+        .ok_or_else(|| "icrc37_is_approved returned no results".to_string())
+}
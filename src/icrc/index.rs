@@ -0,0 +1,171 @@
+// The `get_account_transactions` endpoint of ICRC-1 index canisters.
+use std::collections::HashMap;
+
+use candid::{CandidType, Deserialize, Nat, Principal};
+use quickjs_wasm_rs::JSValue;
+
+use super::{nat_to_js, Account};
+use crate::call_policy;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct GetAccountTransactionsArgs {
+    pub account: Account,
+    pub start: Option<Nat>,
+    pub max_results: Nat,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Mint {
+    pub amount: Nat,
+    pub to: Account,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Burn {
+    pub amount: Nat,
+    pub from: Account,
+    pub spender: Option<Account>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Transfer {
+    pub amount: Nat,
+    pub from: Account,
+    pub to: Account,
+    pub spender: Option<Account>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+    pub fee: Option<Nat>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Approve {
+    pub amount: Nat,
+    pub from: Account,
+    pub spender: Account,
+    pub expected_allowance: Option<Nat>,
+    pub expires_at: Option<u64>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+    pub fee: Option<Nat>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Transaction {
+    pub kind: String,
+    pub mint: Option<Mint>,
+    pub burn: Option<Burn>,
+    pub transfer: Option<Transfer>,
+    pub approve: Option<Approve>,
+    pub timestamp: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TransactionWithId {
+    pub id: Nat,
+    pub transaction: Transaction,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct GetTransactions {
+    pub balance: Nat,
+    pub transactions: Vec<TransactionWithId>,
+    pub oldest_tx_id: Option<Nat>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct GetTransactionsErr {
+    pub message: String,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum GetTransactionsResult {
+    Ok(GetTransactions),
+    Err(GetTransactionsErr),
+}
+
+pub async fn get_account_transactions(
+    index: Principal,
+    args: GetAccountTransactionsArgs,
+) -> Result<GetTransactions, String> {
+    call_policy::check(&index, "get_account_transactions")?;
+    let (result,): (GetTransactionsResult,) =
+        ic_cdk::call(index, "get_account_transactions", (args,))
+            .await
+            .map_err(|(code, message)| {
+                format!(
+                    "get_account_transactions failed with code {:?}: {}",
+                    code, message
+                )
+            })?;
+    match result {
+        GetTransactionsResult::Ok(transactions) => Ok(transactions),
+        GetTransactionsResult::Err(err) => Err(err.message),
+    }
+}
+
+impl TransactionWithId {
+    /// Converts the transaction to a flat JS object with the fields of its
+    /// kind, e.g. `{id, kind: "transfer", timestamp, amount, from, to, ...}`.
+    /// Timestamps are in milliseconds like `Date.now()`.
+    pub fn to_js(&self) -> JSValue {
+        let transaction = &self.transaction;
+        let mut fields = HashMap::from([
+            ("id".to_string(), nat_to_js(&self.id)),
+            (
+                "kind".to_string(),
+                JSValue::String(transaction.kind.clone()),
+            ),
+            ("timestamp".to_string(), millis(transaction.timestamp)),
+        ]);
+        let mut set = |name: &str, value: Option<JSValue>| {
+            if let Some(value) = value {
+                fields.insert(name.to_string(), value);
+            }
+        };
+        if let Some(mint) = &transaction.mint {
+            set("amount", Some(nat_to_js(&mint.amount)));
+            set("to", Some(mint.to.to_js()));
+            set("memo", mint.memo.clone().map(JSValue::ArrayBuffer));
+            set("createdAtTime", mint.created_at_time.map(millis));
+        }
+        if let Some(burn) = &transaction.burn {
+            set("amount", Some(nat_to_js(&burn.amount)));
+            set("from", Some(burn.from.to_js()));
+            set("spender", burn.spender.as_ref().map(Account::to_js));
+            set("memo", burn.memo.clone().map(JSValue::ArrayBuffer));
+            set("createdAtTime", burn.created_at_time.map(millis));
+        }
+        if let Some(transfer) = &transaction.transfer {
+            set("amount", Some(nat_to_js(&transfer.amount)));
+            set("from", Some(transfer.from.to_js()));
+            set("to", Some(transfer.to.to_js()));
+            set("spender", transfer.spender.as_ref().map(Account::to_js));
+            set("memo", transfer.memo.clone().map(JSValue::ArrayBuffer));
+            set("createdAtTime", transfer.created_at_time.map(millis));
+            set("fee", transfer.fee.as_ref().map(nat_to_js));
+        }
+        if let Some(approve) = &transaction.approve {
+            set("amount", Some(nat_to_js(&approve.amount)));
+            set("from", Some(approve.from.to_js()));
+            set("spender", Some(approve.spender.to_js()));
+            set(
+                "expectedAllowance",
+                approve.expected_allowance.as_ref().map(nat_to_js),
+            );
+            set("expiresAt", approve.expires_at.map(millis));
+            set("memo", approve.memo.clone().map(JSValue::ArrayBuffer));
+            set("createdAtTime", approve.created_at_time.map(millis));
+            set("fee", approve.fee.as_ref().map(nat_to_js));
+        }
+        JSValue::Object(fields)
+    }
+}
+
+fn millis(nanos: u64) -> JSValue {
+    JSValue::Float((nanos / 1_000_000) as f64)
+}
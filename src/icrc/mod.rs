@@ -0,0 +1,444 @@
+// Clients of ICRC token and NFT standards exposed to JS as `ic.icrc`.
+//
+// `ic.icrc.getBlocks(ledger, start, length)` fetches blocks of an ICRC-3 block
+// log and follows the callbacks of archived blocks, so the result contains all
+// existing blocks of the range ordered by id. The result is
+// `{logLength, blocks: [{id, block}]}` where blocks are generic values.
+//
+// `ic.icrc.nft` is a client of ICRC-7 collections with the ICRC-37 approval
+// extension. All functions take the collection id as the first argument and
+// return promises:
+// - `ownerOf(collection, tokenId)` resolves with an account or null,
+// - `tokensOf(collection, account, {prev, take})` with token ids,
+// - `tokenMetadata(collection, tokenId)` with an object or null,
+// - `collectionMetadata(collection)` with an object,
+// - `transfer(collection, {tokenId, to, memo})`,
+//   `approve(collection, {tokenId, spender, expiresAt, memo})` and
+//   `transferFrom(collection, {tokenId, from, to, memo})` with the transaction
+//   index,
+// - `isApproved(collection, {tokenId, spender})` with a boolean.
+//
+// `ic.icrc.index.accountTransactions(index, account, {start, maxResults})`
+// fetches the transactions of an account from an ICRC-1 index canister,
+// newest first. It resolves with `{balance, oldestTxId, transactions}` where
+// each transaction is a flat object with the fields of its kind. Pass the id
+// of the last returned transaction as `start` to fetch the next page.
+//
+// Accounts are `{owner, subaccount}` objects with the subaccount as an optional
+// ArrayBuffer. Token ids and other natural numbers are accepted as numbers or
+// decimal strings. Generic values are converted to JS as follows: blobs become
+// ArrayBuffers, texts become strings, natural numbers and integers become
+// decimal strings because they may exceed the safe integer range, arrays
+// become arrays and maps become objects.
+use std::collections::HashMap;
+
+use candid::{CandidType, Deserialize, Int, Nat, Principal};
+use quickjs_wasm_rs::{CallbackArg, JSContextRef, JSError, JSValue, JSValueRef};
+
+use crate::{engine, jobs};
+
+pub mod icrc1;
+pub mod icrc3;
+pub mod icrc7;
+pub mod index;
+
+// The default number of transactions in a page of the index canister.
+const DEFAULT_MAX_RESULTS: u64 = 100;
+
+/// The generic value of ICRC-3 blocks and ICRC-7 metadata.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum Value {
+    Blob(Vec<u8>),
+    Text(String),
+    Nat(Nat),
+    Int(Int),
+    Array(Vec<Value>),
+    Map(Vec<(String, Value)>),
+}
+
+impl Value {
+    pub fn to_js(&self) -> JSValue {
+        match self {
+            Value::Blob(bytes) => JSValue::ArrayBuffer(bytes.clone()),
+            Value::Text(text) => JSValue::String(text.clone()),
+            Value::Nat(nat) => JSValue::String(nat.0.to_string()),
+            Value::Int(int) => JSValue::String(int.0.to_string()),
+            Value::Array(values) => JSValue::Array(values.iter().map(Value::to_js).collect()),
+            Value::Map(entries) => metadata_to_js(entries),
+        }
+    }
+}
+
+fn metadata_to_js(entries: &[(String, Value)]) -> JSValue {
+    JSValue::Object(
+        entries
+            .iter()
+            .map(|(key, value)| (key.clone(), value.to_js()))
+            .collect(),
+    )
+}
+
+/// An ICRC-1 account.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<Vec<u8>>,
+}
+
+impl Account {
+    pub fn from_js(value: &JSValue) -> Result<Self, anyhow::Error> {
+        let JSValue::Object(fields) = value else {
+            anyhow::bail!("Expected an account object");
+        };
+        let owner = match fields.get("owner") {
+            Some(JSValue::String(owner)) => Principal::from_text(owner)?,
+            _ => anyhow::bail!("Expected the owner of the account to be a principal"),
+        };
+        Ok(Account {
+            owner,
+            subaccount: optional_bytes(fields, "subaccount")?,
+        })
+    }
+
+    pub fn to_js(&self) -> JSValue {
+        let mut fields =
+            HashMap::from([("owner".to_string(), JSValue::String(self.owner.to_text()))]);
+        if let Some(subaccount) = &self.subaccount {
+            fields.insert(
+                "subaccount".to_string(),
+                JSValue::ArrayBuffer(subaccount.clone()),
+            );
+        }
+        JSValue::Object(fields)
+    }
+}
+
+/// A helper that extracts a natural number from a JS number or a decimal
+/// string.
+pub fn nat(value: &JSValue, name: &str) -> Result<Nat, anyhow::Error> {
+    match value {
+        JSValue::String(value) => value
+            .parse::<Nat>()
+            .map_err(|_| JSError::Type(format!("Expected {} to be a natural number", name)).into()),
+        value => Ok(Nat::from(jobs::number(value, name)? as u64)),
+    }
+}
+
+fn optional_bytes(
+    fields: &HashMap<String, JSValue>,
+    name: &str,
+) -> Result<Option<Vec<u8>>, anyhow::Error> {
+    match fields.get(name) {
+        None | Some(JSValue::Undefined | JSValue::Null) => Ok(None),
+        Some(JSValue::ArrayBuffer(bytes)) => Ok(Some(bytes.clone())),
+        Some(_) => Err(JSError::Type(format!("Expected {} to be an ArrayBuffer", name)).into()),
+    }
+}
+
+fn optional_time(
+    fields: &HashMap<String, JSValue>,
+    name: &str,
+) -> Result<Option<u64>, anyhow::Error> {
+    match fields.get(name) {
+        None | Some(JSValue::Undefined | JSValue::Null) => Ok(None),
+        // Timestamps in JS are in milliseconds like `Date.now()`.
+        Some(value) => Ok(Some((jobs::number(value, name)? * 1e6) as u64)),
+    }
+}
+
+fn field<'a>(
+    fields: &'a HashMap<String, JSValue>,
+    name: &str,
+) -> Result<&'a JSValue, anyhow::Error> {
+    fields
+        .get(name)
+        .ok_or_else(|| JSError::Type(format!("Expected the {} field", name)).into())
+}
+
+pub fn nat_to_js(nat: &Nat) -> JSValue {
+    JSValue::String(nat.0.to_string())
+}
+
+pub fn link(context: &JSContextRef) -> Result<(), anyhow::Error> {
+    fn get_blocks<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 3 {
+            return Err(JSError::Type(format!("Expected 3 arguments, got {}", args.len())).into());
+        }
+        let ledger: String = args[0].try_into()?;
+        let ledger = Principal::from_text(ledger)?;
+        let start = jobs::number(&args[1].to_js_value()?, "start")? as u64;
+        let length = jobs::number(&args[2].to_js_value()?, "length")? as u64;
+        engine::promise_for(context, async move {
+            let (log_length, blocks) = icrc3::get_blocks(ledger, start, length).await?;
+            let blocks = blocks
+                .iter()
+                .map(|block| {
+                    JSValue::Object(HashMap::from([
+                        ("id".to_string(), nat_to_js(&block.id)),
+                        ("block".to_string(), block.block.to_js()),
+                    ]))
+                })
+                .collect();
+            Ok(JSValue::Object(HashMap::from([
+                ("logLength".to_string(), nat_to_js(&log_length)),
+                ("blocks".to_string(), JSValue::Array(blocks)),
+            ])))
+        })
+    }
+
+    // Returns the collection and the other arguments of an NFT function.
+    fn nft_args(
+        args: &[CallbackArg],
+        count: usize,
+    ) -> Result<(Principal, Vec<JSValue>), anyhow::Error> {
+        if args.len() != count {
+            return Err(
+                JSError::Type(format!("Expected {} arguments, got {}", count, args.len())).into(),
+            );
+        }
+        let collection: String = args[0].try_into()?;
+        let values = args[1..]
+            .iter()
+            .map(|arg| arg.to_js_value())
+            .collect::<Result<_, _>>()?;
+        Ok((Principal::from_text(collection)?, values))
+    }
+
+    fn options(value: &JSValue) -> Result<HashMap<String, JSValue>, anyhow::Error> {
+        match value {
+            JSValue::Object(fields) => Ok(fields.clone()),
+            JSValue::Undefined => Ok(HashMap::new()),
+            _ => Err(JSError::Type("Expected an options object".to_string()).into()),
+        }
+    }
+
+    fn owner_of<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        let (collection, values) = nft_args(args, 2)?;
+        let token_id = nat(&values[0], "tokenId")?;
+        engine::promise_for(context, async move {
+            let owner = icrc7::owner_of(collection, token_id).await?;
+            Ok(owner.map(|owner| owner.to_js()).unwrap_or(JSValue::Null))
+        })
+    }
+
+    fn tokens_of<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() < 2 || args.len() > 3 {
+            return Err(
+                JSError::Type(format!("Expected 2 or 3 arguments, got {}", args.len())).into(),
+            );
+        }
+        let collection: String = args[0].try_into()?;
+        let collection = Principal::from_text(collection)?;
+        let account = Account::from_js(&args[1].to_js_value()?)?;
+        let options = match args.get(2) {
+            Some(arg) => options(&arg.to_js_value()?)?,
+            None => HashMap::new(),
+        };
+        let prev = options
+            .get("prev")
+            .map(|prev| nat(prev, "prev"))
+            .transpose()?;
+        let take = options
+            .get("take")
+            .map(|take| nat(take, "take"))
+            .transpose()?;
+        engine::promise_for(context, async move {
+            let tokens = icrc7::tokens_of(collection, account, prev, take).await?;
+            Ok(JSValue::Array(tokens.iter().map(nat_to_js).collect()))
+        })
+    }
+
+    fn token_metadata<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        let (collection, values) = nft_args(args, 2)?;
+        let token_id = nat(&values[0], "tokenId")?;
+        engine::promise_for(context, async move {
+            let metadata = icrc7::token_metadata(collection, token_id).await?;
+            Ok(metadata
+                .map(|metadata| metadata_to_js(&metadata))
+                .unwrap_or(JSValue::Null))
+        })
+    }
+
+    fn collection_metadata<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        let (collection, _) = nft_args(args, 1)?;
+        engine::promise_for(context, async move {
+            let metadata = icrc7::collection_metadata(collection).await?;
+            Ok(metadata_to_js(&metadata))
+        })
+    }
+
+    fn transfer<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        let (collection, values) = nft_args(args, 2)?;
+        let fields = options(&values[0])?;
+        let arg = icrc7::TransferArg {
+            from_subaccount: optional_bytes(&fields, "fromSubaccount")?,
+            to: Account::from_js(field(&fields, "to")?)?,
+            token_id: nat(field(&fields, "tokenId")?, "tokenId")?,
+            memo: optional_bytes(&fields, "memo")?,
+            created_at_time: Some(ic_cdk::api::time()),
+        };
+        engine::promise_for(context, async move {
+            Ok(nat_to_js(&icrc7::transfer(collection, arg).await?))
+        })
+    }
+
+    fn approve<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        let (collection, values) = nft_args(args, 2)?;
+        let fields = options(&values[0])?;
+        let arg = icrc7::ApproveTokenArg {
+            token_id: nat(field(&fields, "tokenId")?, "tokenId")?,
+            approval_info: icrc7::ApprovalInfo {
+                spender: Account::from_js(field(&fields, "spender")?)?,
+                from_subaccount: optional_bytes(&fields, "fromSubaccount")?,
+                expires_at: optional_time(&fields, "expiresAt")?,
+                memo: optional_bytes(&fields, "memo")?,
+                created_at_time: ic_cdk::api::time(),
+            },
+        };
+        engine::promise_for(context, async move {
+            Ok(nat_to_js(&icrc7::approve(collection, arg).await?))
+        })
+    }
+
+    fn transfer_from<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        let (collection, values) = nft_args(args, 2)?;
+        let fields = options(&values[0])?;
+        let arg = icrc7::TransferFromArg {
+            spender_subaccount: optional_bytes(&fields, "spenderSubaccount")?,
+            from: Account::from_js(field(&fields, "from")?)?,
+            to: Account::from_js(field(&fields, "to")?)?,
+            token_id: nat(field(&fields, "tokenId")?, "tokenId")?,
+            memo: optional_bytes(&fields, "memo")?,
+            created_at_time: Some(ic_cdk::api::time()),
+        };
+        engine::promise_for(context, async move {
+            Ok(nat_to_js(&icrc7::transfer_from(collection, arg).await?))
+        })
+    }
+
+    fn is_approved<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        let (collection, values) = nft_args(args, 2)?;
+        let fields = options(&values[0])?;
+        let arg = icrc7::IsApprovedArg {
+            spender: Account::from_js(field(&fields, "spender")?)?,
+            from_subaccount: optional_bytes(&fields, "fromSubaccount")?,
+            token_id: nat(field(&fields, "tokenId")?, "tokenId")?,
+        };
+        engine::promise_for(context, async move {
+            Ok(JSValue::Bool(icrc7::is_approved(collection, arg).await?))
+        })
+    }
+
+    let nft = context.object_value()?;
+    nft.set_property("ownerOf", context.wrap_callback2(owner_of)?)?;
+    nft.set_property("tokensOf", context.wrap_callback2(tokens_of)?)?;
+    nft.set_property("tokenMetadata", context.wrap_callback2(token_metadata)?)?;
+    nft.set_property(
+        "collectionMetadata",
+        context.wrap_callback2(collection_metadata)?,
+    )?;
+    nft.set_property("transfer", context.wrap_callback2(transfer)?)?;
+    nft.set_property("approve", context.wrap_callback2(approve)?)?;
+    nft.set_property("transferFrom", context.wrap_callback2(transfer_from)?)?;
+    nft.set_property("isApproved", context.wrap_callback2(is_approved)?)?;
+
+    fn account_transactions<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() < 2 || args.len() > 3 {
+            return Err(
+                JSError::Type(format!("Expected 2 or 3 arguments, got {}", args.len())).into(),
+            );
+        }
+        let index: String = args[0].try_into()?;
+        let index = Principal::from_text(index)?;
+        let account = Account::from_js(&args[1].to_js_value()?)?;
+        let options = match args.get(2) {
+            Some(arg) => options(&arg.to_js_value()?)?,
+            None => HashMap::new(),
+        };
+        let args = index::GetAccountTransactionsArgs {
+            account,
+            start: options
+                .get("start")
+                .map(|start| nat(start, "start"))
+                .transpose()?,
+            max_results: match options.get("maxResults") {
+                Some(max_results) => nat(max_results, "maxResults")?,
+                None => Nat::from(DEFAULT_MAX_RESULTS),
+            },
+        };
+        engine::promise_for(context, async move {
+            let result = index::get_account_transactions(index, args).await?;
+            let transactions = result
+                .transactions
+                .iter()
+                .map(index::TransactionWithId::to_js)
+                .collect();
+            Ok(JSValue::Object(HashMap::from([
+                ("balance".to_string(), nat_to_js(&result.balance)),
+                (
+                    "oldestTxId".to_string(),
+                    result
+                        .oldest_tx_id
+                        .as_ref()
+                        .map(nat_to_js)
+                        .unwrap_or(JSValue::Null),
+                ),
+                ("transactions".to_string(), JSValue::Array(transactions)),
+            ])))
+        })
+    }
+
+    let index = context.object_value()?;
+    index.set_property(
+        "accountTransactions",
+        context.wrap_callback2(account_transactions)?,
+    )?;
+
+    let icrc = context.object_value()?;
+    icrc.set_property("getBlocks", context.wrap_callback2(get_blocks)?)?;
+    icrc.set_property("nft", nft)?;
+    icrc.set_property("index", index)?;
+
+    engine::namespace(context)?.set_property("icrc", icrc)?;
+    Ok(())
+}
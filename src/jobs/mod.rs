@@ -0,0 +1,456 @@
+// A durable job queue stored in stable memory and drained by timers.
+//
+// A job is a call of a global JS function with a JSON payload at the given
+// time. Jobs survive upgrades and traps: before a job is executed, it is moved
+// to the running jobs, where it stays while its JS handler awaits calls. The
+// execution confirms that it started in its own message, so a job that is
+// still unconfirmed after `CONFIRM_TIMEOUT_NANOS` has trapped and is retried.
+// A job that does not complete within `RUN_TIMEOUT_NANOS` is retried too. If
+// the execution succeeds, then the job is removed.
+use std::{borrow::Cow, cell::RefCell, time::Duration};
+
+use candid::{CandidType, Deserialize};
+use ic_cdk_timers::TimerId;
+use ic_stable_structures::{storable::Bound, StableBTreeMap, StableCell, Storable};
+use quickjs_wasm_rs::{CallbackArg, JSContextRef, JSError, JSValue, JSValueRef};
+
+use crate::{
+    dead_letters, engine, json,
+    stable::{self, Candid, Memory},
+    system_api::{self, Level},
+};
+
+// The maximum number of jobs started by a single drain of the queue.
+const MAX_BATCH: usize = 16;
+
+// The maximum delay between two retries of a job.
+const MAX_RETRY_DELAY_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+// The time after which a started job that has not confirmed its execution is
+// considered trapped. Executions start in a zero-delay timer, so this is long.
+const CONFIRM_TIMEOUT_NANOS: u64 = 60 * 1_000_000_000;
+
+// The time after which a running job is retried even though its execution has
+// not completed, e.g. because a callback trapped. It is generous so that jobs
+// awaiting slow calls are not executed twice.
+const RUN_TIMEOUT_NANOS: u64 = 60 * 60 * 1_000_000_000;
+
+/// The retry policy of a job.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct RetryPolicy {
+    /// The maximum number of times the job is executed.
+    pub max_attempts: u32,
+    /// The delay before the first retry. Each subsequent retry doubles it.
+    pub backoff_nanos: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            backoff_nanos: 1_000_000_000,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Returns the delay before the next attempt after the given one.
+    pub fn delay(&self, attempt: u32) -> u64 {
+        let factor = 1_u64
+            .checked_shl(attempt.saturating_sub(1))
+            .unwrap_or(u64::MAX);
+        self.backoff_nanos
+            .saturating_mul(factor)
+            .min(MAX_RETRY_DELAY_NANOS)
+    }
+
+    /// Parses a retry policy from a JS object `{maxAttempts, backoffMs}`.
+    /// Missing fields and `undefined` get the default values.
+    pub fn from_js(value: &JSValue) -> Result<Self, anyhow::Error> {
+        let mut policy = RetryPolicy::default();
+        match value {
+            JSValue::Undefined | JSValue::Null => {}
+            JSValue::Object(fields) => {
+                if let Some(value) = fields.get("maxAttempts") {
+                    policy.max_attempts = number(value, "maxAttempts")? as u32;
+                }
+                if let Some(value) = fields.get("backoffMs") {
+                    policy.backoff_nanos = (number(value, "backoffMs")? * 1e6) as u64;
+                }
+            }
+            _ => {
+                return Err(
+                    JSError::Type("Expected the retry policy to be an object".to_string()).into(),
+                )
+            }
+        }
+        Ok(policy)
+    }
+}
+
+/// A persisted job.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Job {
+    pub id: u64,
+    /// The name of the global JS function that handles the job.
+    pub name: String,
+    /// The JSON-encoded argument of the handler.
+    pub payload: String,
+    /// The number of started executions.
+    pub attempts: u32,
+    pub policy: RetryPolicy,
+    /// The error of the last failed execution.
+    pub last_error: Option<String>,
+}
+
+// Jobs are ordered by their execution time.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+struct JobKey {
+    run_at: u64,
+    id: u64,
+}
+
+// A job whose execution has started but not completed.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct Running {
+    job: Job,
+    started_at: u64,
+    /// Whether the execution got past the synchronous part of the handler.
+    confirmed: bool,
+}
+
+impl Running {
+    // Returns the time when the execution is considered failed.
+    fn expires_at(&self) -> u64 {
+        let timeout = if self.confirmed {
+            RUN_TIMEOUT_NANOS
+        } else {
+            CONFIRM_TIMEOUT_NANOS
+        };
+        self.started_at.saturating_add(timeout)
+    }
+}
+
+thread_local! {
+    // The jobs that wait for their next execution.
+    static JOBS: RefCell<StableBTreeMap<JobKey, Candid<Job>, Memory>> =
+        RefCell::new(StableBTreeMap::init(stable::memory(stable::JOBS)));
+
+    // The jobs whose execution is in flight by id.
+    static RUNNING: RefCell<StableBTreeMap<u64, Candid<Running>, Memory>> =
+        RefCell::new(StableBTreeMap::init(stable::memory(stable::JOBS_RUNNING)));
+
+    // The id of the next enqueued job.
+    static NEXT_ID: RefCell<StableCell<u64, Memory>> =
+        RefCell::new(StableCell::init(stable::memory(stable::JOBS_SEQUENCE), 0).unwrap());
+
+    // The timer that drains the queue and the time when it fires.
+    static DRAIN_TIMER: RefCell<Option<(u64, TimerId)>> = RefCell::new(None);
+}
+
+/// Adds a new job to the queue and returns its id.
+pub fn enqueue(name: String, payload: serde_json::Value, run_at: u64, policy: RetryPolicy) -> u64 {
+    let id = NEXT_ID.with(|next_id| {
+        let mut next_id = next_id.borrow_mut();
+        let id = *next_id.get();
+        next_id.set(id + 1).unwrap();
+        id
+    });
+    let job = Job {
+        id,
+        name,
+        payload: payload.to_string(),
+        attempts: 0,
+        policy,
+        last_error: None,
+    };
+    JOBS.with(|jobs| jobs.borrow_mut().insert(JobKey { run_at, id }, Candid(job)));
+    schedule();
+    id
+}
+
+/// Removes the job with the given id from the queue. Returns true if the job
+/// was found.
+pub fn cancel(id: u64) -> bool {
+    if RUNNING.with(|running| running.borrow_mut().remove(&id).is_some()) {
+        return true;
+    }
+    JOBS.with(|jobs| {
+        let mut jobs = jobs.borrow_mut();
+        let key = jobs.iter().map(|(key, _)| key).find(|key| key.id == id);
+        match key {
+            Some(key) => jobs.remove(&key).is_some(),
+            None => false,
+        }
+    })
+}
+
+/// Arms the timer that drains the queue when the earliest job is due.
+/// Must be called after an upgrade because timers do not survive upgrades.
+pub fn schedule() {
+    let next_job = JOBS.with(|jobs| jobs.borrow().iter().next().map(|(key, _)| key.run_at));
+    let next_expiry = RUNNING.with(|running| {
+        running
+            .borrow()
+            .iter()
+            .map(|(_, Candid(running))| running.expires_at())
+            .min()
+    });
+    let Some(run_at) = next_job.into_iter().chain(next_expiry).min() else {
+        return;
+    };
+    DRAIN_TIMER.with(|timer| {
+        let mut timer = timer.borrow_mut();
+        if let Some((scheduled_at, _)) = *timer {
+            if scheduled_at <= run_at {
+                return;
+            }
+        }
+        if let Some((_, id)) = timer.take() {
+            ic_cdk_timers::clear_timer(id);
+        }
+        let delay = Duration::from_nanos(run_at.saturating_sub(ic_cdk::api::time()));
+        *timer = Some((run_at, ic_cdk_timers::set_timer(delay, drain)));
+    });
+}
+
+// Retries the running jobs that have expired and starts all due jobs, each of
+// them in a separate message such that a trap in one job does not affect the
+// others.
+fn drain() {
+    DRAIN_TIMER.with(|timer| *timer.borrow_mut() = None);
+    let now = ic_cdk::api::time();
+    let expired: Vec<_> = RUNNING.with(|running| {
+        let mut running = running.borrow_mut();
+        let expired: Vec<_> = running
+            .iter()
+            .filter(|(_, Candid(running))| running.expires_at() <= now)
+            .collect();
+        for (id, _) in expired.iter() {
+            running.remove(id);
+        }
+        expired
+    });
+    for (_, Candid(running)) in expired {
+        let err = if running.confirmed {
+            "The attempt did not complete in time (trapped in a callback?)"
+        } else {
+            "The attempt trapped"
+        };
+        fail(running.job, err.to_string());
+    }
+    let due: Vec<_> = JOBS.with(|jobs| {
+        let mut jobs = jobs.borrow_mut();
+        let due: Vec<_> = jobs
+            .iter()
+            .take_while(|(key, _)| key.run_at <= now)
+            .take(MAX_BATCH)
+            .collect();
+        for (key, _) in due.iter() {
+            jobs.remove(key);
+        }
+        due
+    });
+    for (_, Candid(mut job)) in due {
+        if job.attempts >= job.policy.max_attempts {
+            // A job leased before the running jobs were tracked.
+            job.last_error = Some("The last attempt did not complete (trapped)".to_string());
+            give_up(job);
+            continue;
+        }
+        job.attempts += 1;
+        let id = job.id;
+        let running = Running {
+            job,
+            started_at: now,
+            confirmed: false,
+        };
+        RUNNING.with(|jobs| jobs.borrow_mut().insert(id, Candid(running)));
+        ic_cdk_timers::set_timer(Duration::ZERO, move || run(id));
+    }
+    schedule();
+}
+
+// Executes the JS handler of the given running job.
+fn run(id: u64) {
+    let Some(Candid(mut running)) = RUNNING.with(|running| running.borrow().get(&id)) else {
+        // The job was cancelled.
+        return;
+    };
+    // Rolled back if the handler traps before it awaits.
+    running.confirmed = true;
+    RUNNING.with(|jobs| jobs.borrow_mut().insert(id, Candid(running.clone())));
+    let job = running.job;
+    let name = job.name.clone();
+    engine::spawn(
+        &name,
+        move |context| {
+            let payload: serde_json::Value = serde_json::from_str(&job.payload)?;
+            let payload = quickjs_wasm_rs::to_qjs_value(context, &json::from_json(&payload))?;
+            let info = context.object_value()?;
+            info.set_property("id", context.value_from_f64(job.id as f64)?)?;
+            info.set_property("attempt", context.value_from_i32(job.attempts as i32)?)?;
+            Ok(vec![payload, info])
+        },
+        move |_context, result| complete(id, result.map(|_| ()).map_err(|err| err.to_string())),
+    );
+}
+
+// Processes the result of execution of the running job with the given id.
+// Results of executions that have expired or were cancelled are ignored.
+fn complete(id: u64, result: Result<(), String>) {
+    let Some(Candid(running)) = RUNNING.with(|running| running.borrow_mut().remove(&id)) else {
+        return;
+    };
+    if let Err(err) = result {
+        fail(running.job, err);
+    }
+    schedule();
+}
+
+// Retries the job after a failed attempt or gives up on it.
+fn fail(mut job: Job, err: String) {
+    job.last_error = Some(err);
+    if job.attempts >= job.policy.max_attempts {
+        give_up(job);
+        return;
+    }
+    let key = JobKey {
+        run_at: ic_cdk::api::time().saturating_add(job.policy.delay(job.attempts)),
+        id: job.id,
+    };
+    JOBS.with(|jobs| jobs.borrow_mut().insert(key, Candid(job)));
+}
+
+// Moves the job that has exhausted all of its attempts to the dead letters.
+fn give_up(job: Job) {
+    system_api::log(
+        Level::Error,
+        Some("jobs"),
+        &[
+            format!(
+                "Job {} ({}) failed after {} attempts:",
+                job.id, job.name, job.policy.max_attempts
+            )
+            .into(),
+            job.last_error.clone().unwrap_or_default().into(),
+        ],
+    );
+    dead_letters::put(job);
+}
+
+pub fn link(context: &JSContextRef) -> Result<(), anyhow::Error> {
+    // Usage: `ic.jobs.enqueue(name, payload, runAt, {maxAttempts, backoffMs})`
+    // where `runAt` is a timestamp in milliseconds like `Date.now()`.
+    fn enqueue<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() < 2 || args.len() > 4 {
+            return Err(
+                JSError::Type(format!("Expected 2 to 4 arguments, got {}", args.len())).into(),
+            );
+        }
+        engine::ensure_writable("ic.jobs.enqueue()")?;
+        let name: String = args[0].try_into()?;
+        let payload = json::to_json(&args[1].to_js_value()?);
+        let run_at = run_at(args.get(2).map(|arg| arg.to_js_value()).transpose()?)?;
+        let policy = match args.get(3) {
+            Some(arg) => RetryPolicy::from_js(&arg.to_js_value()?)?,
+            None => RetryPolicy::default(),
+        };
+        let id = self::enqueue(name, payload, run_at, policy);
+        context.value_from_f64(id as f64)
+    }
+
+    // Usage: `ic.schedule.at(timestamp, handlerName, payload)`. A shorthand
+    // for `ic.jobs.enqueue()` with the default retry policy.
+    fn at<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() < 2 || args.len() > 3 {
+            return Err(
+                JSError::Type(format!("Expected 2 or 3 arguments, got {}", args.len())).into(),
+            );
+        }
+        engine::ensure_writable("ic.schedule.at()")?;
+        let run_at = run_at(Some(args[0].to_js_value()?))?;
+        let name: String = args[1].try_into()?;
+        let payload = match args.get(2) {
+            Some(arg) => json::to_json(&arg.to_js_value()?),
+            None => serde_json::Value::Null,
+        };
+        let id = self::enqueue(name, payload, run_at, RetryPolicy::default());
+        context.value_from_f64(id as f64)
+    }
+
+    fn cancel<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 1 {
+            return Err(JSError::Type(format!("Expected 1 argument, got {}", args.len())).into());
+        }
+        engine::ensure_writable("Canceling a job")?;
+        let id = number(&args[0].to_js_value()?, "id")?;
+        context.value_from_bool(self::cancel(id as u64))
+    }
+
+    let jobs = context.object_value()?;
+    jobs.set_property("enqueue", context.wrap_callback2(enqueue)?)?;
+    jobs.set_property("cancel", context.wrap_callback2(cancel)?)?;
+
+    engine::namespace(context)?.set_property("jobs", jobs)?;
+
+    let schedule = context.object_value()?;
+    schedule.set_property("at", context.wrap_callback2(at)?)?;
+    schedule.set_property("cancel", context.wrap_callback2(cancel)?)?;
+
+    engine::namespace(context)?.set_property("schedule", schedule)?;
+    Ok(())
+}
+
+// Converts a timestamp in milliseconds to nanoseconds. A missing timestamp
+// means now.
+fn run_at(value: Option<JSValue>) -> Result<u64, anyhow::Error> {
+    match value {
+        None | Some(JSValue::Undefined) | Some(JSValue::Null) => Ok(ic_cdk::api::time()),
+        Some(JSValue::Int(millis)) => Ok((millis.max(0) as u64).saturating_mul(1_000_000)),
+        Some(JSValue::Float(millis)) => Ok((millis.max(0.0) * 1e6) as u64),
+        Some(_) => Err(JSError::Type("Expected the time to be a number".to_string()).into()),
+    }
+}
+
+/// A helper that extracts a non-negative number from a JS value.
+pub fn number(value: &JSValue, name: &str) -> Result<f64, anyhow::Error> {
+    match value {
+        JSValue::Int(value) if *value >= 0 => Ok(*value as f64),
+        JSValue::Float(value) if *value >= 0.0 => Ok(*value),
+        _ => Err(JSError::Type(format!("Expected {} to be a non-negative number", name)).into()),
+    }
+}
+
+impl Storable for JobKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut bytes = Vec::with_capacity(16);
+        bytes.extend_from_slice(&self.run_at.to_be_bytes());
+        bytes.extend_from_slice(&self.id.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self {
+            run_at: u64::from_be_bytes(bytes[0..8].try_into().unwrap()),
+            id: u64::from_be_bytes(bytes[8..16].try_into().unwrap()),
+        }
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 16,
+        is_fixed_size: true,
+    };
+}
@@ -0,0 +1,95 @@
+use quickjs_wasm_rs::{CallbackArg, JSContextRef, JSError, JSValue, JSValueRef};
+use serde_json::{Number, Value};
+
+use crate::{crypto, engine};
+
+/// Converts the given JS value into a JSON value.
+///
+/// `undefined` becomes `null` and array buffers become arrays of bytes.
+/// Numbers that cannot be represented in JSON (NaN, infinities) become `null`.
+pub fn to_json(value: &JSValue) -> Value {
+    match value {
+        JSValue::Undefined | JSValue::Null => Value::Null,
+        JSValue::Bool(value) => Value::Bool(*value),
+        JSValue::Int(value) => Value::from(*value),
+        JSValue::Float(value) => Number::from_f64(*value)
+            .map(Value::Number)
+            .unwrap_or(Value::Null),
+        JSValue::String(value) => Value::String(value.clone()),
+        JSValue::Array(values) => Value::Array(values.iter().map(to_json).collect()),
+        JSValue::ArrayBuffer(bytes) => {
+            Value::Array(bytes.iter().map(|b| Value::from(*b)).collect())
+        }
+        JSValue::Object(fields) => Value::Object(
+            fields
+                .iter()
+                .map(|(key, value)| (key.clone(), to_json(value)))
+                .collect(),
+        ),
+    }
+}
+
+/// Converts the given JSON value into a JS value.
+///
+/// Integers that fit into 32 bits are represented as JS integers, all other
+/// numbers are represented as JS floats.
+pub fn from_json(value: &Value) -> JSValue {
+    match value {
+        Value::Null => JSValue::Null,
+        Value::Bool(value) => JSValue::Bool(*value),
+        Value::Number(value) => match value.as_i64().map(i32::try_from) {
+            Some(Ok(value)) => JSValue::Int(value),
+            _ => JSValue::Float(value.as_f64().unwrap_or(f64::NAN)),
+        },
+        Value::String(value) => JSValue::String(value.clone()),
+        Value::Array(values) => JSValue::Array(values.iter().map(from_json).collect()),
+        Value::Object(fields) => JSValue::Object(
+            fields
+                .iter()
+                .map(|(key, value)| (key.clone(), from_json(value)))
+                .collect(),
+        ),
+    }
+}
+
+/// Parses the given JSON text directly into a JS value.
+pub fn parse<'a>(context: &'a JSContextRef, text: &[u8]) -> Result<JSValueRef<'a>, anyhow::Error> {
+    let value: Value = serde_json::from_slice(text)?;
+    quickjs_wasm_rs::to_qjs_value(context, &from_json(&value))
+}
+
+// Usage: `ic.json.parseFast(textOrArrayBuffer)` and `ic.json.stringifyFast(value)`.
+// They follow `to_json()` and `from_json()` rather than the JS semantics, e.g.
+// there is no reviver, replacer or `toJSON()`, but they cost far fewer
+// instructions than `JSON.parse()` and `JSON.stringify()` on large payloads.
+pub fn link(context: &JSContextRef) -> Result<(), anyhow::Error> {
+    fn parse_fast<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 1 {
+            return Err(JSError::Type(format!("Expected 1 argument, got {}", args.len())).into());
+        }
+        let text = crypto::bytes(&args[0])?;
+        parse(context, &text).map_err(|err| JSError::Syntax(err.to_string()).into())
+    }
+
+    fn stringify_fast<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 1 {
+            return Err(JSError::Type(format!("Expected 1 argument, got {}", args.len())).into());
+        }
+        let value = to_json(&args[0].to_js_value()?);
+        context.value_from_str(&value.to_string())
+    }
+
+    let json = context.object_value()?;
+    json.set_property("parseFast", context.wrap_callback2(parse_fast)?)?;
+    json.set_property("stringifyFast", context.wrap_callback2(stringify_fast)?)?;
+    engine::namespace(context)?.set_property("json", json)?;
+    Ok(())
+}
@@ -0,0 +1,14 @@
+// Runs the JSON-RPC calls validated by jsonrpc/mod.rs concurrently and returns
+// one `{result}` or `{error}` object per call.
+Object.defineProperty(globalThis, "__jsonRpcDispatch__", {
+	enumerable: false,
+	value: async function (calls) {
+		const results = await Promise.allSettled(calls.map(async (call) => {
+			const params = call.params === null ? [] : Array.isArray(call.params) ? call.params : [call.params];
+			return await __endpoints__[call.method](...params);
+		}));
+		return results.map((result) => result.status === "fulfilled"
+			? { result: result.value === undefined ? null : result.value }
+			: { error: String(result.reason && result.reason.message || result.reason) });
+	},
+});
@@ -0,0 +1,245 @@
+// A JSON-RPC 2.0 server at `/rpc` that dispatches to the endpoints registered
+// with `ic.registerUpdate()` and `ic.registerQuery()`.
+//
+// Positional params are passed as arguments of the handler and named params
+// as a single object argument. Params are validated against the schema of the
+// method defined with `ic.schemas.define(method, schema)` as a JSON array of
+// arguments. Requests that call only query endpoints are answered in the
+// `http_request` query. Other requests are upgraded to update calls. Methods
+// marked with `ic.auth.rejectAnonymous(method)` require a bearer token and
+// calls of methods over their quota fail with the limit exceeded error.
+use ic_cdk::api::call::ManualReply;
+use quickjs_wasm_rs::JSContextRef;
+use serde_json::{json, Value};
+
+use crate::{
+    auth::{middleware, Identity},
+    endpoints::{self, Kind},
+    engine,
+    http::{HttpRequest, HttpResponse},
+    json, quotas, redaction, schemas,
+};
+
+/// The HTTP path of the JSON-RPC endpoint.
+pub const PATH: &str = "/rpc";
+
+// The dispatcher of validated calls defined in jsonrpc.js.
+const DISPATCHER_FILE: &str = "jsonrpc.js";
+const DISPATCHER_SCRIPT: &str = include_str!("jsonrpc.js");
+const DISPATCH: &str = "__jsonRpcDispatch__";
+
+// The error codes defined by the JSON-RPC 2.0 specification.
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const INTERNAL_ERROR: i64 = -32603;
+// The error code of exceptions thrown by handlers.
+const SERVER_ERROR: i64 = -32000;
+// The error code of calls rejected by the auth middleware.
+const UNAUTHORIZED: i64 = -32001;
+// The error code of calls over their quota.
+const LIMIT_EXCEEDED: i64 = -32005;
+
+// An entry of a request: either an error found during validation or a call
+// to run. Notifications have no id and get no response.
+enum Entry {
+    Error {
+        id: Value,
+        code: i64,
+        message: String,
+    },
+    Call {
+        id: Option<Value>,
+        method: String,
+        params: Value,
+    },
+}
+
+fn parse(body: &[u8]) -> Result<(Vec<Entry>, bool), Value> {
+    let body: Value = serde_json::from_slice(body)
+        .map_err(|err| error(Value::Null, PARSE_ERROR, &err.to_string()))?;
+    match body {
+        Value::Array(requests) if requests.is_empty() => {
+            Err(error(Value::Null, INVALID_REQUEST, "The batch is empty"))
+        }
+        Value::Array(requests) => Ok((requests.iter().map(entry).collect(), true)),
+        request => Ok((vec![entry(&request)], false)),
+    }
+}
+
+fn entry(request: &Value) -> Entry {
+    let id = request.get("id").cloned();
+    let invalid = |message: &str| Entry::Error {
+        id: id.clone().unwrap_or(Value::Null),
+        code: INVALID_REQUEST,
+        message: message.to_string(),
+    };
+    if request.get("jsonrpc") != Some(&json!("2.0")) {
+        return invalid("The jsonrpc field must be \"2.0\"");
+    }
+    let Some(method) = request.get("method").and_then(|method| method.as_str()) else {
+        return invalid("The method field must be a string");
+    };
+    let params = request.get("params").cloned().unwrap_or(Value::Null);
+    if !matches!(params, Value::Array(_) | Value::Object(_) | Value::Null) {
+        return invalid("The params field must be an array or an object");
+    }
+    if endpoints::kind(method).is_none() {
+        return Entry::Error {
+            id: id.unwrap_or(Value::Null),
+            code: METHOD_NOT_FOUND,
+            message: format!("Unknown method {}", method),
+        };
+    }
+    let args = match &params {
+        Value::Array(params) => Value::Array(params.clone()),
+        Value::Null => Value::Array(vec![]),
+        params => Value::Array(vec![params.clone()]),
+    };
+    if let Err(issues) = schemas::validate(method, &args) {
+        return Entry::Error {
+            id: id.unwrap_or(Value::Null),
+            code: INVALID_PARAMS,
+            message: schemas::describe(&issues),
+        };
+    }
+    Entry::Call {
+        id,
+        method: method.to_string(),
+        params,
+    }
+}
+
+fn error(id: Value, code: i64, message: &str) -> Value {
+    json!({"jsonrpc": "2.0", "id": id, "error": {"code": code, "message": message}})
+}
+
+/// Returns true if the request calls only query endpoints, so that it can be
+/// answered in a query call.
+pub fn is_read_only(request: &HttpRequest) -> bool {
+    match parse(&request.body) {
+        Ok((entries, _)) => entries.iter().all(|entry| match entry {
+            Entry::Error { .. } => true,
+            Entry::Call { method, .. } => {
+                endpoints::kind(method) == Some(Kind::Query) && !quotas::is_limited(method)
+            }
+        }),
+        Err(_) => true,
+    }
+}
+
+/// Handles a JSON-RPC request over HTTP sent by the given identity.
+pub fn handle(request: &HttpRequest, identity: &Identity) -> ManualReply<HttpResponse> {
+    if request.method.to_uppercase() != "POST" {
+        return ManualReply::one(HttpResponse::error(
+            405,
+            "method_not_allowed",
+            "JSON-RPC requests must use POST",
+        ));
+    }
+    let (entries, is_batch) = match parse(&request.body) {
+        Ok(parsed) => parsed,
+        Err(response) => return ManualReply::one(HttpResponse::json(200, &response)),
+    };
+    let entries: Vec<Entry> = entries
+        .into_iter()
+        .map(|entry| match entry {
+            Entry::Call { id, method, params } => {
+                let checked = middleware::check(&method, identity)
+                    .map_err(|message| (UNAUTHORIZED, message))
+                    .and_then(|()| {
+                        quotas::consume(&method, identity)
+                            .map_err(|err| (LIMIT_EXCEEDED, err.to_string()))
+                    });
+                match checked {
+                    Ok(()) => Entry::Call { id, method, params },
+                    Err((code, message)) => Entry::Error {
+                        id: id.unwrap_or(Value::Null),
+                        code,
+                        message,
+                    },
+                }
+            }
+            entry => entry,
+        })
+        .collect();
+    let calls: Vec<Value> = entries
+        .iter()
+        .filter_map(|entry| match entry {
+            Entry::Call { method, params, .. } => Some(json!({"method": method, "params": params})),
+            Entry::Error { .. } => None,
+        })
+        .collect();
+    if calls.is_empty() {
+        return ManualReply::one(respond(entries, vec![], is_batch));
+    }
+    engine::execute(
+        DISPATCH,
+        move |context| {
+            Ok(vec![quickjs_wasm_rs::to_qjs_value(
+                context,
+                &json::from_json(&Value::Array(calls)),
+            )?])
+        },
+        move |_context, result| {
+            let results = result
+                .and_then(|value| Ok(json::to_json(&quickjs_wasm_rs::from_qjs_value(&value)?)));
+            match results {
+                Ok(Value::Array(results)) => ManualReply::one(respond(entries, results, is_batch)),
+                Ok(_) => ManualReply::one(HttpResponse::json(
+                    200,
+                    &error(
+                        Value::Null,
+                        INTERNAL_ERROR,
+                        "Unexpected result of the dispatcher",
+                    ),
+                )),
+                Err(err) => ManualReply::one(HttpResponse::json(
+                    200,
+                    &error(
+                        Value::Null,
+                        INTERNAL_ERROR,
+                        &redaction::redact(&err.to_string()),
+                    ),
+                )),
+            }
+        },
+    )
+}
+
+// Merges the validation errors and the results of the calls in the order of
+// the requests.
+fn respond(entries: Vec<Entry>, results: Vec<Value>, is_batch: bool) -> HttpResponse {
+    let mut results = results.into_iter();
+    let responses: Vec<Value> = entries
+        .into_iter()
+        .filter_map(|entry| match entry {
+            Entry::Error { id, code, message } => Some(error(id, code, &message)),
+            Entry::Call { id, .. } => {
+                let result = results.next().unwrap_or(Value::Null);
+                let id = id?;
+                Some(match result.get("error") {
+                    Some(message) => error(id, SERVER_ERROR, message.as_str().unwrap_or_default()),
+                    None => json!({"jsonrpc": "2.0", "id": id, "result": result["result"]}),
+                })
+            }
+        })
+        .collect();
+    match (is_batch, responses.len()) {
+        (_, 0) => HttpResponse {
+            status_code: 204,
+            headers: vec![],
+            body: vec![],
+            upgrade: None,
+            streaming_strategy: None,
+        },
+        (false, _) => HttpResponse::json(200, &responses[0]),
+        (true, _) => HttpResponse::json(200, &Value::Array(responses)),
+    }
+}
+
+pub fn link(context: &JSContextRef) -> Result<(), anyhow::Error> {
+    context.eval_global(DISPATCHER_FILE, DISPATCHER_SCRIPT)?;
+    Ok(())
+}
@@ -0,0 +1,143 @@
+// Encryption at rest of KV namespaces.
+//
+// Values of keys that start with an encrypted namespace are encrypted with
+// AES-256-GCM before they are stored. The AES key is derived from a vetKey of
+// the canister, which is fetched once per start in a timer and cached on the
+// heap, so the same key is recovered after upgrades. Until it is ready, writes
+// and reads of encrypted entries fail.
+//
+// An encrypted value is stored as `~` followed by the base64 encoding of the
+// nonce and the ciphertext. JSON text never starts with `~`, so encrypted
+// values are recognized without the configuration.
+use std::{cell::RefCell, time::Duration};
+
+use aes_gcm::{aead::Aead, Aes256Gcm, KeyInit, Nonce};
+use base64::{engine::general_purpose::STANDARD, Engine};
+
+use crate::{
+    crypto,
+    system_api::{self, Level},
+    vetkd,
+};
+
+// Separates the KV key from other vetKD keys of the canister.
+const DERIVATION_CONTEXT: &[u8] = b"ic-quickjs-kv";
+const DERIVATION_INPUT: &[u8] = b"aes-256-gcm";
+
+const MARKER: char = '~';
+const NONCE_SIZE: usize = 12;
+
+// The delay before retrying a failed key derivation.
+const RETRY_DELAY: Duration = Duration::from_secs(60);
+
+struct Cipher {
+    aes: Aes256Gcm,
+    key: Vec<u8>,
+    // Together with the time, makes each nonce unique. Within one round the
+    // time does not change, and it advances across upgrades.
+    counter: u64,
+}
+
+thread_local! {
+    static NAMESPACES: RefCell<Vec<String>> = RefCell::new(vec![]);
+
+    static CIPHER: RefCell<Option<Cipher>> = RefCell::new(None);
+
+    static IS_DERIVING: RefCell<bool> = RefCell::new(false);
+}
+
+/// Encrypts the values of all keys that start with the given prefix.
+pub fn encrypt_namespace(prefix: String) {
+    NAMESPACES.with(|namespaces| namespaces.borrow_mut().push(prefix));
+    schedule_derivation(Duration::ZERO);
+}
+
+pub fn is_encrypted_key(key: &str) -> bool {
+    NAMESPACES.with(|namespaces| {
+        namespaces
+            .borrow()
+            .iter()
+            .any(|prefix| key.starts_with(prefix.as_str()))
+    })
+}
+
+pub fn is_encrypted_value(value: &str) -> bool {
+    value.starts_with(MARKER)
+}
+
+fn schedule_derivation(delay: Duration) {
+    let is_ready = CIPHER.with(|cipher| cipher.borrow().is_some());
+    if is_ready || IS_DERIVING.with(|flag| flag.replace(true)) {
+        return;
+    }
+    ic_cdk_timers::set_timer(delay, || ic_cdk::spawn(derive()));
+}
+
+async fn derive() {
+    let result = vetkd::own_key(DERIVATION_CONTEXT, DERIVATION_INPUT.to_vec()).await;
+    IS_DERIVING.with(|flag| *flag.borrow_mut() = false);
+    match result {
+        Ok(vetkey) => {
+            let key = vetkey.derive_symmetric_key("ic-quickjs-kv-aes-256-gcm", 32);
+            let aes = Aes256Gcm::new_from_slice(&key).unwrap();
+            CIPHER.with(|cipher| {
+                *cipher.borrow_mut() = Some(Cipher {
+                    aes,
+                    key,
+                    counter: 0,
+                })
+            });
+        }
+        Err(err) => {
+            system_api::log(
+                Level::Error,
+                Some("kv"),
+                &[format!("Failed to derive the KV key: {}", err).into()],
+            );
+            schedule_derivation(RETRY_DELAY);
+        }
+    }
+}
+
+fn with_cipher<T>(
+    f: impl FnOnce(&mut Cipher) -> Result<T, anyhow::Error>,
+) -> Result<T, anyhow::Error> {
+    CIPHER.with(|cipher| match cipher.borrow_mut().as_mut() {
+        Some(cipher) => f(cipher),
+        None => anyhow::bail!("The KV encryption key is not ready yet, retry later"),
+    })
+}
+
+pub fn encrypt(plaintext: &str) -> Result<String, anyhow::Error> {
+    with_cipher(|cipher| {
+        cipher.counter += 1;
+        let mut input = cipher.key.clone();
+        input.extend_from_slice(&ic_cdk::api::time().to_be_bytes());
+        input.extend_from_slice(&cipher.counter.to_be_bytes());
+        let nonce = crypto::sha256(&input)[..NONCE_SIZE].to_vec();
+        let ciphertext = cipher
+            .aes
+            .encrypt(Nonce::from_slice(&nonce), plaintext.as_bytes())
+            .map_err(|err| anyhow::anyhow!("Failed to encrypt: {}", err))?;
+        Ok(format!(
+            "{}{}",
+            MARKER,
+            STANDARD.encode([nonce, ciphertext].concat())
+        ))
+    })
+}
+
+pub fn decrypt(stored: &str) -> Result<String, anyhow::Error> {
+    let bytes = STANDARD.decode(&stored[MARKER.len_utf8()..])?;
+    if bytes.len() < NONCE_SIZE {
+        anyhow::bail!("The encrypted value is too short");
+    }
+    let (nonce, ciphertext) = bytes.split_at(NONCE_SIZE);
+    with_cipher(|cipher| {
+        let plaintext = cipher
+            .aes
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|err| anyhow::anyhow!("Failed to decrypt: {}", err))?;
+        Ok(String::from_utf8(plaintext)?)
+    })
+}
@@ -0,0 +1,51 @@
+// Usage: `ic.kv.defineIndex(name, (value, key) => terms)` when the script is
+// loaded, where the terms are a string, an array of strings or null. Every
+// `ic.kv.set` and `ic.kv.casSet` passes the terms of all indexes to Rust,
+// which replaces the index entries of the key. The backfill job indexes the
+// other entries in batches.
+(function () {
+	// The number of entries indexed by one run of the backfill job.
+	const BACKFILL_BATCH = 100;
+
+	const extractors = new Map();
+	const {defineIndex, set, casSet, unindexed, reindex, finishBackfill} = ic.kv;
+	delete ic.kv.unindexed;
+	delete ic.kv.reindex;
+	delete ic.kv.finishBackfill;
+
+	function terms(key, value) {
+		const pairs = [];
+		for (const [name, extract] of extractors) {
+			const result = extract(value, key);
+			for (const term of Array.isArray(result) ? result : [result]) {
+				if (term !== null && term !== undefined) {
+					pairs.push([name, String(term)]);
+				}
+			}
+		}
+		return pairs;
+	}
+
+	ic.kv.defineIndex = (name, extract) => {
+		if (typeof extract !== "function") {
+			throw new TypeError("Expected the extract function of the index");
+		}
+		defineIndex(name);
+		extractors.set(name, extract);
+	};
+
+	// The handler of the backfill job queued by Rust.
+	Object.defineProperty(globalThis, "__kvIndexBackfill__", {
+		value() {
+			const {keys, next} = unindexed(BACKFILL_BATCH);
+			for (const key of keys) {
+				const value = ic.kv.get(key);
+				reindex(key, value === undefined ? [] : terms(key, value));
+			}
+			finishBackfill(next);
+		},
+	});
+
+	ic.kv.set = (key, value) => set(key, value, terms(key, value));
+	ic.kv.casSet = (key, value, expectedVersion) => casSet(key, value, expectedVersion, terms(key, value));
+})();
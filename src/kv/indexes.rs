@@ -0,0 +1,227 @@
+// Secondary indexes of KV entries.
+//
+// An index maps terms to the keys of the entries that contain them. The terms
+// of an entry are computed in JS by the extract functions of indexes.js on
+// every write and passed here as `[index, term]` pairs, which replace the
+// previous terms of the key. Deleting an entry removes its terms.
+//
+// An index entry is stored under `index \0 term \0 key`, so the keys of a term
+// are found with a range scan in key order. The terms of each key are stored
+// too, so that a write or a deletion finds the index entries to remove.
+//
+// The entries that were written before an index was defined and the entries
+// written by Rust code, e.g. by replication or a shard call, are indexed by a
+// backfill job in batches. A new index restarts the backfill from the first
+// key, and a Rust-side write removes the terms of the key and marks it as
+// stale until the job recomputes them. Until then, lookups miss these entries.
+use std::{cell::RefCell, collections::BTreeSet, ops::Bound};
+
+use candid::{CandidType, Deserialize};
+use ic_stable_structures::{StableBTreeMap, StableCell};
+
+use crate::{
+    jobs::{self, RetryPolicy},
+    stable::{self, Candid, Memory},
+};
+
+const SEPARATOR: char = '\0';
+
+// The maximum size of an index name and of a term in bytes.
+const MAX_NAME_SIZE: usize = 64;
+const MAX_TERM_SIZE: usize = 1024;
+
+// The global JS function of indexes.js that indexes a batch of entries.
+const BACKFILL_JOB: &str = "__kvIndexBackfill__";
+
+// The progress of the backfill.
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+struct Backfill {
+    // The indexes defined so far, so that a new one restarts the backfill.
+    known: Vec<String>,
+    // The key from which the entries are still to be indexed.
+    cursor: Option<String>,
+    // Whether a backfill job is queued.
+    scheduled: bool,
+}
+
+thread_local! {
+    // The indexes defined by the script, which defines them again after an
+    // upgrade.
+    static DEFINED: RefCell<BTreeSet<String>> = RefCell::new(BTreeSet::new());
+
+    static ENTRIES: RefCell<StableBTreeMap<String, String, Memory>> =
+        RefCell::new(StableBTreeMap::init(stable::memory(stable::KV_INDEX_ENTRIES)));
+
+    static TERMS: RefCell<StableBTreeMap<String, Candid<Vec<(String, String)>>, Memory>> =
+        RefCell::new(StableBTreeMap::init(stable::memory(stable::KV_INDEX_TERMS)));
+
+    static BACKFILL: RefCell<StableCell<Candid<Backfill>, Memory>> = RefCell::new(
+        StableCell::init(stable::memory(stable::KV_INDEX_BACKFILL), Candid(Backfill::default()))
+            .unwrap(),
+    );
+
+    // The keys written by Rust code and the times of the writes.
+    static STALE: RefCell<StableBTreeMap<String, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(stable::memory(stable::KV_INDEX_STALE)));
+}
+
+/// Defines an index. Entries written before are indexed by the backfill job.
+pub fn define(name: String) -> Result<(), String> {
+    if name.is_empty() || name.len() > MAX_NAME_SIZE || name.contains(SEPARATOR) {
+        return Err(format!("Invalid index name {:?}", name));
+    }
+    DEFINED.with(|defined| defined.borrow_mut().insert(name.clone()));
+    let is_new = update_backfill(|backfill| {
+        if backfill.known.contains(&name) {
+            return false;
+        }
+        backfill.known.push(name);
+        backfill.cursor = Some(String::new());
+        true
+    });
+    if is_new {
+        schedule_backfill();
+    }
+    Ok(())
+}
+
+/// Checks that the `[index, term]` pairs can be stored.
+pub fn validate(terms: &[(String, String)]) -> Result<(), String> {
+    for (index, term) in terms.iter() {
+        if !is_defined(index) {
+            return Err(format!("The index {} is not defined", index));
+        }
+        if term.len() > MAX_TERM_SIZE || term.contains(SEPARATOR) {
+            return Err(format!("Invalid term of the index {}", index));
+        }
+    }
+    Ok(())
+}
+
+/// Replaces the `[index, term]` pairs of the key.
+pub fn update(key: &str, terms: Vec<(String, String)>) -> Result<(), String> {
+    validate(&terms)?;
+    remove(key);
+    let terms: Vec<(String, String)> = terms
+        .into_iter()
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect();
+    if terms.is_empty() {
+        return Ok(());
+    }
+    ENTRIES.with(|entries| {
+        let mut entries = entries.borrow_mut();
+        for (index, term) in terms.iter() {
+            entries.insert(entry_key(index, term, key), key.to_string());
+        }
+    });
+    TERMS.with(|keys| keys.borrow_mut().insert(key.to_string(), Candid(terms)));
+    Ok(())
+}
+
+/// Removes the index entries of the key written by Rust code, which cannot
+/// compute its terms, and queues the key for the backfill job.
+pub fn mark_stale(key: &str) {
+    if DEFINED.with(|defined| defined.borrow().is_empty()) {
+        return;
+    }
+    remove(key);
+    STALE.with(|stale| {
+        stale
+            .borrow_mut()
+            .insert(key.to_string(), ic_cdk::api::time())
+    });
+    schedule_backfill();
+}
+
+/// Removes the index entries of the key.
+pub fn remove(key: &str) {
+    STALE.with(|stale| stale.borrow_mut().remove(&key.to_string()));
+    let Some(Candid(terms)) = TERMS.with(|keys| keys.borrow_mut().remove(&key.to_string())) else {
+        return;
+    };
+    ENTRIES.with(|entries| {
+        let mut entries = entries.borrow_mut();
+        for (index, term) in terms.iter() {
+            entries.remove(&entry_key(index, term, key));
+        }
+    });
+}
+
+/// Returns up to `limit` keys of the entries with the term in the index.
+pub fn lookup(index: &str, term: &str, limit: usize, reverse: bool) -> Result<Vec<String>, String> {
+    if !is_defined(index) {
+        return Err(format!("The index {} is not defined", index));
+    }
+    let prefix = format!("{}{}{}{}", index, SEPARATOR, term, SEPARATOR);
+    // The separator is the smallest character, so the next one ends the range.
+    let end = format!("{}{}{}\u{1}", index, SEPARATOR, term);
+    Ok(ENTRIES.with(|entries| {
+        let entries = entries.borrow();
+        let range = entries.range((Bound::Included(prefix), Bound::Excluded(end)));
+        if reverse {
+            range.rev().take(limit).map(|(_, key)| key).collect()
+        } else {
+            range.take(limit).map(|(_, key)| key).collect()
+        }
+    }))
+}
+
+/// Starts a batch of the backfill. Returns up to `limit` stale keys and the
+/// key from which the entries are still to be indexed.
+pub fn backfill_batch(limit: usize) -> (Vec<String>, Option<String>) {
+    let cursor = update_backfill(|backfill| {
+        backfill.scheduled = false;
+        backfill.cursor.clone()
+    });
+    let keys = STALE.with(|stale| {
+        stale
+            .borrow()
+            .iter()
+            .take(limit)
+            .map(|(key, _)| key)
+            .collect()
+    });
+    (keys, cursor)
+}
+
+/// Ends a batch of the backfill and queues the next one if entries remain.
+pub fn finish_backfill_batch(cursor: Option<String>) {
+    let is_done = cursor.is_none();
+    update_backfill(|backfill| backfill.cursor = cursor);
+    if !is_done || STALE.with(|stale| !stale.borrow().is_empty()) {
+        schedule_backfill();
+    }
+}
+
+// Queues a backfill job unless one is queued.
+fn schedule_backfill() {
+    let is_scheduled = update_backfill(|backfill| std::mem::replace(&mut backfill.scheduled, true));
+    if !is_scheduled {
+        jobs::enqueue(
+            BACKFILL_JOB.to_string(),
+            serde_json::Value::Null,
+            ic_cdk::api::time(),
+            RetryPolicy::default(),
+        );
+    }
+}
+
+fn update_backfill<T>(f: impl FnOnce(&mut Backfill) -> T) -> T {
+    BACKFILL.with(|cell| {
+        let mut cell = cell.borrow_mut();
+        let mut backfill = cell.get().0.clone();
+        let result = f(&mut backfill);
+        cell.set(Candid(backfill)).unwrap();
+        result
+    })
+}
+
+fn is_defined(index: &str) -> bool {
+    DEFINED.with(|defined| defined.borrow().contains(index))
+}
+
+fn entry_key(index: &str, term: &str, key: &str) -> String {
+    format!("{}{}{}{}{}", index, SEPARATOR, term, SEPARATOR, key)
+}
@@ -0,0 +1,743 @@
+// A key-value store of JSON values in stable memory exposed to JS as `ic.kv`.
+//
+// The contents of the store can be replicated to a sibling canister running
+// the same Wasm module, e.g. to keep a standby replica or to migrate to another
+// subnet. The replica accepts entries only from the configured source canister.
+//
+// `ic.kv.encrypt(prefix)` encrypts the values of keys with the given prefix at
+// rest. Replicas receive the ciphertexts, which they cannot decrypt because the
+// key is derived per canister.
+//
+// Every write bumps the version of the key. Handlers that read, await and
+// write use `ic.kv.getWithVersion(key)` and `ic.kv.casSet(key, value,
+// version)` to detect writes of other messages in between.
+//
+// `ic.kv.range(startKey, endKey, limit, {reverse})` and `ic.kv.prefixScan(
+// prefix, {limit, reverse})` return `[key, value]` pairs in key order, which
+// is the order of the UTF-8 bytes of the keys. The start key is included and
+// the end key is excluded, so the next page of a forward scan starts at the
+// last key followed by `"\0"` and the next page of a reverse scan ends at the
+// last key.
+//
+// `ic.kv.defineIndex("byOwner", (value, key) => value.owner)` maintains a
+// secondary index of the terms returned by the function, a string, an array of
+// strings or null, and `ic.kv.lookup("byOwner", owner, {limit, reverse})`
+// returns the `[key, value]` pairs with the term in key order. Entries written
+// before the index was defined or by Rust code are indexed by a background
+// job. See indexes.rs.
+//
+// The JS functions store values in the format of `ic.persist` from persist.js,
+// so Maps, Sets, Dates, BigInts and typed arrays survive a round trip.
+use std::{cell::RefCell, ops::Bound};
+
+use candid::{CandidType, Deserialize, Principal};
+use ic_stable_structures::{StableBTreeMap, StableCell};
+use quickjs_wasm_rs::{CallbackArg, JSContextRef, JSError, JSValue, JSValueRef};
+
+use crate::{
+    call_policy, engine, jobs, json, replicas,
+    stable::{self, Candid, Memory},
+};
+
+mod encryption;
+mod indexes;
+
+// The codec of values that JSON cannot represent, which wraps `ic.kv.get` and
+// `ic.kv.set`.
+const PERSIST_FILE: &str = "persist.js";
+const PERSIST_SCRIPT: &str = include_str!("persist.js");
+
+// The extract functions of the indexes, which wrap the writes.
+const INDEXES_FILE: &str = "indexes.js";
+const INDEXES_SCRIPT: &str = include_str!("indexes.js");
+
+// The maximum size of a key and a JSON-encoded value in bytes.
+const MAX_KEY_SIZE: usize = 1024;
+const MAX_VALUE_SIZE: usize = 1024 * 1024;
+
+// The default and maximum number of entries returned by a scan.
+const DEFAULT_SCAN_LIMIT: usize = 100;
+const MAX_SCAN_LIMIT: usize = 1000;
+
+// The maximum total size of entries sent in one replication call.
+const MAX_REPLICATION_BATCH_SIZE: usize = 1024 * 1024;
+
+/// The canister method that receives replicated entries.
+pub const REPLICATE_RECEIVE_METHOD: &str = "kv_replicate_receive";
+
+/// A change of a KV entry that is streamed to read replicas.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum Change {
+    Set { key: String, value: String },
+    Delete { key: String },
+}
+
+thread_local! {
+    static KV: RefCell<StableBTreeMap<String, String, Memory>> =
+        RefCell::new(StableBTreeMap::init(stable::memory(stable::KV)));
+
+    // The number of changes of each key, which is kept after deletion so that
+    // a deleted and recreated entry gets a new version.
+    static VERSIONS: RefCell<StableBTreeMap<String, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(stable::memory(stable::KV_VERSIONS)));
+
+    // The canister that is allowed to replicate its entries into this one.
+    static REPLICATION_SOURCE: RefCell<StableCell<Candid<Option<Principal>>, Memory>> =
+        RefCell::new(StableCell::init(stable::memory(stable::KV_REPLICATION_SOURCE), Candid(None)).unwrap());
+}
+
+pub fn get(key: &str) -> Result<Option<serde_json::Value>, anyhow::Error> {
+    let Some(value) = KV.with(|kv| kv.borrow().get(&key.to_string())) else {
+        return Ok(None);
+    };
+    Ok(Some(decode(value)?))
+}
+
+fn decode(value: String) -> Result<serde_json::Value, anyhow::Error> {
+    let value = if encryption::is_encrypted_value(&value) {
+        encryption::decrypt(&value)?
+    } else {
+        value
+    };
+    Ok(serde_json::from_str(&value)?)
+}
+
+/// Returns up to `limit` entries with keys in the given range in ascending or,
+/// if `reverse` is set, descending key order.
+pub fn range(
+    start: Bound<String>,
+    end: Bound<String>,
+    limit: usize,
+    reverse: bool,
+) -> Result<Vec<(String, serde_json::Value)>, anyhow::Error> {
+    let entries: Vec<(String, String)> = KV.with(|kv| {
+        let kv = kv.borrow();
+        let range = kv.range((start, end));
+        if reverse {
+            range.rev().take(limit).collect()
+        } else {
+            range.take(limit).collect()
+        }
+    });
+    entries
+        .into_iter()
+        .map(|(key, value)| Ok((key, decode(value)?)))
+        .collect()
+}
+
+/// Returns up to `limit` entries whose keys start with the prefix.
+pub fn prefix_scan(
+    prefix: &str,
+    limit: usize,
+    reverse: bool,
+) -> Result<Vec<(String, serde_json::Value)>, anyhow::Error> {
+    let end = match prefix_successor(prefix) {
+        Some(end) => Bound::Excluded(end),
+        None => Bound::Unbounded,
+    };
+    range(Bound::Included(prefix.to_string()), end, limit, reverse)
+}
+
+// The smallest string that is greater than all strings with the prefix, or
+// `None` if there is none.
+fn prefix_successor(prefix: &str) -> Option<String> {
+    let mut successor = prefix.to_string();
+    while let Some(last) = successor.pop() {
+        let next = match last as u32 + 1 {
+            0xD800 => Some('\u{E000}'),
+            next => char::from_u32(next),
+        };
+        if let Some(next) = next {
+            successor.push(next);
+            return Some(successor);
+        }
+    }
+    None
+}
+
+/// Returns the version of the key, which changes on every write of the key.
+/// Keys that were never written have version 0.
+pub fn version(key: &str) -> u64 {
+    VERSIONS.with(|versions| versions.borrow().get(&key.to_string()).unwrap_or_default())
+}
+
+fn bump_version(key: &str) {
+    VERSIONS.with(|versions| {
+        let mut versions = versions.borrow_mut();
+        let version = versions.get(&key.to_string()).unwrap_or_default();
+        versions.insert(key.to_string(), version + 1);
+    });
+}
+
+/// Sets the value only if the key still has the expected version and returns
+/// whether it did. See `write()` for the terms.
+pub fn compare_and_set(
+    key: String,
+    value: &serde_json::Value,
+    expected_version: u64,
+    terms: Option<Vec<(String, String)>>,
+) -> Result<bool, anyhow::Error> {
+    if version(&key) != expected_version {
+        return Ok(false);
+    }
+    write(key, value, terms)?;
+    Ok(true)
+}
+
+/// Sets the value. The terms of the key are recomputed by the backfill job of
+/// the indexes.
+pub fn set(key: String, value: &serde_json::Value) -> Result<(), anyhow::Error> {
+    write(key, value, None)
+}
+
+// Sets the value and replaces the terms of the key with the given ones, which
+// the caller has validated, or marks them as stale if there are none.
+fn write(
+    key: String,
+    value: &serde_json::Value,
+    terms: Option<Vec<(String, String)>>,
+) -> Result<(), anyhow::Error> {
+    store(key.clone(), value)?;
+    match terms {
+        Some(terms) => indexes::update(&key, terms).map_err(anyhow::Error::msg),
+        None => {
+            indexes::mark_stale(&key);
+            Ok(())
+        }
+    }
+}
+
+// Sets the value without updating the indexes.
+fn store(key: String, value: &serde_json::Value) -> Result<(), anyhow::Error> {
+    let value = value.to_string();
+    if key.len() > MAX_KEY_SIZE {
+        anyhow::bail!(
+            "The key is too large: {} bytes, the limit is {} bytes",
+            key.len(),
+            MAX_KEY_SIZE
+        );
+    }
+    if value.len() > MAX_VALUE_SIZE {
+        anyhow::bail!(
+            "The value is too large: {} bytes, the limit is {} bytes",
+            value.len(),
+            MAX_VALUE_SIZE
+        );
+    }
+    let value = if encryption::is_encrypted_key(&key) {
+        encryption::encrypt(&value)?
+    } else {
+        value
+    };
+    KV.with(|kv| kv.borrow_mut().insert(key.clone(), value.clone()));
+    bump_version(&key);
+    replicas::record(Change::Set { key, value });
+    Ok(())
+}
+
+pub fn delete(key: &str) -> bool {
+    let deleted = KV.with(|kv| kv.borrow_mut().remove(&key.to_string()).is_some());
+    if deleted {
+        bump_version(key);
+        indexes::remove(key);
+        replicas::record(Change::Delete {
+            key: key.to_string(),
+        });
+    }
+    deleted
+}
+
+/// Sets the canister that is allowed to replicate its entries into this one.
+pub fn set_replication_source(source: Option<Principal>) {
+    REPLICATION_SOURCE.with(|cell| cell.borrow_mut().set(Candid(source)).unwrap());
+}
+
+/// Returns an error if the caller is not the replication source.
+pub fn check_replication_source() -> Result<(), String> {
+    let source = REPLICATION_SOURCE.with(|cell| cell.borrow().get().0);
+    if source != Some(ic_cdk::caller()) {
+        return Err("The caller is not the replication source of this canister".to_string());
+    }
+    Ok(())
+}
+
+/// Applies the changes streamed from the primary canister. Unlike `set()` and
+/// `delete()`, this does not stream the changes further.
+pub fn apply_changes(changes: Vec<Change>) {
+    KV.with(|kv| {
+        let mut kv = kv.borrow_mut();
+        for change in changes {
+            match change {
+                Change::Set { key, value } => {
+                    bump_version(&key);
+                    indexes::mark_stale(&key);
+                    kv.insert(key, value);
+                }
+                Change::Delete { key } => {
+                    bump_version(&key);
+                    indexes::remove(&key);
+                    kv.remove(&key);
+                }
+            }
+        }
+    });
+}
+
+/// Returns all entries with their stored values, which may be encrypted.
+pub fn export() -> Vec<(String, String)> {
+    KV.with(|kv| kv.borrow().iter().collect())
+}
+
+/// Stores the exported entries of a backup.
+pub fn import(entries: Vec<(String, String)>) {
+    apply_changes(
+        entries
+            .into_iter()
+            .map(|(key, value)| Change::Set { key, value })
+            .collect(),
+    );
+}
+
+/// Stores the entries received from the replication source.
+pub fn replicate_receive(entries: Vec<(String, String)>) -> Result<(), String> {
+    check_replication_source()?;
+    KV.with(|kv| {
+        let mut kv = kv.borrow_mut();
+        for (key, value) in entries {
+            bump_version(&key);
+            indexes::mark_stale(&key);
+            kv.insert(key, value);
+        }
+    });
+    Ok(())
+}
+
+/// Sends all entries to the given canister in batches and returns the number
+/// of sent entries.
+///
+/// Replication is not atomic: the entries changed while the replication is in
+/// progress may or may not be included. Entries deleted in this canister are
+/// not deleted in the target canister.
+pub async fn replicate_to(target: Principal) -> Result<u64, String> {
+    call_policy::check(&target, REPLICATE_RECEIVE_METHOD)?;
+    let mut start = Bound::Unbounded;
+    let mut count = 0;
+    loop {
+        let batch: Vec<(String, String)> = KV.with(|kv| {
+            let kv = kv.borrow();
+            let mut size = 0;
+            let mut len = 0;
+            kv.range((start.clone(), Bound::Unbounded))
+                .take_while(|(key, value)| {
+                    size += key.len() + value.len();
+                    len += 1;
+                    // Always include at least one entry to make progress.
+                    len == 1 || size <= MAX_REPLICATION_BATCH_SIZE
+                })
+                .collect()
+        });
+        let Some((last_key, _)) = batch.last() else {
+            return Ok(count);
+        };
+        start = Bound::Excluded(last_key.clone());
+        count += batch.len() as u64;
+        ic_cdk::call::<_, (Result<(), String>,)>(target, REPLICATE_RECEIVE_METHOD, (batch,))
+            .await
+            .map_err(|(code, message)| {
+                format!("Replication call failed with code {:?}: {}", code, message)
+            })?
+            .0?;
+    }
+}
+
+fn scan_limit(value: &JSValue) -> Result<usize, anyhow::Error> {
+    match value {
+        JSValue::Undefined | JSValue::Null => Ok(DEFAULT_SCAN_LIMIT),
+        value => Ok((jobs::number(value, "limit")? as usize).min(MAX_SCAN_LIMIT)),
+    }
+}
+
+// Parses `{limit, reverse}`.
+fn scan_options(options: &JSValue) -> Result<(usize, bool), anyhow::Error> {
+    match options {
+        JSValue::Undefined | JSValue::Null => Ok((DEFAULT_SCAN_LIMIT, false)),
+        JSValue::Object(options) => {
+            let limit = scan_limit(options.get("limit").unwrap_or(&JSValue::Undefined))?;
+            let reverse = matches!(options.get("reverse"), Some(JSValue::Bool(true)));
+            Ok((limit, reverse))
+        }
+        _ => Err(JSError::Type("Expected an options object".to_string()).into()),
+    }
+}
+
+// Parses the `[index, term]` pairs computed by indexes.js.
+fn index_terms(arg: &CallbackArg) -> Result<Vec<(String, String)>, anyhow::Error> {
+    let JSValue::Array(pairs) = arg.to_js_value()? else {
+        return Err(JSError::Type("Expected the index terms to be an array".to_string()).into());
+    };
+    pairs
+        .into_iter()
+        .map(|pair| match pair {
+            JSValue::Array(pair) => match <[JSValue; 2]>::try_from(pair) {
+                Ok([JSValue::String(index), JSValue::String(term)]) => Ok((index, term)),
+                _ => Err(JSError::Type(
+                    "Expected an index term to be an [index, term] pair".to_string(),
+                )
+                .into()),
+            },
+            _ => Err(JSError::Type(
+                "Expected an index term to be an [index, term] pair".to_string(),
+            )
+            .into()),
+        })
+        .collect()
+}
+
+fn entries_to_js(
+    context: &JSContextRef,
+    entries: Vec<(String, serde_json::Value)>,
+) -> Result<JSValueRef, anyhow::Error> {
+    let array = context.array_value()?;
+    for (key, value) in entries {
+        let entry = context.array_value()?;
+        entry.append_property(context.value_from_str(&key)?)?;
+        entry.append_property(quickjs_wasm_rs::to_qjs_value(
+            context,
+            &json::from_json(&value),
+        )?)?;
+        array.append_property(entry)?;
+    }
+    Ok(array)
+}
+
+pub fn link(context: &JSContextRef) -> Result<(), anyhow::Error> {
+    fn get<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 1 {
+            return Err(JSError::Type(format!("Expected 1 argument, got {}", args.len())).into());
+        }
+        let key: String = args[0].try_into()?;
+        match self::get(&key)? {
+            Some(value) => quickjs_wasm_rs::to_qjs_value(context, &json::from_json(&value)),
+            None => context.undefined_value(),
+        }
+    }
+
+    fn set<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 2 && args.len() != 3 {
+            return Err(
+                JSError::Type(format!("Expected 2 or 3 arguments, got {}", args.len())).into(),
+            );
+        }
+        engine::ensure_writable("ic.kv.set()")?;
+        let key: String = args[0].try_into()?;
+        let value = json::to_json(&args[1].to_js_value()?);
+        let terms = args.get(2).map(index_terms).transpose()?;
+        if let Some(terms) = &terms {
+            indexes::validate(terms).map_err(anyhow::Error::msg)?;
+        }
+        write(key, &value, terms)?;
+        context.undefined_value()
+    }
+
+    // Returns `{value, version}` where the value is undefined for a missing
+    // key.
+    fn get_with_version<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 1 {
+            return Err(JSError::Type(format!("Expected 1 argument, got {}", args.len())).into());
+        }
+        let key: String = args[0].try_into()?;
+        let js = context.object_value()?;
+        match self::get(&key)? {
+            Some(value) => js.set_property(
+                "value",
+                quickjs_wasm_rs::to_qjs_value(context, &json::from_json(&value))?,
+            )?,
+            None => js.set_property("value", context.undefined_value()?)?,
+        }
+        js.set_property("version", context.value_from_f64(version(&key) as f64)?)?;
+        Ok(js)
+    }
+
+    // Usage: `ic.kv.casSet(key, value, expectedVersion)`. Returns false
+    // without writing if another write changed the version of the key.
+    fn cas_set<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 3 && args.len() != 4 {
+            return Err(
+                JSError::Type(format!("Expected 3 or 4 arguments, got {}", args.len())).into(),
+            );
+        }
+        engine::ensure_writable("ic.kv.casSet()")?;
+        let key: String = args[0].try_into()?;
+        let value = json::to_json(&args[1].to_js_value()?);
+        let expected_version = jobs::number(&args[2].to_js_value()?, "expectedVersion")? as u64;
+        let terms = args.get(3).map(index_terms).transpose()?;
+        if let Some(terms) = &terms {
+            indexes::validate(terms).map_err(anyhow::Error::msg)?;
+        }
+        let written = compare_and_set(key, &value, expected_version, terms)?;
+        context.value_from_bool(written)
+    }
+
+    fn delete<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 1 {
+            return Err(JSError::Type(format!("Expected 1 argument, got {}", args.len())).into());
+        }
+        engine::ensure_writable("ic.kv.delete()")?;
+        let key: String = args[0].try_into()?;
+        context.value_from_bool(self::delete(&key))
+    }
+
+    // Usage: `ic.kv.range(startKey, endKey, limit, {reverse})` where a null or
+    // undefined key leaves the range open. Returns `[key, value]` pairs.
+    fn range<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() < 2 || args.len() > 4 {
+            return Err(
+                JSError::Type(format!("Expected 2 to 4 arguments, got {}", args.len())).into(),
+            );
+        }
+        let start = match args[0].to_js_value()? {
+            JSValue::Undefined | JSValue::Null => Bound::Unbounded,
+            JSValue::String(key) => Bound::Included(key),
+            _ => return Err(JSError::Type("Expected startKey to be a string".to_string()).into()),
+        };
+        let end = match args[1].to_js_value()? {
+            JSValue::Undefined | JSValue::Null => Bound::Unbounded,
+            JSValue::String(key) => Bound::Excluded(key),
+            _ => return Err(JSError::Type("Expected endKey to be a string".to_string()).into()),
+        };
+        let limit = match args.get(2) {
+            Some(limit) => scan_limit(&limit.to_js_value()?)?,
+            None => DEFAULT_SCAN_LIMIT,
+        };
+        let reverse = match args.get(3) {
+            Some(options) => scan_options(&options.to_js_value()?)?.1,
+            None => false,
+        };
+        entries_to_js(context, self::range(start, end, limit, reverse)?)
+    }
+
+    // Usage: `ic.kv.prefixScan(prefix, {limit, reverse})`.
+    fn prefix_scan<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.is_empty() || args.len() > 2 {
+            return Err(
+                JSError::Type(format!("Expected 1 or 2 arguments, got {}", args.len())).into(),
+            );
+        }
+        let prefix: String = args[0].try_into()?;
+        let (limit, reverse) = match args.get(1) {
+            Some(options) => scan_options(&options.to_js_value()?)?,
+            None => (DEFAULT_SCAN_LIMIT, false),
+        };
+        entries_to_js(context, self::prefix_scan(&prefix, limit, reverse)?)
+    }
+
+    // Usage: `ic.kv.defineIndex(name)`. The wrapper of indexes.js also takes
+    // the extract function.
+    fn define_index<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 1 {
+            return Err(JSError::Type(format!("Expected 1 argument, got {}", args.len())).into());
+        }
+        let name: String = args[0].try_into()?;
+        indexes::define(name).map_err(anyhow::Error::msg)?;
+        context.undefined_value()
+    }
+
+    // Returns `{keys, next}` with up to `limit` keys whose terms are missing
+    // and the key from which the backfill continues, or null when it is done.
+    // Used by the backfill job of indexes.js.
+    fn unindexed<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 1 {
+            return Err(JSError::Type(format!("Expected 1 argument, got {}", args.len())).into());
+        }
+        engine::ensure_writable("Indexing entries")?;
+        let limit = scan_limit(&args[0].to_js_value()?)?;
+        let (mut keys, cursor) = indexes::backfill_batch(limit);
+        let next = match cursor {
+            Some(cursor) if keys.len() < limit => {
+                let remaining = limit - keys.len();
+                let mut range: Vec<String> = KV.with(|kv| {
+                    kv.borrow()
+                        .range((Bound::Included(cursor), Bound::Unbounded))
+                        .take(remaining + 1)
+                        .map(|(key, _)| key)
+                        .collect()
+                });
+                let next = (range.len() > remaining).then(|| range.remove(remaining));
+                keys.extend(range);
+                next
+            }
+            cursor => cursor,
+        };
+        let js = context.object_value()?;
+        let array = context.array_value()?;
+        for key in keys {
+            array.append_property(context.value_from_str(&key)?)?;
+        }
+        js.set_property("keys", array)?;
+        match next {
+            Some(next) => js.set_property("next", context.value_from_str(&next)?)?,
+            None => js.set_property("next", context.null_value()?)?,
+        }
+        Ok(js)
+    }
+
+    // Replaces the terms of the key computed by the backfill job.
+    fn reindex<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 2 {
+            return Err(JSError::Type(format!("Expected 2 arguments, got {}", args.len())).into());
+        }
+        engine::ensure_writable("Indexing entries")?;
+        let key: String = args[0].try_into()?;
+        let terms = index_terms(&args[1])?;
+        indexes::update(&key, terms).map_err(anyhow::Error::msg)?;
+        context.undefined_value()
+    }
+
+    // Ends a batch of the backfill job with the key from which it continues.
+    fn finish_backfill<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 1 {
+            return Err(JSError::Type(format!("Expected 1 argument, got {}", args.len())).into());
+        }
+        engine::ensure_writable("Indexing entries")?;
+        let next = match args[0].to_js_value()? {
+            JSValue::Null | JSValue::Undefined => None,
+            JSValue::String(next) => Some(next),
+            _ => return Err(JSError::Type("Expected next to be a string".to_string()).into()),
+        };
+        indexes::finish_backfill_batch(next);
+        context.undefined_value()
+    }
+
+    // Usage: `ic.kv.lookup(index, term, {limit, reverse})`. Returns `[key,
+    // value]` pairs.
+    fn lookup<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() < 2 || args.len() > 3 {
+            return Err(
+                JSError::Type(format!("Expected 2 or 3 arguments, got {}", args.len())).into(),
+            );
+        }
+        let index: String = args[0].try_into()?;
+        let term: String = args[1].try_into()?;
+        let (limit, reverse) = match args.get(2) {
+            Some(options) => scan_options(&options.to_js_value()?)?,
+            None => (DEFAULT_SCAN_LIMIT, false),
+        };
+        let keys = indexes::lookup(&index, &term, limit, reverse).map_err(anyhow::Error::msg)?;
+        let mut entries = vec![];
+        for key in keys {
+            if let Some(value) = self::get(&key)? {
+                entries.push((key, value));
+            }
+        }
+        entries_to_js(context, entries)
+    }
+
+    // Usage: `ic.kv.encrypt('secret/')` when the script is loaded.
+    fn encrypt<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 1 {
+            return Err(JSError::Type(format!("Expected 1 argument, got {}", args.len())).into());
+        }
+        let prefix: String = args[0].try_into()?;
+        encryption::encrypt_namespace(prefix);
+        context.undefined_value()
+    }
+
+    // Returns a promise that resolves with the number of replicated entries.
+    // The replication counts as one call against the budget of the message
+    // regardless of the number of batches.
+    fn replicate_to<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 1 {
+            return Err(JSError::Type(format!("Expected 1 argument, got {}", args.len())).into());
+        }
+        let target: String = args[0].try_into()?;
+        let target = Principal::from_text(target)?;
+        engine::charge_call(context, 0)?;
+        let (id, promise) = engine::promise(context)?;
+        ic_cdk::spawn(async move {
+            let result = self::replicate_to(target).await;
+            engine::settle(id, move |context| match result {
+                Ok(count) => context.value_from_f64(count as f64),
+                Err(err) => Err(anyhow::anyhow!(err)),
+            });
+        });
+        Ok(promise)
+    }
+
+    let kv = context.object_value()?;
+    kv.set_property("get", context.wrap_callback2(get)?)?;
+    kv.set_property("set", context.wrap_callback2(set)?)?;
+    kv.set_property("getWithVersion", context.wrap_callback2(get_with_version)?)?;
+    kv.set_property("casSet", context.wrap_callback2(cas_set)?)?;
+    kv.set_property("delete", context.wrap_callback2(delete)?)?;
+    kv.set_property("range", context.wrap_callback2(range)?)?;
+    kv.set_property("prefixScan", context.wrap_callback2(prefix_scan)?)?;
+    kv.set_property("defineIndex", context.wrap_callback2(define_index)?)?;
+    kv.set_property("lookup", context.wrap_callback2(lookup)?)?;
+    kv.set_property("unindexed", context.wrap_callback2(unindexed)?)?;
+    kv.set_property("reindex", context.wrap_callback2(reindex)?)?;
+    kv.set_property("finishBackfill", context.wrap_callback2(finish_backfill)?)?;
+    kv.set_property("encrypt", context.wrap_callback2(encrypt)?)?;
+    kv.set_property("replicateTo", context.wrap_callback2(replicate_to)?)?;
+
+    engine::namespace(context)?.set_property("kv", kv)?;
+
+    context.eval_global(PERSIST_FILE, PERSIST_SCRIPT)?;
+    context.eval_global(INDEXES_FILE, INDEXES_SCRIPT)?;
+    Ok(())
+}
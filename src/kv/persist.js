@@ -0,0 +1,140 @@
+// Usage: `ic.persist.encode(value)` and `ic.persist.decode(encoded)`. Converts
+// values with Map, Set, Date, BigInt, ArrayBuffer, typed arrays, `undefined`
+// and non-finite numbers into plain JSON values and back. Plain JSON values
+// are kept as is, so data stored before stays readable. Other values become
+// `{"$type": name, "value": ...}` objects with a deterministic layout: the
+// entries of maps, sets and objects keep their insertion order and bytes are
+// hex-encoded. `ic.kv` uses this format for all values.
+ic.persist = (function () {
+	const TYPED_ARRAYS = {
+		Int8Array, Uint8Array, Uint8ClampedArray, Int16Array, Uint16Array,
+		Int32Array, Uint32Array, Float32Array, Float64Array, BigInt64Array, BigUint64Array,
+	};
+
+	function toHex(buffer) {
+		return Array.from(new Uint8Array(buffer), (byte) => byte.toString(16).padStart(2, "0")).join("");
+	}
+
+	function fromHex(hex) {
+		const bytes = new Uint8Array(hex.length / 2);
+		for (let i = 0; i < bytes.length; i++) {
+			bytes[i] = parseInt(hex.substr(i * 2, 2), 16);
+		}
+		return bytes.buffer;
+	}
+
+	function tagged(type, value) {
+		return { $type: type, value };
+	}
+
+	function encode(value) {
+		switch (typeof value) {
+			case "undefined":
+				return tagged("undefined", null);
+			case "bigint":
+				return tagged("BigInt", value.toString());
+			case "number":
+				return Number.isFinite(value) ? value : tagged("Number", String(value));
+			case "function":
+			case "symbol":
+				throw new TypeError(`Cannot persist a ${typeof value}`);
+		}
+		if (value === null || typeof value !== "object") {
+			return value;
+		}
+		if (Array.isArray(value)) {
+			return value.map(encode);
+		}
+		if (value instanceof Map) {
+			return tagged("Map", Array.from(value, ([k, v]) => [encode(k), encode(v)]));
+		}
+		if (value instanceof Set) {
+			return tagged("Set", Array.from(value, encode));
+		}
+		if (value instanceof Date) {
+			return tagged("Date", value.getTime());
+		}
+		if (value instanceof ArrayBuffer) {
+			return tagged("ArrayBuffer", toHex(value));
+		}
+		if (ArrayBuffer.isView(value)) {
+			const bytes = value.buffer.slice(value.byteOffset, value.byteOffset + value.byteLength);
+			return tagged(value.constructor.name, toHex(bytes));
+		}
+		const fields = {};
+		for (const key of Object.keys(value)) {
+			fields[key] = encode(value[key]);
+		}
+		// Escape objects that look like tagged values.
+		return "$type" in fields ? tagged("Object", fields) : fields;
+	}
+
+	function decode(value) {
+		if (value === null || typeof value !== "object") {
+			return value;
+		}
+		if (Array.isArray(value)) {
+			return value.map(decode);
+		}
+		if (!("$type" in value)) {
+			const fields = {};
+			for (const key of Object.keys(value)) {
+				fields[key] = decode(value[key]);
+			}
+			return fields;
+		}
+		const type = value.$type;
+		const inner = value.value;
+		switch (type) {
+			case "undefined":
+				return undefined;
+			case "BigInt":
+				return BigInt(inner);
+			case "Number":
+				return Number(inner);
+			case "Map":
+				return new Map(inner.map(([k, v]) => [decode(k), decode(v)]));
+			case "Set":
+				return new Set(inner.map(decode));
+			case "Date":
+				return new Date(inner);
+			case "ArrayBuffer":
+				return fromHex(inner);
+			case "Object": {
+				const fields = {};
+				for (const key of Object.keys(inner)) {
+					fields[key] = decode(inner[key]);
+				}
+				return fields;
+			}
+		}
+		if (type in TYPED_ARRAYS) {
+			return new TYPED_ARRAYS[type](fromHex(inner));
+		}
+		throw new TypeError(`Unknown persisted type ${type}`);
+	}
+
+	return { encode, decode };
+})();
+
+(function () {
+	const get = ic.kv.get;
+	const set = ic.kv.set;
+	const getWithVersion = ic.kv.getWithVersion;
+	const casSet = ic.kv.casSet;
+	const range = ic.kv.range;
+	const prefixScan = ic.kv.prefixScan;
+	const decodeEntries = (entries) => entries.map(([key, value]) => [key, ic.persist.decode(value)]);
+	ic.kv.get = (key) => ic.persist.decode(get(key));
+	ic.kv.set = (key, value, ...rest) => set(key, ic.persist.encode(value), ...rest);
+	ic.kv.getWithVersion = (key) => {
+		const { value, version } = getWithVersion(key);
+		return { value: ic.persist.decode(value), version };
+	};
+	ic.kv.casSet = (key, value, expectedVersion, ...rest) =>
+		casSet(key, ic.persist.encode(value), expectedVersion, ...rest);
+	ic.kv.range = (...args) => decodeEntries(range(...args));
+	ic.kv.prefixScan = (...args) => decodeEntries(prefixScan(...args));
+	const lookup = ic.kv.lookup;
+	ic.kv.lookup = (...args) => decodeEntries(lookup(...args));
+})();
@@ -1,40 +1,645 @@
 use ic_cdk::api::call::ManualReply;
 use quickjs_wasm_rs::JSContextRef;
 
+mod audit;
+mod auth;
+mod backup;
+mod batch;
+mod btc;
+mod call_metrics;
+mod call_policy;
+mod candid_js;
+mod cbor;
+mod census;
+mod certification;
+mod certified_responses;
+mod chain;
+mod compress;
+mod config;
+mod converters;
+mod costs;
+mod crypto;
+mod dead_letters;
+mod deployer;
+mod deployment;
+#[cfg(feature = "dev-repl")]
+mod dev;
+mod encoding;
+mod endpoints;
 mod engine;
+mod env;
+mod eth;
+mod events;
+mod evm_rpc;
+#[cfg(feature = "fault-injection")]
+mod faults;
+mod flags;
+mod graphql;
+mod http;
+mod http_outcalls;
+mod icrc;
+mod jobs;
+mod json;
+mod jsonrpc;
+mod kv;
+mod locks;
 mod management_canister;
+mod migrations;
+mod monitoring;
+mod multicall;
+mod nns;
+mod outbox;
+mod persistence;
+mod pool;
+mod pqueue;
+mod profiler;
+mod quotas;
+mod recorder;
+mod redaction;
+mod replicas;
+mod ring;
+mod schemas;
+mod search;
+mod sessions;
+mod shards;
+mod signatures;
+mod source_maps;
+mod sql;
+mod stable;
+mod storage;
 mod system_api;
+mod testing;
+mod timers;
+mod topup;
+mod vault;
+mod vetkd;
 
 const SCRIPT_NAME: &str = "ic.js";
 const SCRIPT: &[u8] = include_bytes!("ic.js");
 
+// The JS functions that the endpoints below call, checked on install and
+// upgrade.
+const JS_ENDPOINTS: &[(&str, endpoints::Kind)] = &[
+    ("query", endpoints::Kind::Update),
+    ("read", endpoints::Kind::Query),
+];
+
+/// A raw-Candid endpoint: passes arbitrary Candid arguments to the JS `query`
+/// function and replies with its result encoded as Candid. Neither the
+/// arguments nor the result have a fixed type, so the interface declares it
+/// with the types that the script accepts and returns.
 #[ic_cdk_macros::update(manual_reply = true)]
-fn query() -> ManualReply<String> {
+fn query() -> ManualReply<()> {
+    let args = match candid_js::arg_data() {
+        Ok(args) => args,
+        Err(err) => return ManualReply::reject(err.to_string()),
+    };
     engine::execute(
         "query",
+        converters::arguments("query", args),
+        candid_js::reply,
+    )
+}
+
+/// Reads a value of the KV store in a query call with the JS `read` function.
+#[ic_cdk_macros::query(manual_reply = true)]
+fn read(key: String) -> ManualReply<String> {
+    engine::execute_query(
+        "read",
+        converters::arguments("read", (key,)),
+        candid_js::reply,
+    )
+}
+
+/// Calls the JS endpoint registered with `ic.export()` whose name is the first
+/// argument with the remaining Candid arguments.
+#[ic_cdk_macros::update(manual_reply = true)]
+fn dispatch() -> ManualReply<()> {
+    endpoints::dispatch(endpoints::Kind::Update)
+}
+
+/// Like `dispatch` for endpoints registered with `{query: true}`.
+#[ic_cdk_macros::query(manual_reply = true)]
+fn dispatch_query() -> ManualReply<()> {
+    endpoints::dispatch(endpoints::Kind::Query)
+}
+
+/// Lists the JS endpoints that clients can call with their argument schemas.
+#[ic_cdk_macros::query]
+fn list_endpoints() -> Vec<endpoints::EndpointInfo> {
+    endpoints::list(JS_ENDPOINTS)
+}
+
+/// Runs several calls of registered JS endpoints in one message. Each call is
+/// a method name with JSON-encoded arguments and gets its own result.
+#[ic_cdk_macros::update(manual_reply = true)]
+fn batch_invoke(calls: Vec<(String, String)>) -> ManualReply<Vec<batch::CallResult>> {
+    batch::invoke(calls)
+}
+
+/// Returns the versions of the engine and the script for verifying what is
+/// deployed.
+#[ic_cdk_macros::query(name = "__engine_info")]
+fn engine_info() -> monitoring::EngineInfo {
+    monitoring::engine_info()
+}
+
+/// A no-op that the waiters of `ic.lock()` call on this canister to keep their
+/// call contexts open until the lock is passed to them.
+#[ic_cdk_macros::update(name = "__lock_wait", guard = "caller_is_self")]
+fn lock_wait() {}
+
+/// Returns the metadata of the active script, which differs from the custom
+/// sections of the module after a script was promoted without an upgrade.
+#[ic_cdk_macros::query]
+fn script_metadata() -> monitoring::ScriptMetadata {
+    monitoring::script_metadata()
+}
+
+/// Serves HTTP requests. JS handlers run in `http_request_update` except for
+/// JSON-RPC requests that call only query endpoints and event polling.
+#[ic_cdk_macros::query(manual_reply = true)]
+fn http_request(request: http::HttpRequest) -> ManualReply<http::HttpResponse> {
+    http::handle_query(request)
+}
+
+/// Serves HTTP requests with the `httpRequest()` JS function.
+#[ic_cdk_macros::update(manual_reply = true)]
+fn http_request_update(request: http::HttpRequest) -> ManualReply<http::HttpResponse> {
+    http::handle_update(request)
+}
+
+/// Returns the next chunk of a streamed HTTP response body.
+#[ic_cdk_macros::query]
+fn http_request_streaming_callback(
+    token: http::StreamingToken,
+) -> http::StreamingCallbackHttpResponse {
+    http::streaming_callback(token).unwrap_or_else(|err| ic_cdk::trap(&err))
+}
+
+/// Applies the transforms selected in JS to the response of an HTTPS outcall.
+#[ic_cdk_macros::query]
+fn transform_outcall(
+    args: ic_cdk::api::management_canister::http_request::TransformArgs,
+) -> ic_cdk::api::management_canister::http_request::HttpResponse {
+    http_outcalls::transforms::apply(args).unwrap_or_else(|err| ic_cdk::trap(&err))
+}
+
+/// Returns the status of the canister for monitoring services. The user JS
+/// script may append custom health indicators by defining a
+/// `healthIndicators()` function.
+#[ic_cdk_macros::update(manual_reply = true)]
+async fn canister_status() -> ManualReply<monitoring::CanisterStatus> {
+    let status = monitoring::self_status().await;
+    if !engine::is_function_defined(monitoring::HEALTH_INDICATORS) {
+        return ManualReply::one(status);
+    }
+    engine::execute(
+        monitoring::HEALTH_INDICATORS,
         |_context| Ok(vec![]),
-        |_context, result| match result {
-            Ok(value) => {
-                let result = value.as_str();
-                match result {
-                    Ok(value) => ManualReply::one(value.to_string()),
-                    Err(err) => ManualReply::reject(err.to_string()),
-                }
+        move |_context, result| {
+            let indicators = result.and_then(|value| monitoring::to_indicators(&value));
+            match indicators {
+                Ok(indicators) => ManualReply::one(monitoring::CanisterStatus {
+                    indicators,
+                    ..status
+                }),
+                Err(err) => ManualReply::reject(redaction::redact(&err.to_string())),
             }
-            Err(err) => ManualReply::reject(err.to_string()),
         },
     )
 }
 
+/// Returns at most `n` most recent log entries as single-line JSON strings.
+#[ic_cdk_macros::query(guard = "caller_is_controller")]
+fn logs(n: u64) -> Vec<String> {
+    system_api::recent_logs(n)
+}
+
+#[ic_cdk_macros::update(guard = "caller_is_controller")]
+fn set_log_level(level: String) -> Result<(), String> {
+    let level = level
+        .parse()
+        .map_err(|err: anyhow::Error| err.to_string())?;
+    system_api::set_log_level(level);
+    Ok(())
+}
+
+/// Returns at most `limit` dead letters starting from the given id.
+#[ic_cdk_macros::query(guard = "caller_is_controller")]
+fn dead_letters(start: u64, limit: u64) -> Vec<dead_letters::DeadLetter> {
+    dead_letters::list(start, limit.min(100) as usize)
+}
+
+/// Enqueues the given dead letter as a new job and returns the new job id.
+#[ic_cdk_macros::update(guard = "caller_is_controller")]
+fn requeue_dead_letter(id: u64) -> Result<u64, String> {
+    dead_letters::requeue(id)
+}
+
+#[ic_cdk_macros::update(guard = "caller_is_controller")]
+fn discard_dead_letter(id: u64) -> Result<(), String> {
+    dead_letters::discard(id)
+}
+
+/// Takes a snapshot of the KV store, config and logs and returns its size and
+/// chunk size for download with `backup_chunk`.
+#[ic_cdk_macros::update(guard = "caller_is_controller")]
+fn create_backup() -> backup::BackupInfo {
+    backup::create()
+}
+
+/// Returns the chunk of the last backup that starts at the given offset.
+#[ic_cdk_macros::query(guard = "caller_is_controller")]
+fn backup_chunk(offset: u64) -> Result<Vec<u8>, String> {
+    backup::chunk(offset)
+}
+
+/// Uploads a chunk of a backup. Upload the chunks in order and then call
+/// `finish_restore` to import them.
+#[ic_cdk_macros::update(guard = "caller_is_controller")]
+fn restore_chunk(offset: u64, chunk: Vec<u8>) -> Result<(), String> {
+    backup::restore_chunk(offset, chunk)
+}
+
+/// Imports the uploaded backup into the KV store, config and logs.
+#[ic_cdk_macros::update(guard = "caller_is_controller")]
+fn finish_restore() -> Result<(), String> {
+    backup::finish_restore()
+}
+
+/// Allows the given canister to replicate its KV entries into this canister.
+#[ic_cdk_macros::update(guard = "caller_is_controller")]
+fn set_kv_replication_source(source: Option<candid::Principal>) {
+    kv::set_replication_source(source);
+}
+
+/// Sets the read replicas that receive all KV changes of this canister.
+#[ic_cdk_macros::update(guard = "caller_is_controller")]
+fn set_replicas(replicas: Vec<candid::Principal>) {
+    replicas::set_replicas(replicas);
+}
+
+/// Applies a batch of KV changes streamed from the primary canister.
+#[ic_cdk_macros::update]
+fn kv_apply_changes(sequence: u64, changes: Vec<kv::Change>) {
+    if let Err(err) = kv::check_replication_source() {
+        ic_cdk::trap(&err);
+    }
+    replicas::check_sequence(sequence);
+    kv::apply_changes(changes);
+}
+
+/// Receives a batch of KV entries from the replication source.
+#[ic_cdk_macros::update]
+fn kv_replicate_receive(entries: Vec<(String, String)>) -> Result<(), String> {
+    kv::replicate_receive(entries)
+}
+
+/// Replaces the set of shard canisters and returns the new shard map version.
+#[ic_cdk_macros::update(guard = "caller_is_controller")]
+fn set_shards(shards: Vec<candid::Principal>) -> u64 {
+    shards::set_shards(shards)
+}
+
+#[ic_cdk_macros::query]
+fn shard_map() -> shards::ShardMap {
+    shards::shard_map()
+}
+
+/// Reads a key of this canister acting as a shard.
+#[ic_cdk_macros::query(guard = "caller_is_controller")]
+fn shard_get(key: String) -> Option<String> {
+    shards::local_get(key)
+}
+
+/// Writes a key of this canister acting as a shard.
+#[ic_cdk_macros::update(guard = "caller_is_controller")]
+fn shard_set(key: String, value: String) -> Result<(), String> {
+    shards::local_set(key, value)
+}
+
+/// Returns the response of a JS query precomputed with
+/// `ic.certifiedResponses.set()` together with the certificate and the witness
+/// that prove that the response is part of the certified state.
+#[ic_cdk_macros::query]
+fn certified_response(key: String) -> Result<certified_responses::CertifiedResponse, String> {
+    certified_responses::get(&key)
+}
+
+/// Returns the canister signature of a message signed with
+/// `ic.signatures.sign(seed, message)` in an earlier update call.
+#[ic_cdk_macros::query]
+fn canister_signature(seed: Vec<u8>, message: Vec<u8>) -> Result<Vec<u8>, String> {
+    signatures::signature(&seed, &message)
+}
+
+/// Sets the Wasm module installed into the pre-provisioned child canisters.
+#[ic_cdk_macros::update(guard = "caller_is_controller")]
+fn set_pool_wasm(wasm: Vec<u8>) {
+    pool::set_wasm(wasm);
+}
+
+/// Returns at most `limit` entries of the audit log recorded with
+/// `ic.audit.record()` starting from `start` together with the certificate and
+/// the witness that prove their inclusion.
+#[ic_cdk_macros::query]
+fn audit_log(start: u64, limit: u64) -> Result<audit::AuditLogPage, String> {
+    audit::page(start, limit.min(100))
+}
+
+/// Restricts the outgoing calls of the JS code to the given canisters and
+/// methods. `null` allows all calls.
+#[ic_cdk_macros::update(guard = "caller_is_controller")]
+fn set_call_policy(policy: Option<call_policy::CallPolicy>) {
+    call_policy::set(policy);
+}
+
+#[ic_cdk_macros::query(guard = "caller_is_controller")]
+fn get_call_policy() -> Option<call_policy::CallPolicy> {
+    call_policy::get()
+}
+
+/// Changes how much detail of errors reaches callers.
+#[ic_cdk_macros::update(guard = "caller_is_controller")]
+fn set_error_detail(detail: redaction::ErrorDetail) {
+    redaction::set(detail);
+}
+
+/// Returns the counters of the outgoing calls per destination canister.
+#[ic_cdk_macros::query(guard = "caller_is_controller")]
+fn call_metrics() -> Vec<(candid::Principal, call_metrics::CallStats)> {
+    call_metrics::all_stats()
+}
+
+/// Injects faults into outgoing calls to the given canister or removes them.
+#[cfg(feature = "fault-injection")]
+#[ic_cdk_macros::update(guard = "caller_is_controller")]
+fn set_call_faults(
+    target: candid::Principal,
+    faults: Option<faults::CallFaults>,
+) -> Result<(), String> {
+    faults::set(target, faults)
+}
+
+/// Sets the source map used to translate the locations in stack traces of the
+/// script with the given name, e.g. `ic.js` or `staged.js`.
+#[ic_cdk_macros::update(guard = "caller_is_controller")]
+fn set_source_map(script_name: String, source_map: String) -> Result<(), String> {
+    source_maps::set(script_name, &source_map)
+}
+
+/// Starts counting the instructions of the global JS functions and registered
+/// endpoint handlers.
+#[ic_cdk_macros::update(guard = "caller_is_controller")]
+fn start_profiling() -> Result<(), String> {
+    profiler::start()
+}
+
+#[ic_cdk_macros::update(guard = "caller_is_controller")]
+fn stop_profiling() -> Result<(), String> {
+    profiler::stop()
+}
+
+/// Returns the instructions per stack of JS functions in the folded format of
+/// flamegraph tools.
+#[ic_cdk_macros::query(guard = "caller_is_controller")]
+fn profile() -> Result<String, String> {
+    profiler::report()
+}
+
+/// Runs the tests defined by the script with `describe()` and `it()` whose
+/// names contain the filter, or all tests without a filter.
+#[ic_cdk_macros::update(guard = "caller_is_controller", manual_reply = true)]
+fn run_tests(filter: Option<String>) -> ManualReply<testing::TestReport> {
+    testing::run(filter)
+}
+
+/// Returns the JSON-encoded census of the objects reachable in the JS heap.
+#[ic_cdk_macros::query(guard = "caller_is_controller")]
+fn heap_census() -> Result<String, String> {
+    census::take()
+}
+
+/// Starts or stops recording the inputs of the JS script.
+#[ic_cdk_macros::update(guard = "caller_is_controller")]
+fn set_recording(enabled: bool) {
+    recorder::set_enabled(enabled);
+}
+
+/// Returns at most `limit` recorded inputs starting from the given sequence
+/// number.
+#[ic_cdk_macros::query(guard = "caller_is_controller")]
+fn recorded_messages(start: u64, limit: u64) -> Vec<recorder::Record> {
+    recorder::list(start, limit.min(100) as usize)
+}
+
+/// Returns a token for the web REPL at `/dev` that expires in an hour.
+#[cfg(feature = "dev-repl")]
+#[ic_cdk_macros::update(guard = "caller_is_controller")]
+async fn dev_session() -> Result<String, String> {
+    dev::create_session().await
+}
+
+/// Sets the feature flag with the given name read by `ic.flags.isEnabled()`.
+#[ic_cdk_macros::update(guard = "caller_is_controller")]
+fn set_flag(name: String, flag: flags::Flag) -> Result<(), String> {
+    flags::set(name, flag)
+}
+
+#[ic_cdk_macros::update(guard = "caller_is_controller")]
+fn remove_flag(name: String) {
+    flags::remove(name);
+}
+
+#[ic_cdk_macros::query(guard = "caller_is_controller")]
+fn list_flags() -> Vec<(String, flags::Flag)> {
+    flags::list()
+}
+
+/// Sets the JSON-encoded value of a config key declared by the script with
+/// `ic.config.define()`. The value is validated against the schema of the key.
+#[ic_cdk_macros::update(guard = "caller_is_controller")]
+fn set_config(key: String, value: String) -> Result<(), String> {
+    config::set(key, &value)
+}
+
+/// Restores the default value of a config key.
+#[ic_cdk_macros::update(guard = "caller_is_controller")]
+fn reset_config(key: String) {
+    config::reset(key);
+}
+
+/// Returns the JSON-encoded values of all config keys.
+#[ic_cdk_macros::query(guard = "caller_is_controller")]
+fn list_config() -> Vec<(String, String)> {
+    config::list()
+}
+
+/// Loads a new version of the JS script next to the active one.
+#[ic_cdk_macros::update(guard = "caller_is_controller")]
+fn stage_script(script: String) -> Result<(), String> {
+    engine::stage(linker, deployment::STAGED_SCRIPT_NAME, &script).map_err(|err| err.to_string())
+}
+
+/// Runs the `smokeTest()` function of the staged script and returns its
+/// JSON-encoded result.
+#[ic_cdk_macros::update(guard = "caller_is_controller")]
+fn check_staged_script() -> Result<String, String> {
+    deployment::check()
+}
+
+/// Switches all endpoints to the staged script.
+#[ic_cdk_macros::update(guard = "caller_is_controller")]
+fn promote_script() -> Result<(), String> {
+    engine::promote().map_err(|err| err.to_string())?;
+    monitoring::mirror_script_metadata();
+    Ok(())
+}
+
+/// Switches all endpoints back to the previously active script.
+#[ic_cdk_macros::update(guard = "caller_is_controller")]
+fn rollback_script() -> Result<(), String> {
+    engine::rollback().map_err(|err| err.to_string())?;
+    monitoring::mirror_script_metadata();
+    Ok(())
+}
+
+/// Rejects new messages to JS endpoints with a retry hint while the pending
+/// ones complete. Poll `drain_status()` until the engine is idle and upgrade.
+#[ic_cdk_macros::update(guard = "caller_is_controller")]
+fn begin_drain() {
+    engine::set_draining(true);
+}
+
+/// Accepts new messages again, e.g. if the upgrade was called off.
+#[ic_cdk_macros::update(guard = "caller_is_controller")]
+fn end_drain() {
+    engine::set_draining(false);
+}
+
+#[ic_cdk_macros::query]
+fn drain_status() -> monitoring::DrainStatus {
+    monitoring::drain_status()
+}
+
 #[ic_cdk_macros::init]
-fn init() {
+fn init(args: Option<env::InstallArgs>) {
+    env::install(args);
+    setup();
+    migrations::mark_latest();
+}
+
+#[ic_cdk_macros::pre_upgrade]
+fn pre_upgrade() {
+    // A trap here cancels the upgrade, so the state is never lost silently.
+    if let Err(err) = persistence::save() {
+        ic_cdk::trap(&err);
+    }
+}
+
+#[ic_cdk_macros::post_upgrade]
+fn post_upgrade(args: Option<env::InstallArgs>) {
+    env::install(args);
+    setup();
+    if let Err(err) = persistence::restore() {
+        ic_cdk::trap(&err);
+    }
+    // A trap here cancels the upgrade and keeps the old code and data.
+    if let Err(err) = migrations::run_pending() {
+        ic_cdk::trap(&err);
+    }
+    // The certified data is reset on upgrade.
+    certified_responses::recertify();
+    audit::recertify();
+    // Timers do not survive upgrades, so re-arm the timers of persisted work.
+    jobs::schedule();
+    outbox::schedule();
+    sessions::schedule();
+}
+
+fn setup() {
     unsafe { ic_wasi_polyfill::init(&[0_u8; 32]) };
     engine::init(linker, SCRIPT_NAME, std::str::from_utf8(SCRIPT).unwrap()).unwrap();
+    let required: Vec<&str> = JS_ENDPOINTS.iter().map(|(name, _kind)| *name).collect();
+    if let Err(err) = deployment::self_check(&required) {
+        ic_cdk::trap(&err);
+    }
+    monitoring::mirror_script_metadata();
+    http::init();
 }
 
 fn linker(context: &JSContextRef) -> Result<(), anyhow::Error> {
     system_api::link(context)?;
+    env::link(context)?;
     management_canister::link(context)?;
+    multicall::link(context)?;
+    candid_js::link(context)?;
+    call_metrics::link(context)?;
+    costs::link(context)?;
+    http_outcalls::link(context)?;
+    deployer::link(context)?;
+    jobs::link(context)?;
+    dead_letters::link(context)?;
+    timers::link(context)?;
+    kv::link(context)?;
+    persistence::link(context)?;
+    storage::link(context)?;
+    search::link(context)?;
+    sql::link(context)?;
+    locks::link(context)?;
+    outbox::link(context)?;
+    pqueue::link(context)?;
+    ring::link(context)?;
+    shards::link(context)?;
+    migrations::link(context)?;
+    certified_responses::link(context)?;
+    signatures::link(context)?;
+    cbor::link(context)?;
+    encoding::link(context)?;
+    json::link(context)?;
+    compress::link(context)?;
+    crypto::link(context)?;
+    eth::link(context)?;
+    btc::link(context)?;
+    evm_rpc::link(context)?;
+    chain::link(context)?;
+    schemas::link(context)?;
+    graphql::link(context)?;
+    endpoints::link(context)?;
+    jsonrpc::link(context)?;
+    batch::link(context)?;
+    profiler::link(context)?;
+    census::link(context)?;
+    testing::link(context)?;
+    events::link(context)?;
+    sessions::link(context)?;
+    auth::link(context)?;
+    quotas::link(context)?;
+    audit::link(context)?;
+    icrc::link(context)?;
+    nns::link(context)?;
+    topup::link(context)?;
+    pool::link(context)?;
+    flags::link(context)?;
+    config::link(context)?;
+    vault::link(context)?;
     // Link other canisters here.
     Ok(())
 }
+
+fn caller_is_self() -> Result<(), String> {
+    if ic_cdk::caller() == ic_cdk::id() {
+        Ok(())
+    } else {
+        Err("Only the canister itself may call this method.".to_string())
+    }
+}
+
+fn caller_is_controller() -> Result<(), String> {
+    if ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        Ok(())
+    } else {
+        Err("The caller is not a controller of the canister.".to_string())
+    }
+}
@@ -0,0 +1,182 @@
+// The queues of the named locks of `ic.lock(name)` in engine.js.
+//
+// A lock is held by a call context until it releases the lock, which passes it
+// to the next waiter in the order of arrival. A waiter gets a promise and is
+// parked on self-calls of the no-op `__lock_wait` endpoint until the lock is
+// passed to it: each pending self-call keeps the call context of the waiter
+// open, and the promise resumes the waiter in its own call context. A waiter
+// gives up after `MAX_WAIT`. If a callback of the holder or of a waiter traps,
+// the cleanup callback releases the locks of its call context and drops it
+// from the queues. Locks are in-memory and do not survive upgrades.
+use std::{
+    cell::RefCell,
+    collections::{HashMap, VecDeque},
+    time::Duration,
+};
+
+use quickjs_wasm_rs::{CallbackArg, JSContextRef, JSError, JSValue, JSValueRef};
+
+use crate::engine::{self, CallContextId};
+
+/// The method that waiters call on this canister while they wait.
+pub const WAIT_METHOD: &str = "__lock_wait";
+
+// The time after which a waiter stops waiting and fails.
+const MAX_WAIT: Duration = Duration::from_secs(5 * 60);
+
+struct Lock {
+    holder: CallContextId,
+    waiters: VecDeque<CallContextId>,
+}
+
+thread_local! {
+    // The held locks with their waiters.
+    static LOCKS: RefCell<HashMap<String, Lock>> = RefCell::new(HashMap::new());
+}
+
+// Passes the lock to its next waiter or frees it.
+fn pass(locks: &mut HashMap<String, Lock>, name: &str) {
+    let Some(lock) = locks.get_mut(name) else {
+        return;
+    };
+    match lock.waiters.pop_front() {
+        Some(next) => lock.holder = next,
+        None => {
+            locks.remove(name);
+        }
+    }
+}
+
+/// Releases all locks held by the given call context and removes it from the
+/// queues of the other locks.
+pub fn release_all(call_context: CallContextId) {
+    LOCKS.with(|locks| {
+        let mut locks = locks.borrow_mut();
+        let held: Vec<String> = locks
+            .iter()
+            .filter(|(_, lock)| lock.holder == call_context)
+            .map(|(name, _)| name.clone())
+            .collect();
+        for lock in locks.values_mut() {
+            lock.waiters.retain(|waiter| *waiter != call_context);
+        }
+        for name in held {
+            pass(&mut locks, &name);
+        }
+    });
+}
+
+// Returns `Some(true)` if the waiter holds the lock, `Some(false)` if it is
+// still queued and `None` if it was dropped from the queue.
+fn is_held_by(name: &str, waiter: CallContextId) -> Option<bool> {
+    LOCKS.with(|locks| {
+        let locks = locks.borrow();
+        let lock = locks.get(name)?;
+        if lock.holder == waiter {
+            return Some(true);
+        }
+        lock.waiters.contains(&waiter).then_some(false)
+    })
+}
+
+fn leave_queue(name: &str, waiter: CallContextId) {
+    LOCKS.with(|locks| {
+        if let Some(lock) = locks.borrow_mut().get_mut(name) {
+            lock.waiters.retain(|queued| *queued != waiter);
+        }
+    });
+}
+
+// Waits on self-calls until the lock is passed to the waiter.
+async fn wait(name: String, waiter: CallContextId) -> Result<JSValue, String> {
+    let deadline = ic_cdk::api::time().saturating_add(MAX_WAIT.as_nanos() as u64);
+    loop {
+        match is_held_by(&name, waiter) {
+            Some(true) => return Ok(JSValue::Undefined),
+            Some(false) => {}
+            None => return Err(format!("The message left the queue of the lock {}", name)),
+        }
+        if ic_cdk::api::time() >= deadline {
+            leave_queue(&name, waiter);
+            return Err(format!(
+                "Timed out after {}s waiting for the lock {}",
+                MAX_WAIT.as_secs(),
+                name
+            ));
+        }
+        if let Err((code, message)) = ic_cdk::call::<_, ()>(ic_cdk::id(), WAIT_METHOD, ()).await {
+            leave_queue(&name, waiter);
+            return Err(format!(
+                "Waiting for the lock {} failed with code {:?}: {}",
+                name, code, message
+            ));
+        }
+    }
+}
+
+pub fn link(context: &JSContextRef) -> Result<(), anyhow::Error> {
+    // Returns `true` if the lock was free and is now held by the caller, or a
+    // promise that resolves when the lock is passed to the caller.
+    fn acquire<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 1 {
+            return Err(JSError::Type(format!("Expected 1 argument, got {}", args.len())).into());
+        }
+        engine::ensure_writable("ic.lock()")?;
+        let name: String = args[0].try_into()?;
+        let caller = engine::entered_call_context(context)?;
+        let acquired = LOCKS.with(|locks| {
+            let mut locks = locks.borrow_mut();
+            match locks.get_mut(&name) {
+                Some(lock) => {
+                    lock.waiters.push_back(caller);
+                    false
+                }
+                None => {
+                    locks.insert(
+                        name.clone(),
+                        Lock {
+                            holder: caller,
+                            waiters: VecDeque::new(),
+                        },
+                    );
+                    true
+                }
+            }
+        });
+        if acquired {
+            return context.value_from_bool(true);
+        }
+        engine::promise_for(context, wait(name, caller))
+    }
+
+    // Passes the lock to the next waiter or frees it.
+    fn release<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 1 {
+            return Err(JSError::Type(format!("Expected 1 argument, got {}", args.len())).into());
+        }
+        let name: String = args[0].try_into()?;
+        LOCKS.with(|locks| {
+            let mut locks = locks.borrow_mut();
+            if !locks.contains_key(&name) {
+                return Err(JSError::Type(format!("The lock {} is not held", name)));
+            }
+            pass(&mut locks, &name);
+            Ok(())
+        })?;
+        context.undefined_value()
+    }
+
+    let locks = context.object_value()?;
+    locks.set_property("acquire", context.wrap_callback2(acquire)?)?;
+    locks.set_property("release", context.wrap_callback2(release)?)?;
+    engine::namespace(context)?.set_property("locks", locks)?;
+    Ok(())
+}
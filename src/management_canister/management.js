@@ -0,0 +1,26 @@
+// Usage: `await managementCanister.statusMany(canisterIds, {concurrency})`.
+// Fetches the status of each canister with at most `concurrency` calls in
+// flight (10 by default) and resolves with one `{canisterId, status}` or
+// `{canisterId, error}` object per canister in the order of the ids.
+managementCanister.statusMany = async function (canisterIds, options = {}) {
+	const concurrency = Math.max(1, options.concurrency || 10);
+	const results = new Array(canisterIds.length);
+	let next = 0;
+	async function worker() {
+		while (next < canisterIds.length) {
+			const i = next++;
+			const canisterId = canisterIds[i];
+			try {
+				results[i] = { canisterId, status: await managementCanister.canister_status(canisterId) };
+			} catch (e) {
+				results[i] = { canisterId, error: String(e && e.message || e) };
+			}
+		}
+	}
+	const workers = [];
+	for (let i = 0; i < Math.min(concurrency, canisterIds.length); i++) {
+		workers.push(worker());
+	}
+	await Promise.all(workers);
+	return results;
+};
@@ -7,6 +7,10 @@ use quickjs_wasm_rs::{CallbackArg, JSContextRef, JSError, JSValueRef};
 
 use crate::engine;
 
+// Helpers on top of the linked methods.
+const HELPERS_FILE: &str = "management.js";
+const HELPERS_SCRIPT: &str = include_str!("management.js");
+
 pub fn link(context: &JSContextRef) -> Result<(), anyhow::Error> {
     fn raw_rand<'a>(
         context: &'a JSContextRef,
@@ -75,5 +79,7 @@ pub fn link(context: &JSContextRef) -> Result<(), anyhow::Error> {
 
     let global = context.global_object()?;
     global.set_property("managementCanister", management)?;
+
+    context.eval_global(HELPERS_FILE, HELPERS_SCRIPT)?;
     Ok(())
 }
@@ -0,0 +1,145 @@
+// Versioned migrations of persisted data.
+//
+// The schema version of the persisted data is stored in stable memory.
+// Migrations are registered by Rust code or by the JS script and are keyed by
+// the schema version that they produce. After an upgrade, all migrations with
+// a version greater than the stored version run in the ascending order before
+// the canister serves any messages. If a migration fails, then `post_upgrade`
+// traps, which cancels the upgrade and keeps the old code and data.
+use std::{cell::RefCell, collections::BTreeMap};
+
+use ic_stable_structures::StableCell;
+use quickjs_wasm_rs::{CallbackArg, JSContextRef, JSError, JSValue, JSValueRef};
+
+use crate::{
+    engine,
+    stable::{self, Memory},
+    system_api::{self, Level},
+};
+
+/// A migration of the persisted data to a new schema version.
+#[derive(Clone)]
+pub enum Migration {
+    /// A Rust function.
+    Rust(fn() -> Result<(), String>),
+    /// The name of a global JS function that receives the old version.
+    Js(String),
+}
+
+thread_local! {
+    // The schema version of the persisted data.
+    static VERSION: RefCell<StableCell<u32, Memory>> =
+        RefCell::new(StableCell::init(stable::memory(stable::SCHEMA_VERSION), 0).unwrap());
+
+    // The registered migrations keyed by the version they produce.
+    static MIGRATIONS: RefCell<BTreeMap<u32, Migration>> = RefCell::new(BTreeMap::new());
+}
+
+/// Registers the migration that produces the given schema version.
+pub fn register(version: u32, migration: Migration) -> Result<(), String> {
+    MIGRATIONS.with(|migrations| {
+        let mut migrations = migrations.borrow_mut();
+        if migrations.contains_key(&version) {
+            return Err(format!(
+                "A migration to version {} is already registered",
+                version
+            ));
+        }
+        migrations.insert(version, migration);
+        Ok(())
+    })
+}
+
+/// Returns the schema version of the persisted data.
+pub fn version() -> u32 {
+    VERSION.with(|version| *version.borrow().get())
+}
+
+fn set_version(new_version: u32) {
+    VERSION.with(|version| version.borrow_mut().set(new_version).unwrap());
+}
+
+/// Marks the data of a freshly installed canister as having the latest schema
+/// version because there is nothing to migrate.
+pub fn mark_latest() {
+    let latest = MIGRATIONS.with(|migrations| migrations.borrow().keys().last().copied());
+    set_version(latest.unwrap_or(0));
+}
+
+/// Runs all pending migrations in the ascending order of versions.
+/// Each successful migration bumps the stored schema version.
+pub fn run_pending() -> Result<(), String> {
+    let current = version();
+    let pending: Vec<_> = MIGRATIONS.with(|migrations| {
+        migrations
+            .borrow()
+            .range(current + 1..)
+            .map(|(version, migration)| (*version, migration.clone()))
+            .collect()
+    });
+    for (version, migration) in pending {
+        let from = self::version();
+        let result = match &migration {
+            Migration::Rust(f) => f(),
+            Migration::Js(name) => engine::execute_sync(
+                name,
+                |context| Ok(vec![context.value_from_i32(from as i32)?]),
+                |_context, _result| Ok(()),
+            )
+            .map_err(|err| err.to_string()),
+        };
+        if let Err(err) = result {
+            return Err(format!(
+                "Migration from version {} to version {} failed: {}",
+                from, version, err
+            ));
+        }
+        set_version(version);
+        system_api::log(
+            Level::Info,
+            Some("migrations"),
+            &[format!("Migrated from version {} to version {}", from, version).into()],
+        );
+    }
+    Ok(())
+}
+
+pub fn link(context: &JSContextRef) -> Result<(), anyhow::Error> {
+    // Usage: `ic.migrations.register(version, functionName)`.
+    fn register<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 2 {
+            return Err(JSError::Type(format!("Expected 2 arguments, got {}", args.len())).into());
+        }
+        let version = match args[0].to_js_value()? {
+            JSValue::Int(version) if version > 0 => version as u32,
+            _ => {
+                return Err(JSError::Type(
+                    "Expected the version to be a positive integer".to_string(),
+                )
+                .into())
+            }
+        };
+        let name: String = args[1].try_into()?;
+        self::register(version, Migration::Js(name)).map_err(JSError::Type)?;
+        context.undefined_value()
+    }
+
+    fn version<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        _args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        context.value_from_f64(self::version() as f64)
+    }
+
+    let migrations = context.object_value()?;
+    migrations.set_property("register", context.wrap_callback2(register)?)?;
+    migrations.set_property("version", context.wrap_callback2(version)?)?;
+
+    engine::namespace(context)?.set_property("migrations", migrations)?;
+    Ok(())
+}
@@ -0,0 +1,193 @@
+use std::cell::RefCell;
+
+use candid::{CandidType, Deserialize, Nat};
+use ic_cdk::api::management_canister::main::{
+    canister_status, CanisterIdRecord, CanisterStatusType,
+};
+use ic_stable_structures::StableCell;
+use quickjs_wasm_rs::{JSValue, JSValueRef};
+
+use crate::{
+    engine, json,
+    stable::{self, Candid, Memory},
+};
+
+/// The name of an optional JS function that returns custom health indicators
+/// as an object that maps indicator names to values.
+pub const HEALTH_INDICATORS: &str = "healthIndicators";
+
+pub const WASM_PAGE_SIZE: u64 = 65536;
+
+/// The status of the canister in the shape of the `canister_status` response
+/// of the management canister extended with custom health indicators.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct CanisterStatus {
+    pub status: Option<CanisterStatusType>,
+    pub module_hash: Option<Vec<u8>>,
+    pub cycles: Nat,
+    pub memory_size: Nat,
+    pub idle_cycles_burned_per_day: Option<Nat>,
+    pub heap_memory_size: Nat,
+    pub stable_memory_size: Nat,
+    pub indicators: Vec<HealthIndicator>,
+}
+
+/// A custom health indicator returned by the JS code.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct HealthIndicator {
+    pub name: String,
+    pub value: String,
+}
+
+/// The versions of the deployed code.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct EngineInfo {
+    pub engine_version: String,
+    pub quickjs_version: String,
+    pub script_hash: String,
+    /// The build time in seconds since the epoch if `compile.sh` built the
+    /// module.
+    pub build_timestamp: Option<u64>,
+    pub features: Vec<String>,
+}
+
+pub fn engine_info() -> EngineInfo {
+    let mut features = vec![];
+    if cfg!(feature = "fault-injection") {
+        features.push("fault-injection".to_string());
+    }
+    if cfg!(feature = "dev-repl") {
+        features.push("dev-repl".to_string());
+    }
+    EngineInfo {
+        engine_version: env!("CARGO_PKG_VERSION").to_string(),
+        quickjs_version: option_env!("QUICKJS_VERSION")
+            .unwrap_or("unknown")
+            .to_string(),
+        script_hash: engine::script_hash(),
+        build_timestamp: option_env!("BUILD_TIMESTAMP")
+            .and_then(|timestamp| timestamp.parse().ok()),
+        features,
+    }
+}
+
+/// The metadata of the active script. The custom sections of the Wasm module
+/// describe the script it was built with, so this mirror in stable memory is
+/// updated whenever another script becomes active without an upgrade.
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct ScriptMetadata {
+    pub script_hash: String,
+    pub engine_version: String,
+    /// The time when the script became active in nanoseconds since the epoch.
+    pub activated_at: u64,
+}
+
+thread_local! {
+    static SCRIPT_METADATA: RefCell<StableCell<Candid<ScriptMetadata>, Memory>> = RefCell::new(
+        StableCell::init(stable::memory(stable::SCRIPT_METADATA), Candid(ScriptMetadata::default())).unwrap(),
+    );
+}
+
+/// Records the metadata of the active script in stable memory.
+pub fn mirror_script_metadata() {
+    let metadata = ScriptMetadata {
+        script_hash: engine::script_hash(),
+        engine_version: env!("CARGO_PKG_VERSION").to_string(),
+        activated_at: ic_cdk::api::time(),
+    };
+    SCRIPT_METADATA.with(|cell| cell.borrow_mut().set(Candid(metadata)).unwrap());
+}
+
+pub fn script_metadata() -> ScriptMetadata {
+    SCRIPT_METADATA.with(|cell| cell.borrow().get().0.clone())
+}
+
+/// The progress of draining before an upgrade.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct DrainStatus {
+    pub draining: bool,
+    /// True if no executions or outgoing calls are pending, so the canister
+    /// can be upgraded safely.
+    pub idle: bool,
+    pub pending_executions: u64,
+    pub pending_calls: u64,
+}
+
+pub fn drain_status() -> DrainStatus {
+    let status = engine::status();
+    DrainStatus {
+        draining: status.draining,
+        idle: status.is_idle(),
+        pending_executions: status.pending_executions as u64,
+        pending_calls: status.pending_calls as u64,
+    }
+}
+
+/// Collects the status of the canister.
+///
+/// The module hash and the idle cycles burn rate are available only if the
+/// canister is its own controller because they come from the management
+/// canister. Otherwise, the status falls back to the values available via the
+/// system API.
+pub async fn self_status() -> CanisterStatus {
+    let heap_memory_size = heap_memory_size();
+    let stable_memory_size = ic_cdk::api::stable::stable64_size() * WASM_PAGE_SIZE;
+    let record = CanisterIdRecord {
+        canister_id: ic_cdk::id(),
+    };
+    match canister_status(record).await {
+        Ok((response,)) => CanisterStatus {
+            status: Some(response.status),
+            module_hash: response.module_hash,
+            cycles: response.cycles,
+            memory_size: response.memory_size,
+            idle_cycles_burned_per_day: Some(response.idle_cycles_burned_per_day),
+            heap_memory_size: heap_memory_size.into(),
+            stable_memory_size: stable_memory_size.into(),
+            indicators: vec![],
+        },
+        Err(_) => CanisterStatus {
+            status: None,
+            module_hash: None,
+            cycles: ic_cdk::api::canister_balance128().into(),
+            memory_size: (heap_memory_size + stable_memory_size).into(),
+            idle_cycles_burned_per_day: None,
+            heap_memory_size: heap_memory_size.into(),
+            stable_memory_size: stable_memory_size.into(),
+            indicators: vec![],
+        },
+    }
+}
+
+/// Converts the result of the `healthIndicators()` JS function into a list of
+/// indicators. String values are kept as is, other values are JSON-encoded.
+pub fn to_indicators(value: &JSValueRef) -> Result<Vec<HealthIndicator>, anyhow::Error> {
+    let fields = match quickjs_wasm_rs::from_qjs_value(value)? {
+        JSValue::Undefined | JSValue::Null => return Ok(vec![]),
+        JSValue::Object(fields) => fields,
+        _ => anyhow::bail!("Expected {}() to return an object", HEALTH_INDICATORS),
+    };
+    let mut indicators: Vec<_> = fields
+        .into_iter()
+        .map(|(name, value)| {
+            let value = match value {
+                JSValue::String(value) => value,
+                value => json::to_json(&value).to_string(),
+            };
+            HealthIndicator { name, value }
+        })
+        .collect();
+    indicators.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(indicators)
+}
+
+pub fn heap_memory_size() -> u64 {
+    #[cfg(target_arch = "wasm32")]
+    {
+        core::arch::wasm32::memory_size(0) as u64 * WASM_PAGE_SIZE
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        0
+    }
+}
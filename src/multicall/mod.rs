@@ -0,0 +1,70 @@
+// Generic outgoing calls exposed to JS as `ic.callRaw(canisterId, method,
+// args, cycles)` and `ic.multicall(calls)`. `ic.call` is the same function.
+// The cycles are a number, a BigInt, a decimal string or `{cycles}`.
+//
+// The arguments are either Candid text like `(42, "text")` or an ArrayBuffer
+// with Candid-encoded bytes. The reply is returned as an ArrayBuffer with the
+// Candid-encoded bytes because its type is unknown to the engine.
+use candid::{IDLArgs, Principal};
+use quickjs_wasm_rs::{CallbackArg, JSContextRef, JSError, JSValue, JSValueRef};
+
+use crate::{engine, system_api};
+
+// The helper that performs several calls defined in multicall.js.
+const MULTICALL_FILE: &str = "multicall.js";
+const MULTICALL_SCRIPT: &str = include_str!("multicall.js");
+
+fn encode(args: &CallbackArg) -> Result<Vec<u8>, anyhow::Error> {
+    match args.to_js_value()? {
+        JSValue::Undefined => Ok(IDLArgs::new(&[]).to_bytes()?),
+        JSValue::String(text) => Ok(text.parse::<IDLArgs>()?.to_bytes()?),
+        JSValue::ArrayBuffer(bytes) => Ok(bytes),
+        _ => Err(JSError::Type(
+            "Expected the arguments to be Candid text or an ArrayBuffer".to_string(),
+        )
+        .into()),
+    }
+}
+
+pub fn link(context: &JSContextRef) -> Result<(), anyhow::Error> {
+    fn call_raw<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() < 2 || args.len() > 4 {
+            return Err(
+                JSError::Type(format!("Expected 2 to 4 arguments, got {}", args.len())).into(),
+            );
+        }
+        let canister_id: String = args[0].try_into()?;
+        let canister_id = Principal::from_text(canister_id)?;
+        let method: String = args[1].try_into()?;
+        let bytes = match args.get(2) {
+            Some(arg) => encode(arg)?,
+            None => IDLArgs::new(&[]).to_bytes()?,
+        };
+        let cycles = match args.get(3).map(|arg| arg.to_js_value()).transpose()? {
+            None | Some(JSValue::Undefined | JSValue::Null) => 0,
+            Some(JSValue::Object(options)) => match options.get("cycles") {
+                None | Some(JSValue::Undefined) => 0,
+                Some(cycles) => system_api::cycles(cycles, "cycles")?,
+            },
+            Some(cycles) => system_api::cycles(&cycles, "cycles")?,
+        };
+        engine::call_with_cycles(
+            context,
+            canister_id,
+            &method,
+            &bytes,
+            cycles,
+            |context, bytes| context.array_buffer_value(&bytes),
+        )
+    }
+
+    let ic = engine::namespace(context)?;
+    ic.set_property("call", context.wrap_callback2(call_raw)?)?;
+    ic.set_property("callRaw", context.wrap_callback2(call_raw)?)?;
+    context.eval_global(MULTICALL_FILE, MULTICALL_SCRIPT)?;
+    Ok(())
+}
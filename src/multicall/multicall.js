@@ -0,0 +1,25 @@
+// Passes BigInt cycles to Rust as decimal strings, also inside `{cycles}`.
+(function () {
+	const toText = (cycles) => typeof cycles === "bigint" ? cycles.toString() : cycles;
+	const callRaw = ic.callRaw;
+	ic.call = ic.callRaw = (...args) => {
+		if (args.length > 3) {
+			const cycles = args[3];
+			args[3] = cycles !== null && typeof cycles === "object" ? {cycles: toText(cycles.cycles)} : toText(cycles);
+		}
+		return callRaw(...args);
+	};
+})();
+
+// Usage: `await ic.multicall([{canisterId, method, args, cycles}, ...])`.
+// Performs all calls in parallel with `ic.callRaw()` and resolves with one
+// `{status, value}` or `{status, reason}` object per call like
+// `Promise.allSettled()`. The calls count towards the concurrency limit and
+// the budget of the call context, so large batches are queued by the engine.
+ic.multicall = function (calls) {
+	if (!Array.isArray(calls)) {
+		throw new TypeError("Expected an array of calls");
+	}
+	return Promise.allSettled(calls.map(async (call) =>
+		ic.callRaw(call.canisterId, call.method, call.args, call.cycles)));
+};
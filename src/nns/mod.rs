@@ -0,0 +1,320 @@
+// A binding of the NNS governance canister exposed to JS as `ic.nns`.
+//
+// The functions manage neurons controlled by this canister and return
+// promises:
+// - `claimOrRefresh({memo})` claims or refreshes the neuron staked with the
+//   given memo in the subaccount of this canister and
+//   `claimOrRefresh({neuronId})` refreshes an existing neuron. Both resolve
+//   with the neuron id.
+// - `follow(neuronId, topic, followees)` sets the followees of a topic.
+// - `vote(neuronId, proposalId, vote)` votes with `"yes"` or `"no"`.
+// - `disburse(neuronId, {toAccount, amountE8s})` disburses the stake of a
+//   dissolved neuron to the given hex account identifier or to this canister
+//   and resolves with the block height of the transfer.
+//
+// Neuron and proposal ids are 64-bit numbers, so they are returned as decimal
+// strings and accepted as numbers or strings.
+use candid::{CandidType, Deserialize, Principal};
+use quickjs_wasm_rs::{CallbackArg, JSContextRef, JSError, JSValue, JSValueRef};
+
+use crate::{call_policy, engine, jobs};
+
+/// The NNS governance canister.
+pub const GOVERNANCE_CANISTER_ID: &str = "rrkah-fqaaa-aaaaa-aaaaq-cai";
+
+#[derive(CandidType, Deserialize, Copy, Clone, Debug)]
+pub struct NeuronId {
+    pub id: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Follow {
+    pub topic: i32,
+    pub followees: Vec<NeuronId>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct RegisterVote {
+    pub vote: i32,
+    pub proposal: Option<NeuronId>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct AccountIdentifier {
+    pub hash: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Amount {
+    pub e8s: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Disburse {
+    pub to_account: Option<AccountIdentifier>,
+    pub amount: Option<Amount>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct MemoAndController {
+    pub controller: Option<Principal>,
+    pub memo: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Empty {}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum By {
+    NeuronIdOrSubaccount(Empty),
+    MemoAndController(MemoAndController),
+    Memo(u64),
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ClaimOrRefresh {
+    pub by: Option<By>,
+}
+
+// The subset of the commands of `manage_neuron` used here.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum Command {
+    Follow(Follow),
+    RegisterVote(RegisterVote),
+    Disburse(Disburse),
+    ClaimOrRefresh(ClaimOrRefresh),
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum NeuronIdOrSubaccount {
+    Subaccount(Vec<u8>),
+    NeuronId(NeuronId),
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ManageNeuron {
+    pub id: Option<NeuronId>,
+    pub command: Option<Command>,
+    pub neuron_id_or_subaccount: Option<NeuronIdOrSubaccount>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct GovernanceError {
+    pub error_message: String,
+    pub error_type: i32,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct DisburseResponse {
+    pub transfer_block_height: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ClaimOrRefreshResponse {
+    pub refreshed_neuron_id: Option<NeuronId>,
+}
+
+// The subset of the responses of `manage_neuron` used here.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum CommandResponse {
+    Error(GovernanceError),
+    Follow(Empty),
+    RegisterVote(Empty),
+    Disburse(DisburseResponse),
+    ClaimOrRefresh(ClaimOrRefreshResponse),
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ManageNeuronResponse {
+    pub command: Option<CommandResponse>,
+}
+
+/// Sends the given command to the given neuron and returns the response.
+pub async fn manage_neuron(
+    neuron: Option<NeuronId>,
+    command: Command,
+) -> Result<CommandResponse, String> {
+    let governance = Principal::from_text(GOVERNANCE_CANISTER_ID).unwrap();
+    let args = ManageNeuron {
+        id: None,
+        command: Some(command),
+        neuron_id_or_subaccount: neuron.map(NeuronIdOrSubaccount::NeuronId),
+    };
+    call_policy::check(&governance, "manage_neuron")?;
+    let (response,): (ManageNeuronResponse,) = ic_cdk::call(governance, "manage_neuron", (args,))
+        .await
+        .map_err(|(code, message)| {
+            format!("manage_neuron failed with code {:?}: {}", code, message)
+        })?;
+    match response.command {
+        Some(CommandResponse::Error(err)) => Err(format!(
+            "manage_neuron failed with error type {}: {}",
+            err.error_type, err.error_message
+        )),
+        Some(response) => Ok(response),
+        None => Err("manage_neuron returned no response".to_string()),
+    }
+}
+
+// A helper that extracts a 64-bit id from a JS number or a decimal string.
+fn id(value: &JSValue, name: &str) -> Result<u64, anyhow::Error> {
+    match value {
+        JSValue::String(value) => value
+            .parse()
+            .map_err(|_| JSError::Type(format!("Expected {} to be a 64-bit number", name)).into()),
+        value => Ok(jobs::number(value, name)? as u64),
+    }
+}
+
+fn neuron_id(value: &JSValue) -> Result<NeuronId, anyhow::Error> {
+    Ok(NeuronId {
+        id: id(value, "neuronId")?,
+    })
+}
+
+pub fn link(context: &JSContextRef) -> Result<(), anyhow::Error> {
+    fn claim_or_refresh<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 1 {
+            return Err(JSError::Type(format!("Expected 1 argument, got {}", args.len())).into());
+        }
+        let JSValue::Object(fields) = args[0].to_js_value()? else {
+            return Err(JSError::Type("Expected {memo} or {neuronId}".to_string()).into());
+        };
+        let (neuron, by) = match (fields.get("memo"), fields.get("neuronId")) {
+            (Some(memo), None) => (
+                None,
+                By::MemoAndController(MemoAndController {
+                    controller: Some(ic_cdk::id()),
+                    memo: id(memo, "memo")?,
+                }),
+            ),
+            (None, Some(neuron)) => (Some(neuron_id(neuron)?), By::NeuronIdOrSubaccount(Empty {})),
+            _ => return Err(JSError::Type("Expected {memo} or {neuronId}".to_string()).into()),
+        };
+        engine::charge_call(context, 0)?;
+        engine::promise_for(context, async move {
+            let command = Command::ClaimOrRefresh(ClaimOrRefresh { by: Some(by) });
+            match manage_neuron(neuron, command).await? {
+                CommandResponse::ClaimOrRefresh(ClaimOrRefreshResponse {
+                    refreshed_neuron_id: Some(neuron),
+                }) => Ok(JSValue::String(neuron.id.to_string())),
+                response => Err(format!("Unexpected response {:?}", response)),
+            }
+        })
+    }
+
+    fn follow<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 3 {
+            return Err(JSError::Type(format!("Expected 3 arguments, got {}", args.len())).into());
+        }
+        let neuron = neuron_id(&args[0].to_js_value()?)?;
+        let topic = jobs::number(&args[1].to_js_value()?, "topic")? as i32;
+        let JSValue::Array(followees) = args[2].to_js_value()? else {
+            return Err(JSError::Type("Expected followees to be an array".to_string()).into());
+        };
+        let followees = followees.iter().map(neuron_id).collect::<Result<_, _>>()?;
+        engine::charge_call(context, 0)?;
+        engine::promise_for(context, async move {
+            manage_neuron(Some(neuron), Command::Follow(Follow { topic, followees })).await?;
+            Ok(JSValue::Undefined)
+        })
+    }
+
+    fn vote<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 3 {
+            return Err(JSError::Type(format!("Expected 3 arguments, got {}", args.len())).into());
+        }
+        let neuron = neuron_id(&args[0].to_js_value()?)?;
+        let proposal = NeuronId {
+            id: id(&args[1].to_js_value()?, "proposalId")?,
+        };
+        let vote: String = args[2].try_into()?;
+        let vote = match vote.as_str() {
+            "yes" => 1,
+            "no" => 2,
+            _ => {
+                return Err(JSError::Type(format!(
+                    "Expected the vote to be yes or no, got {}",
+                    vote
+                ))
+                .into())
+            }
+        };
+        engine::charge_call(context, 0)?;
+        engine::promise_for(context, async move {
+            let command = Command::RegisterVote(RegisterVote {
+                vote,
+                proposal: Some(proposal),
+            });
+            manage_neuron(Some(neuron), command).await?;
+            Ok(JSValue::Undefined)
+        })
+    }
+
+    fn disburse<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.is_empty() || args.len() > 2 {
+            return Err(
+                JSError::Type(format!("Expected 1 or 2 arguments, got {}", args.len())).into(),
+            );
+        }
+        let neuron = neuron_id(&args[0].to_js_value()?)?;
+        let mut disburse = Disburse {
+            to_account: None,
+            amount: None,
+        };
+        if let Some(JSValue::Object(fields)) =
+            args.get(1).map(|arg| arg.to_js_value()).transpose()?
+        {
+            let hash = match fields.get("toAccount") {
+                None | Some(JSValue::Undefined | JSValue::Null) => None,
+                Some(JSValue::String(account)) => Some(hex::decode(account)?),
+                Some(JSValue::ArrayBuffer(account)) => Some(account.clone()),
+                Some(_) => {
+                    return Err(
+                        JSError::Type("Expected toAccount to be a hex string".to_string()).into(),
+                    )
+                }
+            };
+            disburse.to_account = hash.map(|hash| AccountIdentifier { hash });
+            if let Some(amount) = fields.get("amountE8s") {
+                disburse.amount = Some(Amount {
+                    e8s: id(amount, "amountE8s")?,
+                });
+            }
+        }
+        engine::charge_call(context, 0)?;
+        engine::promise_for(context, async move {
+            match manage_neuron(Some(neuron), Command::Disburse(disburse)).await? {
+                CommandResponse::Disburse(response) => {
+                    Ok(JSValue::String(response.transfer_block_height.to_string()))
+                }
+                response => Err(format!("Unexpected response {:?}", response)),
+            }
+        })
+    }
+
+    let nns = context.object_value()?;
+    nns.set_property("claimOrRefresh", context.wrap_callback2(claim_or_refresh)?)?;
+    nns.set_property("follow", context.wrap_callback2(follow)?)?;
+    nns.set_property("vote", context.wrap_callback2(vote)?)?;
+    nns.set_property("disburse", context.wrap_callback2(disburse)?)?;
+
+    engine::namespace(context)?.set_property("nns", nns)?;
+    Ok(())
+}
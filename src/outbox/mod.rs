@@ -0,0 +1,336 @@
+// A transactional outbox for outgoing calls.
+//
+// Instead of making an outgoing call directly, JS code records the intent to
+// make the call. The intent is persisted in the same message as the state
+// changes of the JS code, so either both of them are committed or none.
+// A background drainer performs the recorded calls with retries, which gives
+// at-least-once delivery. The result of a call is optionally delivered to a JS
+// function via the job queue.
+//
+// An intent is in flight from the start of its call until the call completes,
+// and it is not attempted again in the meantime. If the callback of the call
+// traps, then the cleanup callback drops the future of the call, which makes
+// the intent due for a retry.
+//
+// The call policy is checked when the intent is recorded and again before
+// each attempt, and the call counts against the budget of the message that
+// records it.
+use std::{cell::RefCell, time::Duration};
+
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk_timers::TimerId;
+use ic_stable_structures::{StableBTreeMap, StableCell};
+use quickjs_wasm_rs::{CallbackArg, JSContextRef, JSError, JSValue, JSValueRef};
+use serde_json::json;
+
+use crate::{
+    call_policy, engine,
+    jobs::{self, RetryPolicy},
+    stable::{self, Candid, Memory},
+    system_api::{self, Level},
+};
+
+// The maximum number of calls started by a single drain of the outbox.
+const MAX_BATCH: usize = 16;
+
+// The interval in nanoseconds of the drains while calls are in flight, which
+// retry the intents whose callbacks trapped.
+const IN_FLIGHT_CHECK_NANOS: u64 = 10 * 60 * 1_000_000_000;
+
+/// A recorded outgoing call.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Intent {
+    pub id: u64,
+    pub canister_id: Principal,
+    pub method: String,
+    /// The Candid-encoded arguments of the call.
+    pub args: Vec<u8>,
+    /// The cycles attached to the call.
+    pub cycles: u128,
+    /// The optional name of the global JS function that receives the result.
+    pub callback: Option<String>,
+    /// The number of started attempts.
+    pub attempts: u32,
+    pub policy: RetryPolicy,
+    /// The time when the call is attempted next.
+    pub next_attempt_at: u64,
+    pub last_error: Option<String>,
+    /// The start time of the call that is in flight.
+    pub in_flight_since: Option<u64>,
+}
+
+thread_local! {
+    static OUTBOX: RefCell<StableBTreeMap<u64, Candid<Intent>, Memory>> =
+        RefCell::new(StableBTreeMap::init(stable::memory(stable::OUTBOX)));
+
+    // The id of the next recorded intent.
+    static NEXT_ID: RefCell<StableCell<u64, Memory>> =
+        RefCell::new(StableCell::init(stable::memory(stable::OUTBOX_SEQUENCE), 0).unwrap());
+
+    // The timer that drains the outbox and the time when it fires.
+    static DRAIN_TIMER: RefCell<Option<(u64, TimerId)>> = RefCell::new(None);
+}
+
+/// Records the intent to make the given outgoing call and returns its id.
+pub fn add(
+    canister_id: Principal,
+    method: String,
+    args: Vec<u8>,
+    cycles: u128,
+    callback: Option<String>,
+    policy: RetryPolicy,
+) -> u64 {
+    let id = NEXT_ID.with(|next_id| {
+        let mut next_id = next_id.borrow_mut();
+        let id = *next_id.get();
+        next_id.set(id + 1).unwrap();
+        id
+    });
+    let intent = Intent {
+        id,
+        canister_id,
+        method,
+        args,
+        cycles,
+        callback,
+        attempts: 0,
+        policy,
+        next_attempt_at: ic_cdk::api::time(),
+        last_error: None,
+        in_flight_since: None,
+    };
+    OUTBOX.with(|outbox| outbox.borrow_mut().insert(id, Candid(intent)));
+    schedule();
+    id
+}
+
+/// Arms the timer that drains the outbox when the earliest intent is due.
+/// Must be called after an upgrade because timers do not survive upgrades.
+pub fn schedule() {
+    let now = ic_cdk::api::time();
+    let next_attempt_at = OUTBOX.with(|outbox| {
+        outbox
+            .borrow()
+            .iter()
+            .map(|(_, Candid(intent))| match intent.in_flight_since {
+                Some(_) => now.saturating_add(IN_FLIGHT_CHECK_NANOS),
+                None => intent.next_attempt_at,
+            })
+            .min()
+    });
+    let Some(next_attempt_at) = next_attempt_at else {
+        return;
+    };
+    DRAIN_TIMER.with(|timer| {
+        let mut timer = timer.borrow_mut();
+        if let Some((scheduled_at, _)) = *timer {
+            if scheduled_at <= next_attempt_at {
+                return;
+            }
+        }
+        if let Some((_, id)) = timer.take() {
+            ic_cdk_timers::clear_timer(id);
+        }
+        let delay = Duration::from_nanos(next_attempt_at.saturating_sub(ic_cdk::api::time()));
+        *timer = Some((next_attempt_at, ic_cdk_timers::set_timer(delay, drain)));
+    });
+}
+
+// Starts the calls of all due intents that are not in flight.
+fn drain() {
+    DRAIN_TIMER.with(|timer| *timer.borrow_mut() = None);
+    let now = ic_cdk::api::time();
+    let due = OUTBOX.with(|outbox| {
+        let mut outbox = outbox.borrow_mut();
+        let due: Vec<_> = outbox
+            .iter()
+            .map(|(_, Candid(intent))| intent)
+            .filter(|intent| intent.in_flight_since.is_none() && intent.next_attempt_at <= now)
+            .take(MAX_BATCH)
+            .collect();
+        let mut started = vec![];
+        for mut intent in due {
+            if intent.attempts >= intent.policy.max_attempts {
+                outbox.remove(&intent.id);
+                started.push(Err(intent));
+                continue;
+            }
+            intent.attempts += 1;
+            intent.in_flight_since = Some(now);
+            outbox.insert(intent.id, Candid(intent.clone()));
+            started.push(Ok(intent));
+        }
+        started
+    });
+    for intent in due {
+        match intent {
+            Ok(intent) => ic_cdk::spawn(deliver(intent)),
+            Err(intent) => {
+                let err = intent
+                    .last_error
+                    .clone()
+                    .unwrap_or_else(|| "The last attempt did not complete".to_string());
+                give_up(intent, err);
+            }
+        }
+    }
+    schedule();
+}
+
+// Makes an intent due for a retry if the future of its call is dropped before
+// the call completes, which happens when the callback traps.
+struct InFlight {
+    id: u64,
+    completed: bool,
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        if !self.completed {
+            retry(self.id, "The callback of the call trapped".to_string());
+        }
+    }
+}
+
+// Performs the outgoing call of the given intent.
+async fn deliver(intent: Intent) {
+    if let Err(err) = call_policy::check(&intent.canister_id, &intent.method) {
+        OUTBOX.with(|outbox| outbox.borrow_mut().remove(&intent.id));
+        give_up(intent, err);
+        return;
+    }
+    let mut in_flight = InFlight {
+        id: intent.id,
+        completed: false,
+    };
+    let result = ic_cdk::api::call::call_raw128(
+        intent.canister_id,
+        &intent.method,
+        &intent.args,
+        intent.cycles,
+    )
+    .await;
+    in_flight.completed = true;
+    match result {
+        Ok(reply) => {
+            OUTBOX.with(|outbox| outbox.borrow_mut().remove(&intent.id));
+            notify(&intent, json!({ "id": intent.id, "reply": reply }));
+        }
+        Err((code, message)) => {
+            let err = format!("Call rejected with code {:?}: {}", code, message);
+            if intent.attempts >= intent.policy.max_attempts {
+                OUTBOX.with(|outbox| outbox.borrow_mut().remove(&intent.id));
+                give_up(intent, err);
+            } else {
+                retry(intent.id, err);
+                schedule();
+            }
+        }
+    }
+}
+
+// Ends the attempt of the intent with the given id and schedules the next one.
+fn retry(id: u64, err: String) {
+    OUTBOX.with(|outbox| {
+        let mut outbox = outbox.borrow_mut();
+        if let Some(Candid(mut intent)) = outbox.get(&id) {
+            intent.in_flight_since = None;
+            intent.next_attempt_at =
+                ic_cdk::api::time().saturating_add(intent.policy.delay(intent.attempts));
+            intent.last_error = Some(err);
+            outbox.insert(id, Candid(intent));
+        }
+    });
+}
+
+// Reports the intent that has exhausted all of its attempts.
+fn give_up(intent: Intent, err: String) {
+    system_api::log(
+        Level::Error,
+        Some("outbox"),
+        &[
+            format!(
+                "Call {} to {}.{} failed after {} attempts:",
+                intent.id, intent.canister_id, intent.method, intent.attempts
+            )
+            .into(),
+            err.clone().into(),
+        ],
+    );
+    notify(&intent, json!({ "id": intent.id, "error": err }));
+}
+
+// Delivers the result of the call to the JS callback, if there is one.
+fn notify(intent: &Intent, result: serde_json::Value) {
+    if let Some(callback) = &intent.callback {
+        jobs::enqueue(
+            callback.clone(),
+            result,
+            ic_cdk::api::time(),
+            RetryPolicy::default(),
+        );
+    }
+}
+
+pub fn link(context: &JSContextRef) -> Result<(), anyhow::Error> {
+    // Usage: `ic.outbox.add(canisterId, method, args, options)` where `args`
+    // is an ArrayBuffer with Candid-encoded arguments and `options` is
+    // `{cycles, callback, maxAttempts, backoffMs}`. The callback receives
+    // `{id, reply}` with the reply bytes or `{id, error}`.
+    fn add<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() < 3 || args.len() > 4 {
+            return Err(
+                JSError::Type(format!("Expected 3 or 4 arguments, got {}", args.len())).into(),
+            );
+        }
+        engine::ensure_writable("ic.outbox.add()")?;
+        let canister_id: String = args[0].try_into()?;
+        let canister_id = Principal::from_text(canister_id)?;
+        let method: String = args[1].try_into()?;
+        let bytes = match args[2].to_js_value()? {
+            JSValue::ArrayBuffer(bytes) => bytes,
+            _ => {
+                return Err(JSError::Type("Expected args to be an ArrayBuffer".to_string()).into())
+            }
+        };
+        let options = match args.get(3) {
+            Some(arg) => arg.to_js_value()?,
+            None => JSValue::Undefined,
+        };
+        let policy = RetryPolicy::from_js(&options)?;
+        let (cycles, callback) = match &options {
+            JSValue::Object(fields) => {
+                let cycles = match fields.get("cycles") {
+                    Some(value) => jobs::number(value, "cycles")? as u128,
+                    None => 0,
+                };
+                let callback = match fields.get("callback") {
+                    Some(JSValue::String(callback)) => Some(callback.clone()),
+                    Some(_) => {
+                        return Err(JSError::Type(
+                            "Expected callback to be a function name".to_string(),
+                        )
+                        .into())
+                    }
+                    None => None,
+                };
+                (cycles, callback)
+            }
+            _ => (0, None),
+        };
+        call_policy::check(&canister_id, &method).map_err(anyhow::Error::msg)?;
+        engine::charge_call(context, cycles)?;
+        let id = self::add(canister_id, method, bytes, cycles, callback, policy);
+        context.value_from_f64(id as f64)
+    }
+
+    let outbox = context.object_value()?;
+    outbox.set_property("add", context.wrap_callback2(add)?)?;
+
+    engine::namespace(context)?.set_property("outbox", outbox)?;
+    Ok(())
+}
@@ -0,0 +1,88 @@
+// Persistence of the JS state across upgrades.
+//
+// The heap of the JS context is lost on upgrade. The script keeps the state
+// that must survive in `globalThis.state`: `pre_upgrade` serializes it into
+// stable memory and `post_upgrade` restores it after the new script has been
+// evaluated, replacing the initial value that the script assigned. Other
+// globals start from scratch.
+//
+// The snapshot carries the version of its format, so a future format can
+// still read old snapshots. A snapshot in an unknown format fails the upgrade.
+// The state is serialized in `pre_upgrade`, so it must stay small enough to
+// fit into the instruction limit of an upgrade; larger data belongs in
+// `ic.kv`.
+use std::cell::RefCell;
+
+use candid::{CandidType, Deserialize};
+use ic_stable_structures::StableCell;
+use quickjs_wasm_rs::JSContextRef;
+
+use crate::{
+    engine,
+    stable::{self, Candid, Memory},
+};
+
+// The helpers that convert the state from and to JSON.
+const PERSISTENCE_FILE: &str = "persistence.js";
+const PERSISTENCE_SCRIPT: &str = include_str!("persistence.js");
+const SAVE_STATE: &str = "__saveState__";
+const RESTORE_STATE: &str = "__restoreState__";
+
+// Version 1 stores the JSON encoding of `ic.persist.encode(state)`.
+const FORMAT_VERSION: u32 = 1;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct Snapshot {
+    version: u32,
+    state: String,
+}
+
+thread_local! {
+    static SNAPSHOT: RefCell<StableCell<Candid<Option<Snapshot>>, Memory>> =
+        RefCell::new(StableCell::init(stable::memory(stable::JS_STATE), Candid(None)).unwrap());
+}
+
+/// Serializes `globalThis.state` into stable memory.
+pub fn save() -> Result<(), String> {
+    let state = engine::execute_sync(
+        SAVE_STATE,
+        |_context| Ok(vec![]),
+        |_context, value| {
+            if value.is_null_or_undefined() {
+                return Ok(None);
+            }
+            Ok(Some(value.as_str()?.to_string()))
+        },
+    )
+    .map_err(|err| format!("Failed to save the JS state: {}", err))?;
+    let snapshot = state.map(|state| Snapshot {
+        version: FORMAT_VERSION,
+        state,
+    });
+    SNAPSHOT.with(|cell| cell.borrow_mut().set(Candid(snapshot)).unwrap());
+    Ok(())
+}
+
+/// Restores `globalThis.state` from the snapshot of the last `save()`.
+pub fn restore() -> Result<(), String> {
+    let Some(snapshot) = SNAPSHOT.with(|cell| cell.borrow().get().0.clone()) else {
+        return Ok(());
+    };
+    if snapshot.version != FORMAT_VERSION {
+        return Err(format!(
+            "Unsupported format version {} of the JS state",
+            snapshot.version
+        ));
+    }
+    engine::execute_sync(
+        RESTORE_STATE,
+        move |context| Ok(vec![context.value_from_str(&snapshot.state)?]),
+        |_context, _value| Ok(()),
+    )
+    .map_err(|err| format!("Failed to restore the JS state: {}", err))
+}
+
+pub fn link(context: &JSContextRef) -> Result<(), anyhow::Error> {
+    context.eval_global(PERSISTENCE_FILE, PERSISTENCE_SCRIPT)?;
+    Ok(())
+}
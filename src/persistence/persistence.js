@@ -0,0 +1,15 @@
+// Saves `globalThis.state` before an upgrade and restores it after. The state
+// is encoded with `ic.persist`, so Maps, Sets, Dates and BigInts survive.
+Object.defineProperty(globalThis, "__saveState__", {
+	enumerable: false,
+	value: function () {
+		return globalThis.state === undefined ? null : JSON.stringify(ic.persist.encode(globalThis.state));
+	},
+});
+
+Object.defineProperty(globalThis, "__restoreState__", {
+	enumerable: false,
+	value: function (json) {
+		globalThis.state = ic.persist.decode(JSON.parse(json));
+	},
+});
@@ -0,0 +1,201 @@
+// A pool of pre-provisioned child canisters exposed to JS as `ic.pool`.
+//
+// `ic.pool.configure({size, cycles})` keeps `size` child canisters created in
+// advance, each with the given cycles and this canister as the controller. If
+// a controller uploaded a Wasm module with `set_pool_wasm`, the children are
+// installed with it. `ic.pool.take()` resolves with the id of a ready child
+// instantly while the pool has one, or creates a child on demand otherwise.
+// The pool is refilled in the background after each take.
+//
+// The ids of the pooled canisters are kept in stable memory, so they survive
+// upgrades. The configuration is set by the JS script on each start.
+use std::{cell::RefCell, time::Duration};
+
+use candid::Principal;
+use ic_cdk::api::management_canister::main::{
+    install_code, CanisterIdRecord, CanisterInstallMode, CanisterSettings, CreateCanisterArgument,
+    InstallCodeArgument,
+};
+use ic_stable_structures::StableCell;
+use quickjs_wasm_rs::{CallbackArg, JSContextRef, JSError, JSValue, JSValueRef};
+
+use crate::{
+    call_policy, engine, jobs,
+    stable::{self, Candid, Memory},
+    system_api::{self, Level},
+};
+
+#[derive(Copy, Clone, Debug)]
+pub struct Config {
+    pub size: usize,
+    pub cycles: u128,
+}
+
+thread_local! {
+    static POOL: RefCell<StableCell<Candid<Vec<Principal>>, Memory>> =
+        RefCell::new(StableCell::init(stable::memory(stable::POOL), Candid(vec![])).unwrap());
+
+    // The module installed into the children. Empty if none was uploaded.
+    static WASM: RefCell<StableCell<Candid<Vec<u8>>, Memory>> =
+        RefCell::new(StableCell::init(stable::memory(stable::POOL_WASM), Candid(vec![])).unwrap());
+
+    static CONFIG: RefCell<Option<Config>> = RefCell::new(None);
+
+    static IS_REFILLING: RefCell<bool> = RefCell::new(false);
+}
+
+pub fn configure(config: Config) {
+    CONFIG.with(|cell| *cell.borrow_mut() = Some(config));
+    schedule_refill();
+}
+
+/// Sets the Wasm module installed into new children.
+pub fn set_wasm(wasm: Vec<u8>) {
+    WASM.with(|cell| cell.borrow_mut().set(Candid(wasm)).unwrap());
+}
+
+/// Returns a ready child canister, creating one if the pool is empty.
+pub async fn take() -> Result<Principal, String> {
+    let pooled = POOL.with(|cell| {
+        let mut cell = cell.borrow_mut();
+        let mut pool = cell.get().0.clone();
+        let canister = pool.pop();
+        cell.set(Candid(pool)).unwrap();
+        canister
+    });
+    schedule_refill();
+    match pooled {
+        Some(canister) => Ok(canister),
+        None => {
+            let cycles = CONFIG.with(|cell| cell.borrow().map(|config| config.cycles));
+            provision(cycles.ok_or_else(|| "The pool is not configured".to_string())?).await
+        }
+    }
+}
+
+fn schedule_refill() {
+    ic_cdk_timers::set_timer(Duration::ZERO, || ic_cdk::spawn(refill()));
+}
+
+// Creates children one by one until the pool has the configured size.
+async fn refill() {
+    if IS_REFILLING.with(|flag| flag.replace(true)) {
+        return;
+    }
+    loop {
+        let Some(config) = CONFIG.with(|cell| *cell.borrow()) else {
+            break;
+        };
+        if POOL.with(|cell| cell.borrow().get().0.len()) >= config.size {
+            break;
+        }
+        match provision(config.cycles).await {
+            Ok(canister) => POOL.with(|cell| {
+                let mut cell = cell.borrow_mut();
+                let mut pool = cell.get().0.clone();
+                pool.push(canister);
+                cell.set(Candid(pool)).unwrap();
+            }),
+            Err(err) => {
+                system_api::log(
+                    Level::Error,
+                    Some("pool"),
+                    &[format!("Failed to refill the pool: {}", err).into()],
+                );
+                break;
+            }
+        }
+    }
+    IS_REFILLING.with(|flag| *flag.borrow_mut() = false);
+}
+
+// Creates a child canister and installs the uploaded module into it.
+async fn provision(cycles: u128) -> Result<Principal, String> {
+    let arg = CreateCanisterArgument {
+        settings: Some(CanisterSettings {
+            controllers: Some(vec![ic_cdk::id()]),
+            compute_allocation: None,
+            memory_allocation: None,
+            freezing_threshold: None,
+        }),
+    };
+    call_policy::check(&Principal::management_canister(), "create_canister")?;
+    let (CanisterIdRecord { canister_id },): (CanisterIdRecord,) =
+        ic_cdk::api::call::call_with_payment128(
+            Principal::management_canister(),
+            "create_canister",
+            (arg,),
+            cycles,
+        )
+        .await
+        .map_err(|(code, message)| {
+            format!("create_canister failed with code {:?}: {}", code, message)
+        })?;
+    let wasm_module = WASM.with(|cell| cell.borrow().get().0.clone());
+    if !wasm_module.is_empty() {
+        call_policy::check(&Principal::management_canister(), "install_code")?;
+        install_code(InstallCodeArgument {
+            mode: CanisterInstallMode::Install,
+            canister_id,
+            wasm_module,
+            arg: vec![],
+        })
+        .await
+        .map_err(|(code, message)| {
+            format!("install_code failed with code {:?}: {}", code, message)
+        })?;
+    }
+    Ok(canister_id)
+}
+
+pub fn link(context: &JSContextRef) -> Result<(), anyhow::Error> {
+    // Usage: `ic.pool.configure({size, cycles})`.
+    fn configure<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 1 {
+            return Err(JSError::Type(format!("Expected 1 argument, got {}", args.len())).into());
+        }
+        let JSValue::Object(fields) = args[0].to_js_value()? else {
+            return Err(
+                JSError::Type("Expected the configuration to be an object".to_string()).into(),
+            );
+        };
+        let number = |name: &str| match fields.get(name) {
+            Some(value) => jobs::number(value, name),
+            None => Err(JSError::Type(format!("Expected the {} field", name)).into()),
+        };
+        self::configure(Config {
+            size: number("size")? as usize,
+            cycles: number("cycles")? as u128,
+        });
+        context.undefined_value()
+    }
+
+    // Returns a promise that resolves with the id of a child canister. The
+    // cycles of one child are charged against the budget of the message
+    // because each take creates a child, either now or during the refill.
+    fn take<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if !args.is_empty() {
+            return Err(JSError::Type(format!("Expected 0 arguments, got {}", args.len())).into());
+        }
+        let cycles = CONFIG.with(|cell| cell.borrow().map_or(0, |config| config.cycles));
+        engine::charge_call(context, cycles)?;
+        engine::promise_for(context, async move {
+            Ok(JSValue::String(self::take().await?.to_text()))
+        })
+    }
+
+    let pool = context.object_value()?;
+    pool.set_property("configure", context.wrap_callback2(configure)?)?;
+    pool.set_property("take", context.wrap_callback2(take)?)?;
+
+    engine::namespace(context)?.set_property("pool", pool)?;
+    Ok(())
+}
@@ -0,0 +1,208 @@
+// A priority queue of JSON values stored in stable memory.
+//
+// Entries are ordered by priority and then by insertion order, so entries
+// with equal priorities are popped in the FIFO order.
+use std::{borrow::Cow, cell::RefCell};
+
+use ic_stable_structures::{storable::Bound, StableBTreeMap, StableCell, Storable};
+use quickjs_wasm_rs::{CallbackArg, JSContextRef, JSError, JSValue, JSValueRef};
+
+use crate::{
+    engine, json,
+    stable::{self, Memory},
+};
+
+// The maximum number of entries in the queue.
+const MAX_ENTRIES: u64 = 1_000_000;
+
+// The maximum size of a JSON-encoded value in bytes.
+const MAX_VALUE_SIZE: usize = 64 * 1024;
+
+// The priority is stored in an encoding that preserves the numeric order.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+struct EntryKey {
+    priority: u64,
+    sequence: u64,
+}
+
+impl EntryKey {
+    fn new(priority: f64, sequence: u64) -> Self {
+        let bits = priority.to_bits();
+        let priority = if bits >> 63 == 1 {
+            !bits
+        } else {
+            bits | (1 << 63)
+        };
+        Self { priority, sequence }
+    }
+
+    fn priority(&self) -> f64 {
+        let bits = self.priority;
+        let bits = if bits >> 63 == 1 {
+            bits & !(1 << 63)
+        } else {
+            !bits
+        };
+        f64::from_bits(bits)
+    }
+}
+
+thread_local! {
+    static QUEUE: RefCell<StableBTreeMap<EntryKey, String, Memory>> =
+        RefCell::new(StableBTreeMap::init(stable::memory(stable::PQUEUE)));
+
+    // The sequence number of the next inserted entry.
+    static NEXT_SEQUENCE: RefCell<StableCell<u64, Memory>> =
+        RefCell::new(StableCell::init(stable::memory(stable::PQUEUE_SEQUENCE), 0).unwrap());
+}
+
+/// Inserts the given JSON value with the given priority.
+pub fn insert(priority: f64, value: &serde_json::Value) -> Result<(), anyhow::Error> {
+    if priority.is_nan() {
+        anyhow::bail!("The priority must be a number");
+    }
+    let value = value.to_string();
+    if value.len() > MAX_VALUE_SIZE {
+        anyhow::bail!(
+            "The value is too large: {} bytes, the limit is {} bytes",
+            value.len(),
+            MAX_VALUE_SIZE
+        );
+    }
+    if len() >= MAX_ENTRIES {
+        anyhow::bail!("The priority queue is full: {} entries", MAX_ENTRIES);
+    }
+    let sequence = NEXT_SEQUENCE.with(|next| {
+        let mut next = next.borrow_mut();
+        let sequence = *next.get();
+        next.set(sequence + 1).unwrap();
+        sequence
+    });
+    QUEUE.with(|queue| {
+        queue
+            .borrow_mut()
+            .insert(EntryKey::new(priority, sequence), value)
+    });
+    Ok(())
+}
+
+/// Returns the entry with the minimum priority without removing it.
+pub fn peek() -> Option<(f64, serde_json::Value)> {
+    QUEUE.with(|queue| {
+        let queue = queue.borrow();
+        let (key, value) = queue.iter().next()?;
+        Some((key.priority(), serde_json::from_str(&value).unwrap()))
+    })
+}
+
+/// Removes and returns the entry with the minimum priority.
+pub fn pop_min() -> Option<(f64, serde_json::Value)> {
+    QUEUE.with(|queue| {
+        let mut queue = queue.borrow_mut();
+        let (key, value) = queue.iter().next()?;
+        queue.remove(&key);
+        Some((key.priority(), serde_json::from_str(&value).unwrap()))
+    })
+}
+
+/// Returns the number of entries in the queue.
+pub fn len() -> u64 {
+    QUEUE.with(|queue| queue.borrow().len())
+}
+
+pub fn link(context: &JSContextRef) -> Result<(), anyhow::Error> {
+    fn insert<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 2 {
+            return Err(JSError::Type(format!("Expected 2 arguments, got {}", args.len())).into());
+        }
+        engine::ensure_writable("ic.pqueue.insert()")?;
+        let priority = match args[0].to_js_value()? {
+            JSValue::Int(priority) => priority as f64,
+            JSValue::Float(priority) => priority,
+            _ => {
+                return Err(
+                    JSError::Type("Expected the priority to be a number".to_string()).into(),
+                )
+            }
+        };
+        let value = json::to_json(&args[1].to_js_value()?);
+        self::insert(priority, &value)?;
+        context.undefined_value()
+    }
+
+    fn pop_min<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        _args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        engine::ensure_writable("ic.pqueue.popMin()")?;
+        to_js_entry(context, self::pop_min())
+    }
+
+    fn peek<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        _args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        to_js_entry(context, self::peek())
+    }
+
+    fn size<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        _args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        context.value_from_f64(len() as f64)
+    }
+
+    let pqueue = context.object_value()?;
+    pqueue.set_property("insert", context.wrap_callback2(insert)?)?;
+    pqueue.set_property("popMin", context.wrap_callback2(pop_min)?)?;
+    pqueue.set_property("peek", context.wrap_callback2(peek)?)?;
+    pqueue.set_property("size", context.wrap_callback2(size)?)?;
+
+    engine::namespace(context)?.set_property("pqueue", pqueue)?;
+    Ok(())
+}
+
+// Converts the given entry into a JS object `{priority, value}` or `null`.
+fn to_js_entry<'a>(
+    context: &'a JSContextRef,
+    entry: Option<(f64, serde_json::Value)>,
+) -> Result<JSValueRef<'a>, anyhow::Error> {
+    let Some((priority, value)) = entry else {
+        return context.null_value();
+    };
+    let entry = context.object_value()?;
+    entry.set_property("priority", context.value_from_f64(priority)?)?;
+    entry.set_property(
+        "value",
+        quickjs_wasm_rs::to_qjs_value(context, &json::from_json(&value))?,
+    )?;
+    Ok(entry)
+}
+
+impl Storable for EntryKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut bytes = Vec::with_capacity(16);
+        bytes.extend_from_slice(&self.priority.to_be_bytes());
+        bytes.extend_from_slice(&self.sequence.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self {
+            priority: u64::from_be_bytes(bytes[0..8].try_into().unwrap()),
+            sequence: u64::from_be_bytes(bytes[8..16].try_into().unwrap()),
+        }
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 16,
+        is_fixed_size: true,
+    };
+}
@@ -0,0 +1,47 @@
+// An opt-in profiler of JS functions.
+//
+// QuickJS has no hooks for function entry and exit, so the profiler
+// instruments the script instead: `start_profiling` replaces the global
+// functions and the registered endpoint handlers with wrappers that count the
+// instructions of each call with the performance counter and `stop_profiling`
+// restores them. Functions captured before the start, e.g. in closures, are
+// not instrumented. Async functions are measured until their first `await`.
+//
+// `profile` returns the self instructions of each stack of wrapped functions
+// in the folded format, one `a;b;c <instructions>` line per stack, which
+// flamegraph tools such as `flamegraph.pl` and speedscope read.
+use quickjs_wasm_rs::JSContextRef;
+
+use crate::engine;
+
+const PROFILER_FILE: &str = "profiler.js";
+const PROFILER_SCRIPT: &str = include_str!("profiler.js");
+
+const START: &str = "__profilerStart__";
+const STOP: &str = "__profilerStop__";
+const REPORT: &str = "__profilerReport__";
+
+pub fn start() -> Result<(), String> {
+    engine::execute_sync(START, |_context| Ok(vec![]), |_context, _value| Ok(()))
+        .map_err(|err| err.to_string())
+}
+
+pub fn stop() -> Result<(), String> {
+    engine::execute_sync(STOP, |_context| Ok(vec![]), |_context, _value| Ok(()))
+        .map_err(|err| err.to_string())
+}
+
+/// Returns the samples collected since the start in the folded format.
+pub fn report() -> Result<String, String> {
+    engine::execute_sync(
+        REPORT,
+        |_context| Ok(vec![]),
+        |_context, value| Ok(value.as_str()?.to_string()),
+    )
+    .map_err(|err| err.to_string())
+}
+
+pub fn link(context: &JSContextRef) -> Result<(), anyhow::Error> {
+    context.eval_global(PROFILER_FILE, PROFILER_SCRIPT)?;
+    Ok(())
+}
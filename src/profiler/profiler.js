@@ -0,0 +1,70 @@
+// The JS part of the profiler. It replaces the global functions and the
+// registered endpoint handlers with wrappers that measure the instructions
+// executed by each call and attribute them to the stack of wrapped functions.
+(function () {
+	// Maps a folded stack like `main;helper` to its self instructions.
+	const samples = new Map();
+	// The frames of the wrapped functions that are running.
+	const stack = [];
+	// The wrapped functions to restore: [holder, name, original].
+	let originals = [];
+
+	function wrap(name, fn) {
+		return function (...args) {
+			const frame = { name, start: ic0.performance_counter(), children: 0 };
+			stack.push(frame);
+			try {
+				return fn.apply(this, args);
+			} finally {
+				const total = ic0.performance_counter() - frame.start;
+				const key = stack.map((frame) => frame.name).join(";");
+				samples.set(key, (samples.get(key) || 0) + total - frame.children);
+				stack.pop();
+				if (stack.length > 0) {
+					stack[stack.length - 1].children += total;
+				}
+			}
+		};
+	}
+
+	function instrument(holder, prefix) {
+		for (const name of Object.keys(holder)) {
+			const fn = holder[name];
+			if (typeof fn !== "function" || name.startsWith("__")) {
+				continue;
+			}
+			holder[name] = wrap(prefix + name, fn);
+			originals.push([holder, name, fn]);
+		}
+	}
+
+	Object.defineProperty(globalThis, "__profilerStart__", {
+		enumerable: false,
+		value: function () {
+			if (originals.length > 0) {
+				return;
+			}
+			samples.clear();
+			instrument(globalThis, "");
+			instrument(__endpoints__, "endpoint:");
+		},
+	});
+
+	Object.defineProperty(globalThis, "__profilerStop__", {
+		enumerable: false,
+		value: function () {
+			for (const [holder, name, fn] of originals) {
+				holder[name] = fn;
+			}
+			originals = [];
+		},
+	});
+
+	// Returns the samples in the folded format of flamegraph tools.
+	Object.defineProperty(globalThis, "__profilerReport__", {
+		enumerable: false,
+		value: function () {
+			return Array.from(samples, ([stack, count]) => `${stack} ${count}`).join("\n");
+		},
+	});
+})();
@@ -0,0 +1,213 @@
+// Per-endpoint and per-sender quotas exposed to JS as `ic.quotas`.
+//
+// `ic.quotas.configure(endpoint, {daily, monthly})` limits the number of calls
+// of the given endpoint, JSON-RPC method or HTTP route by each sender per UTC
+// day and calendar month. The `*` endpoint sets the limits of endpoints without
+// their own limits. Counters are stored in stable memory, so they survive
+// upgrades, while the limits are configured by the JS script on each start.
+//
+// Calls over the limit are rejected: HTTP requests get a 429 response with a
+// `retry-after` header. Counters cannot be updated in query calls, so limited
+// requests are always served by update calls.
+use std::{cell::RefCell, collections::HashMap, ops::Bound, time::Duration};
+
+use ic_stable_structures::StableBTreeMap;
+use quickjs_wasm_rs::{CallbackArg, JSContextRef, JSError, JSValue, JSValueRef};
+
+use crate::{
+    auth::Identity,
+    engine, jobs,
+    stable::{self, Memory},
+};
+
+// The endpoint whose limits apply to endpoints without their own limits.
+const DEFAULT_ENDPOINT: &str = "*";
+
+// The interval between two removals of counters of past periods.
+const GC_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// The maximum number of counters removed by one garbage collection.
+const MAX_GC_BATCH: usize = 1000;
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Limits {
+    pub daily: Option<u64>,
+    pub monthly: Option<u64>,
+}
+
+/// The error of a call over the limit.
+#[derive(Debug)]
+pub struct QuotaExceeded {
+    pub endpoint: String,
+    /// The number of seconds until the counter that reached its limit resets.
+    pub retry_after: u64,
+}
+
+impl std::fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "The quota of {} is exhausted, retry in {} seconds",
+            self.endpoint, self.retry_after
+        )
+    }
+}
+
+impl std::error::Error for QuotaExceeded {}
+
+thread_local! {
+    // Maps `<period>/<endpoint>/<sender>` to the number of calls in the period
+    // where the period is `d<day>` or `m<month>` with zero-padded numbers, so
+    // that the counters of past periods come first.
+    static COUNTERS: RefCell<StableBTreeMap<String, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(stable::memory(stable::QUOTAS)));
+
+    static LIMITS: RefCell<HashMap<String, Limits>> = RefCell::new(HashMap::new());
+
+    static GC_ARMED: RefCell<bool> = RefCell::new(false);
+}
+
+pub fn configure(endpoint: String, limits: Limits) {
+    LIMITS.with(|all| all.borrow_mut().insert(endpoint, limits));
+    GC_ARMED.with(|armed| {
+        if !armed.replace(true) {
+            ic_cdk_timers::set_timer_interval(GC_INTERVAL, gc);
+        }
+    });
+}
+
+fn limits(endpoint: &str) -> Option<Limits> {
+    LIMITS.with(|all| {
+        let all = all.borrow();
+        all.get(endpoint)
+            .or_else(|| all.get(DEFAULT_ENDPOINT))
+            .copied()
+    })
+}
+
+/// Returns true if calls of the given endpoint are counted.
+pub fn is_limited(endpoint: &str) -> bool {
+    limits(endpoint).is_some()
+}
+
+// Returns the current day and month since the Unix epoch and the number of
+// seconds until each of them ends.
+fn periods(now: u64) -> ((u64, u64), (u64, u64)) {
+    let day = now / NANOS_PER_DAY;
+    let day_ends_in = ((day + 1) * NANOS_PER_DAY - now) / 1_000_000_000;
+    // Converts the day to a date in the proleptic Gregorian calendar.
+    let z = day + 719468;
+    let era = z / 146097;
+    let day_of_era = z - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day_of_month = day_of_year - (153 * mp + 2) / 5;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+    let days_in_month = match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    };
+    let month_ends_in = day_ends_in + (days_in_month - day_of_month - 1) * 24 * 60 * 60;
+    ((day, day_ends_in), (year * 12 + month - 1, month_ends_in))
+}
+
+/// Counts a call of the given endpoint by the given sender. Returns an error
+/// without counting the call if it exceeds a limit.
+pub fn consume(endpoint: &str, identity: &Identity) -> Result<(), QuotaExceeded> {
+    let Some(limits) = limits(endpoint) else {
+        return Ok(());
+    };
+    let ((day, day_ends_in), (month, month_ends_in)) = periods(ic_cdk::api::time());
+    let sender = identity.key();
+    let counters = [
+        (
+            format!("d{:010}/{}/{}", day, endpoint, sender),
+            limits.daily,
+            day_ends_in,
+        ),
+        (
+            format!("m{:010}/{}/{}", month, endpoint, sender),
+            limits.monthly,
+            month_ends_in,
+        ),
+    ];
+    COUNTERS.with(|all| {
+        let mut all = all.borrow_mut();
+        for (key, limit, ends_in) in counters.iter() {
+            let count = all.get(key).unwrap_or_default();
+            if limit.is_some_and(|limit| count >= limit) {
+                return Err(QuotaExceeded {
+                    endpoint: endpoint.to_string(),
+                    retry_after: *ends_in,
+                });
+            }
+        }
+        for (key, limit, _) in counters {
+            if limit.is_some() {
+                let count = all.get(&key).unwrap_or_default();
+                all.insert(key, count + 1);
+            }
+        }
+        Ok(())
+    })
+}
+
+// Removes a batch of counters of past days and months.
+fn gc() {
+    let ((day, _), (month, _)) = periods(ic_cdk::api::time());
+    for end in [format!("d{:010}", day), format!("m{:010}", month)] {
+        let start = end[..1].to_string();
+        COUNTERS.with(|all| {
+            let mut all = all.borrow_mut();
+            let stale: Vec<String> = all
+                .range((Bound::Included(start), Bound::Excluded(end)))
+                .map(|(key, _)| key)
+                .take(MAX_GC_BATCH)
+                .collect();
+            for key in stale {
+                all.remove(&key);
+            }
+        });
+    }
+}
+
+pub fn link(context: &JSContextRef) -> Result<(), anyhow::Error> {
+    // Usage: `ic.quotas.configure(endpoint, {daily, monthly})`.
+    fn configure<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 2 {
+            return Err(JSError::Type(format!("Expected 2 arguments, got {}", args.len())).into());
+        }
+        engine::ensure_writable("ic.quotas.configure()")?;
+        let endpoint: String = args[0].try_into()?;
+        let JSValue::Object(fields) = args[1].to_js_value()? else {
+            return Err(JSError::Type("Expected the limits to be an object".to_string()).into());
+        };
+        let limit = |name: &str| match fields.get(name) {
+            None | Some(JSValue::Undefined | JSValue::Null) => Ok(None),
+            Some(value) => jobs::number(value, name).map(|limit| Some(limit as u64)),
+        };
+        let limits = Limits {
+            daily: limit("daily")?,
+            monthly: limit("monthly")?,
+        };
+        self::configure(endpoint, limits);
+        context.undefined_value()
+    }
+
+    let quotas = context.object_value()?;
+    quotas.set_property("configure", context.wrap_callback2(configure)?)?;
+
+    engine::namespace(context)?.set_property("quotas", quotas)?;
+    Ok(())
+}
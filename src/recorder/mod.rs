@@ -0,0 +1,98 @@
+// An opt-in recorder of the inputs of the JS script for debugging.
+//
+// When a controller enables it with `set_recording(true)`, the engine records
+// each execution of a JS method with its JSON arguments, caller and time,
+// each outgoing call with its Candid arguments, and the reply or reject of the
+// call. `recorded_messages(start, limit)` returns the records, which are enough
+// to re-execute the same inputs against the script elsewhere.
+//
+// Records are kept on the heap in a bounded buffer, so they are lost on
+// upgrade and the oldest ones are dropped when the buffer is full.
+use std::{cell::RefCell, collections::VecDeque};
+
+use candid::{CandidType, Deserialize, Principal};
+
+// The maximum number of records kept in the buffer.
+const MAX_RECORDS: usize = 1000;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum Event {
+    Execution {
+        method: String,
+        caller: Principal,
+        /// The JSON-encoded array of arguments.
+        args: String,
+    },
+    Call {
+        callback_id: i32,
+        canister_id: Principal,
+        method: String,
+        args: Vec<u8>,
+    },
+    Reply {
+        callback_id: i32,
+        data: Vec<u8>,
+    },
+    Reject {
+        callback_id: i32,
+        message: String,
+    },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Record {
+    pub seq: u64,
+    pub time: u64,
+    pub event: Event,
+}
+
+thread_local! {
+    static IS_ENABLED: RefCell<bool> = RefCell::new(false);
+
+    static RECORDS: RefCell<VecDeque<Record>> = RefCell::new(VecDeque::new());
+
+    static NEXT_SEQ: RefCell<u64> = RefCell::new(0);
+}
+
+pub fn set_enabled(enabled: bool) {
+    IS_ENABLED.with(|flag| *flag.borrow_mut() = enabled);
+}
+
+pub fn is_enabled() -> bool {
+    IS_ENABLED.with(|flag| *flag.borrow())
+}
+
+pub fn record(event: Event) {
+    if !is_enabled() {
+        return;
+    }
+    let seq = NEXT_SEQ.with(|next| {
+        let mut next = next.borrow_mut();
+        *next += 1;
+        *next - 1
+    });
+    RECORDS.with(|records| {
+        let mut records = records.borrow_mut();
+        if records.len() == MAX_RECORDS {
+            records.pop_front();
+        }
+        records.push_back(Record {
+            seq,
+            time: ic_cdk::api::time(),
+            event,
+        });
+    });
+}
+
+/// Returns at most `limit` records starting from the given sequence number.
+pub fn list(start: u64, limit: usize) -> Vec<Record> {
+    RECORDS.with(|records| {
+        records
+            .borrow()
+            .iter()
+            .filter(|record| record.seq >= start)
+            .take(limit)
+            .cloned()
+            .collect()
+    })
+}
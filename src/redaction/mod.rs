@@ -0,0 +1,75 @@
+// Redaction of the details of execution errors in reject messages and HTTP
+// error bodies.
+//
+// Errors of JS code carry messages, stack traces and internal ids that may
+// reveal how the canister works. Controllers choose how much of them callers
+// see with the `error_detail` field of the init or upgrade argument or with
+// `set_error_detail()`:
+// - `Full` passes errors as is, which is the default for development.
+// - `Message` keeps the first line of the message and drops the stack trace.
+// - `Generic` replaces the error with a reference to the log entry.
+// Redacted errors are logged with their full detail and the reference, so
+// the `logs` query shows what went wrong.
+use std::cell::{Cell, RefCell};
+
+use candid::{CandidType, Deserialize};
+use ic_stable_structures::StableCell;
+
+use crate::{
+    stable::{self, Candid, Memory},
+    system_api::{self, Level},
+};
+
+/// How much detail of errors reaches callers.
+#[derive(CandidType, Deserialize, Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum ErrorDetail {
+    #[default]
+    Full,
+    Message,
+    Generic,
+}
+
+thread_local! {
+    static DETAIL: RefCell<StableCell<Candid<ErrorDetail>, Memory>> =
+        RefCell::new(StableCell::init(stable::memory(stable::ERROR_DETAIL), Candid(ErrorDetail::Full)).unwrap());
+
+    // Distinguishes the references of errors logged at the same time.
+    static NEXT_REFERENCE: Cell<u64> = Cell::new(0);
+}
+
+pub fn set(detail: ErrorDetail) {
+    DETAIL.with(|cell| cell.borrow_mut().set(Candid(detail)).unwrap());
+}
+
+pub fn get() -> ErrorDetail {
+    DETAIL.with(|cell| cell.borrow().get().0)
+}
+
+/// Returns the error as callers may see it and logs the full detail if it
+/// differs.
+pub fn redact(err: &str) -> String {
+    let public = match get() {
+        ErrorDetail::Full => return err.to_string(),
+        ErrorDetail::Message => err.lines().next().unwrap_or_default().to_string(),
+        ErrorDetail::Generic => {
+            let reference = NEXT_REFERENCE.with(|next| {
+                let reference = next.get();
+                next.set(reference + 1);
+                reference
+            });
+            format!(
+                "Internal error, reference {:x}-{}",
+                ic_cdk::api::time(),
+                reference
+            )
+        }
+    };
+    if public != err {
+        system_api::log(
+            Level::Error,
+            Some("errors"),
+            &[format!("{}:", public).into(), err.to_string().into()],
+        );
+    }
+    public
+}
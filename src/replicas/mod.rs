@@ -0,0 +1,111 @@
+// Streaming of KV changes from a primary canister to read replicas.
+//
+// The primary records every KV change and sends all changes of a message in
+// one batch to each replica using one-way (notify) calls. Replicas run the same
+// Wasm module and JS code, so read-heavy query endpoints can be served by any
+// of them. Each replica must have the primary as its replication source.
+//
+// Notify calls are not guaranteed to be delivered. Batches carry sequence
+// numbers, so a replica detects a missed batch and reports it in the logs.
+// A replica that missed a batch can be resynchronized with `ic.kv.replicateTo`.
+use std::{cell::RefCell, time::Duration};
+
+use candid::Principal;
+use ic_stable_structures::StableCell;
+
+use crate::{
+    kv::Change,
+    stable::{self, Candid, Memory},
+    system_api::{self, Level},
+};
+
+/// The canister method of replicas that receives batches of changes.
+pub const APPLY_CHANGES_METHOD: &str = "kv_apply_changes";
+
+thread_local! {
+    static REPLICAS: RefCell<StableCell<Candid<Vec<Principal>>, Memory>> =
+        RefCell::new(StableCell::init(stable::memory(stable::REPLICAS), Candid(vec![])).unwrap());
+
+    // The changes that have not been sent to the replicas yet.
+    static PENDING: RefCell<Vec<Change>> = RefCell::new(vec![]);
+
+    // The sequence number of the last sent batch on the primary.
+    static SENT_SEQUENCE: RefCell<u64> = RefCell::new(0);
+
+    // The sequence number of the last applied batch on a replica.
+    static APPLIED_SEQUENCE: RefCell<u64> = RefCell::new(0);
+}
+
+pub fn replicas() -> Vec<Principal> {
+    REPLICAS.with(|replicas| replicas.borrow().get().0.clone())
+}
+
+pub fn set_replicas(replicas: Vec<Principal>) {
+    REPLICAS.with(|cell| cell.borrow_mut().set(Candid(replicas)).unwrap());
+}
+
+/// Records a change to be sent to the replicas at the end of the message.
+pub fn record(change: Change) {
+    if REPLICAS.with(|replicas| replicas.borrow().get().0.is_empty()) {
+        return;
+    }
+    let is_first = PENDING.with(|pending| {
+        let mut pending = pending.borrow_mut();
+        pending.push(change);
+        pending.len() == 1
+    });
+    if is_first {
+        ic_cdk_timers::set_timer(Duration::ZERO, flush);
+    }
+}
+
+// Sends all pending changes to the replicas in one batch.
+fn flush() {
+    let changes = PENDING.with(|pending| std::mem::take(&mut *pending.borrow_mut()));
+    if changes.is_empty() {
+        return;
+    }
+    let sequence = SENT_SEQUENCE.with(|sequence| {
+        let mut sequence = sequence.borrow_mut();
+        *sequence += 1;
+        *sequence
+    });
+    for replica in replicas() {
+        if let Err(code) =
+            ic_cdk::notify(replica, APPLY_CHANGES_METHOD, (sequence, changes.clone()))
+        {
+            system_api::log(
+                Level::Warn,
+                Some("replicas"),
+                &[format!(
+                    "Failed to send batch {} to {}: {:?}",
+                    sequence, replica, code
+                )
+                .into()],
+            );
+        }
+    }
+}
+
+/// Checks the sequence number of a batch received by a replica. The sequence
+/// restarts from 1 when the primary is upgraded.
+pub fn check_sequence(sequence: u64) {
+    let expected = APPLIED_SEQUENCE.with(|applied| {
+        let mut applied = applied.borrow_mut();
+        let expected = *applied + 1;
+        *applied = sequence;
+        expected
+    });
+    if sequence != expected && sequence != 1 {
+        system_api::log(
+            Level::Warn,
+            Some("replicas"),
+            &[format!(
+                "Missed batches from {} to {}, the replica may be out of sync",
+                expected,
+                sequence - 1
+            )
+            .into()],
+        );
+    }
+}
@@ -0,0 +1,113 @@
+// A fixed-capacity circular buffer of strings stored in stable memory.
+//
+// Each entry gets a sequence number. When the buffer is full, pushing a new
+// entry removes the oldest one, so the buffer never grows beyond its capacity.
+use std::cell::RefCell;
+
+use ic_stable_structures::StableBTreeMap;
+use quickjs_wasm_rs::{CallbackArg, JSContextRef, JSError, JSValue, JSValueRef};
+
+use crate::{
+    engine, json,
+    stable::{self, Memory},
+};
+
+// The capacity and the maximum entry size of the ring available to JS.
+const JS_RING_CAPACITY: u64 = 10_000;
+const JS_RING_MAX_ENTRY_SIZE: usize = 4 * 1024;
+
+/// A bounded history of entries.
+pub struct Ring {
+    entries: StableBTreeMap<u64, String, Memory>,
+    capacity: u64,
+}
+
+impl Ring {
+    pub fn init(memory: Memory, capacity: u64) -> Self {
+        Self {
+            entries: StableBTreeMap::init(memory),
+            capacity,
+        }
+    }
+
+    /// Appends the given entry and returns its sequence number.
+    pub fn push(&mut self, entry: String) -> u64 {
+        // Sequence numbers of the stored entries are contiguous.
+        let first = self.entries.iter().next().map(|(key, _)| key).unwrap_or(0);
+        let len = self.entries.len();
+        let sequence = first + len;
+        self.entries.insert(sequence, entry);
+        if len + 1 > self.capacity {
+            self.entries.remove(&first);
+        }
+        sequence
+    }
+
+    /// Returns at most `n` most recent entries from the oldest to the newest.
+    pub fn last(&self, n: u64) -> Vec<(u64, String)> {
+        let first = self.entries.iter().next().map(|(key, _)| key).unwrap_or(0);
+        let len = self.entries.len();
+        let start = first + len - n.min(len);
+        self.entries.range(start..).collect()
+    }
+}
+
+thread_local! {
+    static JS_RING: RefCell<Ring> =
+        RefCell::new(Ring::init(stable::memory(stable::JS_RING), JS_RING_CAPACITY));
+}
+
+pub fn link(context: &JSContextRef) -> Result<(), anyhow::Error> {
+    fn push<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 1 {
+            return Err(JSError::Type(format!("Expected 1 argument, got {}", args.len())).into());
+        }
+        engine::ensure_writable("ic.ring.push()")?;
+        let entry = json::to_json(&args[0].to_js_value()?).to_string();
+        if entry.len() > JS_RING_MAX_ENTRY_SIZE {
+            anyhow::bail!(
+                "The entry is too large: {} bytes, the limit is {} bytes",
+                entry.len(),
+                JS_RING_MAX_ENTRY_SIZE
+            );
+        }
+        let sequence = JS_RING.with(|ring| ring.borrow_mut().push(entry));
+        context.value_from_f64(sequence as f64)
+    }
+
+    fn last<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 1 {
+            return Err(JSError::Type(format!("Expected 1 argument, got {}", args.len())).into());
+        }
+        let n = match args[0].to_js_value()? {
+            JSValue::Int(n) if n >= 0 => n as u64,
+            JSValue::Float(n) if n >= 0.0 => n as u64,
+            _ => return Err(JSError::Type("Expected a non-negative number".to_string()).into()),
+        };
+        let entries = JS_RING.with(|ring| ring.borrow().last(n));
+        let result = context.array_value()?;
+        for (_, entry) in entries {
+            let entry: serde_json::Value = serde_json::from_str(&entry)?;
+            result.append_property(quickjs_wasm_rs::to_qjs_value(
+                context,
+                &json::from_json(&entry),
+            )?)?;
+        }
+        Ok(result)
+    }
+
+    let ring = context.object_value()?;
+    ring.set_property("push", context.wrap_callback2(push)?)?;
+    ring.set_property("last", context.wrap_callback2(last)?)?;
+
+    engine::namespace(context)?.set_property("ring", ring)?;
+    Ok(())
+}
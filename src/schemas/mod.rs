@@ -0,0 +1,151 @@
+// JSON Schema validation of inputs exposed to JS as `ic.schemas`.
+//
+// JS attaches a schema to an HTTP route (e.g. `POST /orders`) or to a Candid
+// endpoint by name with `ic.schemas.define(name, schema)`. The inputs are
+// validated in Rust before the JS handler runs. The arguments of a Candid
+// endpoint are validated as a JSON array.
+use std::{cell::RefCell, collections::HashMap};
+
+use jsonschema::JSONSchema;
+use quickjs_wasm_rs::{CallbackArg, JSContextRef, JSError, JSValueRef};
+use serde_json::Value;
+
+use crate::{engine, json};
+
+/// A violation of a schema.
+#[derive(Clone, Debug)]
+pub struct Issue {
+    /// The JSON pointer of the invalid part of the input.
+    pub path: String,
+    pub message: String,
+}
+
+thread_local! {
+    // Schemas are compiled once when the script defines them.
+    static SCHEMAS: RefCell<HashMap<String, JSONSchema>> = RefCell::new(HashMap::new());
+
+    // The source of the schemas for introspection.
+    static SOURCES: RefCell<HashMap<String, Value>> = RefCell::new(HashMap::new());
+}
+
+pub fn define(name: String, schema: &Value) -> Result<(), anyhow::Error> {
+    let compiled = JSONSchema::compile(schema)
+        .map_err(|err| anyhow::anyhow!("Invalid schema for {}: {}", name, err))?;
+    SOURCES.with(|sources| sources.borrow_mut().insert(name.clone(), schema.clone()));
+    SCHEMAS.with(|schemas| schemas.borrow_mut().insert(name, compiled));
+    Ok(())
+}
+
+/// Returns the schema with the given name as defined by the script.
+pub fn source(name: &str) -> Option<Value> {
+    SOURCES.with(|sources| sources.borrow().get(name).cloned())
+}
+
+pub fn is_defined(name: &str) -> bool {
+    SCHEMAS.with(|schemas| schemas.borrow().contains_key(name))
+}
+
+/// Validates the input against the schema with the given name. Inputs without
+/// a schema are valid.
+pub fn validate(name: &str, input: &Value) -> Result<(), Vec<Issue>> {
+    SCHEMAS.with(|schemas| {
+        let schemas = schemas.borrow();
+        let Some(schema) = schemas.get(name) else {
+            return Ok(());
+        };
+        schema.validate(input).map_err(|errors| {
+            errors
+                .map(|error| Issue {
+                    path: error.instance_path.to_string(),
+                    message: error.to_string(),
+                })
+                .collect()
+        })
+    })
+}
+
+/// Validates the JS arguments of a Candid endpoint against the schema of the
+/// endpoint. Call it in the `Arguments` function before the JS handler runs.
+pub fn check_arguments(endpoint: &str, args: &[JSValueRef]) -> Result<(), anyhow::Error> {
+    if !is_defined(endpoint) {
+        return Ok(());
+    }
+    let input = args
+        .iter()
+        .map(|arg| Ok(json::to_json(&quickjs_wasm_rs::from_qjs_value(arg)?)))
+        .collect::<Result<Vec<_>, anyhow::Error>>()?;
+    if let Err(issues) = validate(endpoint, &Value::Array(input)) {
+        anyhow::bail!("Invalid arguments of {}: {}", endpoint, describe(&issues));
+    }
+    Ok(())
+}
+
+/// Joins the issues into a single message for error replies.
+pub fn describe(issues: &[Issue]) -> String {
+    let issues: Vec<_> = issues
+        .iter()
+        .map(|issue| format!("{}: {}", issue.path, issue.message))
+        .collect();
+    issues.join("; ")
+}
+
+/// Validates the JSON body of an HTTP request against the schema of its route,
+/// e.g. `POST /orders`. Call it before the JS handler runs. Routes without a
+/// schema accept any body.
+pub fn check_body(route: &str, body: &[u8]) -> Result<(), Vec<Issue>> {
+    if !is_defined(route) {
+        return Ok(());
+    }
+    match serde_json::from_slice::<Value>(body) {
+        Ok(body) => validate(route, &body),
+        Err(err) => Err(vec![Issue {
+            path: String::new(),
+            message: format!("The body is not valid JSON: {}", err),
+        }]),
+    }
+}
+
+pub fn link(context: &JSContextRef) -> Result<(), anyhow::Error> {
+    fn define<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 2 {
+            return Err(JSError::Type(format!("Expected 2 arguments, got {}", args.len())).into());
+        }
+        let name: String = args[0].try_into()?;
+        let schema = json::to_json(&args[1].to_js_value()?);
+        self::define(name, &schema)?;
+        context.undefined_value()
+    }
+
+    // Returns an array of `{path, message}` issues, which is empty if the
+    // value is valid.
+    fn validate<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 2 {
+            return Err(JSError::Type(format!("Expected 2 arguments, got {}", args.len())).into());
+        }
+        let name: String = args[0].try_into()?;
+        let value = json::to_json(&args[1].to_js_value()?);
+        let issues = context.array_value()?;
+        for issue in self::validate(&name, &value).err().unwrap_or_default() {
+            let js = context.object_value()?;
+            js.set_property("path", context.value_from_str(&issue.path)?)?;
+            js.set_property("message", context.value_from_str(&issue.message)?)?;
+            issues.append_property(js)?;
+        }
+        Ok(issues)
+    }
+
+    let schemas = context.object_value()?;
+    schemas.set_property("define", context.wrap_callback2(define)?)?;
+    schemas.set_property("validate", context.wrap_callback2(validate)?)?;
+
+    engine::namespace(context)?.set_property("schemas", schemas)?;
+    Ok(())
+}
@@ -0,0 +1,277 @@
+// Full-text search over documents indexed by JS, exposed as `ic.search`.
+//
+// `ic.search.index(id, text)` tokenizes the text and stores a postings list
+// entry `term \0 id` with the frequency of the term in the document. Indexing
+// a document again replaces its terms and `ic.search.remove(id)` drops them.
+// `ic.search.query(text, {limit})` ranks the documents that contain any term
+// of the query with BM25 and returns `[{id, score}]` by descending score.
+//
+// Tokens are the lowercase runs of letters and digits, so the index works for
+// languages that separate words with spaces or punctuation. The documents are
+// not stored; keep them in `ic.kv` under the same id.
+use std::{cell::RefCell, collections::BTreeMap, ops::Bound};
+
+use candid::{CandidType, Deserialize};
+use ic_stable_structures::{StableBTreeMap, StableCell};
+use quickjs_wasm_rs::{CallbackArg, JSContextRef, JSError, JSValue, JSValueRef};
+
+use crate::{
+    engine, jobs,
+    stable::{self, Candid, Memory},
+};
+
+const SEPARATOR: char = '\0';
+
+// Longer tokens are dropped, they are rarely words.
+const MAX_TOKEN_SIZE: usize = 64;
+
+// The maximum size of a document id and of an indexed text in bytes.
+const MAX_ID_SIZE: usize = 256;
+const MAX_TEXT_SIZE: usize = 1024 * 1024;
+
+// The maximum number of distinct terms of a query and of postings read per
+// term, which bound the instructions of a query.
+const MAX_QUERY_TERMS: usize = 16;
+const MAX_POSTINGS_PER_TERM: usize = 10_000;
+
+const DEFAULT_LIMIT: usize = 10;
+const MAX_LIMIT: usize = 100;
+
+// The BM25 parameters commonly used as defaults.
+const K1: f64 = 1.2;
+const B: f64 = 0.75;
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+struct Stats {
+    documents: u64,
+    total_length: u64,
+}
+
+// The terms of an indexed document, used to remove its postings.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct Document {
+    length: u64,
+    terms: Vec<String>,
+}
+
+thread_local! {
+    static POSTINGS: RefCell<StableBTreeMap<String, u32, Memory>> =
+        RefCell::new(StableBTreeMap::init(stable::memory(stable::SEARCH_POSTINGS)));
+
+    static DOCUMENTS: RefCell<StableBTreeMap<String, Candid<Document>, Memory>> =
+        RefCell::new(StableBTreeMap::init(stable::memory(stable::SEARCH_DOCUMENTS)));
+
+    static STATS: RefCell<StableCell<Candid<Stats>, Memory>> =
+        RefCell::new(StableCell::init(stable::memory(stable::SEARCH_STATS), Candid(Stats::default())).unwrap());
+}
+
+/// A document that matches a query.
+pub struct Hit {
+    pub id: String,
+    pub score: f64,
+}
+
+/// Splits the text into lowercase tokens.
+pub fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|token| !token.is_empty() && token.len() <= MAX_TOKEN_SIZE)
+        .map(|token| token.to_lowercase())
+        .collect()
+}
+
+/// Indexes the text of the document, replacing its previous text.
+pub fn index(id: &str, text: &str) -> Result<(), String> {
+    if id.is_empty() || id.len() > MAX_ID_SIZE || id.contains(SEPARATOR) {
+        return Err(format!("Invalid document id {:?}", id));
+    }
+    if text.len() > MAX_TEXT_SIZE {
+        return Err(format!(
+            "The text is too large: {} bytes, the limit is {} bytes",
+            text.len(),
+            MAX_TEXT_SIZE
+        ));
+    }
+    remove(id);
+    let tokens = tokenize(text);
+    let mut frequencies: BTreeMap<String, u32> = BTreeMap::new();
+    for token in tokens.iter() {
+        *frequencies.entry(token.clone()).or_default() += 1;
+    }
+    POSTINGS.with(|postings| {
+        let mut postings = postings.borrow_mut();
+        for (term, frequency) in frequencies.iter() {
+            postings.insert(posting_key(term, id), *frequency);
+        }
+    });
+    let document = Document {
+        length: tokens.len() as u64,
+        terms: frequencies.into_keys().collect(),
+    };
+    update_stats(1, document.length as i64);
+    DOCUMENTS.with(|documents| {
+        documents
+            .borrow_mut()
+            .insert(id.to_string(), Candid(document))
+    });
+    Ok(())
+}
+
+/// Removes the document from the index and returns whether it was indexed.
+pub fn remove(id: &str) -> bool {
+    let Some(Candid(document)) =
+        DOCUMENTS.with(|documents| documents.borrow_mut().remove(&id.to_string()))
+    else {
+        return false;
+    };
+    POSTINGS.with(|postings| {
+        let mut postings = postings.borrow_mut();
+        for term in document.terms.iter() {
+            postings.remove(&posting_key(term, id));
+        }
+    });
+    update_stats(-1, -(document.length as i64));
+    true
+}
+
+/// Returns up to `limit` documents that match the query, best first.
+pub fn query(text: &str, limit: usize) -> Vec<Hit> {
+    let mut terms = tokenize(text);
+    terms.sort();
+    terms.dedup();
+    terms.truncate(MAX_QUERY_TERMS);
+    let Candid(stats) = STATS.with(|stats| stats.borrow().get().clone());
+    if stats.documents == 0 {
+        return vec![];
+    }
+    let average_length = stats.total_length as f64 / stats.documents as f64;
+    let mut scores: BTreeMap<String, f64> = BTreeMap::new();
+    for term in terms.iter() {
+        let postings = postings(term);
+        let idf = (1.0
+            + (stats.documents as f64 - postings.len() as f64 + 0.5)
+                / (postings.len() as f64 + 0.5))
+            .ln();
+        for (id, frequency) in postings {
+            let length = DOCUMENTS.with(|documents| {
+                documents
+                    .borrow()
+                    .get(&id)
+                    .map(|document| document.0.length)
+            });
+            let length = length.unwrap_or_default() as f64;
+            let frequency = frequency as f64;
+            let score = idf * frequency * (K1 + 1.0)
+                / (frequency + K1 * (1.0 - B + B * length / average_length.max(1.0)));
+            *scores.entry(id).or_default() += score;
+        }
+    }
+    let mut hits: Vec<Hit> = scores
+        .into_iter()
+        .map(|(id, score)| Hit { id, score })
+        .collect();
+    // Equal scores are ordered by id to keep the result deterministic.
+    hits.sort_by(|a, b| b.score.total_cmp(&a.score).then_with(|| a.id.cmp(&b.id)));
+    hits.truncate(limit);
+    hits
+}
+
+// Returns the documents that contain the term with the frequencies.
+fn postings(term: &str) -> Vec<(String, u32)> {
+    let start = format!("{}{}", term, SEPARATOR);
+    // The separator is the smallest character, so the next one ends the range.
+    let end = format!("{}\u{1}", term);
+    POSTINGS.with(|postings| {
+        postings
+            .borrow()
+            .range((Bound::Included(start.clone()), Bound::Excluded(end)))
+            .take(MAX_POSTINGS_PER_TERM)
+            .map(|(key, frequency)| (key[start.len()..].to_string(), frequency))
+            .collect()
+    })
+}
+
+fn posting_key(term: &str, id: &str) -> String {
+    format!("{}{}{}", term, SEPARATOR, id)
+}
+
+fn update_stats(documents: i64, length: i64) {
+    STATS.with(|cell| {
+        let mut cell = cell.borrow_mut();
+        let Candid(mut stats) = cell.get().clone();
+        stats.documents = stats.documents.saturating_add_signed(documents);
+        stats.total_length = stats.total_length.saturating_add_signed(length);
+        cell.set(Candid(stats)).unwrap();
+    });
+}
+
+pub fn link(context: &JSContextRef) -> Result<(), anyhow::Error> {
+    // Usage: `ic.search.index(id, text)`.
+    fn index<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 2 {
+            return Err(JSError::Type(format!("Expected 2 arguments, got {}", args.len())).into());
+        }
+        engine::ensure_writable("ic.search.index()")?;
+        let id: String = args[0].try_into()?;
+        let text: String = args[1].try_into()?;
+        self::index(&id, &text).map_err(anyhow::Error::msg)?;
+        context.undefined_value()
+    }
+
+    fn remove<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 1 {
+            return Err(JSError::Type(format!("Expected 1 argument, got {}", args.len())).into());
+        }
+        engine::ensure_writable("ic.search.remove()")?;
+        let id: String = args[0].try_into()?;
+        context.value_from_bool(self::remove(&id))
+    }
+
+    // Usage: `ic.search.query(text, {limit})`. Returns `[{id, score}]`.
+    fn query<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.is_empty() || args.len() > 2 {
+            return Err(
+                JSError::Type(format!("Expected 1 or 2 arguments, got {}", args.len())).into(),
+            );
+        }
+        let text: String = args[0].try_into()?;
+        let limit = match args
+            .get(1)
+            .map(|options| options.to_js_value())
+            .transpose()?
+        {
+            None | Some(JSValue::Undefined | JSValue::Null) => DEFAULT_LIMIT,
+            Some(JSValue::Object(options)) => match options.get("limit") {
+                None | Some(JSValue::Undefined) => DEFAULT_LIMIT,
+                Some(limit) => (jobs::number(limit, "limit")? as usize).min(MAX_LIMIT),
+            },
+            Some(_) => return Err(JSError::Type("Expected an options object".to_string()).into()),
+        };
+        let hits = context.array_value()?;
+        for hit in self::query(&text, limit) {
+            let js = context.object_value()?;
+            js.set_property("id", context.value_from_str(&hit.id)?)?;
+            js.set_property("score", context.value_from_f64(hit.score)?)?;
+            hits.append_property(js)?;
+        }
+        Ok(hits)
+    }
+
+    let search = context.object_value()?;
+    search.set_property("index", context.wrap_callback2(index)?)?;
+    search.set_property("remove", context.wrap_callback2(remove)?)?;
+    search.set_property("query", context.wrap_callback2(query)?)?;
+    engine::namespace(context)?.set_property("search", search)?;
+    Ok(())
+}
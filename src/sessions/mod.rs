@@ -0,0 +1,309 @@
+// Login sessions for HTTP-facing JS apps exposed to JS as `ic.sessions`.
+//
+// A session stores JSON data in stable memory until it expires. Sessions are
+// identified by tokens of the form `<id>.<signature>` where the id is random
+// and the signature is an HMAC-SHA256 of the id with a secret key of the
+// canister. Tokens with invalid signatures are rejected without a lookup.
+// Each access with `touch()` extends the session by its TTL. Expired sessions
+// are removed by a periodic timer that scans an index ordered by expiry.
+use std::{cell::RefCell, ops::Bound, time::Duration};
+
+use candid::{CandidType, Deserialize};
+use hmac::{Hmac, Mac};
+use ic_cdk_timers::TimerId;
+use ic_stable_structures::{StableBTreeMap, StableCell};
+use quickjs_wasm_rs::{CallbackArg, JSContextRef, JSError, JSValueRef};
+use sha2::Sha256;
+
+use crate::{
+    engine, jobs, json,
+    stable::{self, Candid, Memory},
+};
+
+// The interval between two garbage collections of expired sessions.
+const GC_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// The maximum number of sessions removed by one garbage collection.
+const MAX_GC_BATCH: usize = 1000;
+
+// The size of a session id in bytes.
+const ID_SIZE: usize = 16;
+
+/// A persisted session.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Session {
+    /// The JSON-encoded data of the session.
+    pub data: String,
+    pub created_at: u64,
+    pub expires_at: u64,
+    pub ttl_nanos: u64,
+}
+
+thread_local! {
+    // Maps hex-encoded session ids to sessions.
+    static SESSIONS: RefCell<StableBTreeMap<String, Candid<Session>, Memory>> =
+        RefCell::new(StableBTreeMap::init(stable::memory(stable::SESSIONS)));
+
+    // Maps `<expires_at>/<id>` keys to session ids so that expired sessions
+    // can be found without scanning all sessions.
+    static EXPIRIES: RefCell<StableBTreeMap<String, String, Memory>> =
+        RefCell::new(StableBTreeMap::init(stable::memory(stable::SESSION_EXPIRIES)));
+
+    // The secret key that signs session tokens. Empty until the first session
+    // is created.
+    static SECRET: RefCell<StableCell<Candid<Vec<u8>>, Memory>> =
+        RefCell::new(StableCell::init(stable::memory(stable::SESSION_SECRET), Candid(vec![])).unwrap());
+
+    static GC_TIMER: RefCell<Option<TimerId>> = RefCell::new(None);
+}
+
+async fn random_bytes() -> Result<Vec<u8>, String> {
+    let (bytes,) = ic_cdk::api::management_canister::main::raw_rand()
+        .await
+        .map_err(|(code, message)| format!("raw_rand failed with code {:?}: {}", code, message))?;
+    Ok(bytes)
+}
+
+// The zero-padded expiry makes the keys sort by expiry.
+fn expiry_key(expires_at: u64, id: &str) -> String {
+    format!("{:020}/{}", expires_at, id)
+}
+
+// Stores the session and moves its index entry from the previous expiry.
+fn insert(id: &str, session: Session, previous_expiry: Option<u64>) {
+    EXPIRIES.with(|expiries| {
+        let mut expiries = expiries.borrow_mut();
+        if let Some(previous) = previous_expiry {
+            expiries.remove(&expiry_key(previous, id));
+        }
+        expiries.insert(expiry_key(session.expires_at, id), id.to_string());
+    });
+    SESSIONS.with(|sessions| {
+        sessions
+            .borrow_mut()
+            .insert(id.to_string(), Candid(session))
+    });
+}
+
+// Removes the session and its index entry.
+fn remove(id: &str) -> bool {
+    match SESSIONS.with(|sessions| sessions.borrow_mut().remove(&id.to_string())) {
+        Some(Candid(session)) => {
+            EXPIRIES.with(|expiries| {
+                expiries
+                    .borrow_mut()
+                    .remove(&expiry_key(session.expires_at, id))
+            });
+            true
+        }
+        None => false,
+    }
+}
+
+/// Creates a session with the given data and TTL and returns its token.
+pub async fn create(data: serde_json::Value, ttl_nanos: u64) -> Result<String, String> {
+    if SECRET.with(|secret| secret.borrow().get().0.is_empty()) {
+        let secret = random_bytes().await?;
+        SECRET.with(|cell| {
+            let mut cell = cell.borrow_mut();
+            // Another session may have set the secret while awaiting.
+            if cell.get().0.is_empty() {
+                cell.set(Candid(secret)).unwrap();
+            }
+        });
+    }
+    let id = hex::encode(&random_bytes().await?[..ID_SIZE]);
+    let now = ic_cdk::api::time();
+    let session = Session {
+        data: data.to_string(),
+        created_at: now,
+        expires_at: now.saturating_add(ttl_nanos),
+        ttl_nanos,
+    };
+    insert(&id, session, None);
+    schedule();
+    Ok(format!("{}.{}", id, sign(&id)))
+}
+
+fn sign(id: &str) -> String {
+    let secret = SECRET.with(|secret| secret.borrow().get().0.clone());
+    let mut mac = Hmac::<Sha256>::new_from_slice(&secret).unwrap();
+    mac.update(id.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+// Returns the session id of a token with a valid signature.
+fn verify(token: &str) -> Option<&str> {
+    let (id, signature) = token.split_once('.')?;
+    let signature = hex::decode(signature).ok()?;
+    let secret = SECRET.with(|secret| secret.borrow().get().0.clone());
+    if secret.is_empty() {
+        return None;
+    }
+    let mut mac = Hmac::<Sha256>::new_from_slice(&secret).unwrap();
+    mac.update(id.as_bytes());
+    mac.verify_slice(&signature).ok()?;
+    Some(id)
+}
+
+/// Returns the data of the session with the given token if it has not
+/// expired.
+pub fn get(token: &str) -> Option<serde_json::Value> {
+    let id = verify(token)?;
+    let Candid(session) = SESSIONS.with(|sessions| sessions.borrow().get(&id.to_string()))?;
+    if session.expires_at <= ic_cdk::api::time() {
+        return None;
+    }
+    serde_json::from_str(&session.data).ok()
+}
+
+/// Extends the session with the given token by its TTL. Returns false if the
+/// session does not exist or has expired.
+pub fn touch(token: &str) -> bool {
+    let Some(id) = verify(token) else {
+        return false;
+    };
+    let now = ic_cdk::api::time();
+    match SESSIONS.with(|sessions| sessions.borrow().get(&id.to_string())) {
+        Some(Candid(mut session)) if session.expires_at > now => {
+            let previous = session.expires_at;
+            session.expires_at = now.saturating_add(session.ttl_nanos);
+            insert(id, session, Some(previous));
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Removes the session with the given token. Returns true if it existed.
+pub fn expire(token: &str) -> bool {
+    let Some(id) = verify(token) else {
+        return false;
+    };
+    remove(id)
+}
+
+/// Arms the timer that removes expired sessions. Must be called after an
+/// upgrade because timers do not survive upgrades.
+pub fn schedule() {
+    backfill();
+    GC_TIMER.with(|timer| {
+        let mut timer = timer.borrow_mut();
+        if timer.is_none() && !SESSIONS.with(|sessions| sessions.borrow().is_empty()) {
+            *timer = Some(ic_cdk_timers::set_timer_interval(GC_INTERVAL, gc));
+        }
+    });
+}
+
+// Indexes sessions created before the expiry index existed. Runs only once
+// because afterwards every session has an index entry.
+fn backfill() {
+    let missing = EXPIRIES.with(|expiries| expiries.borrow().is_empty())
+        && !SESSIONS.with(|sessions| sessions.borrow().is_empty());
+    if !missing {
+        return;
+    }
+    SESSIONS.with(|sessions| {
+        EXPIRIES.with(|expiries| {
+            let mut expiries = expiries.borrow_mut();
+            for (id, Candid(session)) in sessions.borrow().iter() {
+                expiries.insert(expiry_key(session.expires_at, &id), id);
+            }
+        })
+    });
+}
+
+// Removes a batch of expired sessions.
+fn gc() {
+    // All keys of sessions that expire at or before `now` sort before the
+    // keys of sessions that expire later.
+    let end = expiry_key(ic_cdk::api::time().saturating_add(1), "");
+    EXPIRIES.with(|expiries| {
+        let mut expiries = expiries.borrow_mut();
+        let expired: Vec<(String, String)> = expiries
+            .range((Bound::Unbounded, Bound::Excluded(end)))
+            .take(MAX_GC_BATCH)
+            .collect();
+        for (key, id) in expired {
+            expiries.remove(&key);
+            SESSIONS.with(|sessions| sessions.borrow_mut().remove(&id));
+        }
+    });
+}
+
+pub fn link(context: &JSContextRef) -> Result<(), anyhow::Error> {
+    // Usage: `ic.sessions.create(data, ttlSeconds)`. Returns a promise that
+    // resolves with the session token.
+    fn create<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 2 {
+            return Err(JSError::Type(format!("Expected 2 arguments, got {}", args.len())).into());
+        }
+        engine::ensure_writable("ic.sessions.create()")?;
+        let data = json::to_json(&args[0].to_js_value()?);
+        let ttl_seconds = jobs::number(&args[1].to_js_value()?, "ttlSeconds")?;
+        let ttl_nanos = (ttl_seconds * 1e9) as u64;
+        let (id, promise) = engine::promise(context)?;
+        ic_cdk::spawn(async move {
+            let result = self::create(data, ttl_nanos).await;
+            engine::settle(id, move |context| match result {
+                Ok(token) => context.value_from_str(&token),
+                Err(err) => Err(anyhow::anyhow!(err)),
+            });
+        });
+        Ok(promise)
+    }
+
+    fn get<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 1 {
+            return Err(JSError::Type(format!("Expected 1 argument, got {}", args.len())).into());
+        }
+        let token: String = args[0].try_into()?;
+        match self::get(&token) {
+            Some(data) => quickjs_wasm_rs::to_qjs_value(context, &json::from_json(&data)),
+            None => context.undefined_value(),
+        }
+    }
+
+    fn touch<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 1 {
+            return Err(JSError::Type(format!("Expected 1 argument, got {}", args.len())).into());
+        }
+        engine::ensure_writable("ic.sessions.touch()")?;
+        let token: String = args[0].try_into()?;
+        context.value_from_bool(self::touch(&token))
+    }
+
+    fn expire<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 1 {
+            return Err(JSError::Type(format!("Expected 1 argument, got {}", args.len())).into());
+        }
+        engine::ensure_writable("ic.sessions.expire()")?;
+        let token: String = args[0].try_into()?;
+        context.value_from_bool(self::expire(&token))
+    }
+
+    let sessions = context.object_value()?;
+    sessions.set_property("create", context.wrap_callback2(create)?)?;
+    sessions.set_property("get", context.wrap_callback2(get)?)?;
+    sessions.set_property("touch", context.wrap_callback2(touch)?)?;
+    sessions.set_property("expire", context.wrap_callback2(expire)?)?;
+
+    engine::namespace(context)?.set_property("sessions", sessions)?;
+    Ok(())
+}
@@ -0,0 +1,212 @@
+// A router that distributes keys over a set of shard canisters using
+// consistent hashing.
+//
+// Each shard runs the same Wasm module and stores its part of the data in its
+// own KV store. The router must be a controller of all shards. The shard map
+// is versioned: every change bumps the version and notifies the optional
+// `onShardMapChange(oldMap, newMap)` JS hook, which can move the affected keys.
+use std::cell::RefCell;
+
+use candid::{
+    utils::{decode_args, encode_args},
+    CandidType, Deserialize, Principal,
+};
+use ic_stable_structures::StableCell;
+use quickjs_wasm_rs::{CallbackArg, JSContextRef, JSError, JSValueRef};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    dead_letters, engine, json, kv,
+    stable::{self, Candid, Memory},
+};
+
+/// The name of an optional JS function that is notified about shard map
+/// changes.
+pub const ON_SHARD_MAP_CHANGE: &str = "onShardMapChange";
+
+// The number of points of each shard on the hash ring.
+const VIRTUAL_NODES: u32 = 64;
+
+/// The set of shards with a version that changes on every update.
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct ShardMap {
+    pub version: u64,
+    pub shards: Vec<Principal>,
+}
+
+thread_local! {
+    static SHARD_MAP: RefCell<StableCell<Candid<ShardMap>, Memory>> =
+        RefCell::new(StableCell::init(stable::memory(stable::SHARD_MAP), Candid(ShardMap::default())).unwrap());
+
+    // The hash ring of the current shard map: sorted points with shard indices.
+    static RING: RefCell<Option<(u64, Vec<(u64, usize)>)>> = RefCell::new(None);
+}
+
+pub fn shard_map() -> ShardMap {
+    SHARD_MAP.with(|map| map.borrow().get().0.clone())
+}
+
+/// Replaces the set of shards and returns the new version of the shard map.
+pub fn set_shards(shards: Vec<Principal>) -> u64 {
+    let old = shard_map();
+    let new = ShardMap {
+        version: old.version + 1,
+        shards,
+    };
+    SHARD_MAP.with(|map| map.borrow_mut().set(Candid(new.clone())).unwrap());
+    let version = new.version;
+    if engine::is_function_defined(ON_SHARD_MAP_CHANGE) {
+        dead_letters::spawn(ON_SHARD_MAP_CHANGE, "shards", move |context| {
+            Ok(vec![to_js_map(context, &old)?, to_js_map(context, &new)?])
+        });
+    }
+    version
+}
+
+/// Returns the shard responsible for the given key.
+pub fn shard_for(key: &str) -> Option<Principal> {
+    let map = shard_map();
+    if map.shards.is_empty() {
+        return None;
+    }
+    RING.with(|ring| {
+        let mut ring = ring.borrow_mut();
+        if ring.as_ref().map(|(version, _)| *version) != Some(map.version) {
+            *ring = Some((map.version, build_ring(&map.shards)));
+        }
+        let (_, points) = ring.as_ref().unwrap();
+        let point = hash(key.as_bytes());
+        let index = match points.binary_search_by_key(&point, |(point, _)| *point) {
+            Ok(index) => index,
+            Err(index) => index % points.len(),
+        };
+        Some(map.shards[points[index].1])
+    })
+}
+
+fn build_ring(shards: &[Principal]) -> Vec<(u64, usize)> {
+    let mut points: Vec<_> = shards
+        .iter()
+        .enumerate()
+        .flat_map(|(index, shard)| {
+            (0..VIRTUAL_NODES).map(move |node| {
+                let mut bytes = shard.as_slice().to_vec();
+                bytes.extend_from_slice(&node.to_be_bytes());
+                (hash(&bytes), index)
+            })
+        })
+        .collect();
+    points.sort();
+    points
+}
+
+fn hash(bytes: &[u8]) -> u64 {
+    let digest = Sha256::digest(bytes);
+    u64::from_be_bytes(digest[0..8].try_into().unwrap())
+}
+
+fn to_js_map<'a>(
+    context: &'a JSContextRef,
+    map: &ShardMap,
+) -> Result<JSValueRef<'a>, anyhow::Error> {
+    let js = context.object_value()?;
+    js.set_property("version", context.value_from_f64(map.version as f64)?)?;
+    let shards = context.array_value()?;
+    for shard in map.shards.iter() {
+        shards.append_property(context.value_from_str(&shard.to_text())?)?;
+    }
+    js.set_property("shards", shards)?;
+    Ok(js)
+}
+
+/// Handles `shard_get` on a shard canister.
+pub fn local_get(key: String) -> Option<String> {
+    match kv::get(&key) {
+        Ok(value) => value.map(|value| value.to_string()),
+        Err(err) => ic_cdk::trap(&err.to_string()),
+    }
+}
+
+/// Handles `shard_set` on a shard canister.
+pub fn local_set(key: String, value: String) -> Result<(), String> {
+    let value: serde_json::Value = serde_json::from_str(&value).map_err(|err| err.to_string())?;
+    kv::set(key, &value).map_err(|err| err.to_string())
+}
+
+pub fn link(context: &JSContextRef) -> Result<(), anyhow::Error> {
+    fn shard_for<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 1 {
+            return Err(JSError::Type(format!("Expected 1 argument, got {}", args.len())).into());
+        }
+        let key: String = args[0].try_into()?;
+        match self::shard_for(&key) {
+            Some(shard) => context.value_from_str(&shard.to_text()),
+            None => context.null_value(),
+        }
+    }
+
+    fn version<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        _args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        context.value_from_f64(shard_map().version as f64)
+    }
+
+    fn get<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 1 {
+            return Err(JSError::Type(format!("Expected 1 argument, got {}", args.len())).into());
+        }
+        let key: String = args[0].try_into()?;
+        let shard =
+            self::shard_for(&key).ok_or_else(|| anyhow::anyhow!("No shards are configured"))?;
+        let args = encode_args((key,))?;
+        engine::call(context, shard, "shard_get", &args, |context, bytes| {
+            let (value,) = decode_args::<(Option<String>,)>(&bytes)?;
+            match value {
+                Some(value) => {
+                    let value: serde_json::Value = serde_json::from_str(&value)?;
+                    quickjs_wasm_rs::to_qjs_value(context, &json::from_json(&value))
+                }
+                None => context.undefined_value(),
+            }
+        })
+    }
+
+    fn set<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 2 {
+            return Err(JSError::Type(format!("Expected 2 arguments, got {}", args.len())).into());
+        }
+        let key: String = args[0].try_into()?;
+        let value = json::to_json(&args[1].to_js_value()?).to_string();
+        let shard =
+            self::shard_for(&key).ok_or_else(|| anyhow::anyhow!("No shards are configured"))?;
+        let args = encode_args((key, value))?;
+        engine::call(context, shard, "shard_set", &args, |context, bytes| {
+            let (result,) = decode_args::<(Result<(), String>,)>(&bytes)?;
+            result.map_err(|err| anyhow::anyhow!(err))?;
+            context.undefined_value()
+        })
+    }
+
+    let shards = context.object_value()?;
+    shards.set_property("shardFor", context.wrap_callback2(shard_for)?)?;
+    shards.set_property("version", context.wrap_callback2(version)?)?;
+    shards.set_property("get", context.wrap_callback2(get)?)?;
+    shards.set_property("set", context.wrap_callback2(set)?)?;
+
+    engine::namespace(context)?.set_property("shards", shards)?;
+    Ok(())
+}
@@ -0,0 +1,181 @@
+// Canister signatures issued by JS code.
+//
+// A canister signature proves that the canister certified a message for a
+// seed. The public key consists of the canister id and the seed, so every seed
+// is a separate key, e.g. one per user in a sign-in flow.
+//
+// Signing takes two steps. An update call adds the path
+// `sig/sha256(seed)/sha256(message)` to the certified state with
+// `ic.signatures.sign(seed, message)`. A later query call to the
+// `canister_signature` endpoint returns the certificate with a witness of that
+// path, which is the signature. Signatures expire from the certified state
+// after a minute to keep the hash tree small.
+use std::{cell::RefCell, collections::VecDeque};
+
+use quickjs_wasm_rs::{CallbackArg, JSContextRef, JSError, JSValueRef};
+use serde_cbor::Value;
+
+use crate::{
+    certification,
+    crypto::{self, bytes},
+    engine,
+};
+
+// The label of the subtree of signatures in the certified state.
+const LABEL: &str = "sig";
+
+// How long a signature can be fetched after signing.
+const SIGNATURE_EXPIRATION_NANOS: u64 = 60 * 1_000_000_000;
+
+// The DER encoding of the algorithm identifier of canister signatures with the
+// OID 1.3.6.1.4.1.56387.1.2.
+const ALGORITHM_ID: &[u8] = &[
+    0x30, 0x0c, 0x06, 0x0a, 0x2b, 0x06, 0x01, 0x04, 0x01, 0x83, 0xb8, 0x43, 0x01, 0x02,
+];
+
+thread_local! {
+    // The paths of signatures in the certified state ordered by expiration.
+    static SIGNATURES: RefCell<VecDeque<(u64, Vec<u8>, Vec<u8>)>> = RefCell::new(VecDeque::new());
+}
+
+/// Returns the DER-encoded public key of the given seed.
+pub fn public_key(seed: &[u8]) -> Vec<u8> {
+    let canister_id = ic_cdk::id();
+    let canister_id = canister_id.as_slice();
+    let mut key = vec![canister_id.len() as u8];
+    key.extend_from_slice(canister_id);
+    key.extend_from_slice(seed);
+
+    // The key is a bit string without unused bits.
+    let mut bit_string = vec![0];
+    bit_string.extend(key);
+    let bit_string = der(0x03, &bit_string);
+
+    let mut info = ALGORITHM_ID.to_vec();
+    info.extend(bit_string);
+    der(0x30, &info)
+}
+
+// Encodes a DER value with the given tag.
+fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut result = vec![tag];
+    let len = content.len();
+    if len < 0x80 {
+        result.push(len as u8);
+    } else {
+        let bytes: Vec<u8> = len
+            .to_be_bytes()
+            .into_iter()
+            .skip_while(|b| *b == 0)
+            .collect();
+        result.push(0x80 | bytes.len() as u8);
+        result.extend(bytes);
+    }
+    result.extend_from_slice(content);
+    result
+}
+
+/// Adds the signature of the given message to the certified state and returns
+/// the public key of the seed.
+pub fn sign(seed: &[u8], message: &[u8]) -> Vec<u8> {
+    let now = ic_cdk::api::time();
+    prune_expired(now);
+    let seed_hash = crypto::sha256(seed).to_vec();
+    let message_hash = crypto::sha256(message).to_vec();
+    certification::insert_nested(LABEL, seed_hash.clone(), message_hash.clone(), vec![]);
+    SIGNATURES.with(|signatures| {
+        signatures.borrow_mut().push_back((
+            now + SIGNATURE_EXPIRATION_NANOS,
+            seed_hash,
+            message_hash,
+        ))
+    });
+    public_key(seed)
+}
+
+fn prune_expired(now: u64) {
+    SIGNATURES.with(|signatures| {
+        let mut signatures = signatures.borrow_mut();
+        while signatures
+            .front()
+            .is_some_and(|(expires_at, _, _)| *expires_at <= now)
+        {
+            let (_, seed_hash, message_hash) = signatures.pop_front().unwrap();
+            let is_duplicate = signatures
+                .iter()
+                .any(|(_, seed, message)| *seed == seed_hash && *message == message_hash);
+            if !is_duplicate {
+                certification::remove_nested(LABEL, &seed_hash, &message_hash);
+            }
+        }
+    });
+}
+
+/// Returns the CBOR-encoded signature of a message signed earlier. Must be
+/// called in a query call.
+pub fn signature(seed: &[u8], message: &[u8]) -> Result<Vec<u8>, String> {
+    let seed_hash = crypto::sha256(seed).to_vec();
+    let message_hash = crypto::sha256(message).to_vec();
+    let now = ic_cdk::api::time();
+    let is_signed = SIGNATURES.with(|signatures| {
+        signatures
+            .borrow()
+            .iter()
+            .any(|(expires_at, seed, message)| {
+                *expires_at > now && *seed == seed_hash && *message == message_hash
+            })
+    });
+    if !is_signed {
+        return Err("The message is not signed or the signature expired".to_string());
+    }
+    let signature = Value::Map(
+        [
+            (
+                Value::Text("certificate".to_string()),
+                Value::Bytes(certification::certificate()?),
+            ),
+            (
+                Value::Text("tree".to_string()),
+                certification::nested_witness(LABEL, &seed_hash, &message_hash),
+            ),
+        ]
+        .into_iter()
+        .collect(),
+    );
+    Ok(certification::encode(&signature))
+}
+
+pub fn link(context: &JSContextRef) -> Result<(), anyhow::Error> {
+    // Returns the public key of the seed. The signature is available via the
+    // `canister_signature` query endpoint.
+    fn sign<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 2 {
+            return Err(JSError::Type(format!("Expected 2 arguments, got {}", args.len())).into());
+        }
+        engine::ensure_writable("ic.signatures.sign()")?;
+        let public_key = self::sign(&bytes(&args[0])?, &bytes(&args[1])?);
+        context.array_buffer_value(&public_key)
+    }
+
+    fn public_key<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 1 {
+            return Err(JSError::Type(format!("Expected 1 argument, got {}", args.len())).into());
+        }
+        context.array_buffer_value(&self::public_key(&bytes(&args[0])?))
+    }
+
+    let signatures = context.object_value()?;
+    signatures.set_property("sign", context.wrap_callback2(sign)?)?;
+    signatures.set_property("publicKey", context.wrap_callback2(public_key)?)?;
+
+    engine::namespace(context)?.set_property("signatures", signatures)?;
+    Ok(())
+}
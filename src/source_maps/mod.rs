@@ -0,0 +1,193 @@
+// Source maps of bundled scripts for readable stack traces.
+//
+// Controllers upload the source map of a script with `set_source_map(name,
+// map)` where `name` is the script name in stack traces, e.g. `ic.js` or
+// `staged.js`, and `map` is a source map v3 in JSON. Locations like
+// `ic.js:12` or `ic.js:12:34` in errors of that script are then translated to
+// the original file, line and column. Without a column, the first mapping of
+// the line is used, which is imprecise for minified scripts.
+//
+// Source maps are kept on the heap, so upload them again after an upgrade.
+use std::{cell::RefCell, collections::HashMap};
+
+use candid::Deserialize;
+
+#[derive(Deserialize)]
+struct RawSourceMap {
+    version: u32,
+    #[serde(default)]
+    sources: Vec<String>,
+    #[serde(default, rename = "sourceRoot")]
+    source_root: Option<String>,
+    mappings: String,
+}
+
+// A mapping from a generated column to an original location. Lines and
+// columns are zero-based.
+#[derive(Copy, Clone, Debug)]
+struct Segment {
+    column: u32,
+    source: u32,
+    line: u32,
+    source_column: u32,
+}
+
+struct SourceMap {
+    sources: Vec<String>,
+    // The segments of each generated line sorted by column.
+    lines: Vec<Vec<Segment>>,
+}
+
+thread_local! {
+    static SOURCE_MAPS: RefCell<HashMap<String, SourceMap>> = RefCell::new(HashMap::new());
+}
+
+pub fn set(script_name: String, map: &str) -> Result<(), String> {
+    let raw: RawSourceMap =
+        serde_json::from_str(map).map_err(|err| format!("Invalid source map: {}", err))?;
+    if raw.version != 3 {
+        return Err(format!("Expected a source map v3, got v{}", raw.version));
+    }
+    let root = raw.source_root.unwrap_or_default();
+    let map = SourceMap {
+        sources: raw
+            .sources
+            .iter()
+            .map(|source| format!("{}{}", root, source))
+            .collect(),
+        lines: decode_mappings(&raw.mappings)?,
+    };
+    SOURCE_MAPS.with(|maps| maps.borrow_mut().insert(script_name, map));
+    Ok(())
+}
+
+fn decode_mappings(mappings: &str) -> Result<Vec<Vec<Segment>>, String> {
+    let mut lines = vec![];
+    // The source index, line and column are relative to the previous segment
+    // in the whole map, the generated column only within the line.
+    let (mut source, mut line, mut source_column) = (0_i64, 0_i64, 0_i64);
+    for encoded_line in mappings.split(';') {
+        let mut segments = vec![];
+        let mut column = 0_i64;
+        for encoded in encoded_line
+            .split(',')
+            .filter(|encoded| !encoded.is_empty())
+        {
+            let fields = decode_vlq(encoded)?;
+            column += fields[0];
+            // Segments with one field have no original location.
+            if fields.len() >= 4 {
+                source += fields[1];
+                line += fields[2];
+                source_column += fields[3];
+                segments.push(Segment {
+                    column: column as u32,
+                    source: source as u32,
+                    line: line as u32,
+                    source_column: source_column as u32,
+                });
+            }
+        }
+        segments.sort_by_key(|segment| segment.column);
+        lines.push(segments);
+    }
+    Ok(lines)
+}
+
+// Decodes the base64 VLQ numbers of a segment.
+fn decode_vlq(encoded: &str) -> Result<Vec<i64>, String> {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut values = vec![];
+    let (mut value, mut shift) = (0_i64, 0);
+    for byte in encoded.bytes() {
+        let digit = ALPHABET
+            .iter()
+            .position(|c| *c == byte)
+            .ok_or_else(|| format!("Invalid mapping {}", encoded))? as i64;
+        value += (digit & 0x1f) << shift;
+        if digit & 0x20 != 0 {
+            shift += 5;
+            continue;
+        }
+        values.push(if value & 1 == 1 {
+            -(value >> 1)
+        } else {
+            value >> 1
+        });
+        value = 0;
+        shift = 0;
+    }
+    if shift != 0 || values.is_empty() {
+        return Err(format!("Invalid mapping {}", encoded));
+    }
+    Ok(values)
+}
+
+impl SourceMap {
+    // Takes one-based lines and columns like stack traces.
+    fn lookup(&self, line: u32, column: Option<u32>) -> Option<String> {
+        let segments = self.lines.get(line.checked_sub(1)? as usize)?;
+        let segment = match column {
+            Some(column) => segments
+                .iter()
+                .rev()
+                .find(|segment| segment.column < column)
+                .or_else(|| segments.first())?,
+            None => segments.first()?,
+        };
+        let source = self.sources.get(segment.source as usize)?;
+        Some(format!(
+            "{}:{}:{}",
+            source,
+            segment.line + 1,
+            segment.source_column + 1
+        ))
+    }
+}
+
+/// Replaces the locations in scripts with source maps in the given text with
+/// the original locations.
+pub fn translate(text: &str) -> String {
+    SOURCE_MAPS.with(|maps| {
+        let maps = maps.borrow();
+        let mut text = text.to_string();
+        for (name, map) in maps.iter() {
+            text = translate_script(&text, name, map);
+        }
+        text
+    })
+}
+
+fn translate_script(text: &str, name: &str, map: &SourceMap) -> String {
+    let prefix = format!("{}:", name);
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find(&prefix) {
+        result.push_str(&rest[..start]);
+        let after = &rest[start + prefix.len()..];
+        let (line, after_line) = split_number(after);
+        let (column, after_column) = match after_line.strip_prefix(':').map(split_number) {
+            Some((Some(column), after_column)) => (Some(column), after_column),
+            _ => (None, after_line),
+        };
+        match line.and_then(|line| map.lookup(line, column)) {
+            Some(location) => {
+                result.push_str(&location);
+                rest = after_column;
+            }
+            None => {
+                result.push_str(&prefix);
+                rest = after;
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+fn split_number(text: &str) -> (Option<u32>, &str) {
+    let end = text
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(text.len());
+    (text[..end].parse().ok(), &text[end..])
+}
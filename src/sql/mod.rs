@@ -0,0 +1,504 @@
+// A minimal relational layer over stable memory exposed to JS as
+// `ic.sql.execute(query, params)`.
+//
+// Tables hold rows of JSON values with typed columns and an optional INTEGER
+// or TEXT primary key. Rows are stored in key order under `table \0 key`,
+// where the key is the primary key or a row id, so a lookup by primary key
+// with `=` reads one row and other queries scan the table. See parser.rs for
+// the supported SQL.
+//
+// `execute()` returns `{rows, changes}`: the selected rows as objects for
+// SELECT and the number of inserted, updated or deleted rows otherwise.
+use std::{cell::RefCell, cmp::Ordering, ops::Bound};
+
+use anyhow::{anyhow, bail, Error};
+use candid::{CandidType, Deserialize};
+use ic_stable_structures::StableBTreeMap;
+use quickjs_wasm_rs::{CallbackArg, JSContextRef, JSError, JSValue, JSValueRef};
+use serde_json::{json, Map, Value};
+
+use crate::{
+    engine, json,
+    stable::{self, Candid, Memory},
+};
+
+mod parser;
+
+use parser::{Column, ColumnType, Condition, Expr, Op, Projection, Statement};
+
+const SEPARATOR: char = '\0';
+
+// The maximum size of a JSON-encoded row in bytes.
+const MAX_ROW_SIZE: usize = 1024 * 1024;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct Table {
+    columns: Vec<Column>,
+    // The id of the next row of a table without a primary key.
+    next_row_id: u64,
+}
+
+impl Table {
+    fn column(&self, name: &str) -> Result<&Column, Error> {
+        self.columns
+            .iter()
+            .find(|column| column.name == name)
+            .ok_or_else(|| anyhow!("No such column: {}", name))
+    }
+
+    fn primary_key(&self) -> Option<&Column> {
+        self.columns.iter().find(|column| column.primary_key)
+    }
+}
+
+thread_local! {
+    static TABLES: RefCell<StableBTreeMap<String, Candid<Table>, Memory>> =
+        RefCell::new(StableBTreeMap::init(stable::memory(stable::SQL_TABLES)));
+
+    static ROWS: RefCell<StableBTreeMap<String, String, Memory>> =
+        RefCell::new(StableBTreeMap::init(stable::memory(stable::SQL_ROWS)));
+}
+
+/// The result of a statement.
+pub struct Output {
+    pub rows: Vec<Value>,
+    pub changes: u64,
+}
+
+/// Executes a single SQL statement with the given positional parameters.
+pub fn execute(sql: &str, params: &[Value]) -> Result<Output, Error> {
+    let statement = parser::parse(sql)?;
+    if !matches!(statement, Statement::Select { .. }) {
+        engine::ensure_writable("Writing SQL tables")?;
+    }
+    let resolve = |expr: &Expr| -> Result<Value, Error> {
+        match expr {
+            Expr::Literal(value) => Ok(value.clone()),
+            Expr::Param(i) => params.get(*i).cloned().ok_or_else(|| {
+                anyhow!(
+                    "Expected at least {} parameters, got {}",
+                    i + 1,
+                    params.len()
+                )
+            }),
+        }
+    };
+    let changes = match statement {
+        Statement::CreateTable {
+            name,
+            columns,
+            if_not_exists,
+        } => {
+            if name.contains(SEPARATOR) {
+                bail!("Invalid table name {:?}", name);
+            }
+            if table(&name).is_ok() {
+                if if_not_exists {
+                    return Ok(Output {
+                        rows: vec![],
+                        changes: 0,
+                    });
+                }
+                bail!("Table {} already exists", name);
+            }
+            for (i, column) in columns.iter().enumerate() {
+                if columns[..i].iter().any(|other| other.name == column.name) {
+                    bail!("Duplicate column {}", column.name);
+                }
+            }
+            put_table(
+                &name,
+                Table {
+                    columns,
+                    next_row_id: 0,
+                },
+            );
+            0
+        }
+        Statement::DropTable { name, if_exists } => {
+            if table(&name).is_err() && if_exists {
+                return Ok(Output {
+                    rows: vec![],
+                    changes: 0,
+                });
+            }
+            table(&name)?;
+            let keys: Vec<String> = scan(&name)?.into_iter().map(|(key, _)| key).collect();
+            ROWS.with(|rows| {
+                let mut rows = rows.borrow_mut();
+                for key in keys.iter() {
+                    rows.remove(key);
+                }
+            });
+            TABLES.with(|tables| tables.borrow_mut().remove(&name));
+            keys.len() as u64
+        }
+        Statement::Insert {
+            table: name,
+            columns,
+            rows,
+        } => {
+            let mut table = table(&name)?;
+            let columns = match columns {
+                Some(columns) => columns,
+                None => table
+                    .columns
+                    .iter()
+                    .map(|column| column.name.clone())
+                    .collect(),
+            };
+            for column in columns.iter() {
+                table.column(column)?;
+            }
+            let mut changes = 0;
+            for values in rows {
+                if values.len() != columns.len() {
+                    bail!("Expected {} values, got {}", columns.len(), values.len());
+                }
+                let mut row: Map<String, Value> = table
+                    .columns
+                    .iter()
+                    .map(|column| (column.name.clone(), Value::Null))
+                    .collect();
+                for (column, value) in columns.iter().zip(values.iter()) {
+                    row.insert(column.clone(), resolve(value)?);
+                }
+                let row = check_row(&table, row)?;
+                let key = match table.primary_key() {
+                    Some(column) => row_key(&name, &row[&column.name])?,
+                    None => {
+                        table.next_row_id += 1;
+                        row_key(&name, &Value::from(table.next_row_id))?
+                    }
+                };
+                if ROWS.with(|rows| rows.borrow().contains_key(&key)) {
+                    bail!("A row with the same primary key already exists in {}", name);
+                }
+                put_row(key, &row)?;
+                changes += 1;
+            }
+            put_table(&name, table);
+            changes
+        }
+        Statement::Select {
+            table: name,
+            projection,
+            filter,
+            order_by,
+            limit,
+        } => {
+            let table = table(&name)?;
+            let mut rows = select(&name, &table, filter.as_ref(), &resolve)?;
+            if let Some((column, descending)) = order_by {
+                table.column(&column)?;
+                rows.sort_by(|(_, a), (_, b)| {
+                    let ordering = order(&a[&column], &b[&column]);
+                    if descending {
+                        ordering.reverse()
+                    } else {
+                        ordering
+                    }
+                });
+            }
+            if let Some(limit) = limit {
+                let limit = resolve(&limit)?
+                    .as_u64()
+                    .ok_or_else(|| anyhow!("Expected LIMIT to be a non-negative integer"))?;
+                rows.truncate(limit as usize);
+            }
+            let rows = match projection {
+                Projection::All => rows
+                    .into_iter()
+                    .map(|(_, row)| Value::Object(row))
+                    .collect(),
+                Projection::Count => vec![json!({"count": rows.len()})],
+                Projection::Columns(columns) => {
+                    for column in columns.iter() {
+                        table.column(column)?;
+                    }
+                    rows.into_iter()
+                        .map(|(_, row)| {
+                            Value::Object(
+                                columns
+                                    .iter()
+                                    .map(|column| (column.clone(), row[column].clone()))
+                                    .collect(),
+                            )
+                        })
+                        .collect()
+                }
+            };
+            return Ok(Output { rows, changes: 0 });
+        }
+        Statement::Update {
+            table: name,
+            assignments,
+            filter,
+        } => {
+            let table = table(&name)?;
+            for (column, _) in assignments.iter() {
+                table.column(column)?;
+            }
+            let rows = select(&name, &table, filter.as_ref(), &resolve)?;
+            let changes = rows.len() as u64;
+            for (key, mut row) in rows {
+                for (column, value) in assignments.iter() {
+                    row.insert(column.clone(), resolve(value)?);
+                }
+                let row = check_row(&table, row)?;
+                let new_key = match table.primary_key() {
+                    Some(column) => row_key(&name, &row[&column.name])?,
+                    None => key.clone(),
+                };
+                if new_key != key {
+                    if ROWS.with(|rows| rows.borrow().contains_key(&new_key)) {
+                        bail!("A row with the same primary key already exists in {}", name);
+                    }
+                    ROWS.with(|rows| rows.borrow_mut().remove(&key));
+                }
+                put_row(new_key, &row)?;
+            }
+            changes
+        }
+        Statement::Delete {
+            table: name,
+            filter,
+        } => {
+            let table = table(&name)?;
+            let rows = select(&name, &table, filter.as_ref(), &resolve)?;
+            ROWS.with(|stored| {
+                let mut stored = stored.borrow_mut();
+                for (key, _) in rows.iter() {
+                    stored.remove(key);
+                }
+            });
+            rows.len() as u64
+        }
+    };
+    Ok(Output {
+        rows: vec![],
+        changes,
+    })
+}
+
+fn table(name: &str) -> Result<Table, Error> {
+    TABLES
+        .with(|tables| tables.borrow().get(&name.to_string()))
+        .map(|table| table.0)
+        .ok_or_else(|| anyhow!("No such table: {}", name))
+}
+
+fn put_table(name: &str, table: Table) {
+    TABLES.with(|tables| tables.borrow_mut().insert(name.to_string(), Candid(table)));
+}
+
+fn put_row(key: String, row: &Map<String, Value>) -> Result<(), Error> {
+    let row = Value::Object(row.clone()).to_string();
+    if row.len() > MAX_ROW_SIZE {
+        bail!(
+            "The row is too large: {} bytes, the limit is {} bytes",
+            row.len(),
+            MAX_ROW_SIZE
+        );
+    }
+    ROWS.with(|rows| rows.borrow_mut().insert(key, row));
+    Ok(())
+}
+
+// Integer keys are stored as hex with the sign bit flipped, so that they sort
+// numerically.
+fn row_key(table: &str, value: &Value) -> Result<String, Error> {
+    let key = match value {
+        Value::Number(number) => match number.as_i64() {
+            Some(number) => format!("{:016x}", (number as u64) ^ (1 << 63)),
+            None => bail!("Expected the primary key to be an integer"),
+        },
+        Value::String(text) if !text.contains(SEPARATOR) => text.clone(),
+        _ => bail!("Invalid primary key {}", value),
+    };
+    Ok(format!("{}{}{}", table, SEPARATOR, key))
+}
+
+// Checks the values of a row against the column types. Integral numbers of
+// REAL columns are kept as they are.
+fn check_row(table: &Table, mut row: Map<String, Value>) -> Result<Map<String, Value>, Error> {
+    for column in table.columns.iter() {
+        let value = row.get_mut(&column.name).unwrap();
+        // JS passes integers as floats in some cases.
+        if let (ColumnType::Integer, Value::Number(number)) = (column.kind, &*value) {
+            let float = number
+                .as_f64()
+                .filter(|float| float.fract() == 0.0 && float.abs() < 9.2e18);
+            if let (None, Some(float)) = (number.as_i64(), float) {
+                *value = Value::from(float as i64);
+            }
+        }
+        let is_valid = match (column.kind, &*value) {
+            (_, Value::Null) => !column.not_null,
+            (ColumnType::Integer, Value::Number(number)) => number.as_i64().is_some(),
+            (ColumnType::Real, Value::Number(_))
+            | (ColumnType::Text, Value::String(_))
+            | (ColumnType::Boolean, Value::Bool(_))
+            | (ColumnType::Json, _) => true,
+            _ => false,
+        };
+        if !is_valid {
+            bail!(
+                "Invalid value {} for column {} of type {:?}",
+                value,
+                column.name,
+                column.kind
+            );
+        }
+    }
+    Ok(row)
+}
+
+// Returns all rows of the table with their keys.
+fn scan(name: &str) -> Result<Vec<(String, Map<String, Value>)>, Error> {
+    let start = format!("{}{}", name, SEPARATOR);
+    // The separator is the smallest character, so the next one ends the range.
+    let end = format!("{}\u{1}", name);
+    ROWS.with(|rows| {
+        rows.borrow()
+            .range((Bound::Included(start), Bound::Excluded(end)))
+            .map(|(key, row)| match serde_json::from_str::<Value>(&row)? {
+                Value::Object(row) => Ok((key, row)),
+                _ => bail!("Invalid row {}", key),
+            })
+            .collect()
+    })
+}
+
+// Returns the rows that match the filter, reading a single row for a lookup by
+// primary key.
+fn select(
+    name: &str,
+    table: &Table,
+    filter: Option<&Condition>,
+    resolve: &dyn Fn(&Expr) -> Result<Value, Error>,
+) -> Result<Vec<(String, Map<String, Value>)>, Error> {
+    let rows = match (filter, table.primary_key()) {
+        (Some(Condition::Compare(column, Op::Eq, expr)), Some(primary_key))
+            if *column == primary_key.name =>
+        {
+            let Ok(key) = row_key(name, &resolve(expr)?) else {
+                return Ok(vec![]);
+            };
+            match ROWS.with(|rows| rows.borrow().get(&key)) {
+                Some(row) => match serde_json::from_str::<Value>(&row)? {
+                    Value::Object(row) => vec![(key, row)],
+                    _ => bail!("Invalid row {}", key),
+                },
+                None => vec![],
+            }
+        }
+        _ => scan(name)?,
+    };
+    let Some(filter) = filter else {
+        return Ok(rows);
+    };
+    let mut selected = vec![];
+    for (key, row) in rows {
+        if matches(table, &row, filter, resolve)? {
+            selected.push((key, row));
+        }
+    }
+    Ok(selected)
+}
+
+fn matches(
+    table: &Table,
+    row: &Map<String, Value>,
+    condition: &Condition,
+    resolve: &dyn Fn(&Expr) -> Result<Value, Error>,
+) -> Result<bool, Error> {
+    Ok(match condition {
+        Condition::IsNull(column, negated) => {
+            table.column(column)?;
+            row[column].is_null() != *negated
+        }
+        Condition::Compare(column, op, expr) => {
+            table.column(column)?;
+            let value = resolve(expr)?;
+            // Comparisons with NULL are false like in SQL.
+            match compare(&row[column], &value) {
+                None if matches!(op, Op::Ne) => !row[column].is_null() && !value.is_null(),
+                None => false,
+                Some(ordering) => match op {
+                    Op::Eq => ordering == Ordering::Equal,
+                    Op::Ne => ordering != Ordering::Equal,
+                    Op::Lt => ordering == Ordering::Less,
+                    Op::Le => ordering != Ordering::Greater,
+                    Op::Gt => ordering == Ordering::Greater,
+                    Op::Ge => ordering != Ordering::Less,
+                },
+            }
+        }
+        Condition::And(a, b) => {
+            matches(table, row, a, resolve)? && matches(table, row, b, resolve)?
+        }
+        Condition::Or(a, b) => matches(table, row, a, resolve)? || matches(table, row, b, resolve)?,
+    })
+}
+
+// Compares values of the same type. JSON values compare only for equality.
+fn compare(a: &Value, b: &Value) -> Option<Ordering> {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => a.as_f64()?.partial_cmp(&b.as_f64()?),
+        (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+        (Value::Bool(a), Value::Bool(b)) => Some(a.cmp(b)),
+        (Value::Null, _) | (_, Value::Null) => None,
+        (a, b) if a == b => Some(Ordering::Equal),
+        _ => None,
+    }
+}
+
+// Orders NULL first and values of different types by type.
+fn order(a: &Value, b: &Value) -> Ordering {
+    fn rank(value: &Value) -> u8 {
+        match value {
+            Value::Null => 0,
+            Value::Bool(_) => 1,
+            Value::Number(_) => 2,
+            Value::String(_) => 3,
+            Value::Array(_) => 4,
+            Value::Object(_) => 5,
+        }
+    }
+    compare(a, b).unwrap_or_else(|| rank(a).cmp(&rank(b)))
+}
+
+pub fn link(context: &JSContextRef) -> Result<(), anyhow::Error> {
+    // Usage: `ic.sql.execute("SELECT * FROM orders WHERE owner = ?", [owner])`.
+    // Returns `{rows, changes}`.
+    fn execute<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.is_empty() || args.len() > 2 {
+            return Err(
+                JSError::Type(format!("Expected 1 or 2 arguments, got {}", args.len())).into(),
+            );
+        }
+        let sql: String = args[0].try_into()?;
+        let params = match args.get(1).map(|params| params.to_js_value()).transpose()? {
+            None | Some(JSValue::Undefined | JSValue::Null) => vec![],
+            Some(JSValue::Array(params)) => params.iter().map(json::to_json).collect(),
+            Some(_) => {
+                return Err(
+                    JSError::Type("Expected the parameters to be an array".to_string()).into(),
+                )
+            }
+        };
+        let output = self::execute(&sql, &params)?;
+        let result = json!({"rows": output.rows, "changes": output.changes});
+        quickjs_wasm_rs::to_qjs_value(context, &json::from_json(&result))
+    }
+
+    let sql = context.object_value()?;
+    sql.set_property("execute", context.wrap_callback2(execute)?)?;
+    engine::namespace(context)?.set_property("sql", sql)?;
+    Ok(())
+}
@@ -0,0 +1,532 @@
+// A parser of the SQL subset of `ic.sql.execute()`.
+//
+// Keywords are case-insensitive, identifiers are case-sensitive and may be
+// quoted with double quotes, strings use single quotes with `''` as escape and
+// `?` is a positional parameter. Statements:
+//
+//   CREATE TABLE [IF NOT EXISTS] t (c INTEGER|REAL|TEXT|BOOLEAN|JSON
+//       [PRIMARY KEY] [NOT NULL], ...)
+//   DROP TABLE [IF EXISTS] t
+//   INSERT INTO t [(c, ...)] VALUES (v, ...), ...
+//   SELECT *|c, ...|COUNT(*) FROM t [WHERE cond] [ORDER BY c [ASC|DESC]]
+//       [LIMIT n]
+//   UPDATE t SET c = v, ... [WHERE cond]
+//   DELETE FROM t [WHERE cond]
+//
+// where a condition combines `c = v`, `c != v`, `c <> v`, `c < v`, `c <= v`,
+// `c > v`, `c >= v`, `c IS [NOT] NULL` with AND and OR.
+use anyhow::{anyhow, bail, Error};
+use candid::{CandidType, Deserialize};
+use serde_json::Value;
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum ColumnType {
+    Integer,
+    Real,
+    Text,
+    Boolean,
+    Json,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Column {
+    pub name: String,
+    pub kind: ColumnType,
+    pub primary_key: bool,
+    pub not_null: bool,
+}
+
+#[derive(Clone, Debug)]
+pub enum Expr {
+    Literal(Value),
+    Param(usize),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Clone, Debug)]
+pub enum Condition {
+    Compare(String, Op, Expr),
+    IsNull(String, bool),
+    And(Box<Condition>, Box<Condition>),
+    Or(Box<Condition>, Box<Condition>),
+}
+
+#[derive(Clone, Debug)]
+pub enum Projection {
+    All,
+    Columns(Vec<String>),
+    Count,
+}
+
+#[derive(Clone, Debug)]
+pub enum Statement {
+    CreateTable {
+        name: String,
+        columns: Vec<Column>,
+        if_not_exists: bool,
+    },
+    DropTable {
+        name: String,
+        if_exists: bool,
+    },
+    Insert {
+        table: String,
+        columns: Option<Vec<String>>,
+        rows: Vec<Vec<Expr>>,
+    },
+    Select {
+        table: String,
+        projection: Projection,
+        filter: Option<Condition>,
+        order_by: Option<(String, bool)>,
+        limit: Option<Expr>,
+    },
+    Update {
+        table: String,
+        assignments: Vec<(String, Expr)>,
+        filter: Option<Condition>,
+    },
+    Delete {
+        table: String,
+        filter: Option<Condition>,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Word(String),
+    Quoted(String),
+    Number(String),
+    Text(String),
+    Symbol(&'static str),
+    Param,
+}
+
+const SYMBOLS: &[&str] = &[
+    "<=", ">=", "!=", "<>", "(", ")", ",", ";", "*", "=", "<", ">", "-",
+];
+
+fn tokenize(sql: &str) -> Result<Vec<Token>, Error> {
+    let chars: Vec<char> = sql.chars().collect();
+    let mut tokens = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c.is_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            tokens.push(Token::Word(chars[start..i].iter().collect()));
+        } else if c.is_ascii_digit()
+            || (c == '.' && chars.get(i + 1).is_some_and(|c| c.is_ascii_digit()))
+        {
+            let start = i;
+            while i < chars.len()
+                && (chars[i].is_ascii_digit() || matches!(chars[i], '.' | 'e' | 'E'))
+            {
+                i += 1;
+            }
+            tokens.push(Token::Number(chars[start..i].iter().collect()));
+        } else if c == '\'' || c == '"' {
+            let mut text = String::new();
+            i += 1;
+            loop {
+                match chars.get(i) {
+                    None => bail!("Unterminated quote in the SQL statement"),
+                    Some(&next) if next == c && chars.get(i + 1) == Some(&c) => {
+                        text.push(c);
+                        i += 2;
+                    }
+                    Some(&next) if next == c => {
+                        i += 1;
+                        break;
+                    }
+                    Some(&next) => {
+                        text.push(next);
+                        i += 1;
+                    }
+                }
+            }
+            tokens.push(if c == '"' {
+                Token::Quoted(text)
+            } else {
+                Token::Text(text)
+            });
+        } else if c == '?' {
+            tokens.push(Token::Param);
+            i += 1;
+        } else {
+            let rest: String = chars[i..chars.len().min(i + 2)].iter().collect();
+            let symbol = SYMBOLS
+                .iter()
+                .find(|symbol| rest.starts_with(**symbol))
+                .ok_or_else(|| anyhow!("Unexpected character {:?} in the SQL statement", c))?;
+            tokens.push(Token::Symbol(*symbol));
+            i += symbol.len();
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+    params: usize,
+}
+
+/// Parses a single SQL statement.
+pub fn parse(sql: &str) -> Result<Statement, Error> {
+    let mut parser = Parser {
+        tokens: tokenize(sql)?,
+        position: 0,
+        params: 0,
+    };
+    let statement = parser.statement()?;
+    parser.accept_symbol(";");
+    if let Some(token) = parser.peek() {
+        bail!("Unexpected {:?} after the SQL statement", token);
+    }
+    Ok(statement)
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Result<Token, Error> {
+        let token = self
+            .tokens
+            .get(self.position)
+            .cloned()
+            .ok_or_else(|| anyhow!("Unexpected end of the SQL statement"))?;
+        self.position += 1;
+        Ok(token)
+    }
+
+    fn accept_keyword(&mut self, keyword: &str) -> bool {
+        match self.peek() {
+            Some(Token::Word(word)) if word.eq_ignore_ascii_case(keyword) => {
+                self.position += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<(), Error> {
+        if !self.accept_keyword(keyword) {
+            bail!(
+                "Expected {} in the SQL statement, got {:?}",
+                keyword,
+                self.peek()
+            );
+        }
+        Ok(())
+    }
+
+    fn accept_symbol(&mut self, symbol: &str) -> bool {
+        match self.peek() {
+            Some(Token::Symbol(next)) if *next == symbol => {
+                self.position += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn expect_symbol(&mut self, symbol: &str) -> Result<(), Error> {
+        if !self.accept_symbol(symbol) {
+            bail!(
+                "Expected {} in the SQL statement, got {:?}",
+                symbol,
+                self.peek()
+            );
+        }
+        Ok(())
+    }
+
+    fn identifier(&mut self) -> Result<String, Error> {
+        match self.next()? {
+            Token::Word(name) | Token::Quoted(name) => Ok(name),
+            token => bail!("Expected a name in the SQL statement, got {:?}", token),
+        }
+    }
+
+    // Parses a comma-separated list in parentheses.
+    fn list<T>(
+        &mut self,
+        mut item: impl FnMut(&mut Self) -> Result<T, Error>,
+    ) -> Result<Vec<T>, Error> {
+        self.expect_symbol("(")?;
+        let mut items = vec![item(self)?];
+        while self.accept_symbol(",") {
+            items.push(item(self)?);
+        }
+        self.expect_symbol(")")?;
+        Ok(items)
+    }
+
+    fn statement(&mut self) -> Result<Statement, Error> {
+        let Token::Word(keyword) = self.next()? else {
+            bail!("Expected a SQL statement");
+        };
+        match keyword.to_ascii_uppercase().as_str() {
+            "CREATE" => self.create_table(),
+            "DROP" => {
+                self.expect_keyword("TABLE")?;
+                let if_exists = self.accept_keyword("IF");
+                if if_exists {
+                    self.expect_keyword("EXISTS")?;
+                }
+                Ok(Statement::DropTable {
+                    name: self.identifier()?,
+                    if_exists,
+                })
+            }
+            "INSERT" => self.insert(),
+            "SELECT" => self.select(),
+            "UPDATE" => self.update(),
+            "DELETE" => {
+                self.expect_keyword("FROM")?;
+                let table = self.identifier()?;
+                let filter = self.filter()?;
+                Ok(Statement::Delete { table, filter })
+            }
+            _ => bail!("Unsupported SQL statement {}", keyword),
+        }
+    }
+
+    fn create_table(&mut self) -> Result<Statement, Error> {
+        self.expect_keyword("TABLE")?;
+        let if_not_exists = self.accept_keyword("IF");
+        if if_not_exists {
+            self.expect_keyword("NOT")?;
+            self.expect_keyword("EXISTS")?;
+        }
+        let name = self.identifier()?;
+        let columns = self.list(|parser| {
+            let name = parser.identifier()?;
+            let kind = match parser.identifier()?.to_ascii_uppercase().as_str() {
+                "INTEGER" | "INT" => ColumnType::Integer,
+                "REAL" | "FLOAT" | "DOUBLE" => ColumnType::Real,
+                "TEXT" | "VARCHAR" => ColumnType::Text,
+                "BOOLEAN" | "BOOL" => ColumnType::Boolean,
+                "JSON" => ColumnType::Json,
+                kind => bail!("Unsupported column type {}", kind),
+            };
+            let mut column = Column {
+                name,
+                kind,
+                primary_key: false,
+                not_null: false,
+            };
+            loop {
+                if parser.accept_keyword("PRIMARY") {
+                    parser.expect_keyword("KEY")?;
+                    column.primary_key = true;
+                    column.not_null = true;
+                } else if parser.accept_keyword("NOT") {
+                    parser.expect_keyword("NULL")?;
+                    column.not_null = true;
+                } else {
+                    return Ok(column);
+                }
+            }
+        })?;
+        if columns.iter().filter(|column| column.primary_key).count() > 1 {
+            bail!("A table can have only one PRIMARY KEY column");
+        }
+        if !matches!(
+            columns
+                .iter()
+                .find(|column| column.primary_key)
+                .map(|column| column.kind),
+            None | Some(ColumnType::Integer | ColumnType::Text)
+        ) {
+            bail!("The PRIMARY KEY column must be INTEGER or TEXT");
+        }
+        Ok(Statement::CreateTable {
+            name,
+            columns,
+            if_not_exists,
+        })
+    }
+
+    fn insert(&mut self) -> Result<Statement, Error> {
+        self.expect_keyword("INTO")?;
+        let table = self.identifier()?;
+        let columns = match self.peek() {
+            Some(Token::Symbol("(")) => Some(self.list(Self::identifier)?),
+            _ => None,
+        };
+        self.expect_keyword("VALUES")?;
+        let mut rows = vec![self.list(Self::expr)?];
+        while self.accept_symbol(",") {
+            rows.push(self.list(Self::expr)?);
+        }
+        Ok(Statement::Insert {
+            table,
+            columns,
+            rows,
+        })
+    }
+
+    fn select(&mut self) -> Result<Statement, Error> {
+        let projection = if self.accept_symbol("*") {
+            Projection::All
+        } else if self.accept_keyword("COUNT") {
+            self.expect_symbol("(")?;
+            self.expect_symbol("*")?;
+            self.expect_symbol(")")?;
+            Projection::Count
+        } else {
+            let mut columns = vec![self.identifier()?];
+            while self.accept_symbol(",") {
+                columns.push(self.identifier()?);
+            }
+            Projection::Columns(columns)
+        };
+        self.expect_keyword("FROM")?;
+        let table = self.identifier()?;
+        let filter = self.filter()?;
+        let order_by = if self.accept_keyword("ORDER") {
+            self.expect_keyword("BY")?;
+            let column = self.identifier()?;
+            let descending = self.accept_keyword("DESC");
+            if !descending {
+                self.accept_keyword("ASC");
+            }
+            Some((column, descending))
+        } else {
+            None
+        };
+        let limit = if self.accept_keyword("LIMIT") {
+            Some(self.expr()?)
+        } else {
+            None
+        };
+        Ok(Statement::Select {
+            table,
+            projection,
+            filter,
+            order_by,
+            limit,
+        })
+    }
+
+    fn update(&mut self) -> Result<Statement, Error> {
+        let table = self.identifier()?;
+        self.expect_keyword("SET")?;
+        let mut assignments = vec![];
+        loop {
+            let column = self.identifier()?;
+            self.expect_symbol("=")?;
+            assignments.push((column, self.expr()?));
+            if !self.accept_symbol(",") {
+                break;
+            }
+        }
+        let filter = self.filter()?;
+        Ok(Statement::Update {
+            table,
+            assignments,
+            filter,
+        })
+    }
+
+    fn filter(&mut self) -> Result<Option<Condition>, Error> {
+        if self.accept_keyword("WHERE") {
+            Ok(Some(self.or()?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    // AND binds tighter than OR.
+    fn or(&mut self) -> Result<Condition, Error> {
+        let mut condition = self.and()?;
+        while self.accept_keyword("OR") {
+            condition = Condition::Or(Box::new(condition), Box::new(self.and()?));
+        }
+        Ok(condition)
+    }
+
+    fn and(&mut self) -> Result<Condition, Error> {
+        let mut condition = self.comparison()?;
+        while self.accept_keyword("AND") {
+            condition = Condition::And(Box::new(condition), Box::new(self.comparison()?));
+        }
+        Ok(condition)
+    }
+
+    fn comparison(&mut self) -> Result<Condition, Error> {
+        let column = self.identifier()?;
+        if self.accept_keyword("IS") {
+            let negated = self.accept_keyword("NOT");
+            self.expect_keyword("NULL")?;
+            return Ok(Condition::IsNull(column, negated));
+        }
+        let op = match self.next()? {
+            Token::Symbol("=") => Op::Eq,
+            Token::Symbol("!=" | "<>") => Op::Ne,
+            Token::Symbol("<") => Op::Lt,
+            Token::Symbol("<=") => Op::Le,
+            Token::Symbol(">") => Op::Gt,
+            Token::Symbol(">=") => Op::Ge,
+            token => bail!(
+                "Expected a comparison in the SQL statement, got {:?}",
+                token
+            ),
+        };
+        Ok(Condition::Compare(column, op, self.expr()?))
+    }
+
+    fn expr(&mut self) -> Result<Expr, Error> {
+        let negative = self.accept_symbol("-");
+        let token = self.next()?;
+        let value = match token {
+            Token::Param if !negative => {
+                self.params += 1;
+                return Ok(Expr::Param(self.params - 1));
+            }
+            Token::Number(number) => {
+                let number = if negative {
+                    format!("-{}", number)
+                } else {
+                    number
+                };
+                match number.parse::<i64>() {
+                    Ok(integer) => Value::from(integer),
+                    Err(_) => Value::from(
+                        number
+                            .parse::<f64>()
+                            .map_err(|_| anyhow!("Invalid number {}", number))?,
+                    ),
+                }
+            }
+            Token::Text(text) if !negative => Value::String(text),
+            Token::Word(word) if !negative && word.eq_ignore_ascii_case("NULL") => Value::Null,
+            Token::Word(word) if !negative && word.eq_ignore_ascii_case("TRUE") => {
+                Value::Bool(true)
+            }
+            Token::Word(word) if !negative && word.eq_ignore_ascii_case("FALSE") => {
+                Value::Bool(false)
+            }
+            token => bail!("Expected a value in the SQL statement, got {:?}", token),
+        };
+        Ok(Expr::Literal(value))
+    }
+}
@@ -0,0 +1,88 @@
+use std::{borrow::Cow, cell::RefCell};
+
+use candid::{CandidType, Deserialize};
+use ic_stable_structures::{
+    memory_manager::{MemoryId, MemoryManager, VirtualMemory},
+    storable::Bound,
+    DefaultMemoryImpl, Storable,
+};
+
+/// The virtual stable memory used by a single stable structure.
+pub type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+// The ids of virtual memories. Each stable structure owns one memory.
+// Never change or reuse an id because that would corrupt persisted data.
+pub const JOBS: MemoryId = MemoryId::new(0);
+pub const JOBS_SEQUENCE: MemoryId = MemoryId::new(1);
+pub const DEAD_LETTERS: MemoryId = MemoryId::new(2);
+pub const DEAD_LETTERS_SEQUENCE: MemoryId = MemoryId::new(3);
+pub const OUTBOX: MemoryId = MemoryId::new(4);
+pub const OUTBOX_SEQUENCE: MemoryId = MemoryId::new(5);
+pub const PQUEUE: MemoryId = MemoryId::new(6);
+pub const PQUEUE_SEQUENCE: MemoryId = MemoryId::new(7);
+pub const JS_RING: MemoryId = MemoryId::new(8);
+pub const LOGS: MemoryId = MemoryId::new(9);
+pub const SCHEMA_VERSION: MemoryId = MemoryId::new(10);
+pub const KV: MemoryId = MemoryId::new(11);
+pub const KV_REPLICATION_SOURCE: MemoryId = MemoryId::new(12);
+pub const SHARD_MAP: MemoryId = MemoryId::new(13);
+pub const REPLICAS: MemoryId = MemoryId::new(14);
+pub const CERTIFIED_RESPONSES: MemoryId = MemoryId::new(15);
+pub const SESSIONS: MemoryId = MemoryId::new(16);
+pub const SESSION_SECRET: MemoryId = MemoryId::new(17);
+pub const QUOTAS: MemoryId = MemoryId::new(18);
+pub const AUDIT_INDEX: MemoryId = MemoryId::new(19);
+pub const AUDIT_DATA: MemoryId = MemoryId::new(20);
+pub const POOL: MemoryId = MemoryId::new(21);
+pub const POOL_WASM: MemoryId = MemoryId::new(22);
+pub const FLAGS: MemoryId = MemoryId::new(23);
+pub const CONFIG: MemoryId = MemoryId::new(24);
+pub const ENV: MemoryId = MemoryId::new(25);
+pub const VAULT: MemoryId = MemoryId::new(26);
+pub const SCRIPT_METADATA: MemoryId = MemoryId::new(27);
+pub const CALL_POLICY: MemoryId = MemoryId::new(28);
+pub const KV_VERSIONS: MemoryId = MemoryId::new(29);
+pub const CALL_METRICS: MemoryId = MemoryId::new(30);
+pub const ERROR_DETAIL: MemoryId = MemoryId::new(31);
+pub const JS_STATE: MemoryId = MemoryId::new(32);
+pub const JS_STABLE_MEMORY: MemoryId = MemoryId::new(33);
+pub const KV_INDEX_ENTRIES: MemoryId = MemoryId::new(34);
+pub const KV_INDEX_TERMS: MemoryId = MemoryId::new(35);
+pub const SEARCH_POSTINGS: MemoryId = MemoryId::new(36);
+pub const SEARCH_DOCUMENTS: MemoryId = MemoryId::new(37);
+pub const SEARCH_STATS: MemoryId = MemoryId::new(38);
+pub const SQL_TABLES: MemoryId = MemoryId::new(39);
+pub const SQL_ROWS: MemoryId = MemoryId::new(40);
+pub const JOBS_RUNNING: MemoryId = MemoryId::new(41);
+pub const TOPUP_PENDING: MemoryId = MemoryId::new(42);
+pub const KV_INDEX_BACKFILL: MemoryId = MemoryId::new(43);
+pub const KV_INDEX_STALE: MemoryId = MemoryId::new(44);
+pub const LOG_LEVEL: MemoryId = MemoryId::new(45);
+pub const SESSION_EXPIRIES: MemoryId = MemoryId::new(46);
+
+thread_local! {
+    // Splits the stable memory of the canister into virtual memories.
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+}
+
+/// Returns the virtual memory with the given id.
+pub fn memory(id: MemoryId) -> Memory {
+    MEMORY_MANAGER.with(|manager| manager.borrow().get(id))
+}
+
+/// A wrapper that stores a Candid-serializable value in a stable structure.
+#[derive(Clone, Debug)]
+pub struct Candid<T>(pub T);
+
+impl<T: CandidType + for<'de> Deserialize<'de>> Storable for Candid<T> {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(candid::encode_one(&self.0).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Candid(candid::decode_one(&bytes).unwrap())
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
@@ -0,0 +1,14 @@
+// Per-caller views of the KV store exposed to JS as `ic.storage.forCaller()`.
+//
+// The views live in storage.js on top of `ic.kv` and `ic.auth.identity()`. The
+// keys of a caller are stored under `caller/<principal>/` in the shared KV
+// store, so they are included in replication and encryption of that prefix.
+use quickjs_wasm_rs::JSContextRef;
+
+const STORAGE_FILE: &str = "storage.js";
+const STORAGE_SCRIPT: &str = include_str!("storage.js");
+
+pub fn link(context: &JSContextRef) -> Result<(), anyhow::Error> {
+    context.eval_global(STORAGE_FILE, STORAGE_SCRIPT)?;
+    Ok(())
+}
@@ -0,0 +1,22 @@
+// Usage: `const storage = ic.storage.forCaller(); storage.set("profile", p)`.
+// Returns a view of `ic.kv` whose keys are prefixed with the subject of the
+// caller, i.e. its principal or the subject of its session or JWT, so the data
+// of different users cannot collide. Create the view at the start of the
+// handler: the caller is the sender of the current message.
+ic.storage = {
+	forCaller() {
+		const identity = ic.auth.identity();
+		if (identity.isAnonymous) {
+			throw new Error("Anonymous callers have no storage");
+		}
+		const prefix = `caller/${identity.subject}/`;
+		return Object.freeze({
+			prefix,
+			get: (key) => ic.kv.get(prefix + key),
+			set: (key, value) => ic.kv.set(prefix + key, value),
+			getWithVersion: (key) => ic.kv.getWithVersion(prefix + key),
+			casSet: (key, value, expectedVersion) => ic.kv.casSet(prefix + key, value, expectedVersion),
+			delete: (key) => ic.kv.delete(prefix + key),
+		});
+	},
+};
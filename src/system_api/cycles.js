@@ -0,0 +1,6 @@
+// Passes BigInt amounts of cycles to Rust as decimal strings, because the
+// values of BigInts are not available to Rust callbacks.
+(function () {
+	const accept = ic0.msg_cycles_accept;
+	ic0.msg_cycles_accept = (max) => accept(typeof max === "bigint" ? max.toString() : max);
+})();
@@ -1,4 +1,126 @@
-use quickjs_wasm_rs::{CallbackArg, JSContextRef, JSValueRef};
+use std::{cell::RefCell, str::FromStr};
+
+use anyhow::anyhow;
+use ic_stable_structures::{Memory, StableCell};
+use quickjs_wasm_rs::{CallbackArg, JSContextRef, JSError, JSValue, JSValueRef};
+use serde_json::{Map, Value};
+
+use crate::{crypto, engine, jobs, json, ring::Ring, stable};
+
+// The number of recent log entries kept in stable memory.
+const LOG_CAPACITY: u64 = 1_000;
+
+const WASM_PAGE_SIZE: u64 = 65536;
+
+// Larger integers lose precision as JS numbers and are returned as BigInts.
+const MAX_SAFE_INTEGER: u128 = (1 << 53) - 1;
+
+// Converts BigInt arguments of `ic0` functions into decimal strings.
+const CYCLES_FILE: &str = "cycles.js";
+const CYCLES_SCRIPT: &str = include_str!("cycles.js");
+
+/// The severity of a log message.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub enum Level {
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl Level {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Level::Debug => "debug",
+            Level::Info => "info",
+            Level::Warn => "warn",
+            Level::Error => "error",
+        }
+    }
+}
+
+impl FromStr for Level {
+    type Err = anyhow::Error;
+
+    fn from_str(level: &str) -> Result<Self, Self::Err> {
+        match level {
+            "debug" => Ok(Level::Debug),
+            "info" => Ok(Level::Info),
+            "warn" => Ok(Level::Warn),
+            "error" => Ok(Level::Error),
+            _ => Err(anyhow!("Unknown log level: {}", level)),
+        }
+    }
+}
+
+thread_local! {
+    // Messages with a lower severity than this level are not printed. It is
+    // stored as the index of the level, so that it survives upgrades.
+    static LOG_LEVEL: RefCell<StableCell<u64, stable::Memory>> = RefCell::new(
+        StableCell::init(stable::memory(stable::LOG_LEVEL), Level::Debug as u64).unwrap(),
+    );
+
+    // The most recent printed log entries.
+    static LOGS: RefCell<Ring> = RefCell::new(Ring::init(stable::memory(stable::LOGS), LOG_CAPACITY));
+
+    // The stable memory of JS exposed as `stableMemory`. It is a virtual
+    // memory of its own, so JS cannot overwrite the stable structures.
+    static JS_STABLE_MEMORY: stable::Memory = stable::memory(stable::JS_STABLE_MEMORY);
+}
+
+/// Changes the minimum severity of printed log messages.
+pub fn set_log_level(level: Level) {
+    LOG_LEVEL.with(|log_level| log_level.borrow_mut().set(level as u64).unwrap());
+}
+
+/// Prints the given values as a single-line JSON log entry if the given level
+/// is not filtered out by the current log level.
+pub fn log(level: Level, label: Option<&str>, values: &[Value]) {
+    if (level as u64) < LOG_LEVEL.with(|log_level| *log_level.borrow().get()) {
+        return;
+    }
+    let message: Vec<String> = values
+        .iter()
+        .map(|value| match value {
+            Value::String(value) => value.clone(),
+            value => value.to_string(),
+        })
+        .collect();
+
+    let mut entry = Map::new();
+    entry.insert("timestamp".into(), ic_cdk::api::time().into());
+    entry.insert("level".into(), level.as_str().into());
+    if let Some(label) = label {
+        entry.insert("label".into(), label.into());
+    }
+    entry.insert("message".into(), message.join(" ").into());
+    let entry = Value::Object(entry).to_string();
+    ic_cdk::println!("{}", entry);
+    LOGS.with(|logs| logs.borrow_mut().push(entry));
+}
+
+/// Returns at most `n` most recent log entries from the oldest to the newest.
+pub fn recent_logs(n: u64) -> Vec<String> {
+    LOGS.with(|logs| logs.borrow().last(n))
+        .into_iter()
+        .map(|(_, entry)| entry)
+        .collect()
+}
+
+/// Returns all log entries from the oldest to the newest.
+pub fn export_logs() -> Vec<String> {
+    recent_logs(LOG_CAPACITY)
+}
+
+/// Appends the exported log entries of a backup.
+pub fn import_logs(entries: Vec<String>) {
+    LOGS.with(|logs| {
+        let mut logs = logs.borrow_mut();
+        for entry in entries {
+            logs.push(entry);
+        }
+    });
+}
 
 pub fn link(context: &JSContextRef) -> Result<(), anyhow::Error> {
     fn debug_print<'a>(
@@ -6,10 +128,46 @@ pub fn link(context: &JSContextRef) -> Result<(), anyhow::Error> {
         _this: &CallbackArg,
         args: &[CallbackArg],
     ) -> Result<JSValueRef<'a>, anyhow::Error> {
-        for arg in args {
-            let value = arg.to_js_value()?;
-            ic_cdk::println!("{:?} ", value);
+        let values = to_json_values(args)?;
+        log(Level::Debug, None, &values);
+        context.undefined_value()
+    }
+
+    // Usage: `ic0.log(level, label, ...values)` where the label may be null.
+    fn log_with_label<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() < 2 {
+            return Err(JSError::Type(format!(
+                "Expected at least 2 arguments, got {}",
+                args.len()
+            ))
+            .into());
         }
+        let level: String = args[0].try_into()?;
+        let level: Level = level.parse()?;
+        let label = match args[1].to_js_value()? {
+            JSValue::Undefined | JSValue::Null => None,
+            JSValue::String(label) => Some(label),
+            _ => return Err(JSError::Type("Expected the label to be a string".to_string()).into()),
+        };
+        let values = to_json_values(&args[2..])?;
+        log(level, label.as_deref(), &values);
+        context.undefined_value()
+    }
+
+    fn set_log_level<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 1 {
+            return Err(JSError::Type(format!("Expected 1 argument, got {}", args.len())).into());
+        }
+        let level: String = args[0].try_into()?;
+        self::set_log_level(level.parse()?);
         context.undefined_value()
     }
 
@@ -22,11 +180,203 @@ pub fn link(context: &JSContextRef) -> Result<(), anyhow::Error> {
         context.value_from_str(&canister_id)
     }
 
+    // Returns the number of instructions executed in the current message.
+    fn performance_counter<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        _args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        context.value_from_f64(ic_cdk::api::performance_counter(0) as f64)
+    }
+
+    // Returns "running", "stopping" or "stopped". Long-running jobs can check
+    // for "stopping" to wind down before the canister stops.
+    fn canister_status<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        _args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        let status = match unsafe { ic0::canister_status() } {
+            1 => "running",
+            2 => "stopping",
+            3 => "stopped",
+            status => return Err(anyhow!("Unknown canister status: {}", status)),
+        };
+        context.value_from_str(status)
+    }
+
+    // Returns the cycles attached to the current message that are not
+    // accepted yet.
+    fn msg_cycles_available<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        _args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        cycles_value(context, ic_cdk::api::call::msg_cycles_available128())
+    }
+
+    // Usage: `ic0.msg_cycles_accept(max)` where the maximum is a number,
+    // BigInt or decimal string. Returns the accepted cycles.
+    fn msg_cycles_accept<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 1 {
+            return Err(JSError::Type(format!("Expected 1 argument, got {}", args.len())).into());
+        }
+        engine::ensure_writable("ic0.msg_cycles_accept()")?;
+        let max = cycles(&args[0].to_js_value()?, "max")?;
+        cycles_value(context, ic_cdk::api::call::msg_cycles_accept128(max))
+    }
+
+    fn canister_cycle_balance<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        _args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        cycles_value(context, ic_cdk::api::canister_balance128())
+    }
+
+    // Returns the size of the stable memory of JS in 64KiB pages.
+    fn stable_size<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        _args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        context.value_from_f64(JS_STABLE_MEMORY.with(|memory| memory.size()) as f64)
+    }
+
+    // Usage: `stableMemory.grow(pages)`. Returns the previous size in pages or
+    // -1 if the memory cannot grow.
+    fn stable_grow<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 1 {
+            return Err(JSError::Type(format!("Expected 1 argument, got {}", args.len())).into());
+        }
+        engine::ensure_writable("stableMemory.grow()")?;
+        let pages = jobs::number(&args[0].to_js_value()?, "pages")? as u64;
+        context.value_from_f64(JS_STABLE_MEMORY.with(|memory| memory.grow(pages)) as f64)
+    }
+
+    // Usage: `stableMemory.read(offset, length)`. Returns an ArrayBuffer.
+    fn stable_read<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 2 {
+            return Err(JSError::Type(format!("Expected 2 arguments, got {}", args.len())).into());
+        }
+        let offset = jobs::number(&args[0].to_js_value()?, "offset")? as u64;
+        let length = jobs::number(&args[1].to_js_value()?, "length")? as u64;
+        check_stable_range(offset, length)?;
+        let mut bytes = vec![0; length as usize];
+        JS_STABLE_MEMORY.with(|memory| memory.read(offset, &mut bytes));
+        context.array_buffer_value(&bytes)
+    }
+
+    // Usage: `stableMemory.write(offset, bytes)` where the bytes are an
+    // ArrayBuffer or a string.
+    fn stable_write<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 2 {
+            return Err(JSError::Type(format!("Expected 2 arguments, got {}", args.len())).into());
+        }
+        engine::ensure_writable("stableMemory.write()")?;
+        let offset = jobs::number(&args[0].to_js_value()?, "offset")? as u64;
+        let bytes = crypto::bytes(&args[1])?;
+        check_stable_range(offset, bytes.len() as u64)?;
+        JS_STABLE_MEMORY.with(|memory| memory.write(offset, &bytes));
+        context.undefined_value()
+    }
+
+    let stable_memory = context.object_value()?;
+    stable_memory.set_property("size", context.wrap_callback2(stable_size)?)?;
+    stable_memory.set_property("grow", context.wrap_callback2(stable_grow)?)?;
+    stable_memory.set_property("read", context.wrap_callback2(stable_read)?)?;
+    stable_memory.set_property("write", context.wrap_callback2(stable_write)?)?;
+
     let ic0 = context.object_value()?;
     ic0.set_property("debug_print", context.wrap_callback2(debug_print)?)?;
+    ic0.set_property("log", context.wrap_callback2(log_with_label)?)?;
+    ic0.set_property("set_log_level", context.wrap_callback2(set_log_level)?)?;
     ic0.set_property("canister_self", context.wrap_callback2(canister_self)?)?;
+    ic0.set_property(
+        "performance_counter",
+        context.wrap_callback2(performance_counter)?,
+    )?;
+    ic0.set_property("canister_status", context.wrap_callback2(canister_status)?)?;
+    ic0.set_property(
+        "msg_cycles_available",
+        context.wrap_callback2(msg_cycles_available)?,
+    )?;
+    ic0.set_property(
+        "msg_cycles_accept",
+        context.wrap_callback2(msg_cycles_accept)?,
+    )?;
+    ic0.set_property(
+        "canister_cycle_balance",
+        context.wrap_callback2(canister_cycle_balance)?,
+    )?;
 
     let global = context.global_object()?;
     global.set_property("ic0", ic0)?;
+    global.set_property("stableMemory", stable_memory)?;
+    context.eval_global(CYCLES_FILE, CYCLES_SCRIPT)?;
     Ok(())
 }
+
+/// Parses an amount of cycles given as a number or a decimal string. BigInts
+/// reach Rust as decimal strings, see cycles.js.
+pub fn cycles(value: &JSValue, name: &str) -> Result<u128, anyhow::Error> {
+    let invalid = || JSError::Type(format!("Expected {} to be a non-negative integer", name));
+    match value {
+        JSValue::Int(value) => u128::try_from(*value).map_err(|_| anyhow::Error::from(invalid())),
+        JSValue::Float(value) if *value >= 0.0 && value.fract() == 0.0 && value.is_finite() => {
+            Ok(*value as u128)
+        }
+        JSValue::String(value) => value.parse().map_err(|_| anyhow::Error::from(invalid())),
+        _ => Err(invalid().into()),
+    }
+}
+
+/// Returns an amount of cycles as a number, or as a BigInt if it is larger
+/// than `Number.MAX_SAFE_INTEGER`.
+pub fn cycles_value<'a>(
+    context: &'a JSContextRef,
+    cycles: u128,
+) -> Result<JSValueRef<'a>, anyhow::Error> {
+    if cycles <= MAX_SAFE_INTEGER {
+        return context.value_from_f64(cycles as f64);
+    }
+    let global = context.global_object()?;
+    let bigint = global.get_property("BigInt")?;
+    bigint.call(&global, &[context.value_from_str(&cycles.to_string())?])
+}
+
+// Fails if the range is outside of the stable memory of JS.
+fn check_stable_range(offset: u64, length: u64) -> Result<(), anyhow::Error> {
+    let size = JS_STABLE_MEMORY.with(|memory| memory.size()) * WASM_PAGE_SIZE;
+    if offset.checked_add(length).map_or(true, |end| end > size) {
+        return Err(JSError::Range(format!(
+            "{} bytes at offset {} are outside of the stable memory of {} bytes",
+            length, offset, size
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+// An internal helper that converts the JS arguments into JSON values.
+fn to_json_values(args: &[CallbackArg]) -> Result<Vec<Value>, anyhow::Error> {
+    args.iter()
+        .map(|arg| Ok(json::to_json(&arg.to_js_value()?)))
+        .collect()
+}
@@ -0,0 +1,82 @@
+// Runs the tests that the script defines with `describe()`, `it()` and
+// `expect()` from testing.js.
+//
+// Tests run in an update call one after another, so they can make real
+// outgoing calls, e.g. on a local replica, and see the effects of the previous
+// tests. A test passes if its function returns or its promise resolves.
+use candid::{CandidType, Deserialize};
+use ic_cdk::api::call::ManualReply;
+use quickjs_wasm_rs::JSContextRef;
+use serde_json::Value;
+
+use crate::{engine, json};
+
+const FRAMEWORK_FILE: &str = "testing.js";
+const FRAMEWORK_SCRIPT: &str = include_str!("testing.js");
+
+const RUN: &str = "__runTests__";
+
+/// The outcome of a single test.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TestResult {
+    /// The names of the enclosing `describe()` blocks and the test joined
+    /// with " > ".
+    pub name: String,
+    pub passed: bool,
+    pub error: Option<String>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TestReport {
+    pub passed: u64,
+    pub failed: u64,
+    pub results: Vec<TestResult>,
+}
+
+/// Runs the tests whose names contain the filter or all tests.
+pub fn run(filter: Option<String>) -> ManualReply<TestReport> {
+    engine::execute(
+        RUN,
+        move |context| {
+            let filter = match filter {
+                Some(filter) => context.value_from_str(&filter)?,
+                None => context.null_value()?,
+            };
+            Ok(vec![filter])
+        },
+        |_context, result| {
+            let report = result.and_then(|value| {
+                to_report(json::to_json(&quickjs_wasm_rs::from_qjs_value(&value)?))
+            });
+            match report {
+                Ok(report) => ManualReply::one(report),
+                Err(err) => ManualReply::reject(err.to_string()),
+            }
+        },
+    )
+}
+
+fn to_report(value: Value) -> Result<TestReport, anyhow::Error> {
+    let Value::Array(results) = value else {
+        anyhow::bail!("Unexpected result of the test runner");
+    };
+    let results: Vec<TestResult> = results
+        .iter()
+        .map(|result| TestResult {
+            name: result["name"].as_str().unwrap_or_default().to_string(),
+            passed: result["passed"].as_bool().unwrap_or_default(),
+            error: result["error"].as_str().map(|error| error.to_string()),
+        })
+        .collect();
+    let passed = results.iter().filter(|result| result.passed).count() as u64;
+    Ok(TestReport {
+        passed,
+        failed: results.len() as u64 - passed,
+        results,
+    })
+}
+
+pub fn link(context: &JSContextRef) -> Result<(), anyhow::Error> {
+    context.eval_global(FRAMEWORK_FILE, FRAMEWORK_SCRIPT)?;
+    Ok(())
+}
@@ -0,0 +1,112 @@
+// A tiny test framework with `describe(name, fn)`, `it(name, fn)` and
+// `expect(value)`. Tests are registered when the script loads and run one
+// after another by `__runTests__(filter)`, which returns one
+// `{name, passed, error}` object per test.
+(function () {
+	const tests = [];
+	const prefix = [];
+
+	function define(name, value) {
+		if (globalThis[name] === undefined) {
+			Object.defineProperty(globalThis, name, { enumerable: false, writable: true, value });
+		}
+	}
+
+	function describe(name, fn) {
+		prefix.push(name);
+		try {
+			fn();
+		} finally {
+			prefix.pop();
+		}
+	}
+
+	function it(name, fn) {
+		tests.push({ name: [...prefix, name].join(" > "), fn });
+	}
+
+	function show(value) {
+		try {
+			return JSON.stringify(value) ?? String(value);
+		} catch (e) {
+			return String(value);
+		}
+	}
+
+	function isEqual(a, b) {
+		if (Object.is(a, b)) {
+			return true;
+		}
+		if (typeof a !== "object" || typeof b !== "object" || a === null || b === null) {
+			return false;
+		}
+		if (a instanceof ArrayBuffer && b instanceof ArrayBuffer) {
+			return isEqual(Array.from(new Uint8Array(a)), Array.from(new Uint8Array(b)));
+		}
+		if (Array.isArray(a) !== Array.isArray(b)) {
+			return false;
+		}
+		const keys = Object.keys(a);
+		if (keys.length !== Object.keys(b).length) {
+			return false;
+		}
+		return keys.every((key) => Object.prototype.hasOwnProperty.call(b, key) && isEqual(a[key], b[key]));
+	}
+
+	function matchers(actual, negated) {
+		function check(passed, description) {
+			if (passed === negated) {
+				throw new Error(`Expected ${show(actual)} ${negated ? "not " : ""}${description}`);
+			}
+		}
+		return {
+			toBe: (expected) => check(Object.is(actual, expected), `to be ${show(expected)}`),
+			toEqual: (expected) => check(isEqual(actual, expected), `to equal ${show(expected)}`),
+			toBeTruthy: () => check(!!actual, "to be truthy"),
+			toBeFalsy: () => check(!actual, "to be falsy"),
+			toBeGreaterThan: (expected) => check(actual > expected, `to be greater than ${show(expected)}`),
+			toBeLessThan: (expected) => check(actual < expected, `to be less than ${show(expected)}`),
+			toContain: (expected) => check(actual.includes(expected), `to contain ${show(expected)}`),
+			toThrow: (message) => {
+				let error = null;
+				try {
+					actual();
+				} catch (e) {
+					error = e;
+				}
+				const passed = error !== null && (message === undefined || String(error.message).includes(message));
+				const description = message === undefined ? "to throw" : `to throw ${show(message)}`;
+				if (passed === negated) {
+					throw new Error(`Expected the function ${negated ? "not " : ""}${description}`);
+				}
+			},
+		};
+	}
+
+	function expect(actual) {
+		return { ...matchers(actual, false), not: matchers(actual, true) };
+	}
+
+	define("describe", describe);
+	define("it", it);
+	define("expect", expect);
+
+	Object.defineProperty(globalThis, "__runTests__", {
+		enumerable: false,
+		value: async function (filter) {
+			const results = [];
+			for (const test of tests) {
+				if (filter !== null && !test.name.includes(filter)) {
+					continue;
+				}
+				try {
+					await test.fn();
+					results.push({ name: test.name, passed: true, error: null });
+				} catch (e) {
+					results.push({ name: test.name, passed: false, error: String(e && e.message || e) });
+				}
+			}
+			return results;
+		},
+	});
+})();
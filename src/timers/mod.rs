@@ -0,0 +1,107 @@
+// `setTimeout`, `setInterval`, `clearTimeout` and `clearInterval` backed by
+// the timers of the canister.
+//
+// A firing timer runs its callback with `engine::spawn()` in a message of its
+// own, so the callback may await calls. Errors are logged. Timers live on the
+// heap and do not survive upgrades; use `ic.jobs` for work that must happen
+// eventually.
+use std::{cell::RefCell, collections::HashMap, time::Duration};
+
+use ic_cdk_timers::TimerId;
+use quickjs_wasm_rs::{CallbackArg, JSContextRef, JSError, JSValue, JSValueRef};
+
+use crate::{
+    engine, jobs,
+    system_api::{self, Level},
+};
+
+// The global functions of timers.js.
+const TIMERS_FILE: &str = "timers.js";
+const TIMERS_SCRIPT: &str = include_str!("timers.js");
+const FIRE_TIMER: &str = "__fireTimer__";
+
+thread_local! {
+    static TIMERS: RefCell<HashMap<u32, TimerId>> = RefCell::new(HashMap::new());
+    static NEXT_ID: RefCell<u32> = RefCell::new(1);
+}
+
+fn set(delay: Duration, repeat: bool) -> u32 {
+    let id = NEXT_ID.with(|next| {
+        let mut next = next.borrow_mut();
+        let id = *next;
+        *next = next.wrapping_add(1).max(1);
+        id
+    });
+    let timer = if repeat {
+        ic_cdk_timers::set_timer_interval(delay, move || fire(id))
+    } else {
+        ic_cdk_timers::set_timer(delay, move || {
+            TIMERS.with(|timers| timers.borrow_mut().remove(&id));
+            fire(id);
+        })
+    };
+    TIMERS.with(|timers| timers.borrow_mut().insert(id, timer));
+    id
+}
+
+fn clear(id: u32) {
+    if let Some(timer) = TIMERS.with(|timers| timers.borrow_mut().remove(&id)) {
+        ic_cdk_timers::clear_timer(timer);
+    }
+}
+
+fn fire(id: u32) {
+    engine::spawn(
+        FIRE_TIMER,
+        move |context| Ok(vec![context.value_from_f64(id as f64)?]),
+        move |_context, result| {
+            if let Err(err) = result {
+                system_api::log(
+                    Level::Error,
+                    Some("timers"),
+                    &[format!("Timer {} failed: {}", id, err).into()],
+                );
+            }
+        },
+    );
+}
+
+pub fn link(context: &JSContextRef) -> Result<(), anyhow::Error> {
+    // Usage: `ic.timers.set(delayMs, repeat)`. Returns the timer id. Use the
+    // global functions instead, which keep the callback.
+    fn set<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 2 {
+            return Err(JSError::Type(format!("Expected 2 arguments, got {}", args.len())).into());
+        }
+        engine::ensure_writable("Setting a timer")?;
+        let delay = jobs::number(&args[0].to_js_value()?, "delay")?;
+        let repeat = matches!(args[1].to_js_value()?, JSValue::Bool(true));
+        let id = self::set(Duration::from_millis(delay as u64), repeat);
+        context.value_from_f64(id as f64)
+    }
+
+    fn clear<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 1 {
+            return Err(JSError::Type(format!("Expected 1 argument, got {}", args.len())).into());
+        }
+        let id = jobs::number(&args[0].to_js_value()?, "id")?;
+        self::clear(id as u32);
+        context.undefined_value()
+    }
+
+    let timers = context.object_value()?;
+    timers.set_property("set", context.wrap_callback2(set)?)?;
+    timers.set_property("clear", context.wrap_callback2(clear)?)?;
+    engine::namespace(context)?.set_property("timers", timers)?;
+
+    context.eval_global(TIMERS_FILE, TIMERS_SCRIPT)?;
+    Ok(())
+}
@@ -0,0 +1,43 @@
+// The callbacks of the timers of timers/mod.rs by timer id.
+Object.defineProperty(globalThis, "__timers__", {
+	enumerable: false,
+	value: new Map(),
+});
+
+// Called by Rust when a timer fires. One-shot timers are forgotten first, so
+// the callback may set a new timer.
+Object.defineProperty(globalThis, "__fireTimer__", {
+	enumerable: false,
+	value: function (id) {
+		const timer = __timers__.get(id);
+		if (!timer) {
+			return null;
+		}
+		if (!timer.repeat) {
+			__timers__.delete(id);
+		}
+		return timer.callback(...timer.args);
+	},
+});
+
+(function () {
+	function set(callback, delay, args, repeat) {
+		if (typeof callback !== "function") {
+			throw new TypeError("Expected the callback to be a function");
+		}
+		const id = ic.timers.set(Math.max(0, Number(delay) || 0), repeat);
+		__timers__.set(id, { callback, args, repeat });
+		return id;
+	}
+
+	function clear(id) {
+		if (__timers__.delete(id)) {
+			ic.timers.clear(id);
+		}
+	}
+
+	globalThis.setTimeout = (callback, delay, ...args) => set(callback, delay, args, false);
+	globalThis.setInterval = (callback, delay, ...args) => set(callback, delay, args, true);
+	globalThis.clearTimeout = clear;
+	globalThis.clearInterval = clear;
+})();
@@ -0,0 +1,310 @@
+// Automatic cycle top-ups configured by JS with `ic.topUp.configure(config)`.
+//
+// A timer checks the cycle balance of the canister periodically. When the
+// balance drops below the threshold, the canister tops itself up from the
+// configured source:
+// - `{threshold, source: "icp", amountE8s}` converts ICP held by the canister
+//   on the ICP ledger into cycles with the cycles minting canister,
+// - `{threshold, source: "wallet", wallet, cycles}` asks a cycles wallet that
+//   has this canister as a controller to send cycles.
+// The optional `intervalSeconds` sets the period of the check. Every top-up
+// and every failure is logged.
+//
+// The block index of an ICP transfer is kept in stable memory until the CMC
+// has processed it, so a failed `notify_top_up` is retried at the next check
+// instead of transferring ICP again.
+use std::{cell::RefCell, time::Duration};
+
+use candid::{CandidType, Deserialize, Nat, Principal};
+use ic_cdk_timers::TimerId;
+use ic_stable_structures::StableCell;
+use quickjs_wasm_rs::{CallbackArg, JSContextRef, JSError, JSValue, JSValueRef};
+
+use crate::{
+    engine,
+    icrc::{icrc1, Account},
+    jobs,
+    stable::{self, Candid, Memory},
+    system_api::{self, Level},
+};
+
+/// The ICP ledger canister.
+pub const ICP_LEDGER_CANISTER_ID: &str = "ryjl3-tyaaa-aaaaa-aaaba-cai";
+
+/// The cycles minting canister.
+pub const CMC_CANISTER_ID: &str = "rkp4c-7iaaa-aaaaa-aaaca-cai";
+
+// The memo of ICP transfers to the CMC that top up a canister ("TPUP").
+const MEMO_TOP_UP_CANISTER: u64 = 0x50555054;
+
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Clone, Debug)]
+pub enum Source {
+    Icp { amount_e8s: u64 },
+    Wallet { wallet: Principal, cycles: u128 },
+}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub threshold: u128,
+    pub source: Source,
+    pub interval: Duration,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct NotifyTopUpArg {
+    block_index: u64,
+    canister_id: Principal,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+enum NotifyError {
+    Refunded {
+        reason: String,
+        block_index: Option<u64>,
+    },
+    Processing,
+    TransactionTooOld(u64),
+    InvalidTransaction(String),
+    Other {
+        error_code: u64,
+        error_message: String,
+    },
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+enum NotifyTopUpResult {
+    Ok(Nat),
+    Err(NotifyError),
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct WalletSend128Arg {
+    canister: Principal,
+    amount: Nat,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+enum WalletResult {
+    Ok,
+    Err(String),
+}
+
+thread_local! {
+    static CONFIG: RefCell<Option<(Config, TimerId)>> = RefCell::new(None);
+
+    static IN_PROGRESS: RefCell<bool> = RefCell::new(false);
+
+    // The block index of the ICP transfer that the CMC has not processed yet.
+    static PENDING_BLOCK: RefCell<StableCell<Candid<Option<u64>>, Memory>> = RefCell::new(
+        StableCell::init(stable::memory(stable::TOPUP_PENDING), Candid(None)).unwrap(),
+    );
+}
+
+pub fn configure(config: Config) {
+    CONFIG.with(|cell| {
+        let mut cell = cell.borrow_mut();
+        if let Some((_, timer)) = cell.take() {
+            ic_cdk_timers::clear_timer(timer);
+        }
+        let timer = ic_cdk_timers::set_timer_interval(config.interval, || ic_cdk::spawn(check()));
+        *cell = Some((config, timer));
+    });
+}
+
+// Tops up the canister if its balance is below the threshold.
+async fn check() {
+    let Some(config) = CONFIG.with(|cell| cell.borrow().as_ref().map(|(config, _)| config.clone()))
+    else {
+        return;
+    };
+    let balance = ic_cdk::api::canister_balance128();
+    if balance >= config.threshold || IN_PROGRESS.with(|flag| flag.replace(true)) {
+        return;
+    }
+    let result = top_up(&config.source).await;
+    IN_PROGRESS.with(|flag| *flag.borrow_mut() = false);
+    match result {
+        Ok(message) => system_api::log(
+            Level::Info,
+            Some("topup"),
+            &[format!("Topped up at a balance of {} cycles: {}", balance, message).into()],
+        ),
+        Err(err) => system_api::log(
+            Level::Error,
+            Some("topup"),
+            &[format!(
+                "Failed to top up at a balance of {} cycles: {}",
+                balance, err
+            )
+            .into()],
+        ),
+    }
+}
+
+async fn top_up(source: &Source) -> Result<String, String> {
+    match source {
+        Source::Icp { amount_e8s } => {
+            if let Some(block_index) = pending_block() {
+                return notify_top_up(block_index).await;
+            }
+            let cmc = Principal::from_text(CMC_CANISTER_ID).unwrap();
+            let arg = icrc1::TransferArg {
+                from_subaccount: None,
+                to: Account {
+                    owner: cmc,
+                    subaccount: Some(subaccount(&ic_cdk::id())),
+                },
+                amount: Nat::from(*amount_e8s),
+                fee: None,
+                memo: Some(MEMO_TOP_UP_CANISTER.to_le_bytes().to_vec()),
+                created_at_time: Some(ic_cdk::api::time()),
+            };
+            let ledger = Principal::from_text(ICP_LEDGER_CANISTER_ID).unwrap();
+            let block_index = icrc1::transfer(ledger, arg).await?;
+            let block_index = u64::try_from(block_index.0).map_err(|err| err.to_string())?;
+            set_pending_block(Some(block_index));
+            notify_top_up(block_index).await
+        }
+        Source::Wallet { wallet, cycles } => {
+            let arg = WalletSend128Arg {
+                canister: ic_cdk::id(),
+                amount: Nat::from(*cycles),
+            };
+            let (result,): (WalletResult,) = ic_cdk::call(*wallet, "wallet_send128", (arg,))
+                .await
+                .map_err(|(code, message)| {
+                    format!("wallet_send128 failed with code {:?}: {}", code, message)
+                })?;
+            match result {
+                WalletResult::Ok => {
+                    Ok(format!("received {} cycles from wallet {}", cycles, wallet))
+                }
+                WalletResult::Err(err) => Err(format!("wallet_send128 failed: {}", err)),
+            }
+        }
+    }
+}
+
+fn pending_block() -> Option<u64> {
+    PENDING_BLOCK.with(|cell| cell.borrow().get().0)
+}
+
+fn set_pending_block(block_index: Option<u64>) {
+    PENDING_BLOCK.with(|cell| cell.borrow_mut().set(Candid(block_index)).unwrap());
+}
+
+// Asks the CMC to convert the ICP transferred in the given block into cycles.
+// The block stays pending if the CMC may still process it.
+async fn notify_top_up(block_index: u64) -> Result<String, String> {
+    let cmc = Principal::from_text(CMC_CANISTER_ID).unwrap();
+    let arg = NotifyTopUpArg {
+        block_index,
+        canister_id: ic_cdk::id(),
+    };
+    let (result,): (NotifyTopUpResult,) = ic_cdk::call(cmc, "notify_top_up", (arg,))
+        .await
+        .map_err(|(code, message)| {
+            format!(
+                "notify_top_up of block {} failed with code {:?}: {}, retrying at the next check",
+                block_index, code, message
+            )
+        })?;
+    match result {
+        NotifyTopUpResult::Ok(cycles) => {
+            set_pending_block(None);
+            Ok(format!(
+                "converted the ICP in block {} into {} cycles",
+                block_index, cycles
+            ))
+        }
+        NotifyTopUpResult::Err(err @ (NotifyError::Processing | NotifyError::Other { .. })) => {
+            Err(format!(
+                "notify_top_up of block {} failed: {:?}, retrying at the next check",
+                block_index, err
+            ))
+        }
+        NotifyTopUpResult::Err(err) => {
+            set_pending_block(None);
+            Err(format!(
+                "notify_top_up of block {} failed: {:?}",
+                block_index, err
+            ))
+        }
+    }
+}
+
+// The subaccount of the CMC that receives ICP for topping up the canister.
+fn subaccount(canister: &Principal) -> Vec<u8> {
+    let bytes = canister.as_slice();
+    let mut subaccount = vec![0; 32];
+    subaccount[0] = bytes.len() as u8;
+    subaccount[1..1 + bytes.len()].copy_from_slice(bytes);
+    subaccount
+}
+
+impl Config {
+    fn from_js(value: &JSValue) -> Result<Self, anyhow::Error> {
+        let JSValue::Object(fields) = value else {
+            return Err(
+                JSError::Type("Expected the configuration to be an object".to_string()).into(),
+            );
+        };
+        let number = |name: &str| match fields.get(name) {
+            Some(value) => jobs::number(value, name),
+            None => Err(JSError::Type(format!("Expected the {} field", name)).into()),
+        };
+        let source = match fields.get("source") {
+            Some(JSValue::String(source)) if source == "icp" => Source::Icp {
+                amount_e8s: number("amountE8s")? as u64,
+            },
+            Some(JSValue::String(source)) if source == "wallet" => {
+                let wallet = match fields.get("wallet") {
+                    Some(JSValue::String(wallet)) => Principal::from_text(wallet)?,
+                    _ => {
+                        return Err(
+                            JSError::Type("Expected wallet to be a principal".to_string()).into(),
+                        )
+                    }
+                };
+                Source::Wallet {
+                    wallet,
+                    cycles: number("cycles")? as u128,
+                }
+            }
+            _ => {
+                return Err(JSError::Type("Expected source to be icp or wallet".to_string()).into())
+            }
+        };
+        let interval = match fields.get("intervalSeconds") {
+            Some(value) => Duration::from_secs_f64(jobs::number(value, "intervalSeconds")?),
+            None => DEFAULT_INTERVAL,
+        };
+        Ok(Config {
+            threshold: number("threshold")? as u128,
+            source,
+            interval,
+        })
+    }
+}
+
+pub fn link(context: &JSContextRef) -> Result<(), anyhow::Error> {
+    fn configure<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 1 {
+            return Err(JSError::Type(format!("Expected 1 argument, got {}", args.len())).into());
+        }
+        self::configure(Config::from_js(&args[0].to_js_value()?)?);
+        context.undefined_value()
+    }
+
+    let top_up = context.object_value()?;
+    top_up.set_property("configure", context.wrap_callback2(configure)?)?;
+
+    engine::namespace(context)?.set_property("topUp", top_up)?;
+    Ok(())
+}
@@ -0,0 +1,221 @@
+// An end-to-end encrypted secrets vault exposed to JS as `ic.vault`.
+//
+// Secrets are encrypted by the client, so only ciphertexts reach the canister
+// and its stable memory. The keys come from vetKD: each caller has a vetKey
+// derived from their principal, which the canister obtains encrypted under a
+// transport key of the client with `ic.vault.encryptedKey(transportPublicKey)`.
+// The client decrypts the vetKey, derives a symmetric key from it and uses it
+// to encrypt and decrypt its secrets. `ic.vault.publicKey()` returns the vetKD
+// public key needed to verify the vetKey.
+//
+// `ic.vault.store(name, ciphertext)` and `ic.vault.load(name)` keep the
+// ciphertexts of the caller in stable memory. Anonymous callers are rejected.
+use std::cell::RefCell;
+
+use candid::Principal;
+use ic_stable_structures::StableBTreeMap;
+use quickjs_wasm_rs::{CallbackArg, JSContextRef, JSError, JSValue, JSValueRef};
+
+use crate::{
+    crypto, engine, jobs,
+    stable::{self, Candid, Memory},
+    vetkd,
+};
+
+// Separates the keys of the vault from other vetKD keys of the canister.
+const DERIVATION_CONTEXT: &[u8] = b"ic-quickjs-vault";
+
+thread_local! {
+    // Maps `<owner>/<name>` to the ciphertext of a secret.
+    static SECRETS: RefCell<StableBTreeMap<String, Candid<Vec<u8>>, Memory>> =
+        RefCell::new(StableBTreeMap::init(stable::memory(stable::VAULT)));
+}
+
+fn owner() -> Result<Principal, String> {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return Err("The vault rejects anonymous callers".to_string());
+    }
+    Ok(caller)
+}
+
+fn secret_key(owner: &Principal, name: &str) -> String {
+    format!("{}/{}", owner, name)
+}
+
+/// Returns the vetKD public key of the vault.
+pub async fn public_key() -> Result<Vec<u8>, String> {
+    vetkd::public_key(DERIVATION_CONTEXT).await
+}
+
+/// Returns the vetKey of the given owner encrypted under the transport key.
+pub async fn encrypted_key(
+    owner: Principal,
+    transport_public_key: Vec<u8>,
+) -> Result<Vec<u8>, String> {
+    vetkd::encrypted_key(
+        DERIVATION_CONTEXT,
+        owner.as_slice().to_vec(),
+        transport_public_key,
+    )
+    .await
+}
+
+pub fn store(owner: &Principal, name: &str, ciphertext: Vec<u8>) {
+    SECRETS.with(|secrets| {
+        secrets
+            .borrow_mut()
+            .insert(secret_key(owner, name), Candid(ciphertext))
+    });
+}
+
+pub fn load(owner: &Principal, name: &str) -> Option<Vec<u8>> {
+    SECRETS.with(|secrets| {
+        secrets
+            .borrow()
+            .get(&secret_key(owner, name))
+            .map(|ciphertext| ciphertext.0)
+    })
+}
+
+pub fn remove(owner: &Principal, name: &str) -> bool {
+    SECRETS.with(|secrets| {
+        secrets
+            .borrow_mut()
+            .remove(&secret_key(owner, name))
+            .is_some()
+    })
+}
+
+fn configure(options: &JSValue) -> Result<(), anyhow::Error> {
+    let JSValue::Object(fields) = options else {
+        return Err(JSError::Type("Expected an options object".to_string()).into());
+    };
+    let key_name = match fields.get("keyName") {
+        Some(JSValue::String(name)) => Some(name.clone()),
+        Some(_) => return Err(JSError::Type("Expected keyName to be a string".to_string()).into()),
+        None => None,
+    };
+    let cycles = fields
+        .get("cycles")
+        .map(|cycles| jobs::number(cycles, "cycles"))
+        .transpose()?;
+    vetkd::configure(|config| {
+        if let Some(key_name) = key_name {
+            config.key_name = key_name;
+        }
+        if let Some(cycles) = cycles {
+            config.cycles = cycles as u128;
+        }
+    });
+    Ok(())
+}
+
+pub fn link(context: &JSContextRef) -> Result<(), anyhow::Error> {
+    // Usage: `ic.vault.configure({keyName, cycles})`. The vetKD key also
+    // encrypts the KV namespaces passed to `ic.kv.encrypt()`.
+    fn configure<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 1 {
+            return Err(JSError::Type(format!("Expected 1 argument, got {}", args.len())).into());
+        }
+        engine::ensure_writable("ic.vault.configure()")?;
+        self::configure(&args[0].to_js_value()?)?;
+        context.undefined_value()
+    }
+
+    // Returns a promise that resolves with the public key as an ArrayBuffer.
+    fn public_key<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if !args.is_empty() {
+            return Err(JSError::Type(format!("Expected 0 arguments, got {}", args.len())).into());
+        }
+        engine::charge_call(context, 0)?;
+        engine::promise_for(context, async move {
+            Ok(JSValue::ArrayBuffer(self::public_key().await?))
+        })
+    }
+
+    // Returns a promise that resolves with the encrypted vetKey of the caller
+    // as an ArrayBuffer.
+    fn encrypted_key<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 1 {
+            return Err(JSError::Type(format!("Expected 1 argument, got {}", args.len())).into());
+        }
+        let owner = owner().map_err(anyhow::Error::msg)?;
+        let transport_public_key = crypto::bytes(&args[0])?;
+        engine::charge_call(context, vetkd::cycles())?;
+        engine::promise_for(context, async move {
+            Ok(JSValue::ArrayBuffer(
+                self::encrypted_key(owner, transport_public_key).await?,
+            ))
+        })
+    }
+
+    fn store<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 2 {
+            return Err(JSError::Type(format!("Expected 2 arguments, got {}", args.len())).into());
+        }
+        engine::ensure_writable("ic.vault.store()")?;
+        let owner = owner().map_err(anyhow::Error::msg)?;
+        let name: String = args[0].try_into()?;
+        self::store(&owner, &name, crypto::bytes(&args[1])?);
+        context.undefined_value()
+    }
+
+    // Returns the ciphertext as an ArrayBuffer or `undefined`.
+    fn load<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 1 {
+            return Err(JSError::Type(format!("Expected 1 argument, got {}", args.len())).into());
+        }
+        let owner = owner().map_err(anyhow::Error::msg)?;
+        let name: String = args[0].try_into()?;
+        match self::load(&owner, &name) {
+            Some(ciphertext) => context.array_buffer_value(&ciphertext),
+            None => context.undefined_value(),
+        }
+    }
+
+    fn remove<'a>(
+        context: &'a JSContextRef,
+        _this: &CallbackArg,
+        args: &[CallbackArg],
+    ) -> Result<JSValueRef<'a>, anyhow::Error> {
+        if args.len() != 1 {
+            return Err(JSError::Type(format!("Expected 1 argument, got {}", args.len())).into());
+        }
+        engine::ensure_writable("ic.vault.remove()")?;
+        let owner = owner().map_err(anyhow::Error::msg)?;
+        let name: String = args[0].try_into()?;
+        context.value_from_bool(self::remove(&owner, &name))
+    }
+
+    let vault = context.object_value()?;
+    vault.set_property("configure", context.wrap_callback2(configure)?)?;
+    vault.set_property("publicKey", context.wrap_callback2(public_key)?)?;
+    vault.set_property("encryptedKey", context.wrap_callback2(encrypted_key)?)?;
+    vault.set_property("store", context.wrap_callback2(store)?)?;
+    vault.set_property("load", context.wrap_callback2(load)?)?;
+    vault.set_property("remove", context.wrap_callback2(remove)?)?;
+
+    engine::namespace(context)?.set_property("vault", vault)?;
+    Ok(())
+}
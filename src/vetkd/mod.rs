@@ -0,0 +1,143 @@
+// Bindings of the vetKD methods of the management canister.
+//
+// Derived keys are identified by a context that separates the uses of vetKD
+// within the canister and an input within the context, e.g. a principal.
+use std::cell::RefCell;
+
+use candid::{CandidType, Deserialize, Principal};
+use ic_vetkeys::{DerivedPublicKey, EncryptedVetKey, TransportSecretKey, VetKey};
+
+use crate::call_policy;
+
+// The fee of `vetkd_derive_key` with the production key.
+const DEFAULT_CYCLES: u128 = 26_153_846_153;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+enum VetKdCurve {
+    #[serde(rename = "bls12_381_g2")]
+    Bls12381G2,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct VetKdKeyId {
+    curve: VetKdCurve,
+    name: String,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct VetKdPublicKeyArgs {
+    canister_id: Option<Principal>,
+    context: Vec<u8>,
+    key_id: VetKdKeyId,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct VetKdPublicKeyResult {
+    public_key: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct VetKdDeriveKeyArgs {
+    input: Vec<u8>,
+    context: Vec<u8>,
+    transport_public_key: Vec<u8>,
+    key_id: VetKdKeyId,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct VetKdDeriveKeyResult {
+    encrypted_key: Vec<u8>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub key_name: String,
+    /// The cycles attached to `vetkd_derive_key`.
+    pub cycles: u128,
+}
+
+thread_local! {
+    static CONFIG: RefCell<Config> = RefCell::new(Config {
+        key_name: "key_1".to_string(),
+        cycles: DEFAULT_CYCLES,
+    });
+}
+
+pub fn configure(update: impl FnOnce(&mut Config)) {
+    CONFIG.with(|config| update(&mut config.borrow_mut()));
+}
+
+/// Returns the cycles attached to `vetkd_derive_key`.
+pub fn cycles() -> u128 {
+    CONFIG.with(|config| config.borrow().cycles)
+}
+
+fn key_id() -> VetKdKeyId {
+    VetKdKeyId {
+        curve: VetKdCurve::Bls12381G2,
+        name: CONFIG.with(|config| config.borrow().key_name.clone()),
+    }
+}
+
+/// Returns the public key of the given context of this canister.
+pub async fn public_key(context: &[u8]) -> Result<Vec<u8>, String> {
+    let args = VetKdPublicKeyArgs {
+        canister_id: None,
+        context: context.to_vec(),
+        key_id: key_id(),
+    };
+    call_policy::check(&Principal::management_canister(), "vetkd_public_key")?;
+    let (result,): (VetKdPublicKeyResult,) = ic_cdk::call(
+        Principal::management_canister(),
+        "vetkd_public_key",
+        (args,),
+    )
+    .await
+    .map_err(|(code, message)| {
+        format!("vetkd_public_key failed with code {:?}: {}", code, message)
+    })?;
+    Ok(result.public_key)
+}
+
+/// Returns the vetKey of the given input encrypted under the transport key.
+pub async fn encrypted_key(
+    context: &[u8],
+    input: Vec<u8>,
+    transport_public_key: Vec<u8>,
+) -> Result<Vec<u8>, String> {
+    let args = VetKdDeriveKeyArgs {
+        input,
+        context: context.to_vec(),
+        transport_public_key,
+        key_id: key_id(),
+    };
+    let cycles = cycles();
+    call_policy::check(&Principal::management_canister(), "vetkd_derive_key")?;
+    let (result,): (VetKdDeriveKeyResult,) = ic_cdk::api::call::call_with_payment128(
+        Principal::management_canister(),
+        "vetkd_derive_key",
+        (args,),
+        cycles,
+    )
+    .await
+    .map_err(|(code, message)| {
+        format!("vetkd_derive_key failed with code {:?}: {}", code, message)
+    })?;
+    Ok(result.encrypted_key)
+}
+
+/// Derives the vetKey of the given input for the canister itself. The key is
+/// decrypted on the heap, so it is visible to the nodes of the subnet.
+pub async fn own_key(context: &[u8], input: Vec<u8>) -> Result<VetKey, String> {
+    let (seed,) = ic_cdk::api::management_canister::main::raw_rand()
+        .await
+        .map_err(|(code, message)| format!("raw_rand failed with code {:?}: {}", code, message))?;
+    let transport_key = TransportSecretKey::from_seed(seed)?;
+    let public_key = DerivedPublicKey::deserialize(&public_key(context).await?)
+        .map_err(|err| format!("Invalid vetKD public key: {:?}", err))?;
+    let encrypted = encrypted_key(context, input.clone(), transport_key.public_key()).await?;
+    EncryptedVetKey::deserialize(&encrypted)
+        .map_err(|err| format!("Invalid encrypted vetKey: {}", err))?
+        .decrypt_and_verify(&transport_key, &public_key, &input)
+        .map_err(|err| format!("Failed to decrypt the vetKey: {}", err))
+}